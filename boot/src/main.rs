@@ -0,0 +1,194 @@
+// Mod for the runner, which turns a kernel binary into a disk image and boots it in QEMU.
+// cargo calls it with the path of the kernel ELF file for `cargo run` and for every
+// test binary, followed by any extra arguments, which are passed on to QEMU:
+//     cargo run -- -m 1G
+// Test binaries live in target/<target>/debug/deps, that's how they are told apart from
+// the kernel. They get TEST_ARGS and exit through the isa-debug-exit device, whose exit
+// code is (value << 1) | 1, so QemuExitCode::Success comes out as 33. A test binary
+// that hasn't exited after TEST_TIMEOUT is killed and counts as failed.
+// Tests also get a FAT32 image on the primary slave, made fresh for every run with the
+// same fatfs crate the bootloader builds its boot partition with. tests/fat.rs reads it.
+// The image boots through the BIOS by default. JONATHAN_OS_BOOT=uefi boots a UEFI image
+// instead, with the OVMF firmware from OVMF_PATH.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::Duration;
+
+use bootloader::{BiosBoot, UefiBoot};
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use wait_timeout::ChildExt;
+
+// COM2 carries the GDB stub, attach with `target remote :4321`.
+const RUN_ARGS: &[&str] = &["-serial", "stdio", "-serial", "tcp::4321,server,nowait"];
+const TEST_ARGS: &[&str] = &[
+    "-device",
+    "isa-debug-exit,iobase=0xf4,iosize=0x04",
+    "-serial",
+    "stdio",
+    "--display",
+    "none",
+    "-smp",
+    "4",
+    "-fw_cfg",
+    "name=opt/jonathan_os/test-fixture,string=hello",
+];
+const TEST_SUCCESS_EXIT_CODE: i32 = 33;
+// Same as bootimage's test-timeout. The kernel's own watchdog ends single hung tests
+// sooner, this is for hangs it can't see, like a test spinning with interrupts off.
+const TEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+const DEFAULT_OVMF_PATH: &str = "/usr/share/ovmf/OVMF.fd";
+
+// FAT32 needs at least 65525 clusters, fatfs makes them 512 bytes at this size.
+const FAT_IMAGE_SIZE: u64 = 40 * 1024 * 1024;
+const FAT_IMAGE_LABEL: [u8; 11] = *b"HOST       ";
+// Byte i of docs/A file with a long name.bin is i % 251.
+const FAT_IMAGE_BIG_SIZE: usize = 20 * 1024 + 7;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Firmware {
+    Bios,
+    Uefi,
+}
+
+impl Firmware {
+    fn from_env() -> Firmware {
+        match env::var("JONATHAN_OS_BOOT").as_deref() {
+            Ok("uefi") => Firmware::Uefi,
+            Ok("bios") | Err(_) => Firmware::Bios,
+            Ok(other) => fail(&format!(
+                "JONATHAN_OS_BOOT must be bios or uefi, not {:?}",
+                other
+            )),
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args_os().skip(1);
+    let kernel = match args.next() {
+        Some(kernel) => PathBuf::from(kernel),
+        None => fail("usage: jonathan_os_boot <kernel> [qemu args...]"),
+    };
+    let extra_args: Vec<_> = args.collect();
+    let firmware = Firmware::from_env();
+    let test = is_test(&kernel);
+
+    let image = create_image(&kernel, firmware);
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    qemu.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()));
+    if firmware == Firmware::Uefi {
+        let ovmf = env::var_os("OVMF_PATH").unwrap_or_else(|| DEFAULT_OVMF_PATH.into());
+        qemu.arg("-bios").arg(ovmf);
+    }
+    qemu.args(if test { TEST_ARGS } else { RUN_ARGS });
+    if test {
+        let fat_image = create_fat_image(&kernel);
+        qemu.arg("-drive")
+            .arg(format!("format=raw,file={},index=1", fat_image.display()));
+    }
+    qemu.args(extra_args);
+
+    let mut child = match qemu.spawn() {
+        Ok(child) => child,
+        Err(error) => fail(&format!("failed to start QEMU: {}", error)),
+    };
+    let status = if test {
+        match child.wait_timeout(TEST_TIMEOUT) {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                fail(&format!("test timed out after {:?}", TEST_TIMEOUT));
+            }
+            Err(error) => Err(error),
+        }
+    } else {
+        child.wait()
+    };
+    let code = match status {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(error) => fail(&format!("failed to wait for QEMU: {}", error)),
+    };
+    if test {
+        process::exit(if code == TEST_SUCCESS_EXIT_CODE { 0 } else { 1 });
+    }
+    process::exit(code);
+}
+
+fn is_test(kernel: &Path) -> bool {
+    kernel
+        .parent()
+        .and_then(Path::file_name)
+        .is_some_and(|dir| dir == "deps")
+}
+
+// Next to the kernel, so each test binary gets its own image.
+fn create_image(kernel: &Path, firmware: Firmware) -> PathBuf {
+    let (image, result) = match firmware {
+        Firmware::Bios => {
+            let image = kernel.with_extension("bios.img");
+            let result = BiosBoot::new(kernel).create_disk_image(&image);
+            (image, result)
+        }
+        Firmware::Uefi => {
+            let image = kernel.with_extension("uefi.img");
+            let result = UefiBoot::new(kernel).create_disk_image(&image);
+            (image, result)
+        }
+    };
+    if let Err(error) = result {
+        fail(&format!("failed to create a disk image: {}", error));
+    }
+    image
+}
+
+fn create_fat_image(kernel: &Path) -> PathBuf {
+    let image = kernel.with_extension("fat.img");
+    if let Err(error) = write_fat_image(&image) {
+        fail(&format!("failed to create the FAT test image: {}", error));
+    }
+    image
+}
+
+fn write_fat_image(image: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image)?;
+    file.set_len(FAT_IMAGE_SIZE)?;
+    let options = FormatVolumeOptions::new()
+        .fat_type(FatType::Fat32)
+        .volume_label(FAT_IMAGE_LABEL);
+    fatfs::format_volume(&mut file, options)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let fs = FileSystem::new(&mut file, FsOptions::new())?;
+    {
+        let root = fs.root_dir();
+        root.create_file("hello.txt")?
+            .write_all(b"Hello from the host!\n")?;
+        let big: Vec<u8> = (0..FAT_IMAGE_BIG_SIZE)
+            .map(|index| (index % 251) as u8)
+            .collect();
+        root.create_dir("docs")?
+            .create_file("A file with a long name.bin")?
+            .write_all(&big)?;
+    }
+    // Counting the free clusters stores them in FSINFO, so the kernel starts from a known count.
+    fs.stats()?;
+    fs.unmount()
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("jonathan_os_boot: {}", message);
+    process::exit(1);
+}
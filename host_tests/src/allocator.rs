@@ -0,0 +1,281 @@
+// Mod for running the kernel's heap allocators on the host.
+// The allocator files are the kernel's own. All they need from around them is
+// `super::align_up` and `super::Locked`, which is a plain spin lock here: the kernel's
+// turns interrupts off, which a user space process can't do. The heap they hand out
+// is an arena from the host's allocator instead of mapped pages.
+// The property tests run random sequences of allocations and frees, and check every
+// allocation against what's still live: it has to be aligned, inside the arena, not
+// overlap another one, and keep what was written to it until it's freed.
+
+use std::alloc::{self, GlobalAlloc, Layout};
+
+use proptest::prelude::*;
+
+#[path = "../../src/allocator/align.rs"]
+mod align;
+#[path = "../../src/allocator/bump.rs"]
+mod bump;
+#[path = "../../src/allocator/fixed_size_block.rs"]
+mod fixed_size_block;
+#[path = "../../src/allocator/linked_list.rs"]
+mod linked_list;
+
+use self::align::align_up;
+use self::bump::BumpAllocator;
+use self::fixed_size_block::FixedSizeBlockAllocator;
+use self::linked_list::LinkedListAllocator;
+
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+//  ---Arena---
+
+const ARENA_SIZE: usize = 64 * 1024;
+
+// Page aligned, like the kernel heap.
+struct Arena {
+    start: *mut u8,
+    layout: Layout,
+}
+
+impl Arena {
+    fn new() -> Self {
+        let layout = Layout::from_size_align(ARENA_SIZE, 4096).unwrap();
+        let start = unsafe { alloc::alloc(layout) };
+        assert!(!start.is_null());
+        Arena { start, layout }
+    }
+
+    fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + ARENA_SIZE
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.start, self.layout) };
+    }
+}
+
+// The allocators keep their free lists in the arena, so they go before it.
+fn bump(arena: &Arena) -> Locked<BumpAllocator> {
+    let allocator = Locked::new(BumpAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), ARENA_SIZE) };
+    allocator
+}
+
+fn linked_list(arena: &Arena) -> Locked<LinkedListAllocator> {
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), ARENA_SIZE) };
+    allocator
+}
+
+fn fixed_size_block(arena: &Arena) -> Locked<FixedSizeBlockAllocator> {
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), ARENA_SIZE) };
+    allocator
+}
+
+//  ---Oracle---
+
+#[derive(Debug, Clone)]
+enum Op {
+    Alloc { size: usize, align: usize },
+    // Frees the live allocation at this index, modulo how many there are.
+    Free(usize),
+}
+
+fn ops() -> impl Strategy<Value = Vec<Op>> {
+    // Small sizes hit the fixed size blocks, the bigger ones their fallback.
+    let size = prop_oneof![1..=64usize, 1..=4096usize];
+    let op = prop_oneof![
+        3 => (size, 0..=12u32).prop_map(|(size, shift)| Op::Alloc { size, align: 1 << shift }),
+        2 => any::<usize>().prop_map(Op::Free),
+    ];
+    prop::collection::vec(op, 1..200)
+}
+
+struct Allocation {
+    ptr: *mut u8,
+    layout: Layout,
+    fill: u8,
+}
+
+impl Allocation {
+    fn start(&self) -> usize {
+        self.ptr as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.layout.size()
+    }
+}
+
+// Runs `ops` on `allocator`, checking it against the allocations that are live.
+fn check_ops(allocator: &impl GlobalAlloc, arena: &Arena, ops: &[Op]) -> Result<(), TestCaseError> {
+    let mut live: Vec<Allocation> = Vec::new();
+
+    for (number, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Alloc { size, align } => {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = unsafe { allocator.alloc(layout) };
+                // Running out of memory is fine.
+                if ptr.is_null() {
+                    continue;
+                }
+
+                let allocation = Allocation {
+                    ptr,
+                    layout,
+                    fill: number as u8,
+                };
+                prop_assert_eq!(allocation.start() % align, 0, "{:?} is misaligned", layout);
+                prop_assert!(
+                    allocation.start() >= arena.start() && allocation.end() <= arena.end(),
+                    "{:?} at {:#x} is outside the arena",
+                    layout,
+                    allocation.start()
+                );
+                for other in &live {
+                    prop_assert!(
+                        allocation.end() <= other.start() || other.end() <= allocation.start(),
+                        "{:?} at {:#x} overlaps {:?} at {:#x}",
+                        layout,
+                        allocation.start(),
+                        other.layout,
+                        other.start()
+                    );
+                }
+
+                unsafe { allocation.ptr.write_bytes(allocation.fill, size) };
+                live.push(allocation);
+            }
+            Op::Free(index) => {
+                if !live.is_empty() {
+                    let allocation = live.swap_remove(index % live.len());
+                    free(allocator, allocation)?;
+                }
+            }
+        }
+    }
+
+    for allocation in live {
+        free(allocator, allocation)?;
+    }
+    Ok(())
+}
+
+fn free(allocator: &impl GlobalAlloc, allocation: Allocation) -> Result<(), TestCaseError> {
+    let bytes = unsafe { std::slice::from_raw_parts(allocation.ptr, allocation.layout.size()) };
+    prop_assert!(
+        bytes.iter().all(|&byte| byte == allocation.fill),
+        "{:?} at {:#x} was overwritten while in use",
+        allocation.layout,
+        allocation.start()
+    );
+    unsafe { allocator.dealloc(allocation.ptr, allocation.layout) };
+    Ok(())
+}
+
+//  ---Tests---
+
+proptest! {
+    #[test]
+    fn align_up_is_the_next_multiple(addr in 0..usize::MAX / 2, shift in 0..32u32) {
+        let align = 1 << shift;
+        let aligned = align_up(addr, align);
+        prop_assert_eq!(aligned % align, 0);
+        prop_assert!(aligned >= addr);
+        prop_assert!(aligned - addr < align);
+    }
+
+    #[test]
+    fn bump_allocations_are_valid(ops in ops()) {
+        let arena = Arena::new();
+        check_ops(&bump(&arena), &arena, &ops)?;
+    }
+
+    #[test]
+    fn linked_list_allocations_are_valid(ops in ops()) {
+        let arena = Arena::new();
+        check_ops(&linked_list(&arena), &arena, &ops)?;
+    }
+
+    #[test]
+    fn fixed_size_block_allocations_are_valid(ops in ops()) {
+        let arena = Arena::new();
+        check_ops(&fixed_size_block(&arena), &arena, &ops)?;
+    }
+}
+
+#[test]
+fn bump_starts_over_when_everything_is_freed() {
+    let arena = Arena::new();
+    let allocator = bump(&arena);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        let first = allocator.alloc(layout);
+        let second = allocator.alloc(layout);
+        allocator.dealloc(first, layout);
+        allocator.dealloc(second, layout);
+
+        let whole = Layout::from_size_align(ARENA_SIZE, 1).unwrap();
+        assert_eq!(allocator.alloc(whole) as usize, arena.start());
+    }
+}
+
+#[test]
+fn linked_list_reuses_freed_regions() {
+    let arena = Arena::new();
+    let allocator = linked_list(&arena);
+    let layout = Layout::from_size_align(256, 16).unwrap();
+    unsafe {
+        let first = allocator.alloc(layout);
+        allocator.dealloc(first, layout);
+        assert_eq!(allocator.alloc(layout), first);
+    }
+}
+
+#[test]
+fn fixed_size_block_reuses_blocks_of_the_same_size() {
+    let arena = Arena::new();
+    let allocator = fixed_size_block(&arena);
+    unsafe {
+        // Both go in 32 byte blocks.
+        let first = allocator.alloc(Layout::from_size_align(24, 8).unwrap());
+        allocator.dealloc(first, Layout::from_size_align(24, 8).unwrap());
+        assert_eq!(
+            allocator.alloc(Layout::from_size_align(32, 4).unwrap()),
+            first
+        );
+    }
+}
+
+#[test]
+fn too_big_allocations_fail() {
+    let arena = Arena::new();
+    let layout = Layout::from_size_align(ARENA_SIZE + 1, 8).unwrap();
+    unsafe {
+        assert!(bump(&arena).alloc(layout).is_null());
+        assert!(linked_list(&arena).alloc(layout).is_null());
+        assert!(fixed_size_block(&arena).alloc(layout).is_null());
+    }
+}
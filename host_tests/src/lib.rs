@@ -0,0 +1,9 @@
+// Tests for the kernel's pure-logic modules, built for the host.
+// Testing anything in the kernel itself means booting it in QEMU. The modules here
+// don't touch the hardware, so their source files are included by path and run under
+// the normal test harness, with proptest and the sanitizers:
+//     cargo test
+//     RUSTFLAGS=-Zsanitizer=address cargo +nightly test --target x86_64-unknown-linux-gnu
+
+#[cfg(test)]
+mod allocator;
@@ -0,0 +1,101 @@
+// Proc macros for the kernel.
+// They live in their own crate because proc macros have to, and get built for the host.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Attribute, ItemFn, Lit, Meta, NestedMeta};
+
+/// Marks a kernel test, like `#[test_case]` but with metadata for the runner.
+///
+/// The std attributes for that go below it and work the same way:
+///
+/// ```ignore
+/// #[kernel_test]
+/// #[should_panic(expected = "index out of bounds")]
+/// fn out_of_bounds() { ... }
+///
+/// #[kernel_test]
+/// #[ignore]
+/// fn slow() { ... }
+/// ```
+#[proc_macro_attribute]
+pub fn kernel_test(args: TokenStream, item: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        let args = TokenStream2::from(args);
+        return syn::Error::new_spanned(args, "kernel_test takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let mut function = parse_macro_input!(item as ItemFn);
+
+    match expand(&mut function) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(function: &mut ItemFn) -> syn::Result<TokenStream2> {
+    if !function.sig.inputs.is_empty() || function.sig.asyncness.is_some() {
+        return Err(syn::Error::new_spanned(
+            &function.sig,
+            "kernel tests are plain fn() functions",
+        ));
+    }
+
+    let mut should_panic = quote!(::jonathan_os::testing::ShouldPanic::No);
+    let mut ignore = false;
+    let mut attrs = Vec::new();
+    for attr in function.attrs.drain(..) {
+        if attr.path.is_ident("should_panic") {
+            should_panic = parse_should_panic(&attr)?;
+        } else if attr.path.is_ident("ignore") {
+            ignore = true;
+        } else {
+            attrs.push(attr);
+        }
+    }
+    function.attrs = attrs;
+
+    let name = &function.sig.ident;
+    let test = format_ident!("__{}_TEST", name, span = Span::call_site());
+    Ok(quote! {
+        #[cfg(test)]
+        #function
+
+        #[cfg(test)]
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        const #test: ::jonathan_os::testing::Test = ::jonathan_os::testing::Test {
+            name: concat!(module_path!(), "::", stringify!(#name)),
+            function: #name,
+            should_panic: #should_panic,
+            ignore: #ignore,
+        };
+    })
+}
+
+// #[should_panic] or #[should_panic(expected = "...")]
+fn parse_should_panic(attr: &Attribute) -> syn::Result<TokenStream2> {
+    match attr.parse_meta()? {
+        Meta::Path(_) => Ok(quote!(::jonathan_os::testing::ShouldPanic::Yes)),
+        Meta::List(list) if list.nested.len() == 1 => match &list.nested[0] {
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("expected") => {
+                match &pair.lit {
+                    Lit::Str(expected) => Ok(quote!(
+                        ::jonathan_os::testing::ShouldPanic::WithMessage(#expected)
+                    )),
+                    lit => Err(syn::Error::new_spanned(lit, "expected a string")),
+                }
+            }
+            nested => Err(syn::Error::new_spanned(
+                nested,
+                "expected `expected = \"...\"`",
+            )),
+        },
+        meta => Err(syn::Error::new_spanned(
+            meta,
+            "expected #[should_panic] or #[should_panic(expected = \"...\")]",
+        )),
+    }
+}
@@ -0,0 +1,396 @@
+// Mod for the ACPI tables.
+// The firmware leaves a Root System Description Pointer somewhere in the BIOS area, or
+// under UEFI in its system table, where the bootloader picks it up. It points at the
+// RSDT (32 bit pointers) or on newer machines the XSDT (64 bit pointers), which list
+// every other table. Each table starts with the same header, is told apart by its 4
+// letter signature, and has a checksum making all its bytes sum to 0.
+// The tables we care about get parsed once into a Platform, which the APIC, PCI and
+// power management code query instead of digging through tables themselves.
+// Everything is read through the physical memory mapping, so memory::init must run first.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::{boot, memory};
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use self::fadt::Fadt;
+use self::hpet::HpetInfo;
+use self::madt::{InterruptOverride, IoApicInfo, LocalApicNmi, Madt, Processor};
+use self::mcfg::PciSegment;
+use crate::apic::{Polarity, Trigger};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// Revision 0 checksums only cover the first 20 bytes.
+const RSDP_V1_LENGTH: usize = 20;
+
+// The EBDA segment is stored at this address in the BIOS data area.
+const EBDA_POINTER: u64 = 0x40E;
+const EBDA_SEARCH_LENGTH: u64 = 1024;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AcpiError {
+    /// memory::init hasn't run, so the tables can't be reached.
+    NoMemoryMapping,
+    /// No valid RSDP in the BIOS area. Probably not an ACPI machine.
+    NoRsdp,
+    /// The table with this signature doesn't sum to 0.
+    BadChecksum([u8; 4]),
+    /// The table with this signature is shorter than its contents need.
+    Truncated([u8; 4]),
+    /// The table with this signature holds something we can't use.
+    Unsupported([u8; 4]),
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Everything below only exists from revision 2 on.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every system description table starts with.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A table found through the RSDT or XSDT.
+#[derive(Debug, Copy, Clone)]
+pub struct Table {
+    pub address: PhysAddr,
+    pub header: SdtHeader,
+}
+
+impl Table {
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &'static [u8] {
+        let start = memory::phys_to_virt(self.address).as_ptr::<u8>();
+        unsafe { core::slice::from_raw_parts(start, self.header.length as usize) }
+    }
+}
+
+//  ---Helpers---
+
+/// Whether the bytes sum to 0, the way every ACPI structure is checked.
+pub fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Reads a `T` from physical memory, which doesn't have to be aligned.
+pub(crate) unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(memory::phys_to_virt(addr).as_ptr::<T>())
+}
+
+/// Reads a little endian `T` at `offset` in a table, or `None` if the table is too short.
+pub(crate) fn field<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    if offset + size_of::<T>() > bytes.len() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(bytes[offset..].as_ptr() as *const T) })
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// ACPI's way of pointing at a register, in memory, IO space or PCI config space.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub(crate) fn parse(bytes: &[u8], offset: usize) -> Option<GenericAddress> {
+        let space = match field::<u8>(bytes, offset)? {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+
+        Some(GenericAddress {
+            space,
+            bit_width: field(bytes, offset + 1)?,
+            bit_offset: field(bytes, offset + 2)?,
+            access_size: field(bytes, offset + 3)?,
+            address: field(bytes, offset + 4)?,
+        })
+    }
+}
+
+//  ---Table Discovery---
+
+fn scan_for_rsdp(start: u64, end: u64) -> Option<Rsdp> {
+    // The RSDP is always on a 16 byte boundary.
+    (start..end)
+        .step_by(16)
+        .map(PhysAddr::new)
+        .filter(|&addr| unsafe { read_phys::<[u8; 8]>(addr) } == *RSDP_SIGNATURE)
+        .map(|addr| unsafe { read_phys::<Rsdp>(addr) })
+        .find(rsdp_valid)
+}
+
+fn rsdp_valid(rsdp: &Rsdp) -> bool {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(rsdp as *const Rsdp as *const u8, size_of::<Rsdp>())
+    };
+    if !checksum_ok(&bytes[..RSDP_V1_LENGTH]) {
+        return false;
+    }
+
+    rsdp.revision < 2 || checksum_ok(bytes)
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    if let Some(address) = boot::rsdp_address() {
+        let rsdp = unsafe { read_phys::<Rsdp>(address) };
+        if rsdp_valid(&rsdp) {
+            return Some(rsdp);
+        }
+    }
+
+    // The BIOS leaves it in the EBDA or the BIOS area.
+    let ebda = u64::from(unsafe { read_phys::<u16>(PhysAddr::new(EBDA_POINTER)) }) << 4;
+    let found = if ebda != 0 {
+        scan_for_rsdp(ebda, ebda + EBDA_SEARCH_LENGTH)
+    } else {
+        None
+    };
+
+    found.or_else(|| scan_for_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+}
+
+fn read_table(address: PhysAddr) -> Table {
+    Table {
+        address,
+        header: unsafe { read_phys::<SdtHeader>(address) },
+    }
+}
+
+fn check_table(table: &Table) -> Result<(), AcpiError> {
+    if (table.header.length as usize) < size_of::<SdtHeader>() {
+        return Err(AcpiError::Truncated(table.header.signature));
+    }
+    if !checksum_ok(table.bytes()) {
+        return Err(AcpiError::BadChecksum(table.header.signature));
+    }
+    Ok(())
+}
+
+/// Calls `f` with every table listed in the XSDT, or the RSDT if there is no XSDT.
+///
+/// Tables with a bad checksum or an address that can't exist are skipped.
+pub fn for_each_table<F: FnMut(Table)>(mut f: F) -> Result<(), AcpiError> {
+    if memory::physical_memory_offset().is_none() {
+        return Err(AcpiError::NoMemoryMapping);
+    }
+    let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        let xsdt =
+            PhysAddr::try_new(rsdp.xsdt_address).map_err(|_| AcpiError::Unsupported(*b"XSDT"))?;
+        (xsdt, 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+
+    let root = read_table(root);
+    check_table(&root)?;
+
+    let entries = (root.header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    for index in 0..entries {
+        let entry = root.address + size_of::<SdtHeader>() + index * entry_size;
+        let address = match entry_size {
+            8 => unsafe { read_phys::<u64>(entry) },
+            _ => u64::from(unsafe { read_phys::<u32>(entry) }),
+        };
+
+        let table = match PhysAddr::try_new(address) {
+            Ok(address) => read_table(address),
+            Err(_) => continue,
+        };
+        if check_table(&table).is_ok() {
+            f(table);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the first valid table with the given signature, like `b"APIC"` for the MADT.
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    let mut found = None;
+    let _ = for_each_table(|table| {
+        if found.is_none() && table.header.signature == *signature {
+            found = Some(table);
+        }
+    });
+    found
+}
+
+/// The table at a physical address that isn't listed in the root table, like the DSDT.
+pub fn table_at(address: PhysAddr) -> Result<Table, AcpiError> {
+    let table = read_table(address);
+    check_table(&table)?;
+    Ok(table)
+}
+
+//  ---Platform---
+
+/// Where an ISA IRQ ends up on the I/O APICs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IsaInterrupt {
+    pub gsi: u32,
+    pub trigger: Trigger,
+    pub polarity: Polarity,
+}
+
+/// Everything the firmware told us about the machine.
+#[derive(Debug, Clone)]
+pub struct Platform {
+    pub local_apic_address: Option<PhysAddr>,
+    /// Whether the 8259 PICs are there too, and need masking before the APICs take over.
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub interrupt_overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<HpetInfo>,
+    /// PCI express config space windows from the MCFG. Empty on machines without one.
+    pub pci_segments: Vec<PciSegment>,
+}
+
+impl Platform {
+    // Only fails if the tables can't be found at all. A broken MADT, FADT, HPET or MCFG
+    // is logged and left out, so the rest of the machine is still usable.
+    fn parse() -> Result<Platform, AcpiError> {
+        let mut platform = Platform {
+            local_apic_address: None,
+            has_legacy_pics: true,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            interrupt_overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+            fadt: None,
+            hpet: None,
+            pci_segments: Vec::new(),
+        };
+
+        let mut tables = Vec::new();
+        for_each_table(|table| tables.push(table))?;
+
+        for table in tables {
+            let bytes = table.bytes();
+            let parsed = match &table.header.signature {
+                b"APIC" => Madt::parse(bytes).map(|madt| {
+                    platform.local_apic_address = Some(madt.local_apic_address);
+                    platform.has_legacy_pics = madt.has_legacy_pics;
+                    platform.processors = madt.processors;
+                    platform.io_apics = madt.io_apics;
+                    platform.interrupt_overrides = madt.interrupt_overrides;
+                    platform.local_apic_nmis = madt.local_apic_nmis;
+                }),
+                b"FACP" => Fadt::parse(bytes).map(|fadt| platform.fadt = Some(fadt)),
+                b"HPET" => HpetInfo::parse(bytes).map(|hpet| platform.hpet = Some(hpet)),
+                b"MCFG" => mcfg::parse(bytes).map(|segments| platform.pci_segments = segments),
+                _ => Ok(()),
+            };
+            if let Err(err) = parsed {
+                crate::log!(Warn, "Ignoring ACPI table: {:?}", err);
+            }
+        }
+
+        Ok(platform)
+    }
+
+    /// Processors the firmware says can be started.
+    pub fn usable_processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors.iter().filter(|processor| processor.usable())
+    }
+
+    /// The I/O APIC handling `gsi`.
+    pub fn io_apic_for_gsi(&self, gsi: u32) -> Option<&IoApicInfo> {
+        self.io_apics
+            .iter()
+            .filter(|io_apic| io_apic.gsi_base <= gsi)
+            .max_by_key(|io_apic| io_apic.gsi_base)
+    }
+
+    /// Where ISA `irq` is wired, after any interrupt source overrides.
+    ///
+    /// Without an override ISA interrupts are identity mapped, edge triggered and active high.
+    pub fn isa_interrupt(&self, irq: u8) -> IsaInterrupt {
+        let default = IsaInterrupt {
+            gsi: u32::from(irq),
+            trigger: Trigger::Edge,
+            polarity: Polarity::ActiveHigh,
+        };
+
+        self.interrupt_overrides
+            .iter()
+            .find(|entry| entry.bus == 0 && entry.source == irq)
+            .map_or(default, |entry| IsaInterrupt {
+                gsi: entry.gsi,
+                trigger: entry.trigger.unwrap_or(default.trigger),
+                polarity: entry.polarity.unwrap_or(default.polarity),
+            })
+    }
+}
+
+static PLATFORM: Once<Result<Platform, AcpiError>> = Once::new();
+
+/// Parses the ACPI tables the first time it is called, and returns the result after that.
+///
+/// Needs memory::init and the heap.
+pub fn platform() -> Result<&'static Platform, AcpiError> {
+    if memory::physical_memory_offset().is_none() {
+        return Err(AcpiError::NoMemoryMapping);
+    }
+
+    PLATFORM
+        .call_once(Platform::parse)
+        .as_ref()
+        .map_err(|err| *err)
+}
+
+/// The platform if `platform` already parsed it successfully. Never touches the tables
+/// or the heap, so it is fine to call from anywhere.
+pub fn cached_platform() -> Option<&'static Platform> {
+    PLATFORM.r#try().and_then(|platform| platform.as_ref().ok())
+}
@@ -0,0 +1,113 @@
+// Mod for the Fixed ACPI Description Table, signature "FACP".
+// It holds the fixed hardware registers used for power management and reset, and
+// points at the DSDT. The table grew over the ACPI revisions, so everything past the
+// first version is optional and read only if the table is long enough.
+
+use x86_64::PhysAddr;
+
+use crate::acpi::{field, AcpiError, GenericAddress};
+
+const SIGNATURE: [u8; 4] = *b"FACP";
+
+const DSDT_OFFSET: usize = 40;
+const SCI_INTERRUPT_OFFSET: usize = 46;
+const SMI_COMMAND_OFFSET: usize = 48;
+const ACPI_ENABLE_OFFSET: usize = 52;
+const ACPI_DISABLE_OFFSET: usize = 53;
+const PM1A_EVENT_OFFSET: usize = 56;
+const PM1B_EVENT_OFFSET: usize = 60;
+const PM1A_CONTROL_OFFSET: usize = 64;
+const PM1B_CONTROL_OFFSET: usize = 68;
+const PM_TIMER_OFFSET: usize = 76;
+const PM1_EVENT_LENGTH_OFFSET: usize = 88;
+const CENTURY_OFFSET: usize = 108;
+const BOOT_ARCH_OFFSET: usize = 109;
+const FLAGS_OFFSET: usize = 112;
+const RESET_REGISTER_OFFSET: usize = 116;
+const RESET_VALUE_OFFSET: usize = 128;
+const X_DSDT_OFFSET: usize = 140;
+
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_NO_VGA: u16 = 1 << 2;
+
+const FLAG_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Fadt {
+    pub dsdt_address: PhysAddr,
+    pub sci_interrupt: u16,
+    /// IO port to write `acpi_enable` to for switching from legacy to ACPI mode. 0 if
+    /// the machine is always in ACPI mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1_event_length: u8,
+    /// IO ports of the PM1 control registers, used for sleep states.
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    /// CMOS register holding the RTC century, if there is one.
+    pub century_register: Option<u8>,
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Parses the whole table, header included.
+    pub fn parse(bytes: &[u8]) -> Result<Fadt, AcpiError> {
+        let truncated = AcpiError::Truncated(SIGNATURE);
+        let u32_at = |offset| field::<u32>(bytes, offset).ok_or(truncated);
+        let u8_at = |offset| field::<u8>(bytes, offset).ok_or(truncated);
+
+        // A 64 bit DSDT pointer wins over the 32 bit one when it is set.
+        let dsdt = match field::<u64>(bytes, X_DSDT_OFFSET) {
+            Some(address) if address != 0 => address,
+            _ => u64::from(u32_at(DSDT_OFFSET)?),
+        };
+
+        let flags = field(bytes, FLAGS_OFFSET).unwrap_or(0);
+        let reset_register = if flags & FLAG_RESET_REGISTER_SUPPORTED != 0 {
+            GenericAddress::parse(bytes, RESET_REGISTER_OFFSET)
+        } else {
+            None
+        };
+
+        Ok(Fadt {
+            dsdt_address: PhysAddr::try_new(dsdt).map_err(|_| AcpiError::Unsupported(SIGNATURE))?,
+            sci_interrupt: field(bytes, SCI_INTERRUPT_OFFSET).ok_or(truncated)?,
+            smi_command_port: u32_at(SMI_COMMAND_OFFSET)?,
+            acpi_enable: u8_at(ACPI_ENABLE_OFFSET)?,
+            acpi_disable: u8_at(ACPI_DISABLE_OFFSET)?,
+            pm1a_event_block: u32_at(PM1A_EVENT_OFFSET)?,
+            pm1b_event_block: u32_at(PM1B_EVENT_OFFSET)?,
+            pm1_event_length: u8_at(PM1_EVENT_LENGTH_OFFSET)?,
+            pm1a_control_block: u32_at(PM1A_CONTROL_OFFSET)?,
+            pm1b_control_block: u32_at(PM1B_CONTROL_OFFSET)?,
+            pm_timer_block: u32_at(PM_TIMER_OFFSET)?,
+            century_register: field(bytes, CENTURY_OFFSET).filter(|&register| register != 0),
+            boot_architecture: field(bytes, BOOT_ARCH_OFFSET).unwrap_or(0),
+            flags,
+            reset_register,
+            reset_value: field(bytes, RESET_VALUE_OFFSET).unwrap_or(0),
+        })
+    }
+
+    /// Whether there are legacy ISA devices. Firmware before ACPI 2 leaves this clear,
+    /// so it only means something on newer tables.
+    pub fn has_legacy_devices(&self) -> bool {
+        self.boot_architecture & BOOT_ARCH_LEGACY_DEVICES != 0
+    }
+
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture & BOOT_ARCH_8042 != 0
+    }
+
+    pub fn has_vga(&self) -> bool {
+        self.boot_architecture & BOOT_ARCH_NO_VGA == 0
+    }
+}
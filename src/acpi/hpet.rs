@@ -0,0 +1,44 @@
+// Mod for the HPET description table, signature "HPET".
+
+use x86_64::PhysAddr;
+
+use crate::acpi::{field, AcpiError, AddressSpace, GenericAddress};
+
+const SIGNATURE: [u8; 4] = *b"HPET";
+
+const BLOCK_ID_OFFSET: usize = 36;
+const ADDRESS_OFFSET: usize = 40;
+const NUMBER_OFFSET: usize = 52;
+const MINIMUM_TICK_OFFSET: usize = 53;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HpetInfo {
+    pub address: PhysAddr,
+    /// Which HPET block this is, when there is more than one.
+    pub number: u8,
+    pub comparators: u8,
+    pub pci_vendor_id: u16,
+    /// Smallest number of counter ticks the firmware says is safe for periodic mode.
+    pub minimum_tick: u16,
+}
+
+impl HpetInfo {
+    /// Parses the whole table, header included.
+    pub fn parse(bytes: &[u8]) -> Result<HpetInfo, AcpiError> {
+        let truncated = AcpiError::Truncated(SIGNATURE);
+        let block_id: u32 = field(bytes, BLOCK_ID_OFFSET).ok_or(truncated)?;
+        let address = GenericAddress::parse(bytes, ADDRESS_OFFSET).ok_or(truncated)?;
+        if address.space != AddressSpace::SystemMemory {
+            return Err(AcpiError::Unsupported(SIGNATURE));
+        }
+
+        Ok(HpetInfo {
+            address: PhysAddr::try_new(address.address)
+                .map_err(|_| AcpiError::Unsupported(SIGNATURE))?,
+            number: field(bytes, NUMBER_OFFSET).ok_or(truncated)?,
+            comparators: ((block_id >> 8) & 0x1F) as u8 + 1,
+            pci_vendor_id: (block_id >> 16) as u16,
+            minimum_tick: field(bytes, MINIMUM_TICK_OFFSET).ok_or(truncated)?,
+        })
+    }
+}
@@ -0,0 +1,190 @@
+// Mod for the Multiple APIC Description Table, signature "APIC".
+// After the local APIC address and a flags word comes a list of variable length
+// entries, each starting with a type and a length byte.
+
+use alloc::vec::Vec;
+
+use x86_64::PhysAddr;
+
+use crate::acpi::{field, AcpiError};
+use crate::apic::{Polarity, Trigger};
+
+const SIGNATURE: [u8; 4] = *b"APIC";
+
+const LOCAL_APIC_ADDRESS_OFFSET: usize = 36;
+const FLAGS_OFFSET: usize = 40;
+const ENTRIES_OFFSET: usize = 44;
+
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// Processor ID in a local APIC NMI entry that means every processor.
+const ALL_PROCESSORS: u8 = 0xFF;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    /// Running now. The boot processor is always enabled.
+    pub enabled: bool,
+    /// Not running, but can be brought online.
+    pub online_capable: bool,
+}
+
+impl Processor {
+    pub fn usable(&self) -> bool {
+        self.enabled || self.online_capable
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ wired to a different GSI, or with different trigger settings.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    /// `None` when the bus default applies.
+    pub trigger: Option<Trigger>,
+    pub polarity: Option<Polarity>,
+}
+
+/// Which local APIC LINT pin is connected to NMI.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LocalApicNmi {
+    /// `None` means every processor.
+    pub processor_id: Option<u8>,
+    pub lint: u8,
+    pub trigger: Option<Trigger>,
+    pub polarity: Option<Polarity>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub interrupt_overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+// The MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3. 0 means "bus default".
+fn inti_flags(flags: u16) -> (Option<Trigger>, Option<Polarity>) {
+    let polarity = match flags & 0b11 {
+        0b01 => Some(Polarity::ActiveHigh),
+        0b11 => Some(Polarity::ActiveLow),
+        _ => None,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => Some(Trigger::Edge),
+        0b11 => Some(Trigger::Level),
+        _ => None,
+    };
+    (trigger, polarity)
+}
+
+impl Madt {
+    /// Parses the whole table, header included.
+    pub fn parse(bytes: &[u8]) -> Result<Madt, AcpiError> {
+        let truncated = AcpiError::Truncated(SIGNATURE);
+        let local_apic_address: u32 =
+            field(bytes, LOCAL_APIC_ADDRESS_OFFSET).ok_or(truncated)?;
+        let flags: u32 = field(bytes, FLAGS_OFFSET).ok_or(truncated)?;
+
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(local_apic_address)),
+            has_legacy_pics: flags & FLAG_PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            interrupt_overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let mut offset = ENTRIES_OFFSET;
+        while offset + 2 <= bytes.len() {
+            let length = bytes[offset + 1] as usize;
+            if length < 2 || offset + length > bytes.len() {
+                return Err(truncated);
+            }
+            let entry = &bytes[offset..offset + length];
+            madt.parse_entry(entry)?;
+            offset += length;
+        }
+
+        Ok(madt)
+    }
+
+    fn parse_entry(&mut self, entry: &[u8]) -> Result<(), AcpiError> {
+        let truncated = AcpiError::Truncated(SIGNATURE);
+        match entry[0] {
+            ENTRY_LOCAL_APIC => {
+                let flags: u32 = field(entry, 4).ok_or(truncated)?;
+                self.processors.push(Processor {
+                    processor_id: u32::from(field::<u8>(entry, 2).ok_or(truncated)?),
+                    apic_id: u32::from(field::<u8>(entry, 3).ok_or(truncated)?),
+                    enabled: flags & PROCESSOR_ENABLED != 0,
+                    online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                });
+            }
+            ENTRY_LOCAL_X2APIC => {
+                let flags: u32 = field(entry, 8).ok_or(truncated)?;
+                self.processors.push(Processor {
+                    processor_id: field(entry, 12).ok_or(truncated)?,
+                    apic_id: field(entry, 4).ok_or(truncated)?,
+                    enabled: flags & PROCESSOR_ENABLED != 0,
+                    online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                });
+            }
+            ENTRY_IO_APIC => self.io_apics.push(IoApicInfo {
+                id: field(entry, 2).ok_or(truncated)?,
+                address: PhysAddr::new(u64::from(field::<u32>(entry, 4).ok_or(truncated)?)),
+                gsi_base: field(entry, 8).ok_or(truncated)?,
+            }),
+            ENTRY_INTERRUPT_OVERRIDE => {
+                let (trigger, polarity) = inti_flags(field(entry, 8).ok_or(truncated)?);
+                self.interrupt_overrides.push(InterruptOverride {
+                    bus: field(entry, 2).ok_or(truncated)?,
+                    source: field(entry, 3).ok_or(truncated)?,
+                    gsi: field(entry, 4).ok_or(truncated)?,
+                    trigger,
+                    polarity,
+                });
+            }
+            ENTRY_LOCAL_APIC_NMI => {
+                let processor: u8 = field(entry, 2).ok_or(truncated)?;
+                let (trigger, polarity) = inti_flags(field(entry, 3).ok_or(truncated)?);
+                self.local_apic_nmis.push(LocalApicNmi {
+                    processor_id: Some(processor).filter(|&id| id != ALL_PROCESSORS),
+                    lint: field(entry, 5).ok_or(truncated)?,
+                    trigger,
+                    polarity,
+                });
+            }
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                let address = field(entry, 4).ok_or(truncated)?;
+                self.local_apic_address =
+                    PhysAddr::try_new(address).map_err(|_| AcpiError::Unsupported(SIGNATURE))?;
+            }
+            // Everything else is for hardware we don't drive.
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
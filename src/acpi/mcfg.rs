@@ -0,0 +1,64 @@
+// Mod for the PCI express memory mapped config table, signature "MCFG".
+// Every entry gives the memory window holding the config space of a range of buses.
+
+use alloc::vec::Vec;
+
+use x86_64::PhysAddr;
+
+use crate::acpi::{field, AcpiError};
+
+const SIGNATURE: [u8; 4] = *b"MCFG";
+
+// 8 reserved bytes follow the header.
+const ENTRIES_OFFSET: usize = 44;
+const ENTRY_LENGTH: usize = 16;
+
+// Each function gets 4 KiB, 8 functions per device and 32 devices per bus.
+const BUS_SHIFT: u64 = 20;
+const DEVICE_SHIFT: u64 = 15;
+const FUNCTION_SHIFT: u64 = 12;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PciSegment {
+    /// Where the config space of bus 0 would be, even if the range starts later.
+    pub base_address: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl PciSegment {
+    /// The config space of one function, or `None` if the bus isn't in this segment.
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+
+        let offset = u64::from(bus) << BUS_SHIFT
+            | u64::from(device) << DEVICE_SHIFT
+            | u64::from(function) << FUNCTION_SHIFT;
+        Some(self.base_address + offset)
+    }
+}
+
+/// Parses the whole table, header included.
+pub fn parse(bytes: &[u8]) -> Result<Vec<PciSegment>, AcpiError> {
+    let truncated = AcpiError::Truncated(SIGNATURE);
+    if bytes.len() < ENTRIES_OFFSET {
+        return Err(truncated);
+    }
+
+    bytes[ENTRIES_OFFSET..]
+        .chunks_exact(ENTRY_LENGTH)
+        .map(|entry| {
+            let base_address = field(entry, 0).ok_or(truncated)?;
+            Ok(PciSegment {
+                base_address: PhysAddr::try_new(base_address)
+                    .map_err(|_| AcpiError::Unsupported(SIGNATURE))?,
+                segment: field(entry, 8).ok_or(truncated)?,
+                start_bus: field(entry, 10).ok_or(truncated)?,
+                end_bus: field(entry, 11).ok_or(truncated)?,
+            })
+        })
+        .collect()
+}
@@ -2,13 +2,17 @@ use core::alloc::{GlobalAlloc, Layout};
 use core::fmt::{Debug, Pointer};
 use core::ptr::null_mut;
 
-use spin::{Mutex, MutexGuard};
 use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::VirtAddr;
 
 use crate::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use crate::boot_params;
+use crate::sync::{IrqSafeSpinlock, IrqSafeSpinlockGuard};
 
+use self::align::align_up;
+
+mod align;
 pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
@@ -17,6 +21,7 @@ pub mod linked_list;
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// The default heap size, the heap_size boot parameter changes it.
 pub const HEAP_SIZE: usize = 10000 * 1024; // 10000 KiB
 
 pub struct DummyAllocator;
@@ -35,9 +40,10 @@ pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    let heap_size = boot_params::get().heap_size();
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + heap_size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -52,31 +58,25 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, heap_size);
     }
 
     Ok(())
 }
 
+// Interrupts stay off while the allocator is locked, so a handler allocating can't deadlock.
 pub struct Locked<A> {
-    inner: Mutex<A>,
+    inner: IrqSafeSpinlock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: Mutex::new(inner),
+            inner: IrqSafeSpinlock::new(inner),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<A> {
+    pub fn lock(&self) -> IrqSafeSpinlockGuard<A> {
         self.inner.lock()
     }
 }
-
-/// Align the given address `addr` upwards to alignment `align`.
-///
-/// Requires that `align` is a power of two.
-fn align_up(addr: usize, align: usize) -> usize {
-    (addr + align - 1) & !(align - 1)
-}
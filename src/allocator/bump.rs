@@ -1,7 +1,7 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
-use crate::allocator::{align_up, Locked};
+use super::{align_up, Locked};
 
 pub struct BumpAllocator {
     heap_start: usize,
@@ -1,7 +1,7 @@
 use core::{mem, ptr};
 use core::alloc::{GlobalAlloc, Layout};
 
-use crate::allocator::Locked;
+use super::Locked;
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
@@ -1,7 +1,7 @@
 use core::{mem, ptr};
 use core::alloc::{GlobalAlloc, Layout};
 
-use crate::allocator::{align_up, Locked};
+use super::{align_up, Locked};
 
 struct ListNode {
     size: usize,
@@ -48,11 +48,11 @@ impl LinkedListAllocator {
         // Make sure the free region can hold the new ListNode
         // After aligning up the address it should still be equal to the address
         assert_eq!(
-            crate::allocator::align_up(addr, mem::align_of::<ListNode>()),
+            align_up(addr, mem::align_of::<ListNode>()),
             addr
         );
 
-        assert!(size >= mem::align_of::<ListNode>());
+        assert!(size >= mem::size_of::<ListNode>());
 
         let mut node = ListNode::new(size);
         node.next = self.head.next.take();
@@ -95,7 +95,7 @@ impl LinkedListAllocator {
         }
 
         let excess_size = region.end_addr() - alloc_end;
-        if excess_size > 0 && excess_size < mem::align_of::<ListNode>() {
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
             return Err(());
         }
 
@@ -110,7 +110,7 @@ impl LinkedListAllocator {
             .align_to(mem::align_of::<ListNode>())
             .expect("alignment adjustment failed")
             .pad_to_align();
-        let size = layout.size().max(mem::align_of::<ListNode>());
+        let size = layout.size().max(mem::size_of::<ListNode>());
         (size, layout.align())
     }
 }
@@ -118,7 +118,7 @@ impl LinkedListAllocator {
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.inner.lock();
+        let mut allocator = self.lock();
 
         if let Some((region, alloc_start)) = allocator.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("Overflow");
@@ -128,14 +128,14 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
                 allocator.add_free_region(alloc_end, excess_size)
             }
 
-            return alloc_start as *mut u8;
+            alloc_start as *mut u8
         } else {
-            return ptr::null_mut();
+            ptr::null_mut()
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let (size, _) = LinkedListAllocator::size_align(layout);
-        self.inner.lock().add_free_region(ptr as usize, size)
+        self.lock().add_free_region(ptr as usize, size)
     }
 }
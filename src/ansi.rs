@@ -0,0 +1,483 @@
+// Mod for parsing ANSI escape sequences.
+// Colored output written for a serial terminal should come out the same on the screen,
+// so the VGA writer and the framebuffer console run what they print through a Parser.
+// It takes one byte at a time and says what to do once a character or a whole sequence
+// is in. Only the common VT100 subset is understood:
+//     ESC [ n A/B/C/D      cursor up/down/forward/back
+//     ESC [ row ; col H    cursor position, also f, counting from 1
+//     ESC [ n J / n K      erase in display / line, 0 to the end, 1 to the start, 2 all
+//     ESC [ ... m          colors and bold (SGR)
+//     ESC [ s / ESC [ u    save / restore the cursor, also ESC 7 / ESC 8
+//     ESC [ ? 25 h / l     show / hide the cursor
+//     ESC c                reset
+// Anything else is swallowed, so unknown sequences don't end up on the screen.
+
+use crate::vga_buffer::Color;
+
+const ESC: u8 = 0x1b;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+// Control bytes come through as Action::Control, these are the ones the consoles act on
+// besides '\n', '\r' and '\t'. Tabs and backspace only move, "\x08 \x08" rubs a char out.
+pub const BACKSPACE: u8 = 0x08;
+pub const BELL: u8 = 0x07;
+pub const TAB_WIDTH: usize = 8;
+
+// More are parsed but ignored.
+const MAX_PARAMS: usize = 16;
+
+// SGR color numbers in order, 30 to 37 and 40 to 47.
+const COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+// 90 to 97 and 100 to 107, and what bold does to the colors above.
+const BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+/// What a writer should do after a byte.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    /// Draw a character. Bytes outside printable ASCII are passed on too, the writer
+    /// decides what to show for them.
+    Print(u8),
+    /// A C0 control character other than ESC, like `\n`.
+    Control(u8),
+    CursorUp(usize),
+    CursorDown(usize),
+    CursorForward(usize),
+    CursorBack(usize),
+    /// Move to `row`, `column`, counting from 0.
+    CursorPosition {
+        row: usize,
+        column: usize,
+    },
+    EraseDisplay(Erase),
+    EraseLine(Erase),
+    SaveCursor,
+    RestoreCursor,
+    ShowCursor(bool),
+    /// Apply the parameters to the writer's `Style`.
+    SelectGraphics(Params),
+    /// Back to the default style, clear the screen and move to the top left.
+    Reset,
+}
+
+/// What part of the display or line to erase, the cursor position included.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Erase {
+    ToEnd,
+    ToStart,
+    All,
+}
+
+impl Erase {
+    fn from_param(param: u16) -> Erase {
+        match param {
+            1 => Erase::ToStart,
+            2 | 3 => Erase::All,
+            _ => Erase::ToEnd,
+        }
+    }
+}
+
+/// The numbers in a CSI sequence. Missing ones are 0.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const fn new() -> Self {
+        Params {
+            values: [0; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.min(MAX_PARAMS)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> u16 {
+        if index < self.len() {
+            self.values[index]
+        } else {
+            0
+        }
+    }
+
+    // For counts, where 0 and missing both mean 1.
+    fn count(&self, index: usize) -> usize {
+        self.get(index).max(1) as usize
+    }
+
+    fn push_digit(&mut self, digit: u8) {
+        if self.len == 0 {
+            self.len = 1;
+        }
+        if let Some(value) = self.values.get_mut(self.len - 1) {
+            *value = value
+                .saturating_mul(10)
+                .saturating_add((digit - b'0') as u16);
+        }
+    }
+
+    fn next(&mut self) {
+        // A leading ';' ends an empty first parameter.
+        if self.len == 0 {
+            self.len = 1;
+        }
+        // One past the end, so digits after that go nowhere.
+        self.len = (self.len + 1).min(MAX_PARAMS + 1);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    // ESC followed by bytes like '(', which pick character sets.
+    EscapeIntermediate,
+    Csi,
+    // ESC [ followed by a private marker, like the '?' in ESC [ ? 25 h.
+    PrivateCsi(u8),
+}
+
+/// Turns a stream of bytes into `Action`s.
+pub struct Parser {
+    state: State,
+    params: Params,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            params: Params::new(),
+        }
+    }
+
+    /// Feeds one byte. Returns what to do, or `None` in the middle of a sequence.
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match (self.state, byte) {
+            (_, ESC) => {
+                self.state = State::Escape;
+                None
+            }
+            (_, CAN) | (_, SUB) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Ground, 0x00..=0x1f) | (State::Ground, 0x7f) => Some(Action::Control(byte)),
+            (State::Ground, _) => Some(Action::Print(byte)),
+            (State::Escape, b'[') => {
+                self.state = State::Csi;
+                self.params = Params::new();
+                None
+            }
+            (State::Escape, 0x20..=0x2f) => {
+                self.state = State::EscapeIntermediate;
+                None
+            }
+            (State::EscapeIntermediate, 0x20..=0x2f) => None,
+            (State::EscapeIntermediate, _) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Escape, _) => {
+                self.state = State::Ground;
+                match byte {
+                    b'7' => Some(Action::SaveCursor),
+                    b'8' => Some(Action::RestoreCursor),
+                    b'c' => Some(Action::Reset),
+                    _ => None,
+                }
+            }
+            (State::Csi, b'<'..=b'?') if self.params.is_empty() => {
+                self.state = State::PrivateCsi(byte);
+                None
+            }
+            (State::Csi, b'0'..=b'9') | (State::PrivateCsi(_), b'0'..=b'9') => {
+                self.params.push_digit(byte);
+                None
+            }
+            (State::Csi, b';') | (State::PrivateCsi(_), b';') => {
+                self.params.next();
+                None
+            }
+            // Intermediate bytes, none of the sequences understood have them.
+            (State::Csi, 0x20..=0x2f) | (State::PrivateCsi(_), 0x20..=0x2f) => None,
+            (State::Csi, 0x40..=0x7e) => {
+                self.state = State::Ground;
+                self.dispatch_csi(byte)
+            }
+            (State::PrivateCsi(marker), 0x40..=0x7e) => {
+                self.state = State::Ground;
+                self.dispatch_private_csi(marker, byte)
+            }
+            // Anything else breaks the sequence off.
+            (State::Csi, _) | (State::PrivateCsi(_), _) => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+
+    fn dispatch_private_csi(&self, marker: u8, byte: u8) -> Option<Action> {
+        match (marker, self.params.get(0), byte) {
+            (b'?', 25, b'h') => Some(Action::ShowCursor(true)),
+            (b'?', 25, b'l') => Some(Action::ShowCursor(false)),
+            _ => None,
+        }
+    }
+
+    fn dispatch_csi(&self, byte: u8) -> Option<Action> {
+        let params = &self.params;
+        let action = match byte {
+            b'A' => Action::CursorUp(params.count(0)),
+            b'B' => Action::CursorDown(params.count(0)),
+            b'C' => Action::CursorForward(params.count(0)),
+            b'D' => Action::CursorBack(params.count(0)),
+            b'H' | b'f' => Action::CursorPosition {
+                row: params.count(0) - 1,
+                column: params.count(1) - 1,
+            },
+            b'J' => Action::EraseDisplay(Erase::from_param(params.get(0))),
+            b'K' => Action::EraseLine(Erase::from_param(params.get(0))),
+            b'm' => Action::SelectGraphics(*params),
+            b's' => Action::SaveCursor,
+            b'u' => Action::RestoreCursor,
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser::new()
+    }
+}
+
+/// The colors text is written in, as set by SGR sequences.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Style {
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    default_foreground: Color,
+    default_background: Color,
+}
+
+impl Style {
+    /// `foreground` and `background` are what SGR 0 goes back to.
+    pub const fn new(foreground: Color, background: Color) -> Self {
+        Style {
+            foreground,
+            background,
+            bold: false,
+            default_foreground: foreground,
+            default_background: background,
+        }
+    }
+
+    /// The foreground color, brightened if bold.
+    pub fn foreground(&self) -> Color {
+        match COLORS.iter().position(|&color| color == self.foreground) {
+            Some(index) if self.bold => BRIGHT_COLORS[index],
+            _ => self.foreground,
+        }
+    }
+
+    pub fn background(&self) -> Color {
+        self.background
+    }
+
+    pub fn reset(&mut self) {
+        *self = Style::new(self.default_foreground, self.default_background);
+    }
+
+    /// Applies the parameters of an SGR sequence. No parameters is a reset.
+    pub fn apply(&mut self, params: &Params) {
+        if params.is_empty() {
+            self.reset();
+            return;
+        }
+
+        let mut index = 0;
+        while index < params.len() {
+            match params.get(index) {
+                0 => self.reset(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                code @ 30..=37 => self.foreground = COLORS[(code - 30) as usize],
+                39 => self.foreground = self.default_foreground,
+                code @ 40..=47 => self.background = COLORS[(code - 40) as usize],
+                49 => self.background = self.default_background,
+                code @ 90..=97 => self.foreground = BRIGHT_COLORS[(code - 90) as usize],
+                code @ 100..=107 => self.background = BRIGHT_COLORS[(code - 100) as usize],
+                // 256 colors as 38;5;n and RGB as 38;2;r;g;b, only the first 16 colors
+                // can be shown. The other numbers must not be read as codes.
+                code @ (38 | 48) => {
+                    let color = match params.get(index + 1) {
+                        5 => {
+                            index += 2;
+                            indexed_color(params.get(index))
+                        }
+                        2 => {
+                            index += 4;
+                            None
+                        }
+                        _ => None,
+                    };
+                    if let Some(color) = color {
+                        if code == 38 {
+                            self.foreground = color;
+                        } else {
+                            self.background = color;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            index += 1;
+        }
+    }
+}
+
+fn indexed_color(index: u16) -> Option<Color> {
+    match index {
+        0..=7 => Some(COLORS[index as usize]),
+        8..=15 => Some(BRIGHT_COLORS[index as usize - 8]),
+        _ => None,
+    }
+}
+
+//  ---Tests---
+
+#[cfg(test)]
+fn parse(bytes: &[u8], actions: &mut [Option<Action>]) -> usize {
+    let mut parser = Parser::new();
+    let mut count = 0;
+    for &byte in bytes {
+        if let Some(action) = parser.advance(byte) {
+            actions[count] = Some(action);
+            count += 1;
+        }
+    }
+    count
+}
+
+#[test_case]
+fn test_plain_text() {
+    let mut actions = [None; 4];
+    assert_eq!(parse(b"a\n\xfe", &mut actions), 3);
+    assert_eq!(actions[0], Some(Action::Print(b'a')));
+    assert_eq!(actions[1], Some(Action::Control(b'\n')));
+    assert_eq!(actions[2], Some(Action::Print(0xfe)));
+}
+
+#[test_case]
+fn test_cursor_sequences() {
+    let mut actions = [None; 8];
+    let count = parse(
+        b"\x1b[A\x1b[3B\x1b[0C\x1b[H\x1b[5;10f\x1b[;7H\x1b7\x1b[u",
+        &mut actions,
+    );
+    assert_eq!(count, 8);
+    assert_eq!(actions[0], Some(Action::CursorUp(1)));
+    assert_eq!(actions[1], Some(Action::CursorDown(3)));
+    assert_eq!(actions[2], Some(Action::CursorForward(1)));
+    assert_eq!(
+        actions[3],
+        Some(Action::CursorPosition { row: 0, column: 0 })
+    );
+    assert_eq!(
+        actions[4],
+        Some(Action::CursorPosition { row: 4, column: 9 })
+    );
+    assert_eq!(
+        actions[5],
+        Some(Action::CursorPosition { row: 0, column: 6 })
+    );
+    assert_eq!(actions[6], Some(Action::SaveCursor));
+    assert_eq!(actions[7], Some(Action::RestoreCursor));
+
+    assert_eq!(parse(b"\x1b[?25l\x1b[?25h", &mut actions), 2);
+    assert_eq!(actions[0], Some(Action::ShowCursor(false)));
+    assert_eq!(actions[1], Some(Action::ShowCursor(true)));
+}
+
+#[test_case]
+fn test_erase_sequences() {
+    let mut actions = [None; 4];
+    assert_eq!(parse(b"\x1b[J\x1b[1J\x1b[2J\x1b[K", &mut actions), 4);
+    assert_eq!(actions[0], Some(Action::EraseDisplay(Erase::ToEnd)));
+    assert_eq!(actions[1], Some(Action::EraseDisplay(Erase::ToStart)));
+    assert_eq!(actions[2], Some(Action::EraseDisplay(Erase::All)));
+    assert_eq!(actions[3], Some(Action::EraseLine(Erase::ToEnd)));
+}
+
+#[test_case]
+fn test_unknown_sequences_swallowed() {
+    let mut actions = [None; 4];
+    assert_eq!(
+        parse(b"\x1b[?1049hx\x1b[5nx\x1b(By\x1b[1\x18z", &mut actions),
+        4
+    );
+    assert_eq!(actions[0], Some(Action::Print(b'x')));
+    assert_eq!(actions[1], Some(Action::Print(b'x')));
+    assert_eq!(actions[2], Some(Action::Print(b'y')));
+    assert_eq!(actions[3], Some(Action::Print(b'z')));
+}
+
+#[test_case]
+fn test_sgr() {
+    let mut actions = [None; 1];
+    let mut style = Style::new(Color::Green, Color::Black);
+    let mut apply = |sequence: &[u8], style: &mut Style| {
+        assert_eq!(parse(sequence, &mut actions), 1);
+        match actions[0] {
+            Some(Action::SelectGraphics(params)) => style.apply(&params),
+            other => panic!("not SGR: {:?}", other),
+        }
+    };
+
+    apply(b"\x1b[31;44m", &mut style);
+    assert_eq!(
+        (style.foreground(), style.background()),
+        (Color::Red, Color::Blue)
+    );
+    apply(b"\x1b[1m", &mut style);
+    assert_eq!(style.foreground(), Color::LightRed);
+    apply(b"\x1b[22;97;39m", &mut style);
+    assert_eq!(style.foreground(), Color::Green);
+    apply(b"\x1b[38;5;11;48;2;1;2;3m", &mut style);
+    assert_eq!(
+        (style.foreground(), style.background()),
+        (Color::Yellow, Color::Blue)
+    );
+    apply(b"\x1b[m", &mut style);
+    assert_eq!(style, Style::new(Color::Green, Color::Black));
+}
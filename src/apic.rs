@@ -1 +1,357 @@
+// Mod for the local APIC and the I/O APIC.
+// The 8259 PICs still handle the legacy ISA interrupts, the local APIC keeps passing
+// them through in virtual wire mode. Interrupts routed through the I/O APIC (like the
+// HPET) arrive at the local APIC directly and have to be acknowledged there instead.
+// Both are memory mapped and reached through the physical memory mapping.
 
+use alloc::vec::Vec;
+use core::ptr;
+
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::{self, AcpiError};
+use crate::interrupts::ApicInterruptIndex;
+use crate::memory;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0xF_FFFF_F000;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ApicError {
+    /// The platform description couldn't be read.
+    Acpi(AcpiError),
+    /// The MADT is missing or lists no I/O APIC.
+    NoIoApic,
+    NotInitialized,
+    /// No I/O APIC has an input for this GSI.
+    InvalidGsi(u32),
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(err: AcpiError) -> Self {
+        ApicError::Acpi(err)
+    }
+}
+
+/// Who an interprocessor interrupt goes to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IpiTarget {
+    /// The CPU with this local APIC ID.
+    Cpu(u8),
+    All,
+    AllButSelf,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+//  ---Local APIC---
+
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, register: usize) -> u32 {
+        ptr::read_volatile((self.base + register).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&mut self, register: usize, value: u32) {
+        ptr::write_volatile((self.base + register).as_mut_ptr::<u32>(), value);
+    }
+
+    pub fn id(&self) -> u8 {
+        (unsafe { self.read(LAPIC_ID) } >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&mut self) {
+        unsafe { self.write(LAPIC_EOI, 0) };
+    }
+
+    fn enable(&mut self) {
+        let spurious = ApicInterruptIndex::Spurious.as_u8() as u32;
+        unsafe { self.write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | spurious) };
+    }
+
+    // Writes the interrupt command register and waits for the local APIC to send it.
+    fn write_icr(&mut self, destination: u8, command: u32) {
+        unsafe {
+            self.write(LAPIC_ICR_HIGH, u32::from(destination) << 24);
+            self.write(LAPIC_ICR_LOW, command);
+            while self.read(LAPIC_ICR_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Resets the CPU with `apic_id`, leaving it waiting for a startup IPI.
+    pub fn send_init(&mut self, apic_id: u8) {
+        self.write_icr(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Starts the CPU with `apic_id` in real mode at physical address `vector * 4096`.
+    pub fn send_startup(&mut self, apic_id: u8, vector: u8) {
+        self.write_icr(
+            apic_id,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(vector),
+        );
+    }
+
+    /// Sends interrupt `vector` to `target`.
+    pub fn send_ipi(&mut self, target: IpiTarget, vector: u8) {
+        let command = ICR_LEVEL_ASSERT | u32::from(vector);
+        match target {
+            IpiTarget::Cpu(apic_id) => self.write_icr(apic_id, command),
+            IpiTarget::All => self.write_icr(0, command | ICR_SHORTHAND_ALL),
+            IpiTarget::AllButSelf => self.write_icr(0, command | ICR_SHORTHAND_ALL_BUT_SELF),
+        }
+    }
+}
+
+//  ---I/O APIC---
+
+pub struct IoApic {
+    base: VirtAddr,
+    // The first global system interrupt this I/O APIC handles.
+    gsi_base: u32,
+}
+
+impl IoApic {
+    // The I/O APIC only has two registers, one selects what the other one reads and writes.
+    unsafe fn read(&mut self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + IOAPIC_SELECT).as_mut_ptr::<u32>(), register);
+        ptr::read_volatile((self.base + IOAPIC_WINDOW).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + IOAPIC_SELECT).as_mut_ptr::<u32>(), register);
+        ptr::write_volatile((self.base + IOAPIC_WINDOW).as_mut_ptr::<u32>(), value);
+    }
+
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    fn handles(&mut self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.pin_count()
+    }
+
+    /// Number of input pins.
+    pub fn pin_count(&mut self) -> u32 {
+        ((unsafe { self.read(IOAPIC_REG_VERSION) } >> 16) & 0xFF) + 1
+    }
+
+    fn write_redirection(&mut self, pin: u32, entry: u64) -> Result<(), ApicError> {
+        if pin >= self.pin_count() {
+            return Err(ApicError::InvalidGsi(self.gsi_base + pin));
+        }
+
+        let register = IOAPIC_REG_REDIRECTION + pin * 2;
+        unsafe {
+            // Mask first so the entry is never live half written.
+            self.write(register, REDIRECTION_MASKED as u32);
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32);
+        }
+        Ok(())
+    }
+
+    /// Sends `pin` to `vector` on the local APIC with ID `destination`.
+    pub fn route(
+        &mut self,
+        pin: u32,
+        vector: u8,
+        destination: u8,
+        trigger: Trigger,
+        polarity: Polarity,
+    ) -> Result<(), ApicError> {
+        let mut entry = vector as u64 | (destination as u64) << 56;
+        if trigger == Trigger::Level {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        if polarity == Polarity::ActiveLow {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        self.write_redirection(pin, entry)
+    }
+
+    pub fn mask(&mut self, pin: u32) -> Result<(), ApicError> {
+        self.write_redirection(pin, REDIRECTION_MASKED)
+    }
+}
+
+pub static LOCAL_APIC: Mutex<Option<LocalApic>> = Mutex::new(None);
+pub static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+//  ---Init---
+
+/// Enables the local APIC and sets up every I/O APIC in the MADT with all pins masked.
+///
+/// Needs memory::init and the heap, for the ACPI tables.
+pub fn init() -> Result<(), ApicError> {
+    let platform = acpi::platform()?;
+    if platform.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let base = enable_in_msr();
+
+    // The MADT knows about address overrides, the MSR is the fallback.
+    let address = platform
+        .local_apic_address
+        .unwrap_or_else(|| PhysAddr::new(base & APIC_BASE_ADDRESS_MASK));
+    let mut local = LocalApic {
+        base: memory::phys_to_virt(address),
+    };
+    local.enable();
+
+    let mut io_apics = Vec::new();
+    for info in &platform.io_apics {
+        let mut io = IoApic {
+            base: memory::phys_to_virt(info.address),
+            gsi_base: info.gsi_base,
+        };
+        for pin in 0..io.pin_count() {
+            io.mask(pin)?;
+        }
+        io_apics.push(io);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *LOCAL_APIC.lock() = Some(local);
+        *IO_APICS.lock() = io_apics;
+    });
+    Ok(())
+}
+
+// Sets the global enable bit in this CPU's APIC base MSR, returns the MSR value.
+fn enable_in_msr() -> u64 {
+    let mut base_msr = Msr::new(IA32_APIC_BASE_MSR);
+    let base = unsafe { base_msr.read() };
+    unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+    base
+}
+
+/// Enables the local APIC of an application processor. `init` has to have run on the BSP.
+///
+/// Every CPU sees its own local APIC at the same address, so they all share LOCAL_APIC.
+pub fn init_ap() -> Result<(), ApicError> {
+    enable_in_msr();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        LOCAL_APIC
+            .lock()
+            .as_mut()
+            .map(LocalApic::enable)
+            .ok_or(ApicError::NotInitialized)
+    })
+}
+
+/// The local APIC ID of the CPU we're running on.
+pub fn local_apic_id() -> Result<u8, ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        LOCAL_APIC
+            .lock()
+            .as_ref()
+            .map(LocalApic::id)
+            .ok_or(ApicError::NotInitialized)
+    })
+}
+
+/// Routes the I/O APIC input for `gsi` to `vector` on this CPU.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> Result<(), ApicError> {
+    let destination = local_apic_id()?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for io_apic in IO_APICS.lock().iter_mut() {
+            if io_apic.handles(gsi) {
+                let pin = gsi - io_apic.gsi_base;
+                return io_apic.route(pin, vector, destination, trigger, polarity);
+            }
+        }
+        Err(ApicError::InvalidGsi(gsi))
+    })
+}
+
+/// Routes ISA `irq` to `vector` on this CPU, following the MADT interrupt source overrides.
+///
+/// The PIC line for the IRQ has to be masked or both will deliver it.
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    let isa = acpi::platform()?.isa_interrupt(irq);
+    route_gsi(isa.gsi, vector, isa.trigger, isa.polarity)
+}
+
+/// Sends an INIT IPI to the CPU with `apic_id`.
+pub fn send_init(apic_id: u8) -> Result<(), ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut local = LOCAL_APIC.lock();
+        let local = local.as_mut().ok_or(ApicError::NotInitialized)?;
+        local.send_init(apic_id);
+        Ok(())
+    })
+}
+
+/// Sends a startup IPI to the CPU with `apic_id`, it starts running at `vector * 4096`.
+pub fn send_startup(apic_id: u8, vector: u8) -> Result<(), ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut local = LOCAL_APIC.lock();
+        let local = local.as_mut().ok_or(ApicError::NotInitialized)?;
+        local.send_startup(apic_id, vector);
+        Ok(())
+    })
+}
+
+/// Sends interrupt `vector` to `target`. Only CPUs smp::init started will handle it.
+pub fn send_ipi(target: IpiTarget, vector: u8) -> Result<(), ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut local = LOCAL_APIC.lock();
+        let local = local.as_mut().ok_or(ApicError::NotInitialized)?;
+        local.send_ipi(target, vector);
+        Ok(())
+    })
+}
+
+/// Acknowledges an interrupt that came through the local APIC.
+pub fn end_of_interrupt() {
+    if let Some(local) = LOCAL_APIC.lock().as_mut() {
+        local.end_of_interrupt();
+    }
+}
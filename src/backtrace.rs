@@ -0,0 +1,129 @@
+// Mod for backtraces by walking the frame pointer chain.
+// The target file sets "frame-pointer": "always", so every function starts with
+//     push %rbp; mov %rsp, %rbp
+// and RBP points at the caller's saved RBP, with the return address right above it.
+// Following the saved RBPs gives the return address of every frame. x86-interrupt
+// handlers push RBP on top of the CPU's frame, so the walk goes on from a handler
+// into the code it interrupted, its RIP standing in for the return address.
+// Only addresses come out, there are no symbols in the kernel. Turn them into
+// functions and lines with the binary that ran:
+//     addr2line -fipe target/x86_64-jonathan_os/debug/deps/<test binary> <addresses>
+
+use core::arch::asm;
+use core::fmt;
+
+use x86_64::VirtAddr;
+
+use crate::memory;
+
+pub const MAX_FRAMES: usize = 32;
+
+// Without the page tables to check against, frames further than this above the first
+// one are taken as garbage.
+const MAX_STACK_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Backtrace {
+    addresses: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    pub const fn empty() -> Self {
+        Backtrace {
+            addresses: [0; MAX_FRAMES],
+            len: 0,
+        }
+    }
+
+    /// The backtrace of the caller, starting at the function it's called from.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov %rbp, {}", out(reg) rbp, options(att_syntax, nomem, nostack)) };
+        unsafe { Backtrace::from_frame(rbp) }
+    }
+
+    /// Walks the chain from the frame `rbp` points at.
+    ///
+    /// # Safety
+    ///
+    /// `rbp` has to be a frame pointer of this stack. The walk stops at a zero RBP or one
+    /// that doesn't look like it's further up the same stack, but a corrupted chain can
+    /// still lead it astray.
+    pub unsafe fn from_frame(mut rbp: u64) -> Self {
+        let mut backtrace = Backtrace::empty();
+        let first = rbp;
+
+        while backtrace.len < MAX_FRAMES && is_frame(rbp, first) {
+            let frame = rbp as *const u64;
+            let return_address = frame.add(1).read();
+            if return_address == 0 {
+                break;
+            }
+            backtrace.addresses[backtrace.len] = return_address;
+            backtrace.len += 1;
+
+            // Callers' frames are at higher addresses.
+            let caller = frame.read();
+            if caller <= rbp {
+                break;
+            }
+            rbp = caller;
+        }
+        backtrace
+    }
+
+    /// Return addresses, innermost first.
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses[..self.len]
+    }
+}
+
+// Whether the 16 bytes of a frame at `rbp` can be read.
+fn is_frame(rbp: u64, first: u64) -> bool {
+    if rbp == 0 || !rbp.is_multiple_of(8) || VirtAddr::try_new(rbp).is_err() {
+        return false;
+    }
+    match memory::physical_memory_offset() {
+        Some(_) => {
+            memory::translate(VirtAddr::new(rbp)).is_some()
+                && memory::translate(VirtAddr::new(rbp + 8)).is_some()
+        }
+        None => rbp - first < MAX_STACK_SIZE,
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, address) in self.addresses().iter().enumerate() {
+            writeln!(f, "{:4}: {:#018x}", index, address)?;
+        }
+        Ok(())
+    }
+}
+
+//  ---Tests---
+
+#[cfg(test)]
+#[inline(never)]
+fn nested(depth: usize) -> Backtrace {
+    if depth == 0 {
+        Backtrace::capture()
+    } else {
+        let backtrace = nested(depth - 1);
+        // Keeps the call from turning into a jump.
+        core::hint::black_box(backtrace)
+    }
+}
+
+#[test_case]
+fn test_capture() {
+    let backtrace = nested(3);
+    let addresses = backtrace.addresses();
+    // nested three times returning into nested, then into this test and its callers.
+    assert!(addresses.len() > 4);
+    assert_eq!(addresses[0], addresses[1]);
+    assert_eq!(addresses[1], addresses[2]);
+    assert_ne!(addresses[2], addresses[3]);
+}
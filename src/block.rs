@@ -0,0 +1,109 @@
+// Mod for block devices, anything that reads and writes fixed size sectors.
+// Filesystems sit on top of the BlockDevice trait so they don't care if the
+// sectors come from an ATA disk or from a chunk of the heap.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub mod ata;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockError {
+    /// The requested blocks are past the end of the device.
+    OutOfRange,
+    /// The buffer length is not a multiple of the block size.
+    BadBufferSize,
+    /// The device reported an error.
+    Io,
+    /// The device stayed busy for too long, or isn't there anymore.
+    Timeout,
+}
+
+pub trait BlockDevice: Send {
+    /// Size in bytes of a single block.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Reads `buf.len() / block_size` blocks starting at block `lba`.
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / block_size` blocks starting at block `lba`.
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Checks that a transfer of `len` bytes starting at `lba` fits on `device`.
+///
+/// Returns the number of blocks in the transfer.
+fn check_transfer(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if !len.is_multiple_of(block_size) {
+        return Err(BlockError::BadBufferSize);
+    }
+
+    let count = (len / block_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+//  ---RAM Disk---
+
+// Block device backed by heap memory.
+// Handy for tests and for loading disk images that were linked into the kernel.
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk with `blocks` sectors.
+    pub fn new(blocks: usize) -> Self {
+        RamDisk {
+            data: vec![0; blocks * SECTOR_SIZE],
+        }
+    }
+
+    /// Creates a disk holding a copy of `image`, padded up to a whole sector.
+    pub fn from_image(image: &[u8]) -> Self {
+        let blocks = image.len().div_ceil(SECTOR_SIZE);
+        let mut disk = RamDisk::new(blocks);
+        disk.data[..image.len()].copy_from_slice(image);
+        disk
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_transfer(self, lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_transfer(self, lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+// Mod for ATA disks using programmed IO (PIO).
+// This is the slow, polling way of talking to IDE disks, but it works on every
+// emulator and doesn't need DMA or interrupts.
+// QEMU puts the boot image on the primary master, so extra images passed with
+// `-drive file=disk.img,format=raw,index=1` show up as the primary slave.
+
+use x86_64::instructions::port::Port;
+
+use crate::block::{check_transfer, BlockDevice, BlockError, SECTOR_SIZE};
+
+const PRIMARY_IO_BASE: u16 = 0x1F0;
+const PRIMARY_CONTROL_BASE: u16 = 0x3F6;
+const SECONDARY_IO_BASE: u16 = 0x170;
+const SECONDARY_CONTROL_BASE: u16 = 0x376;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+// Device control register bit that stops the drive from raising IRQ 14/15.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+// LBA28 can address at most 2^28 sectors and 256 sectors per command.
+const MAX_LBA28: u64 = 1 << 28;
+const MAX_SECTORS_PER_COMMAND: u64 = 256;
+
+// Status reads take about a microsecond, so polling gives up after several seconds.
+// Cache flushes on real disks can take a while.
+const POLL_LIMIT: u32 = 10_000_000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Bus {
+    Primary,
+    Secondary,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+pub struct AtaDisk {
+    drive: Drive,
+    sectors: u64,
+    data: Port<u16>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_select: Port<u8>,
+    // Reads give the status, writes send a command.
+    command: Port<u8>,
+    // Reads give the alternate status, writes set the device control bits.
+    control: Port<u8>,
+}
+
+impl AtaDisk {
+    /// Probes for an ATA disk with IDENTIFY.
+    ///
+    /// Returns `None` if nothing is attached or the device is not a plain ATA
+    /// disk (ATAPI CD drives for example).
+    pub fn probe(bus: Bus, drive: Drive) -> Option<AtaDisk> {
+        let (io_base, control_base) = match bus {
+            Bus::Primary => (PRIMARY_IO_BASE, PRIMARY_CONTROL_BASE),
+            Bus::Secondary => (SECONDARY_IO_BASE, SECONDARY_CONTROL_BASE),
+        };
+
+        let mut disk = AtaDisk {
+            drive,
+            sectors: 0,
+            data: Port::new(io_base),
+            sector_count: Port::new(io_base + 2),
+            lba_low: Port::new(io_base + 3),
+            lba_mid: Port::new(io_base + 4),
+            lba_high: Port::new(io_base + 5),
+            drive_select: Port::new(io_base + 6),
+            command: Port::new(io_base + 7),
+            control: Port::new(control_base),
+        };
+
+        disk.sectors = unsafe { disk.identify()? };
+        Some(disk)
+    }
+
+    unsafe fn identify(&mut self) -> Option<u64> {
+        self.control.write(CONTROL_NIEN);
+        self.select(0);
+        self.sector_count.write(0);
+        self.lba_low.write(0);
+        self.lba_mid.write(0);
+        self.lba_high.write(0);
+        self.command.write(CMD_IDENTIFY);
+
+        // A status of zero (or a floating bus) means there is no drive.
+        let status = self.command.read();
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+
+        if !(0..POLL_LIMIT).any(|_| self.command.read() & STATUS_BSY == 0) {
+            return None;
+        }
+
+        // ATAPI and SATA devices set these registers to a signature.
+        if self.lba_mid.read() != 0 || self.lba_high.read() != 0 {
+            return None;
+        }
+
+        self.wait_for_data().ok()?;
+
+        let mut identify = [0u16; 256];
+        for word in identify.iter_mut() {
+            *word = self.data.read();
+        }
+
+        // Words 60 and 61 hold the number of LBA28 addressable sectors.
+        let sectors = u64::from(identify[60]) | u64::from(identify[61]) << 16;
+        if sectors == 0 {
+            return None;
+        }
+
+        Some(sectors)
+    }
+
+    /// Selects the drive and sets the top four bits of the LBA.
+    unsafe fn select(&mut self, lba: u64) {
+        let drive_bit = match self.drive {
+            Drive::Master => 0,
+            Drive::Slave => 1 << 4,
+        };
+
+        // Bit 6 turns on LBA addressing, bits 5 and 7 are always set.
+        self.drive_select
+            .write(0xE0 | drive_bit | ((lba >> 24) as u8 & 0x0F));
+
+        // Reading the alternate status four times gives the drive its 400ns to switch.
+        for _ in 0..4 {
+            self.control.read();
+        }
+    }
+
+    unsafe fn wait_for_data(&mut self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.command.read();
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::Io);
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    unsafe fn wait_while_busy(&mut self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.command.read();
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::Io);
+            }
+            return Ok(());
+        }
+        Err(BlockError::Timeout)
+    }
+
+    unsafe fn start_command(&mut self, command: u8, lba: u64, count: u64) {
+        self.select(lba);
+        // A count of zero means 256 sectors.
+        self.sector_count.write(count as u8);
+        self.lba_low.write(lba as u8);
+        self.lba_mid.write((lba >> 8) as u8);
+        self.lba_high.write((lba >> 16) as u8);
+        self.command.write(command);
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors.min(MAX_LBA28)
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_transfer(self, lba, buf.len())?;
+        let mut done = 0;
+
+        while done < count {
+            let chunk = (count - done).min(MAX_SECTORS_PER_COMMAND);
+            unsafe {
+                self.start_command(CMD_READ_SECTORS, lba + done, chunk);
+
+                for sector in done..done + chunk {
+                    self.wait_for_data()?;
+                    let start = sector as usize * SECTOR_SIZE;
+                    for bytes in buf[start..start + SECTOR_SIZE].chunks_exact_mut(2) {
+                        bytes.copy_from_slice(&self.data.read().to_le_bytes());
+                    }
+                }
+            }
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = check_transfer(self, lba, buf.len())?;
+        let mut done = 0;
+
+        while done < count {
+            let chunk = (count - done).min(MAX_SECTORS_PER_COMMAND);
+            unsafe {
+                self.start_command(CMD_WRITE_SECTORS, lba + done, chunk);
+
+                for sector in done..done + chunk {
+                    self.wait_for_data()?;
+                    let start = sector as usize * SECTOR_SIZE;
+                    for bytes in buf[start..start + SECTOR_SIZE].chunks_exact(2) {
+                        self.data.write(u16::from_le_bytes([bytes[0], bytes[1]]));
+                    }
+                }
+
+                self.wait_while_busy()?;
+            }
+            done += chunk;
+        }
+
+        unsafe {
+            self.command.write(CMD_CACHE_FLUSH);
+            self.wait_while_busy()
+        }
+    }
+}
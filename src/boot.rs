@@ -0,0 +1,81 @@
+// Mod for what the bootloader hands over.
+// bootloader 0.11 boots the kernel from a BIOS or a UEFI disk image, the runner in
+// boot/ builds both. Either way the kernel starts in long mode at the function given to
+// entry_point!, with a BootInfo describing the machine: the memory regions, the
+// framebuffer, where the ACPI RSDP is and where physical memory is mapped.
+// BOOTLOADER_CONFIG asks for that mapping, the kernel reaches page tables and MMIO
+// registers through it.
+// take() splits BootInfo into the parts the rest of the kernel uses and hands the
+// framebuffer to the console. It has to run before anything prints.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[doc(hidden)]
+pub use bootloader_api;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+pub use bootloader_api::info::MemoryRegion;
+pub use bootloader_api::BootInfo;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{framebuffer, memory};
+
+/// What the kernel asks the bootloader for. entry_point! puts it in the kernel binary.
+pub const BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+/// Makes `$path`, a `fn(&'static mut BootInfo) -> !`, the kernel's entry point.
+#[macro_export]
+macro_rules! entry_point {
+    ($path:path) => {
+        $crate::boot::bootloader_api::entry_point!(
+            $path,
+            config = &$crate::boot::BOOTLOADER_CONFIG
+        );
+    };
+}
+
+/// The parts of BootInfo the kernel uses.
+pub struct Boot {
+    pub physical_memory_offset: VirtAddr,
+    pub memory_regions: &'static [MemoryRegion],
+}
+
+// 0 if the bootloader didn't find it.
+static RSDP_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Splits up what the bootloader handed over. Call it first thing in the entry point.
+///
+/// Panics if physical memory isn't mapped, which BOOTLOADER_CONFIG asks for.
+pub fn take(boot_info: &'static mut BootInfo) -> Boot {
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("physical memory is not mapped"),
+    );
+    memory::set_physical_memory_offset(physical_memory_offset);
+    if let Some(address) = boot_info.rsdp_addr.into_option() {
+        RSDP_ADDRESS.store(address, Ordering::Relaxed);
+    }
+    // Without one print! falls back to the VGA text buffer.
+    if let Some(framebuffer) = boot_info.framebuffer.as_mut().into_option() {
+        framebuffer::init(framebuffer);
+    }
+
+    Boot {
+        physical_memory_offset,
+        memory_regions: &boot_info.memory_regions,
+    }
+}
+
+/// The physical address of the ACPI RSDP, if the bootloader found it. UEFI firmware
+/// has no BIOS areas to scan for it, the bootloader gets it from the EFI system table.
+pub fn rsdp_address() -> Option<PhysAddr> {
+    match RSDP_ADDRESS.load(Ordering::Relaxed) {
+        0 => None,
+        address => Some(PhysAddr::new(address)),
+    }
+}
@@ -0,0 +1,477 @@
+// Mod for the kernel command line.
+// The bootloader doesn't pass one on, so it comes from a QEMU fw_cfg file instead:
+//     -fw_cfg name=opt/jonathan_os/cmdline,string="heap_size=16M timer_hz=100"
+// It's a list of key=value words, with quotes around values that have spaces in them:
+//     log_level=debug test="--exact sync::mutex" on_panic=reboot
+// init reads it once, early in the kernel's init, and the subsystems set themselves up
+// from get() after that. A value that doesn't parse leaves its parameter at the
+// default, warnings() says which ones those were. Keys the kernel doesn't know are
+// kept, value() still finds them.
+
+use core::fmt;
+
+use crate::allocator::HEAP_SIZE;
+use crate::fw_cfg;
+use crate::sync::Once;
+use crate::time::pit;
+
+/// The fw_cfg file the command line is read from.
+pub const CMDLINE_FILE: &str = "opt/jonathan_os/cmdline";
+
+const CMDLINE_SIZE: usize = 512;
+
+// Smaller heaps don't get the kernel through init.
+const MIN_HEAP_SIZE: usize = 64 * 1024;
+const MAX_HEAP_SIZE: usize = 1 << 30;
+
+// Slower than this needs a PIT divisor over 16 bits. Much faster and the CPU does little
+// but take timer interrupts.
+const MIN_TIMER_HZ: u32 = 19;
+const MAX_TIMER_HZ: u32 = 10_000;
+
+/// How much the kernel prints while it runs, through `log!` and `serial_log!`. Panics
+/// and what the shell prints don't go through them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        })
+    }
+}
+
+/// What the kernel does after printing a panic.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PanicAction {
+    Halt,
+    Shutdown,
+    Reboot,
+    /// Waits for GDB on the stub's port even if it never attached, then halts.
+    Debug,
+}
+
+impl fmt::Display for PanicAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PanicAction::Halt => "halt",
+            PanicAction::Shutdown => "shutdown",
+            PanicAction::Reboot => "reboot",
+            PanicAction::Debug => "debug",
+        })
+    }
+}
+
+/// Why the command line as a whole was ignored.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CmdlineError {
+    TooLong,
+    NotUtf8,
+}
+
+/// What is wrong with a single parameter.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParamError {
+    UnknownKey,
+    MissingValue,
+    InvalidValue,
+    UnterminatedQuote,
+}
+
+/// A parameter the kernel left at its default, or didn't know.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Warning<'a> {
+    pub key: &'a str,
+    pub error: ParamError,
+}
+
+impl fmt::Display for Warning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "boot parameter {:?}: {:?}", self.key, self.error)
+    }
+}
+
+//  ---Parsing---
+
+// A word of the command line. Bare keys have no value.
+struct Param<'a> {
+    key: &'a str,
+    value: Result<Option<&'a str>, ParamError>,
+}
+
+struct Words<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Words<'a> {
+    type Item = Param<'a>;
+
+    fn next(&mut self) -> Option<Param<'a>> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        let rest = &rest[key_end..];
+
+        let (value, rest) = match rest.strip_prefix('=') {
+            None => (Ok(None), rest),
+            Some(rest) => match rest.strip_prefix('"') {
+                Some(quoted) => match quoted.find('"') {
+                    Some(end) => (Ok(Some(&quoted[..end])), &quoted[end + 1..]),
+                    None => (Err(ParamError::UnterminatedQuote), ""),
+                },
+                None => {
+                    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    (Ok(Some(&rest[..end])), &rest[end..])
+                }
+            },
+        };
+        self.rest = rest;
+        Some(Param { key, value })
+    }
+}
+
+fn words(cmdline: &str) -> Words<'_> {
+    Words { rest: cmdline }
+}
+
+// The parameters the kernel knows.
+const KEYS: &[&str] = &["heap_size", "log_level", "on_panic", "test", "timer_hz"];
+
+// A known parameter with its value parsed.
+enum Setting {
+    HeapSize(usize),
+    LogLevel(LogLevel),
+    OnPanic(PanicAction),
+    TimerHz(u32),
+    // Parsed by testing::options when the tests start.
+    Test,
+}
+
+fn parse_param(param: &Param) -> Result<Setting, ParamError> {
+    if !KEYS.contains(&param.key) {
+        return Err(ParamError::UnknownKey);
+    }
+    let value = param.value?.ok_or(ParamError::MissingValue)?;
+
+    match param.key {
+        "heap_size" => match parse_size(value) {
+            Some(size) if (MIN_HEAP_SIZE..=MAX_HEAP_SIZE).contains(&size) => {
+                Ok(Setting::HeapSize(size))
+            }
+            _ => Err(ParamError::InvalidValue),
+        },
+        "log_level" => match value {
+            "error" => Ok(Setting::LogLevel(LogLevel::Error)),
+            "warn" => Ok(Setting::LogLevel(LogLevel::Warn)),
+            "info" => Ok(Setting::LogLevel(LogLevel::Info)),
+            "debug" => Ok(Setting::LogLevel(LogLevel::Debug)),
+            _ => Err(ParamError::InvalidValue),
+        },
+        "on_panic" => match value {
+            "halt" => Ok(Setting::OnPanic(PanicAction::Halt)),
+            "shutdown" => Ok(Setting::OnPanic(PanicAction::Shutdown)),
+            "reboot" => Ok(Setting::OnPanic(PanicAction::Reboot)),
+            "debug" => Ok(Setting::OnPanic(PanicAction::Debug)),
+            _ => Err(ParamError::InvalidValue),
+        },
+        "timer_hz" => match value.parse() {
+            Ok(hz) if (MIN_TIMER_HZ..=MAX_TIMER_HZ).contains(&hz) => Ok(Setting::TimerHz(hz)),
+            _ => Err(ParamError::InvalidValue),
+        },
+        _ => Ok(Setting::Test),
+    }
+}
+
+// Bytes, with an optional K, M or G suffix for powers of 1024.
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+//  ---BootParams---
+
+/// The parsed command line.
+pub struct BootParams {
+    cmdline: [u8; CMDLINE_SIZE],
+    len: usize,
+    heap_size: usize,
+    log_level: LogLevel,
+    on_panic: PanicAction,
+    timer_hz: u32,
+}
+
+impl BootParams {
+    /// Every parameter at its default.
+    pub const fn new() -> Self {
+        BootParams {
+            cmdline: [0; CMDLINE_SIZE],
+            len: 0,
+            heap_size: HEAP_SIZE,
+            log_level: LogLevel::Info,
+            on_panic: PanicAction::Halt,
+            timer_hz: pit::DEFAULT_FREQUENCY,
+        }
+    }
+
+    pub fn parse(cmdline: &str) -> Result<Self, CmdlineError> {
+        if cmdline.len() > CMDLINE_SIZE {
+            return Err(CmdlineError::TooLong);
+        }
+        let mut params = BootParams::new();
+        params.cmdline[..cmdline.len()].copy_from_slice(cmdline.as_bytes());
+        params.len = cmdline.len();
+
+        // Later words win.
+        for param in words(cmdline) {
+            match parse_param(&param) {
+                Ok(Setting::HeapSize(size)) => params.heap_size = size,
+                Ok(Setting::LogLevel(level)) => params.log_level = level,
+                Ok(Setting::OnPanic(action)) => params.on_panic = action,
+                Ok(Setting::TimerHz(hz)) => params.timer_hz = hz,
+                Ok(Setting::Test) | Err(_) => {}
+            }
+        }
+        Ok(params)
+    }
+
+    /// Reads the command line from `CMDLINE_FILE`, the defaults if there is none.
+    pub fn from_fw_cfg() -> Result<Self, CmdlineError> {
+        let file = match fw_cfg::find(CMDLINE_FILE) {
+            Some(file) => file,
+            None => return Ok(BootParams::new()),
+        };
+        if file.size() > CMDLINE_SIZE {
+            return Err(CmdlineError::TooLong);
+        }
+
+        let mut buffer = [0; CMDLINE_SIZE];
+        let len = file.read(&mut buffer);
+        let cmdline = core::str::from_utf8(&buffer[..len]).map_err(|_| CmdlineError::NotUtf8)?;
+        BootParams::parse(cmdline)
+    }
+
+    pub fn cmdline(&self) -> &str {
+        // Copied from a str in parse.
+        core::str::from_utf8(&self.cmdline[..self.len]).unwrap_or("")
+    }
+
+    /// The value given for `key`, the last one if there are several. Works for keys
+    /// the kernel doesn't know too.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        words(self.cmdline())
+            .filter(|param| param.key == key)
+            .filter_map(|param| param.value.ok().flatten())
+            .last()
+    }
+
+    /// The parameters that were ignored, in the order they were given.
+    pub fn warnings(&self) -> impl Iterator<Item = Warning<'_>> {
+        words(self.cmdline()).filter_map(|param| {
+            parse_param(&param).err().map(|error| Warning {
+                key: param.key,
+                error,
+            })
+        })
+    }
+
+    /// Size of the kernel heap in bytes.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        self.log_level
+    }
+
+    /// What the panic handler does once the panic is printed.
+    pub fn on_panic(&self) -> PanicAction {
+        self.on_panic
+    }
+
+    /// The rate the PIT interrupts at.
+    pub fn timer_hz(&self) -> u32 {
+        self.timer_hz
+    }
+
+    /// Options for the test runner, see `testing::options`.
+    pub fn test_args(&self) -> Option<&str> {
+        self.value("test")
+    }
+}
+
+impl Default for BootParams {
+    fn default() -> Self {
+        BootParams::new()
+    }
+}
+
+// One key=value line for each parameter the kernel knows.
+impl fmt::Display for BootParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap_size={}", self.heap_size)?;
+        writeln!(f, "log_level={}", self.log_level)?;
+        writeln!(f, "on_panic={}", self.on_panic)?;
+        writeln!(f, "timer_hz={}", self.timer_hz)?;
+        if let Some(args) = self.test_args() {
+            writeln!(f, "test=\"{}\"", args)?;
+        }
+        Ok(())
+    }
+}
+
+//  ---Registry---
+
+static PARAMS: Once<BootParams> = Once::new();
+static DEFAULTS: BootParams = BootParams::new();
+
+/// Reads and parses the command line. Only the first call does anything.
+///
+/// A command line that can't be read is ignored, and everything keeps its default.
+pub fn init() -> Result<(), CmdlineError> {
+    let mut result = Ok(());
+    PARAMS.call_once(|| {
+        BootParams::from_fw_cfg().unwrap_or_else(|err| {
+            result = Err(err);
+            BootParams::new()
+        })
+    });
+    result
+}
+
+/// The boot parameters, the defaults before `init`.
+pub fn get() -> &'static BootParams {
+    PARAMS.get().unwrap_or(&DEFAULTS)
+}
+
+//  ---Logging---
+
+/// Whether log_level lets messages at `level` through.
+pub fn log_enabled(level: LogLevel) -> bool {
+    get().log_level() >= level
+}
+
+/// `println!` for messages at a LogLevel, left out if log_level is lower:
+///     log!(Warn, "APIC init failed: {:?}", err);
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::boot_params::log_enabled($crate::boot_params::LogLevel::$level) {
+            $crate::println!($($arg)*);
+        }
+    };
+}
+
+/// Like `log!`, but only to the serial port.
+#[macro_export]
+macro_rules! serial_log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::boot_params::log_enabled($crate::boot_params::LogLevel::$level) {
+            $crate::serial_println!($($arg)*);
+        }
+    };
+}
+
+//  ---Tests---
+
+#[test_case]
+fn test_parse() {
+    let params = BootParams::parse("heap_size=16M  timer_hz=100 log_level=debug").unwrap();
+    assert_eq!(params.heap_size(), 16 << 20);
+    assert_eq!(params.timer_hz(), 100);
+    assert_eq!(params.log_level(), LogLevel::Debug);
+    assert_eq!(params.warnings().next(), None);
+
+    let params = BootParams::parse("timer_hz=100 timer_hz=250").unwrap();
+    assert_eq!(params.timer_hz(), 250);
+    assert_eq!(params.heap_size(), HEAP_SIZE);
+}
+
+#[test_case]
+fn test_quoted_values() {
+    let params = BootParams::parse(r#"test="--exact sync::mutex" log_level=warn"#).unwrap();
+    assert_eq!(params.test_args(), Some("--exact sync::mutex"));
+    assert_eq!(params.log_level(), LogLevel::Warn);
+    assert_eq!(params.on_panic(), PanicAction::Halt);
+
+    let params = BootParams::parse("on_panic=shutdown on_panic=\"reboot\"").unwrap();
+    assert_eq!(params.on_panic(), PanicAction::Reboot);
+    let params = BootParams::parse("on_panic=debug").unwrap();
+    assert_eq!(params.on_panic(), PanicAction::Debug);
+
+    let params = BootParams::parse(r#"test="--exact"#).unwrap();
+    assert_eq!(params.test_args(), None);
+    assert_eq!(
+        params.warnings().next(),
+        Some(Warning {
+            key: "test",
+            error: ParamError::UnterminatedQuote
+        })
+    );
+}
+
+#[test_case]
+fn test_warnings() {
+    let params = BootParams::parse("quiet heap_size=1T timer_hz=5 log_level debug=1").unwrap();
+    assert_eq!(params.heap_size(), HEAP_SIZE);
+    assert_eq!(params.timer_hz(), pit::DEFAULT_FREQUENCY);
+    assert_eq!(params.value("debug"), Some("1"));
+
+    let mut warnings = params
+        .warnings()
+        .map(|warning| (warning.key, warning.error));
+    assert_eq!(warnings.next(), Some(("quiet", ParamError::UnknownKey)));
+    assert_eq!(
+        warnings.next(),
+        Some(("heap_size", ParamError::InvalidValue))
+    );
+    assert_eq!(
+        warnings.next(),
+        Some(("timer_hz", ParamError::InvalidValue))
+    );
+    assert_eq!(
+        warnings.next(),
+        Some(("log_level", ParamError::MissingValue))
+    );
+    assert_eq!(warnings.next(), Some(("debug", ParamError::UnknownKey)));
+    assert_eq!(warnings.next(), None);
+}
+
+#[test_case]
+fn test_sizes() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("64k"), Some(64 << 10));
+    assert_eq!(parse_size("1G"), Some(1 << 30));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size(""), None);
+    assert_eq!(parse_size("99999999999999999999G"), None);
+}
+
+// There is no command line in test-args, everything is at its default.
+#[test_case]
+fn test_defaults() {
+    assert!(fw_cfg::find(CMDLINE_FILE).is_none());
+    assert_eq!(get().timer_hz(), pit::DEFAULT_FREQUENCY);
+    assert_eq!(get().heap_size(), HEAP_SIZE);
+    assert_eq!(get().test_args(), None);
+}
@@ -0,0 +1,43 @@
+// Mod for printing when the kernel is going down.
+// A panic or fatal exception can hit while the screen or SERIAL1 is locked, by the code
+// that crashed or by another CPU that will never unlock it. println! would spin on the
+// lock forever and the crash message would never show up. These macros wait a little
+// for the lock and then take it anyway. The output may get mixed up with whatever the
+// holder was printing, but it gets out.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{serial, vga_buffer};
+
+// How long to wait for a lock before taking it, should be long enough to finish a line.
+pub(crate) const LOCK_SPINS: usize = 1 << 20;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    vga_buffer::_emergency_print(args);
+    serial::_emergency_print(args);
+}
+
+/// Prints to the screen and the serial port, even if their locks are held.
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::emergency::_print(format_args!($($arg)*)));
+}
+
+/// Like `emergency_print!`, appending a newline.
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n"));
+    ($($arg:tt)*) => ($crate::emergency_print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Call first thing in a panic handler. Returns false if a panic is already being
+/// handled, the handler should halt then instead of printing. Otherwise a panic while
+/// printing the panic recurses until the stack overflows.
+pub fn begin_panic() -> bool {
+    x86_64::instructions::interrupts::disable();
+    !PANICKING.swap(true, Ordering::SeqCst)
+}
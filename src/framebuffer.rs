@@ -0,0 +1,772 @@
+// Mod for a text console on a pixel framebuffer.
+// There is no VGA text mode under UEFI, and bootloader 0.11 switches to a graphics mode
+// when booting from the BIOS too. Either way the kernel gets a linear framebuffer in
+// whatever resolution and pixel format the firmware picked. The console draws the font
+// from font.rs into it, and print! goes here instead of the VGA buffer when there is one.
+// It understands the same ANSI escape sequences as the VGA writer.
+// Video memory is slow to read back on real hardware. Once init_back_buffer has mapped
+// a copy in RAM, everything is drawn there and only the rows that changed are copied
+// over, so scrolling never reads the framebuffer. Until then it draws straight into it.
+// The text on screen is kept as characters too, along with the lines that scrolled off
+// the top. Shift+PageUp/PageDown pages through those like on the VGA console, any output
+// jumps back down. There's no hardware cursor, it's drawn as a bar under the character
+// in the line spacing.
+
+use core::fmt;
+use core::ops::Range;
+use core::{ptr, slice};
+
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::ansi::{Action, Erase, Parser, Style, BACKSPACE, BELL, TAB_WIDTH};
+use crate::sync::{IrqSafeSpinlock, Once};
+use crate::vga_buffer::Color;
+
+mod font;
+
+/// Where `init_back_buffer` maps the back buffer.
+pub const BACK_BUFFER_START: u64 = 0x_3333_3333_0000;
+
+// Wider screens get the font scaled up, lines stay about as long as on the VGA console.
+const MIN_COLUMNS: usize = 80;
+// Blank pixel rows under each line of text, before scaling.
+const LINE_SPACING: usize = 2;
+
+// The most text kept track of. The scale keeps lines shorter than twice MIN_COLUMNS,
+// anything below MAX_ROWS stays blank.
+const MAX_COLUMNS: usize = 2 * MIN_COLUMNS;
+const MAX_ROWS: usize = 96;
+// Lines kept after they scroll off the top, a few screens' worth
+const SCROLLBACK_LINES: usize = 200;
+
+// What ESC [ 0 m goes back to.
+const DEFAULT_FOREGROUND: Color = Color::Green;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+
+// The VGA text mode colors, as RGB.
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xaa],
+    [0x00, 0xaa, 0x00],
+    [0x00, 0xaa, 0xaa],
+    [0xaa, 0x00, 0x00],
+    [0xaa, 0x00, 0xaa],
+    [0xaa, 0x55, 0x00],
+    [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55],
+    [0x55, 0x55, 0xff],
+    [0x55, 0xff, 0x55],
+    [0x55, 0xff, 0xff],
+    [0xff, 0x55, 0x55],
+    [0xff, 0x55, 0xff],
+    [0xff, 0xff, 0x55],
+    [0xff, 0xff, 0xff],
+];
+
+/// The console, if the bootloader found a framebuffer. `boot::take` sets it up.
+pub static CONSOLE: Once<IrqSafeSpinlock<FrameBufferWriter<'static>>> = Once::new();
+
+/// Sets up the console on `framebuffer` and clears the screen.
+pub fn init(framebuffer: &'static mut FrameBuffer) {
+    // Too big to build on the stack. call_once only runs the closure once, so this is the
+    // only reference to it.
+    static mut TEXT: Text = Text::new();
+
+    let info = framebuffer.info();
+    let buffer = framebuffer.buffer_mut();
+    CONSOLE.call_once(move || {
+        let text = unsafe { &mut *ptr::addr_of_mut!(TEXT) };
+        IrqSafeSpinlock::new(FrameBufferWriter::new(info, buffer, text))
+    });
+}
+
+/// Maps memory for a back buffer, the console draws into that from then on. Does nothing
+/// if there is no console.
+pub fn init_back_buffer(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let console = match CONSOLE.get() {
+        Some(console) => console,
+        None => return Ok(()),
+    };
+    let size = console.lock().framebuffer.len();
+    let page_range = {
+        let start = VirtAddr::new(BACK_BUFFER_START);
+        let end = start + size - 1u64;
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(end);
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    let back_buffer = unsafe { slice::from_raw_parts_mut(BACK_BUFFER_START as *mut u8, size) };
+    let mut console = console.lock();
+    // The one time the framebuffer is read.
+    back_buffer.copy_from_slice(console.framebuffer);
+    console.back_buffer = Some(back_buffer);
+    Ok(())
+}
+
+// `color` as the bytes of one pixel.
+fn pixel(info: &FrameBufferInfo, color: Color) -> [u8; 4] {
+    let [red, green, blue] = PALETTE[color as usize];
+    match info.pixel_format {
+        PixelFormat::Rgb => [red, green, blue, 0],
+        PixelFormat::Bgr => [blue, green, red, 0],
+        PixelFormat::Unknown {
+            red_position,
+            green_position,
+            blue_position,
+        } => ((red as u32) << red_position
+            | (green as u32) << green_position
+            | (blue as u32) << blue_position)
+            .to_le_bytes(),
+        // U8, and anything newer, gets the brightness.
+        _ => {
+            let gray = (red as u32 * 77 + green as u32 * 150 + blue as u32 * 29) >> 8;
+            [gray as u8, 0, 0, 0]
+        }
+    }
+}
+
+// One character on the screen and its colors.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Cell {
+    char: u8,
+    foreground: Color,
+    background: Color,
+}
+
+// All zeros, so Text starts out in .bss. Never drawn, clear() fills the screen first.
+const EMPTY: Cell = Cell {
+    char: 0,
+    foreground: Color::Black,
+    background: Color::Black,
+};
+
+type Line = [Cell; MAX_COLUMNS];
+
+// The text on the screen, and a ring of the lines that scrolled off, oldest first
+struct Text {
+    screen: [Line; MAX_ROWS],
+    scrollback: [Line; SCROLLBACK_LINES],
+    start: usize,
+    len: usize,
+}
+
+impl Text {
+    const fn new() -> Self {
+        Text {
+            screen: [[EMPTY; MAX_COLUMNS]; MAX_ROWS],
+            scrollback: [[EMPTY; MAX_COLUMNS]; SCROLLBACK_LINES],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    // Once it's full the oldest line makes room
+    fn push(&mut self, line: Line) {
+        let end = (self.start + self.len) % SCROLLBACK_LINES;
+        self.scrollback[end] = line;
+        if self.len < SCROLLBACK_LINES {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    // The scrollback and then the screen, as one list of lines
+    fn line(&self, index: usize) -> &Line {
+        match index.checked_sub(self.len) {
+            Some(row) => &self.screen[row],
+            None => &self.scrollback[(self.start + index) % SCROLLBACK_LINES],
+        }
+    }
+}
+
+/// Writes text to a framebuffer, scrolling when it reaches the bottom.
+pub struct FrameBufferWriter<'a> {
+    info: FrameBufferInfo,
+    framebuffer: &'a mut [u8],
+    back_buffer: Option<&'a mut [u8]>,
+    scale: usize,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    parser: Parser,
+    style: Style,
+    saved_cursor: (usize, usize),
+    cursor_visible: bool,
+    // The row and column the cursor is drawn at, if it is
+    cursor: Option<(usize, usize)>,
+    text: &'a mut Text,
+    // How many lines back the screen shows
+    view_offset: usize,
+    // Pixel rows drawn into the back buffer but not copied to the framebuffer yet.
+    dirty: Range<usize>,
+}
+
+impl<'a> FrameBufferWriter<'a> {
+    fn new(info: FrameBufferInfo, framebuffer: &'a mut [u8], text: &'a mut Text) -> Self {
+        let scale = (info.width / (MIN_COLUMNS * font::WIDTH)).max(1);
+        let mut writer = FrameBufferWriter {
+            info,
+            framebuffer,
+            back_buffer: None,
+            scale,
+            columns: (info.width / (font::WIDTH * scale)).clamp(1, MAX_COLUMNS),
+            rows: (info.height / ((font::HEIGHT + LINE_SPACING) * scale)).clamp(1, MAX_ROWS),
+            column: 0,
+            row: 0,
+            parser: Parser::new(),
+            style: Style::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+            saved_cursor: (0, 0),
+            cursor_visible: true,
+            cursor: None,
+            text,
+            view_offset: 0,
+            dirty: 0..0,
+        };
+        writer.text.start = 0;
+        writer.text.len = 0;
+        writer.clear();
+        writer
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn cell_width(&self) -> usize {
+        font::WIDTH * self.scale
+    }
+
+    fn cell_height(&self) -> usize {
+        (font::HEIGHT + LINE_SPACING) * self.scale
+    }
+
+    // Bytes per row of pixels.
+    fn line_size(&self) -> usize {
+        self.info.stride * self.info.bytes_per_pixel
+    }
+
+    // Where drawing goes, the back buffer if there is one.
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.framebuffer,
+        }
+    }
+}
+
+impl FrameBufferWriter<'_> {
+    // Check the char or byte and then draw it, write_string flushes it
+    fn write_byte(&mut self, char: u8) {
+        match char {
+            b'\n' => self.new_line(),
+            char => {
+                if self.column >= self.columns {
+                    self.new_line();
+                }
+
+                self.draw_char(char);
+                self.column += 1;
+            }
+        }
+    }
+
+    pub fn write_string(&mut self, str: &str) {
+        self.scroll_to_live();
+        self.hide_cursor();
+        for char in str.bytes() {
+            if let Some(action) = self.parser.advance(char) {
+                self.perform(action);
+            }
+        }
+        self.draw_cursor();
+        self.flush();
+    }
+
+    /// Blanks the screen and moves to the top left.
+    pub fn clear(&mut self) {
+        self.scroll_to_live();
+        self.hide_cursor();
+        self.erase_all();
+        self.draw_cursor();
+        self.flush();
+    }
+}
+
+impl FrameBufferWriter<'_> {
+    // Same as the VGA writer, everything outside printable ASCII becomes a box.
+    fn perform(&mut self, action: Action) {
+        let last_row = self.rows - 1;
+        let last_column = self.columns - 1;
+
+        match action {
+            Action::Print(char @ 0x20..=0x7e) => self.write_byte(char),
+            Action::Control(b'\n') => self.new_line(),
+            Action::Control(b'\r') => self.column = 0,
+            Action::Control(b'\t') => {
+                self.column = ((self.column / TAB_WIDTH + 1) * TAB_WIDTH).min(last_column)
+            }
+            Action::Control(BACKSPACE) => {
+                self.column = self.column.min(last_column).saturating_sub(1)
+            }
+            Action::Control(BELL) => {}
+            Action::Print(_) | Action::Control(_) => self.write_byte(0xfe),
+            Action::CursorUp(count) => self.row = self.row.saturating_sub(count),
+            Action::CursorDown(count) => self.row = (self.row + count).min(last_row),
+            Action::CursorForward(count) => self.column = (self.column + count).min(last_column),
+            Action::CursorBack(count) => self.column = self.column.saturating_sub(count),
+            Action::CursorPosition { row, column } => {
+                self.row = row.min(last_row);
+                self.column = column.min(last_column);
+            }
+            Action::EraseDisplay(erase) => {
+                let rows = match erase {
+                    Erase::ToEnd => self.row + 1..self.rows,
+                    Erase::ToStart => 0..self.row,
+                    Erase::All => 0..self.rows,
+                };
+                for row in rows {
+                    self.erase(row, 0..self.columns);
+                }
+                if erase != Erase::All {
+                    self.perform(Action::EraseLine(erase));
+                }
+            }
+            Action::EraseLine(erase) => {
+                // Right after the last column the cursor is past the end of the line.
+                let column = self.column.min(last_column);
+                let columns = match erase {
+                    Erase::ToEnd => column..self.columns,
+                    Erase::ToStart => 0..column + 1,
+                    Erase::All => 0..self.columns,
+                };
+                self.erase(self.row, columns);
+            }
+            Action::SaveCursor => self.saved_cursor = (self.row, self.column),
+            Action::RestoreCursor => (self.row, self.column) = self.saved_cursor,
+            Action::ShowCursor(visible) => self.cursor_visible = visible,
+            Action::SelectGraphics(params) => self.style.apply(&params),
+            Action::Reset => {
+                self.style.reset();
+                self.erase_all();
+            }
+        }
+    }
+}
+
+impl FrameBufferWriter<'_> {
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    // Move every line of text up one, the bottom line ends up blank. The top line goes
+    // into the scrollback.
+    fn scroll(&mut self) {
+        let top_line = self.text.screen[0];
+        self.text.push(top_line);
+        self.text.screen.copy_within(1..self.rows, 0);
+        self.text.screen[self.rows - 1] = [self.blank(); MAX_COLUMNS];
+
+        let text_height = self.rows * self.cell_height();
+        let line_size = self.line_size();
+        let cell_size = self.cell_height() * line_size;
+        let bottom_row = text_height - self.cell_height();
+        self.buffer_mut()
+            .copy_within(cell_size..text_height * line_size, 0);
+        let background = self.style.background();
+        self.fill(bottom_row..text_height, 0..self.info.width, background);
+        self.mark_dirty(0..text_height);
+    }
+
+    fn draw_char(&mut self, char: u8) {
+        let cell = Cell {
+            char,
+            foreground: self.style.foreground(),
+            background: self.style.background(),
+        };
+        self.text.screen[self.row][self.column] = cell;
+        self.draw_cell(self.row, self.column, cell);
+    }
+
+    fn draw_cell(&mut self, row: usize, column: usize, cell: Cell) {
+        let glyph = font::glyph(cell.char);
+        let foreground = pixel(&self.info, cell.foreground);
+        let background = pixel(&self.info, cell.background);
+        let left = column * self.cell_width();
+        let top = row * self.cell_height();
+
+        for y in 0..self.cell_height() {
+            // Past the end of the glyph is the line spacing.
+            let bits = glyph.get(y / self.scale).copied().unwrap_or(0);
+            for x in 0..self.cell_width() {
+                let color = if bits >> (x / self.scale) & 1 != 0 {
+                    foreground
+                } else {
+                    background
+                };
+                self.put_pixel(left + x, top + y, color);
+            }
+        }
+        self.mark_dirty(top..top + self.cell_height());
+    }
+
+    // Blank `columns` of text on `row`.
+    fn erase(&mut self, row: usize, columns: Range<usize>) {
+        let blank = self.blank();
+        self.text.screen[row][columns.clone()].fill(blank);
+
+        let top = row * self.cell_height();
+        let left = columns.start * self.cell_width();
+        let right = columns.end * self.cell_width();
+        self.fill(top..top + self.cell_height(), left..right, blank.background);
+    }
+
+    // Blank the whole screen, past the last row and column too, and go to the top left.
+    fn erase_all(&mut self) {
+        let blank = self.blank();
+        for line in &mut self.text.screen[..self.rows] {
+            line.fill(blank);
+        }
+        self.fill(0..self.info.height, 0..self.info.width, blank.background);
+        self.column = 0;
+        self.row = 0;
+    }
+
+    // What erased text turns into, blanks keep the background color like a terminal's.
+    fn blank(&self) -> Cell {
+        Cell {
+            char: b' ',
+            foreground: self.style.foreground(),
+            background: self.style.background(),
+        }
+    }
+
+    // Set the pixels in `rows` and `columns` to `color`.
+    fn fill(&mut self, rows: Range<usize>, columns: Range<usize>, color: Color) {
+        let color = pixel(&self.info, color);
+        for y in rows.clone() {
+            for x in columns.clone() {
+                self.put_pixel(x, y, color);
+            }
+        }
+        self.mark_dirty(rows);
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, pixel: [u8; 4]) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+        let bytes_per_pixel = self.info.bytes_per_pixel.min(pixel.len());
+        let offset = y * self.line_size() + x * self.info.bytes_per_pixel;
+        self.buffer_mut()[offset..offset + bytes_per_pixel]
+            .copy_from_slice(&pixel[..bytes_per_pixel]);
+    }
+
+    fn mark_dirty(&mut self, rows: Range<usize>) {
+        if self.dirty.is_empty() {
+            self.dirty = rows;
+        } else {
+            self.dirty = self.dirty.start.min(rows.start)..self.dirty.end.max(rows.end);
+        }
+    }
+
+    // Copy what changed in the back buffer to the framebuffer.
+    fn flush(&mut self) {
+        if let Some(back_buffer) = &self.back_buffer {
+            let line_size = self.info.stride * self.info.bytes_per_pixel;
+            let bytes = self.dirty.start * line_size..self.dirty.end * line_size;
+            self.framebuffer[bytes.clone()].copy_from_slice(&back_buffer[bytes]);
+        }
+        self.dirty = 0..0;
+    }
+}
+
+impl FrameBufferWriter<'_> {
+    // The pixel rows under the character at `row`, `column`.
+    fn cursor_pixels(&self, row: usize, column: usize) -> (Range<usize>, Range<usize>) {
+        let top = row * self.cell_height() + font::HEIGHT * self.scale;
+        let bottom = (row + 1) * self.cell_height();
+        let left = column * self.cell_width();
+        (top..bottom, left..left + self.cell_width())
+    }
+
+    // Draw the cursor where the next character goes, in its color. It's hidden while the
+    // scrollback is showing.
+    fn draw_cursor(&mut self) {
+        if !self.cursor_visible || self.view_offset > 0 {
+            return;
+        }
+        // Right after the last column it stays on the last column until the next char
+        let (row, column) = (self.row, self.column.min(self.columns - 1));
+        let (rows, columns) = self.cursor_pixels(row, column);
+        self.fill(rows, columns, self.style.foreground());
+        self.cursor = Some((row, column));
+    }
+
+    // Put back the line spacing the cursor was drawn over.
+    fn hide_cursor(&mut self) {
+        if let Some((row, column)) = self.cursor.take() {
+            let (rows, columns) = self.cursor_pixels(row, column);
+            let background = self.text.screen[row][column].background;
+            self.fill(rows, columns, background);
+        }
+    }
+}
+
+impl FrameBufferWriter<'_> {
+    /// Shows `lines` older lines, as far back as the scrollback goes.
+    pub fn scroll_back(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset + lines);
+        self.flush();
+    }
+
+    /// Shows `lines` newer lines, as far as the live screen.
+    pub fn scroll_forward(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_sub(lines));
+        self.flush();
+    }
+
+    fn scroll_to_live(&mut self) {
+        if self.view_offset > 0 {
+            self.set_view_offset(0);
+        }
+    }
+
+    // The screen shows `rows` lines of the scrollback and the live screen, ending
+    // `offset` lines before the end. The live screen itself is never touched.
+    fn set_view_offset(&mut self, offset: usize) {
+        let offset = offset.min(self.text.len);
+        if offset == self.view_offset {
+            return;
+        }
+        self.view_offset = offset;
+
+        let top = self.text.len - offset;
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                let cell = self.text.line(top + row)[column];
+                self.draw_cell(row, column, cell);
+            }
+        }
+        // Redrawing the line spacing took the cursor with it.
+        self.cursor = None;
+        self.draw_cursor();
+    }
+}
+
+impl fmt::Write for FrameBufferWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+//  ---Tests---
+
+// Two columns and two rows of text.
+#[cfg(test)]
+const TEST_WIDTH: usize = 2 * font::WIDTH;
+#[cfg(test)]
+const TEST_HEIGHT: usize = 2 * (font::HEIGHT + LINE_SPACING);
+#[cfg(test)]
+const TEST_SIZE: usize = TEST_WIDTH * TEST_HEIGHT * 4;
+
+#[cfg(test)]
+fn test_info() -> FrameBufferInfo {
+    FrameBufferInfo {
+        byte_len: TEST_SIZE,
+        width: TEST_WIDTH,
+        height: TEST_HEIGHT,
+        pixel_format: PixelFormat::Bgr,
+        bytes_per_pixel: 4,
+        stride: TEST_WIDTH,
+    }
+}
+
+// Text is too big for the test stack, the tests take turns with this one.
+#[cfg(test)]
+static TEST_TEXT: IrqSafeSpinlock<Text> = IrqSafeSpinlock::new(Text::new());
+
+// Whether the cell at `column`, `row` in `buffer` shows `char`.
+#[cfg(test)]
+fn shows(buffer: &[u8], column: usize, row: usize, char: u8) -> bool {
+    shows_in(buffer, column, row, char, DEFAULT_FOREGROUND)
+}
+
+#[cfg(test)]
+fn shows_in(buffer: &[u8], column: usize, row: usize, char: u8, color: Color) -> bool {
+    let info = test_info();
+    let foreground = pixel(&info, color);
+    let glyph = font::glyph(char);
+    (0..font::HEIGHT).all(|y| {
+        (0..font::WIDTH).all(|x| {
+            let offset = ((row * (font::HEIGHT + LINE_SPACING) + y) * info.stride
+                + column * font::WIDTH
+                + x)
+                * info.bytes_per_pixel;
+            let set = buffer[offset..offset + 4] == foreground;
+            set == (glyph[y] >> x & 1 != 0)
+        })
+    })
+}
+
+// Whether the cursor is drawn under the cell at `column`, `row` in `buffer`.
+#[cfg(test)]
+fn cursor_at(buffer: &[u8], column: usize, row: usize) -> bool {
+    let info = test_info();
+    let foreground = pixel(&info, DEFAULT_FOREGROUND);
+    (font::HEIGHT..font::HEIGHT + LINE_SPACING).all(|y| {
+        (0..font::WIDTH).all(|x| {
+            let offset = ((row * (font::HEIGHT + LINE_SPACING) + y) * info.stride
+                + column * font::WIDTH
+                + x)
+                * info.bytes_per_pixel;
+            buffer[offset..offset + 4] == foreground
+        })
+    })
+}
+
+#[test_case]
+fn test_pixel_formats() {
+    let mut info = test_info();
+    info.pixel_format = PixelFormat::Rgb;
+    assert_eq!(pixel(&info, Color::Brown), [0xaa, 0x55, 0x00, 0]);
+    info.pixel_format = PixelFormat::Bgr;
+    assert_eq!(pixel(&info, Color::Brown), [0x00, 0x55, 0xaa, 0]);
+    info.pixel_format = PixelFormat::Unknown {
+        red_position: 0,
+        green_position: 8,
+        blue_position: 16,
+    };
+    assert_eq!(pixel(&info, Color::Brown), [0xaa, 0x55, 0x00, 0]);
+    info.pixel_format = PixelFormat::U8;
+    assert_eq!(pixel(&info, Color::White)[0], 0xff);
+    assert_eq!(pixel(&info, Color::Black)[0], 0x00);
+}
+
+#[test_case]
+fn test_write_and_wrap() {
+    let mut framebuffer = [0xffu8; TEST_SIZE];
+    let mut text = TEST_TEXT.lock();
+    let mut writer = FrameBufferWriter::new(test_info(), &mut framebuffer, &mut text);
+    assert_eq!((writer.columns(), writer.rows()), (2, 2));
+    writer.write_string("ab!");
+    assert!(shows(&framebuffer, 0, 0, b'a'));
+    assert!(shows(&framebuffer, 1, 0, b'b'));
+    assert!(shows(&framebuffer, 0, 1, b'!'));
+    assert!(shows(&framebuffer, 1, 1, b' '));
+}
+
+#[test_case]
+fn test_scroll() {
+    let mut framebuffer = [0u8; TEST_SIZE];
+    let mut text = TEST_TEXT.lock();
+    let mut writer = FrameBufferWriter::new(test_info(), &mut framebuffer, &mut text);
+    writer.write_string("a\nb\nc");
+    assert!(shows(&framebuffer, 0, 0, b'b'));
+    assert!(shows(&framebuffer, 0, 1, b'c'));
+}
+
+#[test_case]
+fn test_back_buffer() {
+    let mut framebuffer = [0u8; TEST_SIZE];
+    let mut back_buffer = [0u8; TEST_SIZE];
+    let mut text = TEST_TEXT.lock();
+    let mut writer = FrameBufferWriter::new(test_info(), &mut framebuffer, &mut text);
+    writer.back_buffer = Some(&mut back_buffer);
+    writer.write_byte(b'x');
+    assert!(!shows(writer.framebuffer, 0, 0, b'x'));
+    writer.write_string("\ny\nz");
+    assert!(shows(&back_buffer, 0, 0, b'y'));
+    assert!(framebuffer[..] == back_buffer[..]);
+}
+
+#[test_case]
+fn test_escape_sequences() {
+    let mut framebuffer = [0u8; TEST_SIZE];
+    let mut text = TEST_TEXT.lock();
+    let mut writer = FrameBufferWriter::new(test_info(), &mut framebuffer, &mut text);
+    writer.write_string("\x1b[2;2Ha\x1b[1;1H\x1b[31mb\x1b[0m\x1b[2;1H\x1b[K");
+    assert!(writer.style == Style::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND));
+    assert!(shows_in(&framebuffer, 0, 0, b'b', Color::Red));
+    assert!(shows(&framebuffer, 1, 1, b' '));
+}
+
+#[test_case]
+fn test_control_chars() {
+    let mut framebuffer = [0u8; TEST_SIZE];
+    let mut text = TEST_TEXT.lock();
+    let mut writer = FrameBufferWriter::new(test_info(), &mut framebuffer, &mut text);
+    writer.write_string("ab\rc\x08\x08d\t\x07e");
+    assert!(shows(&framebuffer, 0, 0, b'd'));
+    assert!(shows(&framebuffer, 1, 0, b'e'));
+    assert!(shows(&framebuffer, 0, 1, b' '));
+}
+
+#[test_case]
+fn test_scrollback() {
+    let mut framebuffer = [0u8; TEST_SIZE];
+    let mut text = TEST_TEXT.lock();
+    let mut writer = FrameBufferWriter::new(test_info(), &mut framebuffer, &mut text);
+    writer.write_string("a\nb\nc\nd");
+    writer.scroll_back(1);
+    assert!(shows(writer.framebuffer, 0, 0, b'b'));
+    assert!(shows(writer.framebuffer, 0, 1, b'c'));
+    writer.scroll_back(10);
+    assert_eq!(writer.view_offset, 2);
+    assert!(shows(writer.framebuffer, 0, 0, b'a'));
+    writer.scroll_forward(1);
+    assert!(shows(writer.framebuffer, 0, 0, b'b'));
+
+    // Output goes back to the live screen first
+    writer.write_string("e");
+    assert_eq!(writer.view_offset, 0);
+    assert!(shows(&framebuffer, 0, 0, b'c'));
+    assert!(shows(&framebuffer, 0, 1, b'd'));
+    assert!(shows(&framebuffer, 1, 1, b'e'));
+}
+
+#[test_case]
+fn test_cursor() {
+    let mut framebuffer = [0u8; TEST_SIZE];
+    let mut text = TEST_TEXT.lock();
+    let mut writer = FrameBufferWriter::new(test_info(), &mut framebuffer, &mut text);
+    assert!(cursor_at(writer.framebuffer, 0, 0));
+    writer.write_string("ab");
+    // Right after the last column it waits on the last column
+    assert!(!cursor_at(writer.framebuffer, 0, 0));
+    assert!(cursor_at(writer.framebuffer, 1, 0));
+    writer.write_string("\n\n");
+    assert!(cursor_at(writer.framebuffer, 0, 1));
+    writer.scroll_back(1);
+    assert!(!cursor_at(writer.framebuffer, 0, 1));
+    writer.scroll_forward(1);
+    assert!(cursor_at(writer.framebuffer, 0, 1));
+    writer.write_string("\x1b[?25l");
+    assert!(!cursor_at(&framebuffer, 0, 1));
+}
@@ -0,0 +1,358 @@
+// Mod for the virtual filesystem (VFS).
+// Filesystem drivers implement the FileSystem trait and get mounted on a path.
+// Every call takes a full path, finds the mount with the longest matching
+// prefix and hands the rest of the path to that filesystem.
+// There are no file handles yet, reads and writes take an offset instead.
+// Symlinks are resolved here rather than in the drivers so that a link can
+// point across mount points.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::block::BlockError;
+
+pub mod ext2;
+pub mod fat;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidPath,
+    NameTooLong,
+    NoSpace,
+    ReadOnly,
+    /// The on-disk structures don't make sense.
+    Corrupt,
+    /// The filesystem uses a feature the driver doesn't implement.
+    Unsupported,
+    NotMounted,
+    /// Symlinks nested deeper than `MAX_SYMLINK_HOPS`, probably a loop.
+    TooManyLinks,
+    Io(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        FsError::Io(err)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+    pub size: u64,
+}
+
+/// Interface every filesystem driver implements.
+///
+/// Paths handed to a filesystem are relative to its mount point, start with
+/// `/` and never contain empty, `.` or `..` components.
+/// Read only filesystems only need the first three methods.
+pub trait FileSystem: Send {
+    /// Returns the metadata of `path` without following a symlink at the end.
+    fn metadata(&mut self, path: &str) -> Result<Metadata, FsError>;
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Reads from the file at `offset`, returning how many bytes were read.
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes to the file at `offset`, growing it if needed.
+    fn write(&mut self, _path: &str, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_file(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Shrinks or grows (with zeros) the file to `size` bytes.
+    fn truncate(&mut self, _path: &str, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes a file or an empty directory.
+    fn remove(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Returns the target of the symlink at `path`.
+    fn read_link(&mut self, _path: &str) -> Result<String, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Flushes any cached state to the disk.
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+//  ---Paths---
+
+/// Splits a path into its components, resolving `.` and `..`.
+///
+/// Returns an error for relative paths since there is no working directory.
+pub fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    Ok(parts)
+}
+
+/// Builds a normalised absolute path from components.
+pub fn join(parts: &[&str]) -> String {
+    let mut path = String::new();
+    for part in parts {
+        path.push('/');
+        path.push_str(part);
+    }
+
+    if path.is_empty() {
+        path.push('/');
+    }
+
+    path
+}
+
+/// Splits a path into its parent and final component.
+///
+/// Returns `None` for the root directory.
+pub fn split_parent(path: &str) -> Result<Option<(String, String)>, FsError> {
+    let mut parts = components(path)?;
+    match parts.pop() {
+        Some(name) => Ok(Some((join(&parts), name.to_string()))),
+        None => Ok(None),
+    }
+}
+
+// Linux gives up after 40 links as well.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Resolves every symlink in `path` and returns the real path.
+///
+/// A symlink as the last component is only followed when `follow_last` is set.
+/// A missing last component is fine so that the path can be created.
+fn resolve(path: &str, follow_last: bool) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    // Components still to walk, in reverse so the next one is at the end.
+    let mut pending: Vec<String> = path.rsplit('/').map(String::from).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    let mut hops = 0;
+
+    while let Some(part) = pending.pop() {
+        match part.as_str() {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => resolved.push(part),
+        }
+
+        let is_last = pending.iter().all(|part| part.is_empty() || part == ".");
+        if is_last && !follow_last {
+            continue;
+        }
+
+        let current = join_owned(&resolved);
+        let meta = match with_fs(&current, |fs, path| fs.metadata(path)) {
+            Err(FsError::NotFound) if is_last => break,
+            meta => meta?,
+        };
+        if meta.file_type != FileType::Symlink {
+            continue;
+        }
+
+        hops += 1;
+        if hops > MAX_SYMLINK_HOPS {
+            return Err(FsError::TooManyLinks);
+        }
+
+        let target = with_fs(&current, |fs, path| fs.read_link(path))?;
+        resolved.pop();
+        if target.starts_with('/') {
+            resolved.clear();
+        }
+        pending.extend(target.rsplit('/').map(String::from));
+    }
+
+    Ok(join_owned(&resolved))
+}
+
+fn join_owned(parts: &[String]) -> String {
+    let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+    join(&parts)
+}
+
+//  ---Mount Table---
+
+struct Mount {
+    // Normalised path such as "/" or "/mnt/host".
+    path: String,
+    fs: Box<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Mounts `fs` at `path`.
+///
+/// The root mount can be made before the directory exists, every other mount
+/// point should be an existing directory so that it shows up in listings.
+pub fn mount(path: &str, fs: Box<dyn FileSystem>) -> Result<(), FsError> {
+    let path = join(&components(path)?);
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::AlreadyExists);
+    }
+
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Unmounts and returns the filesystem mounted at `path`, syncing it first.
+pub fn unmount(path: &str) -> Result<Box<dyn FileSystem>, FsError> {
+    let path = join(&components(path)?);
+    let mut mounts = MOUNTS.lock();
+
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(FsError::NotMounted)?;
+
+    mounts[index].fs.sync()?;
+    Ok(mounts.remove(index).fs)
+}
+
+/// Runs `f` with the filesystem that owns `path` and the path inside it.
+fn with_fs<R>(
+    path: &str,
+    f: impl FnOnce(&mut dyn FileSystem, &str) -> Result<R, FsError>,
+) -> Result<R, FsError> {
+    let parts = components(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    // Find the mount whose path matches the most leading components.
+    let mut best: Option<(usize, usize)> = None;
+    for (index, mount) in mounts.iter().enumerate() {
+        let mount_parts = components(&mount.path)?;
+        if parts.starts_with(&mount_parts) && best.is_none_or(|(_, len)| mount_parts.len() > len) {
+            best = Some((index, mount_parts.len()));
+        }
+    }
+
+    let (index, len) = best.ok_or(FsError::NotMounted)?;
+    let inner_path = join(&parts[len..]);
+    f(mounts[index].fs.as_mut(), &inner_path)
+}
+
+//  ---File Operations---
+
+/// Returns the metadata of `path`, following symlinks.
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    with_fs(&resolve(path, true)?, |fs, path| fs.metadata(path))
+}
+
+/// Returns the metadata of `path` without following a symlink at the end.
+pub fn symlink_metadata(path: &str) -> Result<Metadata, FsError> {
+    with_fs(&resolve(path, false)?, |fs, path| fs.metadata(path))
+}
+
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    with_fs(&resolve(path, false)?, |fs, path| fs.read_link(path))
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    with_fs(&resolve(path, true)?, |fs, path| fs.read_dir(path))
+}
+
+pub fn read(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    with_fs(&resolve(path, true)?, |fs, path| fs.read(path, offset, buf))
+}
+
+/// Reads a whole file into memory.
+pub fn read_to_vec(path: &str) -> Result<Vec<u8>, FsError> {
+    with_fs(&resolve(path, true)?, |fs, path| {
+        let meta = fs.metadata(path)?;
+        if meta.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        let mut data = vec![0; meta.size as usize];
+        let read = fs.read(path, 0, &mut data)?;
+        data.truncate(read);
+        Ok(data)
+    })
+}
+
+pub fn write(path: &str, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+    with_fs(&resolve(path, true)?, |fs, path| {
+        fs.write(path, offset, buf)
+    })
+}
+
+pub fn create_file(path: &str) -> Result<(), FsError> {
+    with_fs(&resolve(path, false)?, |fs, path| fs.create_file(path))
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    with_fs(&resolve(path, false)?, |fs, path| fs.create_dir(path))
+}
+
+pub fn truncate(path: &str, size: u64) -> Result<(), FsError> {
+    with_fs(&resolve(path, true)?, |fs, path| fs.truncate(path, size))
+}
+
+pub fn remove(path: &str) -> Result<(), FsError> {
+    with_fs(&resolve(path, false)?, |fs, path| fs.remove(path))
+}
+
+/// Syncs every mounted filesystem.
+pub fn sync() -> Result<(), FsError> {
+    for mount in MOUNTS.lock().iter_mut() {
+        mount.fs.sync()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,469 @@
+// Mod for reading ext2 filesystems.
+// Only reading is supported, any attempt to change the filesystem gets ReadOnly.
+// Files are found through the direct, indirect, double indirect and triple
+// indirect block pointers in the inode. Extents (ext4) are not supported.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::BlockDevice;
+use crate::fs::{self, DirEntry, FileSystem, FileType, FsError, Metadata};
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+// Incompatible features we know how to read. Anything else and we refuse to mount.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+const TRIPLE_INDIRECT_BLOCK: usize = 14;
+
+// Fast symlinks keep their target inside the block pointers, so it's shorter than this.
+const FAST_SYMLINK_MAX: u64 = 60;
+
+// Limits on what gets read into memory in one go, a corrupt size can't run the heap dry.
+// Descriptors for 16 TiB at the default 32768 blocks of 4 KiB per group.
+const MAX_DESCRIPTOR_TABLE: usize = 4 << 20;
+const MAX_DIR_SIZE: u64 = 16 << 20;
+
+#[derive(Debug, Clone)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    // 512 byte sectors holding data, without the extended attribute block.
+    data_sectors: u32,
+    // The 15 block pointers, kept raw because fast symlinks store text here.
+    block: [u8; 60],
+}
+
+impl Inode {
+    fn file_type(&self) -> Result<FileType, FsError> {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_FILE => Ok(FileType::File),
+            MODE_DIRECTORY => Ok(FileType::Directory),
+            MODE_SYMLINK => Ok(FileType::Symlink),
+            // Devices, FIFOs and sockets mean nothing to us yet.
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn block_pointer(&self, index: usize) -> u32 {
+        read_u32(&self.block, index * 4)
+    }
+}
+
+pub struct Ext2FileSystem {
+    device: Box<dyn BlockDevice>,
+    block_size: usize,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    // Block number of the inode table of every block group.
+    inode_tables: Vec<u32>,
+    volume_name: String,
+}
+
+impl Ext2FileSystem {
+    /// Reads the superblock and block group descriptors from `device` and
+    /// mounts the filesystem.
+    pub fn mount(mut device: Box<dyn BlockDevice>) -> Result<Self, FsError> {
+        let device_block = device.block_size();
+        let first = SUPERBLOCK_OFFSET / device_block;
+        let count = SUPERBLOCK_SIZE.div_ceil(device_block);
+        let mut buf = vec![0; count * device_block];
+        device.read_blocks(first as u64, &mut buf)?;
+        let superblock = &buf[SUPERBLOCK_OFFSET % device_block..];
+
+        if read_u16(superblock, 56) != EXT2_MAGIC {
+            return Err(FsError::Corrupt);
+        }
+
+        let device_size = device.block_count() * device_block as u64;
+        let inodes_count = read_u32(superblock, 0);
+        let blocks_count = read_u32(superblock, 4);
+        let first_data_block = read_u32(superblock, 20);
+        let log_block_size = read_u32(superblock, 24);
+        let blocks_per_group = read_u32(superblock, 32);
+        let inodes_per_group = read_u32(superblock, 40);
+        let rev_level = read_u32(superblock, 76);
+
+        if log_block_size > 6 || blocks_per_group == 0 || inodes_per_group == 0 {
+            return Err(FsError::Corrupt);
+        }
+        let block_size = 1024usize << log_block_size;
+        if !block_size.is_multiple_of(device_block) {
+            return Err(FsError::Unsupported);
+        }
+
+        // Revision 0 filesystems have fixed 128 byte inodes and no feature flags.
+        let (inode_size, incompat) = if rev_level == 0 {
+            (128, 0)
+        } else {
+            (read_u16(superblock, 88) as usize, read_u32(superblock, 96))
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if inode_size < 128 || inode_size > block_size {
+            return Err(FsError::Corrupt);
+        }
+
+        let name_bytes = &superblock[120..136];
+        let name_len = name_bytes.iter().position(|&byte| byte == 0).unwrap_or(16);
+        let volume_name = String::from_utf8_lossy(&name_bytes[..name_len]).into_owned();
+
+        // Each group's block bitmap is a single block.
+        if blocks_per_group > 8 * block_size as u32 {
+            return Err(FsError::Corrupt);
+        }
+        let group_count = blocks_count
+            .checked_sub(first_data_block)
+            .and_then(|blocks| blocks.checked_add(blocks_per_group - 1))
+            .ok_or(FsError::Corrupt)?
+            / blocks_per_group;
+        let group_count = group_count as usize;
+
+        // A corrupt superblock could otherwise have us read and allocate way past the end.
+        if u64::from(blocks_count) * block_size as u64 > device_size
+            || u64::from(inodes_count) > group_count as u64 * u64::from(inodes_per_group)
+        {
+            return Err(FsError::Corrupt);
+        }
+        const DESCRIPTOR_SIZE: usize = 32;
+        if group_count * DESCRIPTOR_SIZE > MAX_DESCRIPTOR_TABLE {
+            return Err(FsError::Unsupported);
+        }
+
+        let mut fs = Ext2FileSystem {
+            device,
+            block_size,
+            inodes_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables: Vec::with_capacity(group_count),
+            volume_name,
+        };
+
+        // The group descriptor table starts in the block after the superblock.
+        let table_blocks = (group_count * DESCRIPTOR_SIZE).div_ceil(block_size);
+        let mut table = vec![0; table_blocks * block_size];
+        for index in 0..table_blocks {
+            let block = first_data_block
+                .checked_add(1 + index as u32)
+                .ok_or(FsError::Corrupt)?;
+            fs.read_block(
+                block,
+                &mut table[index * block_size..(index + 1) * block_size],
+            )?;
+        }
+
+        for group in 0..group_count {
+            let inode_table = read_u32(&table, group * DESCRIPTOR_SIZE + 8);
+            if inode_table == 0 || inode_table >= blocks_count {
+                return Err(FsError::Corrupt);
+            }
+            fs.inode_tables.push(inode_table);
+        }
+
+        Ok(fs)
+    }
+
+    pub fn volume_name(&self) -> &str {
+        &self.volume_name
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+
+//  ---Blocks and Inodes---
+
+impl Ext2FileSystem {
+    fn read_block(&mut self, block: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let device_blocks = (self.block_size / self.device.block_size()) as u64;
+        self.device
+            .read_blocks(u64::from(block) * device_blocks, buf)?;
+        Ok(())
+    }
+
+    fn read_inode(&mut self, number: u32) -> Result<Inode, FsError> {
+        if number == 0 || number > self.inodes_count {
+            return Err(FsError::Corrupt);
+        }
+
+        let index = number - 1;
+        let group = (index / self.inodes_per_group) as usize;
+        let offset = (index % self.inodes_per_group) as usize * self.inode_size;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupt)?;
+
+        let mut buf = vec![0; self.block_size];
+        let block = table
+            .checked_add((offset / self.block_size) as u32)
+            .ok_or(FsError::Corrupt)?;
+        self.read_block(block, &mut buf)?;
+        let raw = &buf[offset % self.block_size..];
+
+        let mode = read_u16(raw, 0);
+        let mut size = u64::from(read_u32(raw, 4));
+        // With large_file the high half of a regular file's size lives where dir_acl used to be.
+        if mode & MODE_TYPE_MASK == MODE_FILE {
+            size |= u64::from(read_u32(raw, 108)) << 32;
+        }
+
+        // i_blocks counts the extended attribute block too.
+        let mut data_sectors = read_u32(raw, 28);
+        if read_u32(raw, 104) != 0 {
+            data_sectors = data_sectors.saturating_sub((self.block_size / 512) as u32);
+        }
+
+        let mut block = [0; 60];
+        block.copy_from_slice(&raw[40..100]);
+
+        Ok(Inode {
+            mode,
+            size,
+            data_sectors,
+            block,
+        })
+    }
+
+    /// Reads entry `index` of the indirect block `block`.
+    fn read_indirect(&mut self, block: u32, index: usize) -> Result<u32, FsError> {
+        if block == 0 {
+            return Ok(0);
+        }
+
+        let mut buf = vec![0; self.block_size];
+        self.read_block(block, &mut buf)?;
+        Ok(read_u32(&buf, index * 4))
+    }
+
+    /// Maps a block index inside a file to a block on disk.
+    ///
+    /// Returns 0 for holes in sparse files.
+    fn map_block(&mut self, inode: &Inode, mut index: u64) -> Result<u32, FsError> {
+        let per_block = (self.block_size / 4) as u64;
+
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.block_pointer(index as usize));
+        }
+        index -= DIRECT_BLOCKS as u64;
+
+        if index < per_block {
+            return self.read_indirect(inode.block_pointer(INDIRECT_BLOCK), index as usize);
+        }
+        index -= per_block;
+
+        if index < per_block * per_block {
+            let indirect = self.read_indirect(
+                inode.block_pointer(DOUBLE_INDIRECT_BLOCK),
+                (index / per_block) as usize,
+            )?;
+            return self.read_indirect(indirect, (index % per_block) as usize);
+        }
+        index -= per_block * per_block;
+
+        if index < per_block * per_block * per_block {
+            let double = self.read_indirect(
+                inode.block_pointer(TRIPLE_INDIRECT_BLOCK),
+                (index / (per_block * per_block)) as usize,
+            )?;
+            let indirect =
+                self.read_indirect(double, ((index / per_block) % per_block) as usize)?;
+            return self.read_indirect(indirect, (index % per_block) as usize);
+        }
+
+        Err(FsError::Corrupt)
+    }
+
+    /// Reads the contents of an inode starting at `offset`.
+    fn read_data(&mut self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+
+        let len = (buf.len() as u64).min(inode.size - offset) as usize;
+        let block_size = self.block_size as u64;
+        let mut block_buf = vec![0; self.block_size];
+        let mut done = 0;
+
+        while done < len {
+            let position = offset + done as u64;
+            let in_block = (position % block_size) as usize;
+            let count = (self.block_size - in_block).min(len - done);
+
+            match self.map_block(inode, position / block_size)? {
+                0 => {
+                    for byte in buf[done..done + count].iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                block => {
+                    self.read_block(block, &mut block_buf)?;
+                    buf[done..done + count].copy_from_slice(&block_buf[in_block..in_block + count]);
+                }
+            }
+
+            done += count;
+        }
+
+        Ok(len)
+    }
+}
+
+//  ---Directories---
+
+impl Ext2FileSystem {
+    /// Lists a directory as (name, inode number) pairs, skipping "." and "..".
+    fn dir_entries(&mut self, inode: &Inode) -> Result<Vec<(String, u32)>, FsError> {
+        if inode.size > MAX_DIR_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let mut data = vec![0; inode.size as usize];
+        self.read_data(inode, 0, &mut data)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let number = read_u32(&data, offset);
+            let rec_len = read_u16(&data, offset + 4) as usize;
+            let name_len = data[offset + 6] as usize;
+
+            if rec_len < 8 || offset + rec_len > data.len() || name_len + 8 > rec_len {
+                return Err(FsError::Corrupt);
+            }
+
+            let name = &data[offset + 8..offset + 8 + name_len];
+            if number != 0 && name != b"." && name != b".." {
+                entries.push((String::from_utf8_lossy(name).into_owned(), number));
+            }
+
+            offset += rec_len;
+        }
+
+        Ok(entries)
+    }
+
+    /// Walks `path` from the root inode and returns the inode it names.
+    fn lookup(&mut self, path: &str) -> Result<Inode, FsError> {
+        let mut inode = self.read_inode(ROOT_INODE)?;
+
+        for part in fs::components(path)? {
+            if inode.file_type()? != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+
+            let number = self
+                .dir_entries(&inode)?
+                .into_iter()
+                .find(|(name, _)| name == part)
+                .map(|(_, number)| number)
+                .ok_or(FsError::NotFound)?;
+            inode = self.read_inode(number)?;
+        }
+
+        Ok(inode)
+    }
+}
+
+impl FileSystem for Ext2FileSystem {
+    fn metadata(&mut self, path: &str) -> Result<Metadata, FsError> {
+        let inode = self.lookup(path)?;
+        let file_type = inode.file_type()?;
+        let size = match file_type {
+            FileType::Directory => 0,
+            _ => inode.size,
+        };
+
+        Ok(Metadata { file_type, size })
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let inode = self.lookup(path)?;
+        if inode.file_type()? != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut entries = Vec::new();
+        for (name, number) in self.dir_entries(&inode)? {
+            let child = self.read_inode(number)?;
+            // Skip special files instead of failing the whole listing.
+            if let Ok(file_type) = child.file_type() {
+                let size = match file_type {
+                    FileType::Directory => 0,
+                    _ => child.size,
+                };
+                entries.push(DirEntry {
+                    name,
+                    file_type,
+                    size,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.lookup(path)?;
+        match inode.file_type()? {
+            FileType::File => self.read_data(&inode, offset, buf),
+            FileType::Directory => Err(FsError::IsADirectory),
+            FileType::Symlink => Err(FsError::InvalidPath),
+        }
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, FsError> {
+        let inode = self.lookup(path)?;
+        if inode.file_type()? != FileType::Symlink {
+            return Err(FsError::InvalidPath);
+        }
+
+        // Like Linux, a symlink without data blocks is a fast one. Targets are at most a
+        // block either way.
+        let target = if inode.data_sectors == 0 {
+            if inode.size >= FAST_SYMLINK_MAX {
+                return Err(FsError::Corrupt);
+            }
+            inode.block[..inode.size as usize].to_vec()
+        } else {
+            if inode.size > self.block_size as u64 {
+                return Err(FsError::Corrupt);
+            }
+            let mut target = vec![0; inode.size as usize];
+            self.read_data(&inode, 0, &mut target)?;
+            target
+        };
+
+        String::from_utf8(target).map_err(|_| FsError::Corrupt)
+    }
+}
+
+//  ---Little Endian Helpers---
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
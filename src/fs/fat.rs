@@ -0,0 +1,1261 @@
+// Mod for FAT16 and FAT32 filesystems.
+// Supports reading and writing files, long file names, creating and removing
+// directories and keeping the FAT32 FSINFO sector up to date.
+// FAT12 (floppies) is not supported.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::BlockDevice;
+use crate::fs::{self, DirEntry, FileSystem, FileType, FsError, Metadata};
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+const DIR_ENTRY_SIZE: usize = 32;
+// Cluster numbers from 0x0FFFFFF7 up are the bad cluster and end of chain markers.
+const MAX_CLUSTER_COUNT: u64 = 0x0FFF_FFF5;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xE5;
+// A real 0xE5 as the first name byte is stored as 0x05.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+// Bits in the reserved byte that Windows and Linux use for all lowercase 8.3 names.
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXT: u8 = 0x10;
+
+const LFN_LAST_ENTRY: u8 = 0x40;
+const LFN_CHARS_PER_ENTRY: usize = 13;
+const MAX_NAME_LEN: usize = 255;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// Timestamps aren't kept, everything is stamped 1980-01-01 00:00, the earliest date FAT can store.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+// Where the entries of a directory live.
+// FAT16 keeps the root directory in a fixed area before the data clusters.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Dir {
+    FixedRoot,
+    Chain(u32),
+}
+
+// A decoded directory entry and where it sits inside its parent.
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    parent: Dir,
+    // Slot of the first long name entry, or of the short entry if there is no long name.
+    first_slot: usize,
+    // Slot of the short (8.3) entry.
+    slot: usize,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+// Long name pieces collected while walking a directory.
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    next_ord: u8,
+    first_slot: usize,
+}
+
+pub struct FatFileSystem {
+    device: Box<dyn BlockDevice>,
+    fat_type: FatType,
+    bytes_per_sector: usize,
+    sectors_per_cluster: u64,
+    reserved_sectors: u64,
+    num_fats: u64,
+    fat_size: u64,
+    // Set when FAT32 mirroring is turned off and only one FAT is live.
+    active_fat: Option<u64>,
+    root_dir_sector: u64,
+    root_dir_sectors: u64,
+    root_cluster: u32,
+    data_start: u64,
+    cluster_count: u32,
+    fs_info_sector: Option<u64>,
+    free_count: Option<u32>,
+    next_free: u32,
+}
+
+impl FatFileSystem {
+    /// Reads the BIOS parameter block (BPB) from the first sector of `device`
+    /// and mounts the filesystem.
+    pub fn mount(mut device: Box<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut boot = vec![0; device.block_size().max(512)];
+        device.read_blocks(0, &mut boot)?;
+
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(FsError::Corrupt);
+        }
+
+        let bytes_per_sector = read_u16(&boot, 11) as usize;
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved_sectors = u64::from(read_u16(&boot, 14));
+        let num_fats = u64::from(boot[16]);
+        let root_entry_count = u64::from(read_u16(&boot, 17));
+        let total_sectors_16 = u64::from(read_u16(&boot, 19));
+        let fat_size_16 = u64::from(read_u16(&boot, 22));
+        let total_sectors_32 = u64::from(read_u32(&boot, 32));
+
+        if !bytes_per_sector.is_power_of_two()
+            || bytes_per_sector < 512
+            || !bytes_per_sector.is_multiple_of(device.block_size())
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || num_fats == 0
+        {
+            return Err(FsError::Corrupt);
+        }
+
+        let fat_size = if fat_size_16 != 0 {
+            fat_size_16
+        } else {
+            u64::from(read_u32(&boot, 36))
+        };
+        let total_sectors = if total_sectors_16 != 0 {
+            total_sectors_16
+        } else {
+            total_sectors_32
+        };
+
+        let root_dir_sector = reserved_sectors + num_fats * fat_size;
+        let root_dir_sectors =
+            (root_entry_count * DIR_ENTRY_SIZE as u64).div_ceil(bytes_per_sector as u64);
+        let data_start = root_dir_sector + root_dir_sectors;
+        let data_sectors = total_sectors
+            .checked_sub(data_start)
+            .ok_or(FsError::Corrupt)?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+
+        // The FAT type is decided purely by the cluster count.
+        let fat_type = if cluster_count < 4085 {
+            return Err(FsError::Unsupported);
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else if cluster_count <= MAX_CLUSTER_COUNT {
+            FatType::Fat32
+        } else {
+            return Err(FsError::Corrupt);
+        };
+
+        // Every cluster needs an entry, and the first two entries are reserved.
+        let entry_size = match fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
+        if fat_size * (bytes_per_sector as u64) < (cluster_count + 2) * entry_size {
+            return Err(FsError::Corrupt);
+        }
+
+        let mut fs = FatFileSystem {
+            device,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            fat_size,
+            active_fat: None,
+            root_dir_sector,
+            root_dir_sectors,
+            root_cluster: 0,
+            data_start,
+            cluster_count: cluster_count as u32,
+            fs_info_sector: None,
+            free_count: None,
+            next_free: 2,
+        };
+
+        if fat_type == FatType::Fat32 {
+            if root_entry_count != 0 || fat_size_16 != 0 {
+                return Err(FsError::Corrupt);
+            }
+
+            let ext_flags = read_u16(&boot, 40);
+            if ext_flags & 0x80 != 0 {
+                fs.active_fat = Some(u64::from(ext_flags & 0x0F));
+            }
+
+            fs.root_cluster = read_u32(&boot, 44);
+            if !fs.is_valid_cluster(fs.root_cluster) {
+                return Err(FsError::Corrupt);
+            }
+
+            let fs_info = u64::from(read_u16(&boot, 48));
+            if fs_info != 0 && fs_info < reserved_sectors {
+                fs.load_fs_info(fs_info)?;
+            }
+        }
+
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Number of free clusters if known.
+    pub fn free_clusters(&self) -> Option<u32> {
+        self.free_count
+    }
+}
+
+//  ---Sectors and Clusters---
+
+impl FatFileSystem {
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let blocks_per_sector = (self.bytes_per_sector / self.device.block_size()) as u64;
+        self.device.read_blocks(sector * blocks_per_sector, buf)?;
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector: u64, buf: &[u8]) -> Result<(), FsError> {
+        let blocks_per_sector = (self.bytes_per_sector / self.device.block_size()) as u64;
+        self.device.write_blocks(sector * blocks_per_sector, buf)?;
+        Ok(())
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster as usize
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= 0x0FFF_FFF8,
+        }
+    }
+
+    /// Returns the sector and byte offset of the FAT entry for `cluster`.
+    fn fat_entry_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster as usize * 2,
+            FatType::Fat32 => cluster as usize * 4,
+        };
+
+        (
+            self.reserved_sectors + (offset / self.bytes_per_sector) as u64,
+            offset % self.bytes_per_sector,
+        )
+    }
+
+    fn read_fat(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let (sector, offset) = self.fat_entry_position(cluster);
+        let sector = sector + self.active_fat.unwrap_or(0) * self.fat_size;
+
+        let mut buf = vec![0; self.bytes_per_sector];
+        self.read_sector(sector, &mut buf)?;
+
+        Ok(match self.fat_type {
+            FatType::Fat16 => u32::from(read_u16(&buf, offset)),
+            FatType::Fat32 => read_u32(&buf, offset) & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Writes `value` into every live copy of the FAT.
+    fn write_fat(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let (sector, offset) = self.fat_entry_position(cluster);
+        let fats = match self.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.num_fats,
+        };
+
+        let mut buf = vec![0; self.bytes_per_sector];
+        for fat in fats {
+            let sector = sector + fat * self.fat_size;
+            self.read_sector(sector, &mut buf)?;
+
+            match self.fat_type {
+                FatType::Fat16 => write_u16(&mut buf, offset, value as u16),
+                FatType::Fat32 => {
+                    // The top four bits are reserved and must be kept.
+                    let old = read_u32(&buf, offset);
+                    write_u32(
+                        &mut buf,
+                        offset,
+                        (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+                    );
+                }
+            }
+
+            self.write_sector(sector, &buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Follows the cluster chain starting at `first`.
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        if first == 0 {
+            return Ok(clusters);
+        }
+
+        let mut cluster = first;
+        loop {
+            // A chain longer than the volume must loop back on itself.
+            if !self.is_valid_cluster(cluster) || clusters.len() > self.cluster_count as usize {
+                return Err(FsError::Corrupt);
+            }
+
+            clusters.push(cluster);
+            let next = self.read_fat(cluster)?;
+            if self.is_end_of_chain(next) {
+                return Ok(clusters);
+            }
+            cluster = next;
+        }
+    }
+
+    /// Finds a free cluster, zeroes it and marks it as the end of a chain.
+    ///
+    /// If `previous` is given the new cluster is linked after it.
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, FsError> {
+        let first = self.next_free.max(2);
+        let last = self.cluster_count + 2;
+
+        let mut found = None;
+        for cluster in (first..last).chain(2..first) {
+            if self.read_fat(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.write_fat(cluster, self.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.write_fat(previous, cluster)?;
+        }
+
+        let zeros = vec![0; self.bytes_per_sector];
+        let start = self.cluster_sector(cluster);
+        for sector in start..start + self.sectors_per_cluster {
+            self.write_sector(sector, &zeros)?;
+        }
+
+        self.free_count = self.free_count.map(|count| count.saturating_sub(1));
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// Marks every cluster in the chain starting at `first` as free.
+    fn free_chain(&mut self, first: u32) -> Result<(), FsError> {
+        let clusters = self.chain(first)?;
+        for &cluster in clusters.iter() {
+            self.write_fat(cluster, 0)?;
+        }
+
+        self.free_count = self
+            .free_count
+            .map(|count| count.saturating_add(clusters.len() as u32));
+        if let Some(&lowest) = clusters.iter().min() {
+            self.next_free = self.next_free.min(lowest);
+        }
+        Ok(())
+    }
+
+    /// Makes sure the chain starting at `first` has at least `needed` clusters.
+    ///
+    /// Returns the chain, with `first` updated if the chain was empty.
+    fn grow_chain(&mut self, first: &mut u32, needed: usize) -> Result<Vec<u32>, FsError> {
+        let mut clusters = self.chain(*first)?;
+        while clusters.len() < needed {
+            let cluster = self.allocate_cluster(clusters.last().copied())?;
+            if clusters.is_empty() {
+                *first = cluster;
+            }
+            clusters.push(cluster);
+        }
+
+        Ok(clusters)
+    }
+}
+
+//  ---FSINFO---
+
+impl FatFileSystem {
+    fn load_fs_info(&mut self, sector: u64) -> Result<(), FsError> {
+        let mut buf = vec![0; self.bytes_per_sector];
+        self.read_sector(sector, &mut buf)?;
+
+        if read_u32(&buf, 0) != FSINFO_LEAD_SIGNATURE
+            || read_u32(&buf, 484) != FSINFO_STRUCT_SIGNATURE
+            || read_u32(&buf, 508) != FSINFO_TRAIL_SIGNATURE
+        {
+            // A bad FSINFO is only a hint that went missing, not a broken volume.
+            return Ok(());
+        }
+
+        self.fs_info_sector = Some(sector);
+
+        let free_count = read_u32(&buf, 488);
+        if free_count != FSINFO_UNKNOWN && free_count <= self.cluster_count {
+            self.free_count = Some(free_count);
+        }
+
+        let next_free = read_u32(&buf, 492);
+        if self.is_valid_cluster(next_free) {
+            self.next_free = next_free;
+        }
+
+        Ok(())
+    }
+
+    fn flush_fs_info(&mut self) -> Result<(), FsError> {
+        let sector = match self.fs_info_sector {
+            Some(sector) => sector,
+            None => return Ok(()),
+        };
+
+        let mut buf = vec![0; self.bytes_per_sector];
+        self.read_sector(sector, &mut buf)?;
+        write_u32(&mut buf, 488, self.free_count.unwrap_or(FSINFO_UNKNOWN));
+        write_u32(&mut buf, 492, self.next_free);
+        self.write_sector(sector, &buf)
+    }
+}
+
+//  ---Directories---
+
+impl FatFileSystem {
+    fn root_dir(&self) -> Dir {
+        match self.fat_type {
+            FatType::Fat16 => Dir::FixedRoot,
+            FatType::Fat32 => Dir::Chain(self.root_cluster),
+        }
+    }
+
+    /// The directory an entry points to. ".." entries use cluster 0 for the root.
+    fn dir_of(&self, first_cluster: u32) -> Dir {
+        if first_cluster == 0 {
+            self.root_dir()
+        } else {
+            Dir::Chain(first_cluster)
+        }
+    }
+
+    /// Lists the sectors that make up a directory in order.
+    fn dir_sectors(&mut self, dir: Dir) -> Result<Vec<u64>, FsError> {
+        match dir {
+            Dir::FixedRoot => {
+                Ok((self.root_dir_sector..self.root_dir_sector + self.root_dir_sectors).collect())
+            }
+            Dir::Chain(first) => {
+                let mut sectors = Vec::new();
+                for cluster in self.chain(first)? {
+                    let start = self.cluster_sector(cluster);
+                    sectors.extend(start..start + self.sectors_per_cluster);
+                }
+                Ok(sectors)
+            }
+        }
+    }
+
+    fn read_dir_raw(&mut self, dir: Dir) -> Result<Vec<u8>, FsError> {
+        let sectors = self.dir_sectors(dir)?;
+        let mut raw = vec![0; sectors.len() * self.bytes_per_sector];
+
+        for (index, &sector) in sectors.iter().enumerate() {
+            let start = index * self.bytes_per_sector;
+            self.read_sector(sector, &mut raw[start..start + self.bytes_per_sector])?;
+        }
+
+        Ok(raw)
+    }
+
+    /// Writes consecutive 32 byte slots starting at `first_slot`.
+    fn write_slots(&mut self, dir: Dir, first_slot: usize, data: &[u8]) -> Result<(), FsError> {
+        let sectors = self.dir_sectors(dir)?;
+        let slots_per_sector = self.bytes_per_sector / DIR_ENTRY_SIZE;
+        let mut buf = vec![0; self.bytes_per_sector];
+        let mut loaded: Option<usize> = None;
+
+        for (index, slot_data) in data.chunks(DIR_ENTRY_SIZE).enumerate() {
+            let slot = first_slot + index;
+            let sector_index = slot / slots_per_sector;
+            let sector = *sectors.get(sector_index).ok_or(FsError::Corrupt)?;
+
+            if loaded != Some(sector_index) {
+                if let Some(previous) = loaded {
+                    self.write_sector(sectors[previous], &buf)?;
+                }
+                self.read_sector(sector, &mut buf)?;
+                loaded = Some(sector_index);
+            }
+
+            let offset = (slot % slots_per_sector) * DIR_ENTRY_SIZE;
+            buf[offset..offset + DIR_ENTRY_SIZE].copy_from_slice(slot_data);
+        }
+
+        if let Some(previous) = loaded {
+            self.write_sector(sectors[previous], &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes every live entry in a directory, skipping "." and "..".
+    fn entries(&mut self, dir: Dir) -> Result<Vec<Entry>, FsError> {
+        let raw = self.read_dir_raw(dir)?;
+        let mut entries = Vec::new();
+        let mut long_name: Option<LongName> = None;
+
+        for (slot, data) in raw.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            match data[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => {
+                    long_name = None;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let attr = data[11];
+            if attr & 0x3F == ATTR_LONG_NAME {
+                let ord = data[0];
+                if ord & LFN_LAST_ENTRY != 0 {
+                    let count = ord & 0x1F;
+                    long_name = Some(LongName {
+                        chars: vec![0xFFFF; count as usize * LFN_CHARS_PER_ENTRY],
+                        checksum: data[13],
+                        next_ord: count,
+                        first_slot: slot,
+                    });
+                }
+
+                // Drop the long name if the pieces show up out of order.
+                let valid = match long_name.as_mut() {
+                    Some(name)
+                        if ord & 0x1F == name.next_ord
+                            && ord & 0x1F != 0
+                            && data[13] == name.checksum =>
+                    {
+                        let start = (name.next_ord as usize - 1) * LFN_CHARS_PER_ENTRY;
+                        for (index, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                            name.chars[start + index] = read_u16(data, offset);
+                        }
+                        name.next_ord -= 1;
+                        true
+                    }
+                    _ => false,
+                };
+                if !valid {
+                    long_name = None;
+                }
+                continue;
+            }
+
+            let long_name = long_name.take();
+            if attr & ATTR_VOLUME_ID != 0 || data[0] == b'.' {
+                continue;
+            }
+
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(&data[..11]);
+
+            let (name, first_slot) = match long_name {
+                Some(long) if long.next_ord == 0 && long.checksum == lfn_checksum(&short_name) => {
+                    let end = long
+                        .chars
+                        .iter()
+                        .position(|&c| c == 0 || c == 0xFFFF)
+                        .unwrap_or(long.chars.len());
+                    let name = core::char::decode_utf16(long.chars[..end].iter().copied())
+                        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                        .collect();
+                    (name, long.first_slot)
+                }
+                _ => (short_name_to_string(&short_name, data[12]), slot),
+            };
+
+            let first_cluster = match self.fat_type {
+                FatType::Fat16 => u32::from(read_u16(data, 26)),
+                FatType::Fat32 => {
+                    u32::from(read_u16(data, 20)) << 16 | u32::from(read_u16(data, 26))
+                }
+            };
+
+            entries.push(Entry {
+                name,
+                attr,
+                first_cluster,
+                size: read_u32(data, 28),
+                parent: dir,
+                first_slot,
+                slot,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Walks `path` from the root directory.
+    ///
+    /// Returns `None` for the root directory itself, which has no entry.
+    fn lookup(&mut self, path: &str) -> Result<Option<Entry>, FsError> {
+        let parts = fs::components(path)?;
+        let mut dir = self.root_dir();
+        let mut found = None;
+
+        for (index, part) in parts.iter().enumerate() {
+            let entry = self
+                .entries(dir)?
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(part))
+                .ok_or(FsError::NotFound)?;
+
+            if index + 1 < parts.len() {
+                if !entry.is_dir() {
+                    return Err(FsError::NotADirectory);
+                }
+                dir = self.dir_of(entry.first_cluster);
+            }
+            found = Some(entry);
+        }
+
+        Ok(found)
+    }
+
+    fn lookup_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        match self.lookup(path)? {
+            None => Ok(self.root_dir()),
+            Some(entry) if entry.is_dir() => Ok(self.dir_of(entry.first_cluster)),
+            Some(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn lookup_file(&mut self, path: &str) -> Result<Entry, FsError> {
+        match self.lookup(path)? {
+            Some(entry) if !entry.is_dir() => Ok(entry),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// Finds `count` free slots in a row, growing the directory if it is full.
+    fn find_free_slots(&mut self, dir: Dir, count: usize) -> Result<usize, FsError> {
+        loop {
+            let raw = self.read_dir_raw(dir)?;
+            let total = raw.len() / DIR_ENTRY_SIZE;
+            let mut run_start = 0;
+            let mut run_len = 0;
+
+            for (slot, data) in raw.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                if data[0] == ENTRY_END {
+                    // Everything after the end marker is free too.
+                    if total - slot + run_len >= count {
+                        return Ok(if run_len > 0 { run_start } else { slot });
+                    }
+                    break;
+                }
+
+                if data[0] == ENTRY_FREE {
+                    if run_len == 0 {
+                        run_start = slot;
+                    }
+                    run_len += 1;
+                    if run_len == count {
+                        return Ok(run_start);
+                    }
+                } else {
+                    run_len = 0;
+                }
+            }
+
+            let first = match dir {
+                // The FAT16 root directory can't grow.
+                Dir::FixedRoot => return Err(FsError::NoSpace),
+                Dir::Chain(first) => first,
+            };
+            let last = *self.chain(first)?.last().ok_or(FsError::Corrupt)?;
+            self.allocate_cluster(Some(last))?;
+        }
+    }
+
+    /// Adds an entry called `name` to the directory at `parent_path`.
+    fn create_entry(
+        &mut self,
+        parent_path: &str,
+        name: &str,
+        attr: u8,
+        first_cluster: u32,
+    ) -> Result<Entry, FsError> {
+        check_name(name)?;
+        let dir = self.lookup_dir(parent_path)?;
+        let existing = self.entries(dir)?;
+
+        if existing
+            .iter()
+            .any(|entry| entry.name.eq_ignore_ascii_case(name))
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // Names that already are valid 8.3 names don't need a long name.
+        let (short_name, needs_long_name) = match exact_short_name(name) {
+            Some(short_name) => (short_name, false),
+            None => {
+                let raw = self.read_dir_raw(dir)?;
+                let taken: Vec<&[u8]> = raw
+                    .chunks_exact(DIR_ENTRY_SIZE)
+                    .take_while(|data| data[0] != ENTRY_END)
+                    .filter(|data| data[0] != ENTRY_FREE)
+                    .map(|data| &data[..11])
+                    .collect();
+                (generate_short_name(name, &taken)?, true)
+            }
+        };
+
+        let mut data = Vec::new();
+        if needs_long_name {
+            long_name_slots(name, lfn_checksum(&short_name), &mut data);
+        }
+
+        let mut short_entry = [0; DIR_ENTRY_SIZE];
+        short_entry[..11].copy_from_slice(&short_name);
+        short_entry[11] = attr;
+        write_u16(&mut short_entry, 16, DEFAULT_DATE);
+        write_u16(&mut short_entry, 18, DEFAULT_DATE);
+        write_u16(&mut short_entry, 20, (first_cluster >> 16) as u16);
+        write_u16(&mut short_entry, 24, DEFAULT_DATE);
+        write_u16(&mut short_entry, 26, first_cluster as u16);
+        data.extend_from_slice(&short_entry);
+
+        let slots = data.len() / DIR_ENTRY_SIZE;
+        let first_slot = self.find_free_slots(dir, slots)?;
+        self.write_slots(dir, first_slot, &data)?;
+
+        Ok(Entry {
+            name: String::from(name),
+            attr,
+            first_cluster,
+            size: 0,
+            parent: dir,
+            first_slot,
+            slot: first_slot + slots - 1,
+        })
+    }
+
+    /// Writes the cluster, size and attributes of `entry` back to its short entry.
+    fn update_entry(&mut self, entry: &Entry) -> Result<(), FsError> {
+        let raw = self.read_dir_raw(entry.parent)?;
+        let start = entry.slot * DIR_ENTRY_SIZE;
+        let mut data = [0; DIR_ENTRY_SIZE];
+        data.copy_from_slice(
+            raw.get(start..start + DIR_ENTRY_SIZE)
+                .ok_or(FsError::Corrupt)?,
+        );
+
+        data[11] = entry.attr;
+        write_u16(&mut data, 18, DEFAULT_DATE);
+        write_u16(&mut data, 20, (entry.first_cluster >> 16) as u16);
+        write_u16(&mut data, 24, DEFAULT_DATE);
+        write_u16(&mut data, 26, entry.first_cluster as u16);
+        write_u32(&mut data, 28, entry.size);
+
+        self.write_slots(entry.parent, entry.slot, &data)
+    }
+}
+
+//  ---File Data---
+
+impl FatFileSystem {
+    /// Reads or writes the bytes of a cluster chain starting at byte `offset`.
+    ///
+    /// Partial sectors are read, patched and written back when writing.
+    fn transfer(
+        &mut self,
+        clusters: &[u32],
+        offset: u64,
+        len: usize,
+        mut read_into: Option<&mut [u8]>,
+        write_from: Option<&[u8]>,
+    ) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut sector_buf = vec![0; self.bytes_per_sector];
+        let mut done = 0;
+
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / cluster_size) as usize)
+                .ok_or(FsError::Corrupt)?;
+            let in_cluster = (position % cluster_size) as usize;
+            let sector = self.cluster_sector(cluster) + (in_cluster / self.bytes_per_sector) as u64;
+            let in_sector = in_cluster % self.bytes_per_sector;
+            let count = (self.bytes_per_sector - in_sector).min(len - done);
+
+            match (read_into.as_mut(), write_from) {
+                (Some(buf), _) => {
+                    self.read_sector(sector, &mut sector_buf)?;
+                    buf[done..done + count]
+                        .copy_from_slice(&sector_buf[in_sector..in_sector + count]);
+                }
+                (None, Some(data)) => {
+                    if count != self.bytes_per_sector {
+                        self.read_sector(sector, &mut sector_buf)?;
+                    }
+                    sector_buf[in_sector..in_sector + count]
+                        .copy_from_slice(&data[done..done + count]);
+                    self.write_sector(sector, &sector_buf)?;
+                }
+                (None, None) => {
+                    // Zero fill
+                    self.read_sector(sector, &mut sector_buf)?;
+                    for byte in sector_buf[in_sector..in_sector + count].iter_mut() {
+                        *byte = 0;
+                    }
+                    self.write_sector(sector, &sector_buf)?;
+                }
+            }
+
+            done += count;
+        }
+
+        Ok(())
+    }
+
+    /// Grows a file to `size` bytes, filling the new space with zeros.
+    fn grow(&mut self, entry: &mut Entry, size: u64) -> Result<Vec<u32>, FsError> {
+        if size > u64::from(u32::MAX) {
+            return Err(FsError::NoSpace);
+        }
+
+        let cluster_size = self.cluster_size() as u64;
+        let old_clusters = self.chain(entry.first_cluster)?.len() as u64;
+        let needed = size.div_ceil(cluster_size) as usize;
+        let clusters = self.grow_chain(&mut entry.first_cluster, needed)?;
+
+        // New clusters come zeroed, only the tail of the old last cluster can hold junk.
+        let old_size = u64::from(entry.size);
+        let junk_end = size.min(old_clusters * cluster_size);
+        if junk_end > old_size {
+            self.transfer(
+                &clusters,
+                old_size,
+                (junk_end - old_size) as usize,
+                None,
+                None,
+            )?;
+        }
+
+        if size > old_size {
+            entry.size = size as u32;
+        }
+        Ok(clusters)
+    }
+
+    /// Shrinks a file to `size` bytes and frees the clusters past the end.
+    fn shrink(&mut self, entry: &mut Entry, size: u64) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size() as u64;
+        let keep = size.div_ceil(cluster_size) as usize;
+        let clusters = self.chain(entry.first_cluster)?;
+
+        if keep == 0 {
+            self.free_chain(entry.first_cluster)?;
+            entry.first_cluster = 0;
+        } else if keep < clusters.len() {
+            self.write_fat(clusters[keep - 1], self.end_of_chain())?;
+            self.free_chain(clusters[keep])?;
+        }
+
+        entry.size = size as u32;
+        Ok(())
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn metadata(&mut self, path: &str) -> Result<Metadata, FsError> {
+        Ok(match self.lookup(path)? {
+            None => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+            Some(entry) => entry_metadata(&entry),
+        })
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.lookup_dir(path)?;
+        Ok(self
+            .entries(dir)?
+            .into_iter()
+            .map(|entry| {
+                let meta = entry_metadata(&entry);
+                DirEntry {
+                    name: entry.name,
+                    file_type: meta.file_type,
+                    size: meta.size,
+                }
+            })
+            .collect())
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let entry = self.lookup_file(path)?;
+        let size = u64::from(entry.size);
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = (buf.len() as u64).min(size - offset) as usize;
+        let clusters = self.chain(entry.first_cluster)?;
+        self.transfer(&clusters, offset, len, Some(&mut buf[..len]), None)?;
+        Ok(len)
+    }
+
+    fn write(&mut self, path: &str, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut entry = self.lookup_file(path)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Past u32::MAX is too big for FAT anyway, grow says so.
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::NoSpace)?;
+        let clusters = self.grow(&mut entry, end)?;
+        self.transfer(&clusters, offset, buf.len(), None, Some(buf))?;
+
+        self.update_entry(&entry)?;
+        self.flush_fs_info()?;
+        Ok(buf.len())
+    }
+
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = fs::split_parent(path)?.ok_or(FsError::AlreadyExists)?;
+        self.create_entry(&parent, &name, ATTR_ARCHIVE, 0)?;
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = fs::split_parent(path)?.ok_or(FsError::AlreadyExists)?;
+        let parent_cluster = match self.lookup_dir(&parent)? {
+            Dir::Chain(cluster) if cluster != self.root_cluster => cluster,
+            _ => 0,
+        };
+
+        let cluster = self.allocate_cluster(None)?;
+        if let Err(err) = self.create_entry(&parent, &name, ATTR_DIRECTORY, cluster) {
+            self.free_chain(cluster)?;
+            return Err(err);
+        }
+
+        // Every directory but the root starts with "." and "..".
+        let mut data = [0; DIR_ENTRY_SIZE * 2];
+        for (index, (dots, target)) in [
+            (&b".          "[..], cluster),
+            (&b"..         "[..], parent_cluster),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let entry = &mut data[index * DIR_ENTRY_SIZE..(index + 1) * DIR_ENTRY_SIZE];
+            entry[..11].copy_from_slice(dots);
+            entry[11] = ATTR_DIRECTORY;
+            write_u16(entry, 16, DEFAULT_DATE);
+            write_u16(entry, 18, DEFAULT_DATE);
+            write_u16(entry, 20, (target >> 16) as u16);
+            write_u16(entry, 24, DEFAULT_DATE);
+            write_u16(entry, 26, *target as u16);
+        }
+        self.write_slots(Dir::Chain(cluster), 0, &data)?;
+
+        self.flush_fs_info()
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), FsError> {
+        let mut entry = self.lookup_file(path)?;
+        if size > u64::from(entry.size) {
+            self.grow(&mut entry, size)?;
+        } else {
+            self.shrink(&mut entry, size)?;
+        }
+
+        self.update_entry(&entry)?;
+        self.flush_fs_info()
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), FsError> {
+        let entry = self.lookup(path)?.ok_or(FsError::InvalidPath)?;
+        if entry.is_dir() && !self.entries(Dir::Chain(entry.first_cluster))?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.free_chain(entry.first_cluster)?;
+
+        // Mark the short entry and its long name pieces as deleted.
+        let raw = self.read_dir_raw(entry.parent)?;
+        let mut data =
+            raw[entry.first_slot * DIR_ENTRY_SIZE..(entry.slot + 1) * DIR_ENTRY_SIZE].to_vec();
+        for slot in data.chunks_exact_mut(DIR_ENTRY_SIZE) {
+            slot[0] = ENTRY_FREE;
+        }
+        self.write_slots(entry.parent, entry.first_slot, &data)?;
+
+        self.flush_fs_info()
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.flush_fs_info()
+    }
+}
+
+fn entry_metadata(entry: &Entry) -> Metadata {
+    if entry.is_dir() {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    } else {
+        Metadata {
+            file_type: FileType::File,
+            size: u64::from(entry.size),
+        }
+    }
+}
+
+//  ---Names---
+
+// Byte offsets of the 13 UCS-2 characters inside a long name entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// Characters allowed in 8.3 names besides A-Z and 0-9.
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte)
+    })
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    if name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return Err(FsError::InvalidPath);
+    }
+
+    Ok(())
+}
+
+fn is_short_name_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&byte)
+}
+
+/// Turns the 11 byte 8.3 name into "NAME.EXT".
+fn short_name_to_string(short_name: &[u8; 11], nt_flags: u8) -> String {
+    let mut base: Vec<u8> = short_name[..8].to_vec();
+    if base[0] == ENTRY_KANJI_E5 {
+        base[0] = ENTRY_FREE;
+    }
+
+    let mut name = String::new();
+    for &byte in base.iter().take_while(|&&byte| byte != b' ') {
+        let byte = if nt_flags & NT_LOWERCASE_BASE != 0 {
+            byte.to_ascii_lowercase()
+        } else {
+            byte
+        };
+        name.push(char::from(byte));
+    }
+
+    let ext: Vec<u8> = short_name[8..]
+        .iter()
+        .copied()
+        .take_while(|&byte| byte != b' ')
+        .collect();
+    if !ext.is_empty() {
+        name.push('.');
+        for byte in ext {
+            let byte = if nt_flags & NT_LOWERCASE_EXT != 0 {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            };
+            name.push(char::from(byte));
+        }
+    }
+
+    name
+}
+
+/// Returns the 8.3 form of `name` if it can be stored without a long name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let bytes = name.as_bytes();
+    let (base, ext) = match name.find('.') {
+        Some(dot) => (&bytes[..dot], &bytes[dot + 1..]),
+        None => (bytes, &[][..]),
+    };
+
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base
+            .iter()
+            .chain(ext.iter())
+            .all(|&byte| is_short_name_char(byte))
+    {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base);
+    short_name[8..8 + ext.len()].copy_from_slice(ext);
+    Some(short_name)
+}
+
+/// Generates a unique "BASIS~N.EXT" name for a long file name.
+fn generate_short_name(name: &str, taken: &[&[u8]]) -> Result<[u8; 11], FsError> {
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let byte = if c.is_ascii() {
+                    c.to_ascii_uppercase() as u8
+                } else {
+                    b'_'
+                };
+                if is_short_name_char(byte) {
+                    byte
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot) => (clean(&trimmed[..dot]), clean(&trimmed[dot + 1..])),
+        None => (clean(trimmed), Vec::new()),
+    };
+    let base = if base.is_empty() { vec![b'_'] } else { base };
+
+    let mut short_name = [b' '; 11];
+    for (index, &byte) in ext.iter().take(3).enumerate() {
+        short_name[8 + index] = byte;
+    }
+
+    for number in 1..1_000_000u32 {
+        let mut tail = [0u8; 8];
+        let mut tail_len = 0;
+        let mut n = number;
+        while n > 0 {
+            tail[7 - tail_len] = b'0' + (n % 10) as u8;
+            tail_len += 1;
+            n /= 10;
+        }
+        tail[7 - tail_len] = b'~';
+        tail_len += 1;
+
+        let base_len = base.len().min(8 - tail_len);
+        for byte in short_name[..8].iter_mut() {
+            *byte = b' ';
+        }
+        short_name[..base_len].copy_from_slice(&base[..base_len]);
+        short_name[base_len..base_len + tail_len].copy_from_slice(&tail[8 - tail_len..]);
+
+        if !taken.iter().any(|&other| other == &short_name[..]) {
+            return Ok(short_name);
+        }
+    }
+
+    Err(FsError::AlreadyExists)
+}
+
+/// Appends the long name entries for `name`, last piece first as they sit on disk.
+fn long_name_slots(name: &str, checksum: u8, data: &mut Vec<u8>) {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_CHARS_PER_ENTRY);
+
+    for ord in (1..=count).rev() {
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        entry[0] = ord as u8 | if ord == count { LFN_LAST_ENTRY } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+
+        // The name ends with a 0x0000 and the rest is padded with 0xFFFF.
+        for (index, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            let position = (ord - 1) * LFN_CHARS_PER_ENTRY + index;
+            let unit = match position {
+                p if p < units.len() => units[p],
+                p if p == units.len() => 0x0000,
+                _ => 0xFFFF,
+            };
+            write_u16(&mut entry, offset, unit);
+        }
+
+        data.extend_from_slice(&entry);
+    }
+}
+
+//  ---Little Endian Helpers---
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,130 @@
+// Mod for QEMU's firmware configuration device.
+// QEMU hands files to the guest through it, given on its command line with
+//     -fw_cfg name=opt/jonathan_os/NAME,string=TEXT   or   -fw_cfg name=...,file=PATH
+// On x86 the device is two I/O ports: writing an item's key to the selector selects
+// it, then every read of the data port returns the item's next byte. The files are
+// found through a directory item listing their names and keys.
+// Real hardware has nothing at those ports, reads come back as 0xFF and the
+// signature check fails.
+
+use x86_64::instructions::port::Port;
+
+use crate::sync::IrqSafeSpinlock;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+// Item keys.
+const SIGNATURE: u16 = 0x0000;
+const FILE_DIR: u16 = 0x0019;
+
+const FILE_NAME_SIZE: usize = 56;
+
+struct Device {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+impl Device {
+    fn select(&mut self, key: u16) {
+        unsafe { self.selector.write(key) };
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        for byte in buffer {
+            *byte = unsafe { self.data.read() };
+        }
+    }
+
+    // Directory entries and the directory size are big endian.
+    fn read_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes);
+        u32::from_be_bytes(bytes)
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let mut bytes = [0; 2];
+        self.read(&mut bytes);
+        u16::from_be_bytes(bytes)
+    }
+}
+
+// Selecting and reading have to happen together.
+static DEVICE: IrqSafeSpinlock<Device> = IrqSafeSpinlock::new(Device {
+    selector: Port::new(SELECTOR_PORT),
+    data: Port::new(DATA_PORT),
+});
+
+/// A file passed in with `-fw_cfg`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct File {
+    key: u16,
+    size: u32,
+}
+
+impl File {
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Reads the start of the file into `buffer`. Returns how many bytes that was.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        let len = buffer.len().min(self.size());
+        let mut device = DEVICE.lock();
+        device.select(self.key);
+        device.read(&mut buffer[..len]);
+        len
+    }
+}
+
+/// Whether we run under QEMU with the device.
+pub fn is_present() -> bool {
+    let mut signature = [0; 4];
+    let mut device = DEVICE.lock();
+    device.select(SIGNATURE);
+    device.read(&mut signature);
+    &signature == b"QEMU"
+}
+
+/// Looks up a file by its full name, like `opt/jonathan_os/test-args`.
+pub fn find(name: &str) -> Option<File> {
+    if !is_present() || name.len() >= FILE_NAME_SIZE {
+        return None;
+    }
+
+    let mut device = DEVICE.lock();
+    device.select(FILE_DIR);
+    let count = device.read_u32();
+    for _ in 0..count {
+        let size = device.read_u32();
+        let key = device.read_u16();
+        let _reserved = device.read_u16();
+        let mut file_name = [0; FILE_NAME_SIZE];
+        device.read(&mut file_name);
+
+        // Zero padded.
+        let len = file_name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(FILE_NAME_SIZE);
+        if &file_name[..len] == name.as_bytes() {
+            return Some(File { key, size });
+        }
+    }
+    None
+}
+
+//  ---Tests---
+
+// The file comes from TEST_ARGS in boot/src/main.rs.
+#[test_case]
+fn test_find_file() {
+    assert!(is_present());
+    let file = find("opt/jonathan_os/test-fixture").unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(file.read(&mut buffer), 5);
+    assert_eq!(&buffer[..5], b"hello");
+
+    assert_eq!(find("opt/jonathan_os/missing"), None);
+}
@@ -0,0 +1,540 @@
+// Mod for a GDB stub speaking the remote serial protocol over a serial port.
+// For debugging where QEMU's own stub (-s) isn't available. After init, the stub takes
+// over whenever the kernel stops: at a breakpoint (an int3, gdb::breakpoint() or one
+// GDB inserted), after a single step, or in a panic if GDB is already attached or
+// on_panic=debug is set. Connect GDB to the port then, with the kernel's ELF file loaded:
+//     target remote localhost:4321
+// The stub only talks while stopped, and only the CPU that stopped waits for GDB, the
+// others keep running.
+// Memory is read and written through the physical memory mapping, after translating the
+// address with the page tables. That's also how breakpoints get into read-only code.
+
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::{TrapFrame, DEBUG_VECTOR, TRAP_FLAG};
+use crate::memory;
+use crate::serial::{self, ComPort, SerialConfig, SerialError};
+use crate::sync::IrqSafeSpinlock;
+
+pub mod packet;
+
+use self::packet::{Response, PACKET_SIZE};
+
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+
+// Signal in stop replies.
+const SIGTRAP: u8 = 5;
+
+// errno values in error replies.
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+
+// GDB's x86_64 registers without a target description: rax, rbx, rcx, rdx, rsi, rdi,
+// rbp, rsp, r8-r15 and rip with 8 bytes, then eflags, cs, ss, ds, es, fs and gs with 4.
+// The FPU and SSE registers after them are left out, GDB shows them as unavailable.
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+
+fn register_size(number: usize) -> usize {
+    if number <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+fn read_register(frame: &mut TrapFrame, number: usize) -> Option<u64> {
+    let value = match number {
+        20 => u64::from(DS::get_reg().0),
+        21 => u64::from(ES::get_reg().0),
+        22 => u64::from(FS::get_reg().0),
+        23 => u64::from(GS::get_reg().0),
+        _ => *register_in_frame(frame, number)?,
+    };
+    Some(value)
+}
+
+// The registers that are saved in the frame. Segment registers other than CS and SS aren't.
+fn register_in_frame(frame: &mut TrapFrame, number: usize) -> Option<&mut u64> {
+    let register = match number {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        RIP => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18 => &mut frame.cs,
+        19 => &mut frame.ss,
+        _ => return None,
+    };
+    Some(register)
+}
+
+// Segment selectors are left alone, a wrong one would fault on the way back.
+fn write_register(frame: &mut TrapFrame, number: usize, value: u64) -> bool {
+    match number {
+        0..=17 => {
+            *register_in_frame(frame, number).unwrap() = value;
+            true
+        }
+        18..=23 => true,
+        _ => false,
+    }
+}
+
+// Where `address` can be reached through the physical memory mapping.
+fn physical_mapping(address: u64) -> Option<*mut u8> {
+    let address = VirtAddr::try_new(address).ok()?;
+    let physical = memory::translate(address)?;
+    Some(memory::phys_to_virt(physical).as_mut_ptr())
+}
+
+fn read_byte(address: u64) -> Option<u8> {
+    Some(unsafe { physical_mapping(address)?.read_volatile() })
+}
+
+fn write_byte(address: u64, byte: u8) -> Option<()> {
+    unsafe { physical_mapping(address)?.write_volatile(byte) };
+    Some(())
+}
+
+//  ---Commands---
+
+/// What the stopped CPU does after a packet.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Resume {
+    /// Stay stopped and reply.
+    Stay,
+    Continue,
+    /// Run one instruction, then stop again.
+    Step,
+    /// Reply, forget the breakpoints and continue.
+    Detach,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+/// The protocol side of the stub: runs commands against a stopped CPU's registers.
+pub struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+impl Stub {
+    pub const fn new() -> Self {
+        Stub {
+            breakpoints: [None; MAX_BREAKPOINTS],
+        }
+    }
+
+    /// Runs the command in `packet` against `frame`. The reply goes to `response`.
+    pub fn handle_packet(
+        &mut self,
+        packet: &[u8],
+        frame: &mut TrapFrame,
+        response: &mut Response,
+    ) -> Resume {
+        let (command, args) = match packet.split_first() {
+            Some((&command, args)) => (command, args),
+            None => return Resume::Stay,
+        };
+
+        match command {
+            b'?' => stop_reply(response),
+            b'g' => {
+                for number in 0..REGISTER_COUNT {
+                    let value = read_register(frame, number).unwrap();
+                    response.push_register(value, register_size(number));
+                }
+            }
+            b'G' => self.write_registers(args, frame, response),
+            b'p' => match packet::parse_hex(args)
+                .and_then(|n| read_register(frame, n as usize).map(|value| (n as usize, value)))
+            {
+                Some((number, value)) => response.push_register(value, register_size(number)),
+                None => response.error(EINVAL),
+            },
+            b'P' => {
+                let written = packet::split(args, b'=').and_then(|(number, value)| {
+                    let number = packet::parse_hex(number)? as usize;
+                    let value = packet::parse_register(value, register_size(number))?;
+                    Some(write_register(frame, number, value))
+                });
+                match written {
+                    Some(true) => response.ok(),
+                    _ => response.error(EINVAL),
+                }
+            }
+            b'm' => self.read_memory(args, response),
+            b'M' => self.write_memory(args, response),
+            b'Z' | b'z' => self.change_breakpoint(command == b'Z', args, response),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    match packet::parse_hex(args) {
+                        Some(address) => frame.rip = address,
+                        None => {
+                            response.error(EINVAL);
+                            return Resume::Stay;
+                        }
+                    }
+                }
+                if command == b's' {
+                    frame.rflags |= TRAP_FLAG;
+                    return Resume::Step;
+                }
+                frame.rflags &= !TRAP_FLAG;
+                return Resume::Continue;
+            }
+            b'D' | b'k' => {
+                self.remove_all_breakpoints();
+                frame.rflags &= !TRAP_FLAG;
+                // GDB doesn't wait for a reply to k.
+                if command == b'D' {
+                    response.ok();
+                }
+                return Resume::Detach;
+            }
+            b'q' => query(args, response),
+            // One thread only, whichever GDB picks is fine.
+            b'H' | b'T' => response.ok(),
+            // Empty means unsupported.
+            _ => {}
+        }
+        Resume::Stay
+    }
+
+    fn write_registers(&mut self, args: &[u8], frame: &mut TrapFrame, response: &mut Response) {
+        let mut rest = args;
+        let mut new_frame = frame.clone();
+        for number in 0..REGISTER_COUNT {
+            let size = register_size(number) * 2;
+            if rest.len() < size {
+                break;
+            }
+            match packet::parse_register(&rest[..size], size / 2) {
+                Some(value) => write_register(&mut new_frame, number, value),
+                None => return response.error(EINVAL),
+            };
+            rest = &rest[size..];
+        }
+        *frame = new_frame;
+        response.ok();
+    }
+
+    // m addr,length
+    fn read_memory(&mut self, args: &[u8], response: &mut Response) {
+        let range = packet::split(args, b',').and_then(|(address, length)| {
+            Some((packet::parse_hex(address)?, packet::parse_hex(length)?))
+        });
+        let (address, length) = match range {
+            Some((address, length)) if length as usize <= PACKET_SIZE / 2 => (address, length),
+            _ => return response.error(EINVAL),
+        };
+
+        for offset in 0..length {
+            match read_byte(address.wrapping_add(offset)) {
+                Some(byte) => response.push_hex_byte(byte),
+                // Partial reads are allowed, but not empty ones.
+                None if offset > 0 => return,
+                None => return response.error(EFAULT),
+            }
+        }
+    }
+
+    // M addr,length:XX...
+    fn write_memory(&mut self, args: &[u8], response: &mut Response) {
+        let mut bytes = [0u8; PACKET_SIZE / 2];
+        let parsed = packet::split(args, b':').and_then(|(range, data)| {
+            let (address, length) = packet::split(range, b',')?;
+            let length = packet::parse_hex(length)? as usize;
+            if packet::parse_hex_bytes(data, &mut bytes)? != length {
+                return None;
+            }
+            Some((packet::parse_hex(address)?, length))
+        });
+        let (address, length) = match parsed {
+            Some(parsed) => parsed,
+            None => return response.error(EINVAL),
+        };
+
+        for (offset, &byte) in bytes[..length].iter().enumerate() {
+            if write_byte(address.wrapping_add(offset as u64), byte).is_none() {
+                return response.error(EFAULT);
+            }
+        }
+        response.ok();
+    }
+
+    // Z0,addr,kind and z0,addr,kind. Only software breakpoints, the rest gets an empty reply.
+    fn change_breakpoint(&mut self, insert: bool, args: &[u8], response: &mut Response) {
+        let address = packet::split(args, b',').and_then(|(kind, rest)| {
+            if kind != b"0" {
+                return None;
+            }
+            let (address, _) = packet::split(rest, b',')?;
+            packet::parse_hex(address)
+        });
+        let address = match address {
+            Some(address) => address,
+            None => return,
+        };
+
+        let result = if insert {
+            self.insert_breakpoint(address)
+        } else {
+            self.remove_breakpoint(address)
+        };
+        match result {
+            Ok(()) => response.ok(),
+            Err(errno) => response.error(errno),
+        }
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> Result<(), u8> {
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|bp| bp.address == address)
+        {
+            return Ok(());
+        }
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ENOSPC)?;
+
+        let original = read_byte(address).ok_or(EFAULT)?;
+        write_byte(address, INT3).ok_or(EFAULT)?;
+        *slot = Some(Breakpoint { address, original });
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> Result<(), u8> {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = *slot {
+                if breakpoint.address == address {
+                    write_byte(address, breakpoint.original).ok_or(EFAULT)?;
+                    *slot = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot.take() {
+                write_byte(breakpoint.address, breakpoint.original);
+            }
+        }
+    }
+
+    /// How many breakpoints are inserted.
+    pub fn breakpoint_count(&self) -> usize {
+        self.breakpoints.iter().flatten().count()
+    }
+}
+
+impl Default for Stub {
+    fn default() -> Self {
+        Stub::new()
+    }
+}
+
+fn stop_reply(response: &mut Response) {
+    response.push(b"S");
+    response.push_hex_byte(SIGTRAP);
+}
+
+fn query(args: &[u8], response: &mut Response) {
+    if args.starts_with(b"Supported") {
+        response.push(b"PacketSize=");
+        response.push_hex_byte((PACKET_SIZE >> 8) as u8);
+        response.push_hex_byte(PACKET_SIZE as u8);
+    } else if args == b"Attached" {
+        // Detaching leaves the kernel running.
+        response.push(b"1");
+    } else if args == b"C" {
+        response.push(b"QC1");
+    } else if args == b"fThreadInfo" {
+        response.push(b"m1");
+    } else if args == b"sThreadInfo" {
+        response.push(b"l");
+    }
+}
+
+//  ---Serial Side---
+
+struct State {
+    com: ComPort,
+    stub: Stub,
+    packet: [u8; PACKET_SIZE],
+    response: Response,
+    // GDB has sent something since attaching, it expects a stop reply when we stop.
+    connected: bool,
+    // The debug exception after the next instruction is ours.
+    stepping: bool,
+}
+
+static STATE: IrqSafeSpinlock<Option<State>> = IrqSafeSpinlock::new(None);
+
+fn receive(com: ComPort) -> u8 {
+    loop {
+        if let Some(byte) = serial::port(com).lock().try_receive() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn send(com: ComPort, bytes: &[u8]) {
+    let mut uart = serial::port(com).lock();
+    for &byte in bytes {
+        uart.send(byte);
+    }
+}
+
+// Waits for a packet with the right checksum, acknowledging it. Returns its length.
+fn receive_packet(com: ComPort, buffer: &mut [u8]) -> usize {
+    'packet: loop {
+        // Acks and anything else between packets are skipped.
+        while receive(com) != b'$' {}
+
+        let mut len = 0;
+        loop {
+            match receive(com) {
+                b'#' => break,
+                // The packet was cut off and a new one started.
+                b'$' => continue 'packet,
+                byte if len < buffer.len() => {
+                    buffer[len] = byte;
+                    len += 1;
+                }
+                _ => {
+                    send(com, b"-");
+                    continue 'packet;
+                }
+            }
+        }
+
+        let high = packet::hex_digit(receive(com));
+        let low = packet::hex_digit(receive(com));
+        let sum = high.and_then(|high| Some(high << 4 | low?));
+        if sum == Some(packet::checksum(&buffer[..len])) {
+            send(com, b"+");
+            return len;
+        }
+        send(com, b"-");
+    }
+}
+
+// Doesn't wait for the ack, the link is QEMU's and doesn't lose bytes.
+fn send_packet(com: ComPort, data: &[u8]) {
+    let mut trailer = Response::new();
+    trailer.push(b"#");
+    trailer.push_hex_byte(packet::checksum(data));
+
+    send(com, b"$");
+    send(com, data);
+    send(com, trailer.as_bytes());
+}
+
+/// Sets up `com` for the stub. From then on every stop goes to GDB.
+pub fn init(com: ComPort) -> Result<(), SerialError> {
+    // No receive interrupt, the stub polls the port while the kernel is stopped.
+    serial::port(com).lock().init(SerialConfig::default())?;
+    *STATE.lock() = Some(State {
+        com,
+        stub: Stub::new(),
+        packet: [0; PACKET_SIZE],
+        response: Response::new(),
+        connected: false,
+        stepping: false,
+    });
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    STATE.lock().is_some()
+}
+
+/// Whether GDB is attached to the stub. False if the stub's lock is held, like when
+/// the stub itself panicked.
+pub fn is_connected() -> bool {
+    STATE
+        .try_lock()
+        .is_some_and(|state| state.as_ref().is_some_and(|state| state.connected))
+}
+
+/// Stops and waits for GDB, if the stub is set up. Does nothing otherwise.
+pub fn breakpoint() {
+    if is_initialized() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// Called from the breakpoint and debug exception handlers. Returns false if the stub
+/// isn't set up, or the exception isn't one it caused.
+pub(crate) fn handle_trap(frame: &mut TrapFrame) -> bool {
+    let mut state = STATE.lock();
+    let state = match state.as_mut() {
+        Some(state) => state,
+        None => return false,
+    };
+    if frame.vector == DEBUG_VECTOR && !state.stepping {
+        return false;
+    }
+    state.stepping = false;
+    frame.rflags &= !TRAP_FLAG;
+
+    if state.connected {
+        state.response.clear();
+        stop_reply(&mut state.response);
+        send_packet(state.com, state.response.as_bytes());
+    }
+
+    loop {
+        let len = receive_packet(state.com, &mut state.packet);
+        state.connected = true;
+        state.response.clear();
+
+        match state
+            .stub
+            .handle_packet(&state.packet[..len], frame, &mut state.response)
+        {
+            Resume::Stay => send_packet(state.com, state.response.as_bytes()),
+            Resume::Continue => return true,
+            Resume::Step => {
+                state.stepping = true;
+                return true;
+            }
+            Resume::Detach => {
+                if !state.response.as_bytes().is_empty() {
+                    send_packet(state.com, state.response.as_bytes());
+                }
+                state.connected = false;
+                return true;
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Mod for the framing and encoding of remote serial protocol packets.
+// A packet is `$data#cc`, cc being the sum of the data bytes mod 256 in hex. Numbers
+// in packets are hex, register values and memory are hex bytes in memory order.
+
+/// The biggest packet the stub takes or sends, told to GDB in the qSupported reply.
+pub const PACKET_SIZE: usize = 4096;
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+pub fn hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// Parses a big endian hex number, like the addresses and lengths in commands.
+pub fn parse_hex(text: &[u8]) -> Option<u64> {
+    if text.is_empty() || text.len() > 16 {
+        return None;
+    }
+    text.iter().try_fold(0u64, |value, &digit| {
+        Some(value << 4 | u64::from(hex_digit(digit)?))
+    })
+}
+
+/// Parses hex bytes, like register values and memory, into `out`. Returns how many.
+pub fn parse_hex_bytes(text: &[u8], out: &mut [u8]) -> Option<usize> {
+    if !text.len().is_multiple_of(2) || text.len() / 2 > out.len() {
+        return None;
+    }
+    for (pair, byte) in text.chunks(2).zip(out.iter_mut()) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(text.len() / 2)
+}
+
+/// Parses a little endian register value of `size` bytes.
+pub fn parse_register(text: &[u8], size: usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    if text.len() != size * 2 || parse_hex_bytes(text, &mut bytes[..size])? != size {
+        return None;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Splits `text` at the first `separator`.
+pub fn split(text: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = text.iter().position(|&byte| byte == separator)?;
+    Some((&text[..position], &text[position + 1..]))
+}
+
+/// The data of a packet being put together. Anything past `PACKET_SIZE` is dropped.
+pub struct Response {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    pub const fn new() -> Self {
+        Response {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(PACKET_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    pub fn push_hex_byte(&mut self, byte: u8) {
+        self.push(&[HEX[usize::from(byte >> 4)], HEX[usize::from(byte & 0xF)]]);
+    }
+
+    /// Pushes the low `size` bytes of `value`, little endian, the way registers are sent.
+    pub fn push_register(&mut self, value: u64, size: usize) {
+        for &byte in &value.to_le_bytes()[..size] {
+            self.push_hex_byte(byte);
+        }
+    }
+
+    pub fn ok(&mut self) {
+        self.push(b"OK");
+    }
+
+    /// An error reply, GDB only shows the number.
+    pub fn error(&mut self, errno: u8) {
+        self.push(b"E");
+        self.push_hex_byte(errno);
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Response::new()
+    }
+}
+
+//  ---Tests---
+
+#[test_case]
+fn test_checksum() {
+    // GDB sends $qSupported#37 when it connects.
+    assert_eq!(checksum(b"qSupported"), 0x37);
+    assert_eq!(checksum(b""), 0);
+}
+
+#[test_case]
+fn test_parse_hex() {
+    assert_eq!(parse_hex(b"ffffffff80001000"), Some(0xFFFF_FFFF_8000_1000));
+    assert_eq!(parse_hex(b"1A"), Some(0x1A));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"12g"), None);
+    assert_eq!(parse_hex(b"11112222333344445"), None);
+}
+
+#[test_case]
+fn test_registers_are_little_endian() {
+    let mut response = Response::new();
+    response.push_register(0x1234_5678, 4);
+    assert_eq!(response.as_bytes(), b"78563412");
+    assert_eq!(parse_register(response.as_bytes(), 4), Some(0x1234_5678));
+    assert_eq!(parse_register(b"785634", 4), None);
+}
@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+
 use lazy_static::lazy_static;
 use x86_64::registers::segmentation::CS;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -5,7 +7,8 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-const STACK_SIZE: usize = 4096 * 5;
+/// Size of the double fault stacks.
+pub(crate) const STACK_SIZE: usize = 4096 * 5;
 
 lazy_static! {
     // TSS is a relic of the past that contains:
@@ -14,40 +17,43 @@ lazy_static! {
             // We are using the IST to give the double fault handler a known good stack.
         // Offset Address of the IO permission bitmap.
 
+    // This one is the BSP's, it runs before there is a heap.
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // TODO: Replace this nasty static mut with a proper stack
-            // This requires memory management
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        // TODO: Replace this nasty static mut with a proper stack
+        // This requires memory management
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(unsafe{&STACK});
-            let stack_end = stack_start + STACK_SIZE;
+        let stack_start = VirtAddr::from_ptr(unsafe{&STACK});
+        let stack_end = stack_start + STACK_SIZE;
 
-            stack_end
-        };
-
-        tss
+        new_tss(stack_end)
     };
 }
 
 lazy_static! {
     // GDT is a relic of the past as well that contains segments such as the TSS
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
+}
+
+fn new_tss(double_fault_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+        },
+    )
 }
 
 struct Selectors {
@@ -55,17 +61,31 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-pub fn init() {
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::segmentation::Segment;
     use x86_64::instructions::tables::load_tss;
 
     // Load the new GDT
-    GDT.0.load();
+    gdt.0.load();
 
     // Reload the code segment
     // Load the new tss struct
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
+
+pub fn init() {
+    load(&GDT);
+}
+
+/// Gives an application processor a GDT and TSS of its own, with double faults handled
+/// on the stack ending at `double_fault_stack_end`.
+///
+/// A TSS is marked busy once loaded so they can't be shared, and every CPU needs its
+/// own double fault stack anyway. smp::init maps those. Needs the heap.
+pub fn init_ap(double_fault_stack_end: VirtAddr) {
+    let tss = Box::leak(Box::new(new_tss(double_fault_stack_end)));
+    load(Box::leak(Box::new(new_gdt(tss))));
+}
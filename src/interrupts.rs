@@ -6,14 +6,22 @@
 // Afterward will probably do it.
 
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, KeyCode, KeyState, Keyboard, ScancodeSet1};
 use pic8259::ChainedPics;
 use spin::Mutex;
 use x86_64::instructions::hlt;
 use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::{gdt, print, println};
+use crate::sync::IrqSafeSpinlock;
+use crate::{
+    apic, emergency_println, gdb, gdt, memory, print, println, ps2, serial, testing, time,
+    vga_buffer,
+};
+
+pub mod trap;
+
+use self::trap::TrapFrame;
 
 //  ---IDT---
 
@@ -23,11 +31,22 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
-        // Here we are setting the handler for the breakpoint interrupt
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Here we are setting the handler for the breakpoint and debug interrupts
+        // They save every register for the GDB stub, see trap.rs.
+        unsafe {
+            idt.breakpoint.set_handler_addr(trap::breakpoint_entry());
+            idt.debug.set_handler_addr(trap::debug_entry());
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt[PicInterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[PicInterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[PicInterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
+        idt[PicInterruptIndex::Com2.as_usize()].set_handler_fn(com2_interrupt_handler);
+        idt[PicInterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[PicInterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[ApicInterruptIndex::Hpet.as_usize()].set_handler_fn(hpet_interrupt_handler);
+        idt[ApicInterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_handler);
+        idt[ApicInterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
 
         unsafe {
             // Here we are setting the double fault handler
@@ -52,14 +71,21 @@ pub fn init_idt() {
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeSpinlock<ChainedPics> =
+    IrqSafeSpinlock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Copy, Clone)]
 #[repr(u8)]
 pub enum PicInterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // IRQ 3 and 4, the serial ports
+    Com2 = PIC_1_OFFSET + 3,
+    Com1,
+    // IRQ 8, the first line on the second PIC
+    Rtc = PIC_2_OFFSET,
+    // IRQ 12, the fifth line on the second PIC
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl PicInterruptIndex {
@@ -72,10 +98,98 @@ impl PicInterruptIndex {
     }
 }
 
+// Vectors for interrupts that arrive through the local APIC, past the ones the PICs use.
+#[derive(Debug, Copy, Clone)]
+#[repr(u8)]
+pub enum ApicInterruptIndex {
+    Hpet = PIC_2_OFFSET + 8,
+    TlbShootdown = 0xFD,
+    // The local APIC needs a vector ending in 0xF for spurious interrupts.
+    Spurious = 0xFF,
+}
+
+impl ApicInterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+//  ---Keyboard---
+
+// What a key press from the keyboard does.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum KeyInput {
+    PageUp,
+    PageDown,
+    Key(DecodedKey),
+}
+
+// Turns the scancodes read from the keyboard into key presses.
+// pc-keyboard keeps its modifiers to itself, so the Shift keys are tracked here too.
+// One bit per key, Shift+PageUp/PageDown pages through the scrollback.
+struct KeyboardInput {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    shift: u8,
+}
+
+impl KeyboardInput {
+    fn new() -> Self {
+        KeyboardInput {
+            keyboard: Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                pc_keyboard::HandleControl::Ignore,
+            ),
+            shift: 0,
+        }
+    }
+
+    // None until a whole key press is in, and for keys that only change modifiers.
+    fn add_byte(&mut self, scancode: u8) -> Option<KeyInput> {
+        let key_event = match self.keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => key_event,
+            _ => return None,
+        };
+
+        let shift_bit = match key_event.code {
+            KeyCode::ShiftLeft => 1,
+            KeyCode::ShiftRight => 2,
+            _ => 0,
+        };
+        match key_event.state {
+            KeyState::Down => self.shift |= shift_bit,
+            KeyState::Up => self.shift &= !shift_bit,
+        }
+
+        let shift = self.shift != 0;
+        match (&key_event.code, &key_event.state) {
+            (KeyCode::PageUp, KeyState::Down) if shift => Some(KeyInput::PageUp),
+            (KeyCode::PageDown, KeyState::Down) if shift => Some(KeyInput::PageDown),
+            _ => self.keyboard.process_keyevent(key_event).map(KeyInput::Key),
+        }
+    }
+}
+
 //  ---Handlers---
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// Called from trap.rs. Stops in the GDB stub if it is set up.
+fn breakpoint_handler(frame: &mut TrapFrame) {
+    if gdb::handle_trap(frame) {
+        return;
+    }
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+}
+
+fn debug_handler(frame: &mut TrapFrame) {
+    if gdb::handle_trap(frame) {
+        return;
+    }
+    // Nothing else single steps. A trap flag left over from a debugger would keep firing.
+    frame.rflags &= !trap::TRAP_FLAG;
 }
 
 // WARNING: This function does not have a guard page.
@@ -96,10 +210,11 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    // Never returns, so whatever was holding the print locks never lets go.
+    emergency_println!("EXCEPTION: PAGE FAULT");
+    emergency_println!("Accessed address: {:?}", Cr2::read());
+    emergency_println!("Error Code: {:?}", error_code);
+    emergency_println!("{:#?}", stack_frame);
 
     loop {
         hlt();
@@ -107,36 +222,33 @@ extern "x86-interrupt" fn page_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+    time::tick(time::TickSource::Pit);
 
     unsafe {
         //apic::APIC.lock().end_of_interrupt();
         PICS.lock()
             .notify_end_of_interrupt(PicInterruptIndex::Timer.as_u8());
     }
+
+    // Last, it may not return.
+    testing::watchdog::check();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(pc_keyboard::Keyboard::new(
-                pc_keyboard::layouts::Us104Key,
-                pc_keyboard::ScancodeSet1,
-                pc_keyboard::HandleControl::Ignore
-            ));
+        static ref KEYBOARD: Mutex<KeyboardInput> = Mutex::new(KeyboardInput::new());
     }
 
     let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                pc_keyboard::DecodedKey::Unicode(character) => print!("{}", character),
-                pc_keyboard::DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    match keyboard.add_byte(scancode) {
+        Some(KeyInput::PageUp) => vga_buffer::page_up(),
+        Some(KeyInput::PageDown) => vga_buffer::page_down(),
+        Some(KeyInput::Key(DecodedKey::Unicode(character))) => print!("{}", character),
+        Some(KeyInput::Key(DecodedKey::RawKey(key))) => print!("{:?}", key),
+        None => {}
     }
 
     unsafe {
@@ -145,3 +257,112 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
             .notify_end_of_interrupt(PicInterruptIndex::Keyboard.as_u8());
     }
 }
+
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    serial::handle_interrupt(serial::ComPort::Com1.irq());
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PicInterruptIndex::Com1.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn com2_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    serial::handle_interrupt(serial::ComPort::Com2.irq());
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PicInterruptIndex::Com2.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    time::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PicInterruptIndex::Rtc.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let mut port = Port::new(0x60);
+
+    let byte: u8 = unsafe { port.read() };
+    ps2::mouse::handle_byte(byte);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PicInterruptIndex::Mouse.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn hpet_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    time::hpet::handle_interrupt();
+
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    memory::tlb::handle_interrupt();
+
+    apic::end_of_interrupt();
+}
+
+// Spurious interrupts must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+//  ---Tests---
+
+// Scancode set 1, releases have the top bit set and 0xe0 comes before the extended keys
+#[cfg(test)]
+const LEFT_SHIFT: &[u8] = &[0x2a];
+#[cfg(test)]
+const LEFT_SHIFT_UP: &[u8] = &[0xaa];
+#[cfg(test)]
+const RIGHT_SHIFT: &[u8] = &[0x36];
+#[cfg(test)]
+const RIGHT_SHIFT_UP: &[u8] = &[0xb6];
+#[cfg(test)]
+const PAGE_UP: &[u8] = &[0xe0, 0x49];
+#[cfg(test)]
+const PAGE_UP_UP: &[u8] = &[0xe0, 0xc9];
+#[cfg(test)]
+const PAGE_DOWN: &[u8] = &[0xe0, 0x51];
+#[cfg(test)]
+const A: &[u8] = &[0x1e];
+
+// What the last of `scancodes` does.
+#[cfg(test)]
+fn press(input: &mut KeyboardInput, scancodes: &[u8]) -> Option<KeyInput> {
+    scancodes
+        .iter()
+        .fold(None, |_, &scancode| input.add_byte(scancode))
+}
+
+#[test_case]
+fn test_shift_pages() {
+    let mut input = KeyboardInput::new();
+    assert_eq!(press(&mut input, LEFT_SHIFT), None);
+    assert_eq!(press(&mut input, PAGE_UP), Some(KeyInput::PageUp));
+    assert_eq!(press(&mut input, PAGE_UP_UP), None);
+    assert_eq!(press(&mut input, PAGE_DOWN), Some(KeyInput::PageDown));
+    assert_eq!(press(&mut input, LEFT_SHIFT_UP), None);
+
+    assert_eq!(press(&mut input, RIGHT_SHIFT), None);
+    assert_eq!(press(&mut input, PAGE_UP), Some(KeyInput::PageUp));
+    assert_eq!(press(&mut input, RIGHT_SHIFT_UP), None);
+}
+
+#[test_case]
+fn test_keys_without_shift() {
+    let mut input = KeyboardInput::new();
+    assert_eq!(
+        press(&mut input, PAGE_UP),
+        Some(KeyInput::Key(DecodedKey::RawKey(KeyCode::PageUp)))
+    );
+    assert_eq!(
+        press(&mut input, A),
+        Some(KeyInput::Key(DecodedKey::Unicode('a')))
+    );
+}
@@ -0,0 +1,126 @@
+// Mod for exception entries that save every register.
+// x86-interrupt handlers only get the frame the CPU pushes (RIP, CS, RFLAGS, RSP, SS),
+// the general purpose registers are saved somewhere the handler can't see. The GDB
+// stub has to read and change all of them, so the debug and breakpoint exceptions
+// enter through the stubs below instead. They push the registers, which together with
+// the CPU's frame form a TrapFrame, call trap_handler with it, and restore everything
+// from it (changes included) before returning.
+
+use core::arch::global_asm;
+
+use x86_64::VirtAddr;
+
+// Both exceptions push no error code, the entries push the vector in its place.
+global_asm!(
+    r#"
+.global trap_debug_entry
+trap_debug_entry:
+    push $1
+    jmp trap_common
+
+.global trap_breakpoint_entry
+trap_breakpoint_entry:
+    push $3
+    jmp trap_common
+
+trap_common:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+
+    // The frame is 8 bytes off a 16 byte boundary here, RBX keeps the unaligned RSP.
+    mov %rsp, %rdi
+    mov %rsp, %rbx
+    and $-16, %rsp
+    cld
+    call trap_handler
+    mov %rbx, %rsp
+
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+    add $8, %rsp
+    iretq
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static trap_debug_entry: u8;
+    static trap_breakpoint_entry: u8;
+}
+
+pub const DEBUG_VECTOR: u64 = 1;
+pub const BREAKPOINT_VECTOR: u64 = 3;
+
+/// RFLAGS bit that makes the CPU raise a debug exception after every instruction.
+pub const TRAP_FLAG: u64 = 1 << 8;
+
+/// The registers of the interrupted code, in the order the entry pushes them.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // Pushed by the CPU.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &trap_debug_entry })
+}
+
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &trap_breakpoint_entry })
+}
+
+#[no_mangle]
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    match frame.vector {
+        DEBUG_VECTOR => super::debug_handler(frame),
+        BREAKPOINT_VECTOR => super::breakpoint_handler(frame),
+        _ => unreachable!(),
+    }
+}
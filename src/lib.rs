@@ -10,18 +10,39 @@
 #![feature(const_mut_refs)]
 
 extern crate alloc;
+// The paths #[kernel_test] expands to work in the kernel's own tests too.
+extern crate self as jonathan_os;
 
+#[cfg(test)]
 use core::panic::PanicInfo;
 
 #[cfg(test)]
-use bootloader::{BootInfo, entry_point};
+use boot::BootInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod ansi;
 pub mod apic;
+pub mod backtrace;
+pub mod block;
+pub mod boot;
+pub mod boot_params;
+pub mod emergency;
+pub mod framebuffer;
+pub mod fs;
+pub mod fw_cfg;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod power;
+pub mod ps2;
 pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod sync;
+pub mod testing;
+pub mod time;
 pub mod vga_buffer;
 
 //  ---Init---
@@ -29,12 +50,29 @@ pub mod vga_buffer;
 pub fn init() {
     interrupts::init_idt();
     gdt::init();
-    unsafe {
-        x86_64::instructions::interrupts::without_interrupts(|| {
-            interrupts::PICS.lock().initialize();
-        });
+    smp::init_bsp();
+    unsafe { interrupts::PICS.lock().initialize() };
+
+    // Nothing to print the error to if COM1 is missing but the screen. The command line
+    // isn't read yet, the default log_level decides.
+    if let Err(err) = serial::init(serial::ComPort::Com1, serial::SerialConfig::default()) {
+        log!(Warn, "COM1 init failed: {:?}", err);
     }
 
+    // Everything after this reads its settings from the command line.
+    if let Err(err) = boot_params::init() {
+        serial_log!(Warn, "Kernel command line ignored: {:?}", err);
+    }
+    for warning in boot_params::get().warnings() {
+        serial_log!(Warn, "Warning: {}", warning);
+    }
+
+    // Runs before interrupts are on, the controller is polled during init.
+    if let Err(err) = ps2::init() {
+        serial_log!(Warn, "PS/2 init failed: {:?}", err);
+    }
+    time::init();
+
     x86_64::instructions::interrupts::enable();
 }
 
@@ -53,57 +91,33 @@ pub fn hlt_loop() -> ! {
 pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11,
+    /// A test hung, see testing::watchdog.
+    TimedOut = 0x12,
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
     unsafe {
-        let mut port = Port::new(0xf4); // We set this port in the cargo file
+        let mut port = Port::new(0xf4); // The runner in boot/ sets this port
         port.write(exit_code as u32);
     }
 }
 
 //  ---Test Components---
 
-pub trait Testable {
-    fn run(&self) -> ();
-}
-
-// Testable trait that adds test name before function call and passed after call if passed
-impl<T> Testable for T
-where
-    T: Fn(),
-{
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
-    }
-}
+pub use testing::{test_panic_handler, test_runner, Testable};
 
-pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
-    exit_qemu(QemuExitCode::Success);
-}
-
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-
-    hlt_loop()
-}
+/// Marks a test with metadata, see `testing`.
+pub use jonathan_os_macros::kernel_test;
 
 #[cfg(test)]
-entry_point!(test_kernel_main);
+crate::entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
     init();
     test_main();
 
@@ -14,26 +14,66 @@ use alloc::vec;
 use alloc::vec::Vec;
 use core::panic::PanicInfo;
 
-use bootloader::{entry_point, BootInfo};
-use x86_64::VirtAddr;
-
-use jonathan_os::{allocator, hlt_loop, memory, println};
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::boot_params;
+use jonathan_os::serial::ComPort;
+use jonathan_os::{
+    allocator, apic, entry_point, framebuffer, gdb, log, memory, power, println, shell, smp, time,
+};
 
 //  ---Main Functions---
 
 // Bootloader macro to force function signature correctness.
 entry_point!(kernel_main);
 
-fn kernel_main(boot_info: &'static BootInfo) -> ! {
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
     println!("Hello World");
 
     jonathan_os::init();
-    let virtual_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(virtual_memory_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(boot.memory_regions) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    if let Err(err) = framebuffer::init_back_buffer(&mut mapper, &mut frame_allocator) {
+        log!(Warn, "Framebuffer back buffer init failed: {:?}", err);
+    }
+
+    // The PIT stays the tick source, the HPET is only set up so it can be picked later.
+    if let Err(err) = apic::init() {
+        log!(Warn, "APIC init failed: {:?}", err);
+    } else if let Err(err) = time::hpet::init() {
+        log!(Warn, "HPET init failed: {:?}", err);
+    }
+    if let Err(err) = power::init() {
+        log!(Warn, "Power management init failed: {:?}", err);
+    }
+    match smp::init(&mut mapper, &mut frame_allocator) {
+        Ok(cpus) => log!(Info, "{} CPUs online", cpus),
+        Err(err) => log!(Warn, "SMP init failed: {:?}", err),
+    }
+    // GDB attaches with `target remote :4321`, see RUN_ARGS in boot/src/main.rs.
+    if let Err(err) = gdb::init(ComPort::Com2) {
+        log!(Warn, "GDB stub init failed: {:?}", err);
+    }
+
+    if boot_params::log_enabled(boot_params::LogLevel::Info) {
+        heap_demo();
+    }
+
+    log!(Info, "It Didn't Crash!");
+
+    // Above, we set the test auto-generated function to be test_main.
+    // Here we call it with the cfg(test) added so if we don't call cargo test,
+    // this function call is ignored.
+    #[cfg(test)]
+    test_main();
+
+    // Commands typed into QEMU's -serial stdio end up here.
+    shell::run(ComPort::Com1)
+}
 
+// Shows off the heap.
+fn heap_demo() {
     let heap_value = Box::new(41);
     println!("heap_value at {:p}", heap_value);
 
@@ -56,16 +96,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         "reference count is {} now",
         Rc::strong_count(&cloned_reference)
     );
-
-    println!("It Didn't Crash!");
-
-    // Above, we set the test auto-generated function to be test_main.
-    // Here we call it with the cfg(test) added so if we don't call cargo test,
-    // this function call is ignored.
-    #[cfg(test)]
-    test_main();
-
-    hlt_loop()
 }
 
 // Create the panic handler needed by the Rust compiler.
@@ -73,9 +103,23 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    if jonathan_os::emergency::begin_panic() {
+        jonathan_os::emergency_println!("{}", info);
+        // Lets GDB look around before anything else happens. Waiting for one that
+        // isn't attached would hang here, so that needs on_panic=debug.
+        if jonathan_os::gdb::is_connected()
+            || boot_params::get().on_panic() == boot_params::PanicAction::Debug
+        {
+            jonathan_os::gdb::breakpoint();
+        }
+    }
 
-    hlt_loop()
+    // on_panic=shutdown or reboot, for machines nobody is watching.
+    match boot_params::get().on_panic() {
+        boot_params::PanicAction::Halt | boot_params::PanicAction::Debug => jonathan_os::hlt_loop(),
+        boot_params::PanicAction::Shutdown => power::shutdown(),
+        boot_params::PanicAction::Reboot => power::reboot(),
+    }
 }
 
 // This panic handler is for tests
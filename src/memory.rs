@@ -1,7 +1,22 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use x86_64::structures::paging::mapper::{FlagUpdateError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
 use x86_64::{PhysAddr, VirtAddr};
 
+pub mod tlb;
+
+// Where the bootloader mapped physical memory, saved by boot::take so drivers can reach
+// MMIO registers and firmware tables. 0 until then.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Frames below 1 MiB are never handed out, they are kept for things that need a real mode
+// address, like the application processor trampoline.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -9,10 +24,89 @@ use x86_64::{PhysAddr, VirtAddr};
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    set_physical_memory_offset(physical_memory_offset);
     let level_4_page_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_page_table, physical_memory_offset)
 }
 
+pub(crate) fn set_physical_memory_offset(offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(offset.as_u64(), Ordering::Relaxed);
+}
+
+/// Returns the offset physical memory is mapped at, or `None` before `boot::take` or
+/// `init` was called.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
+/// Returns the virtual address `addr` can be reached at through the physical memory mapping.
+///
+/// Panics if `init` hasn't been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = physical_memory_offset().expect("boot::take has not been called");
+    offset + addr.as_u64()
+}
+
+/// Translates `addr` with the active page tables, `None` if it isn't mapped.
+///
+/// Only reads the tables, so unlike `OffsetPageTable::translate_addr` it works without
+/// the mapper, from exception handlers for example.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let offset = physical_memory_offset()?;
+    let (level_4_frame, _) = x86_64::registers::control::Cr3::read();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table_address = level_4_frame.start_address();
+    for (level, &index) in indexes.iter().enumerate() {
+        let table = unsafe { &*(offset + table_address.as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // 1 GiB pages end the walk at level 3, 2 MiB pages at level 2.
+        if (level == 1 || level == 2) && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 1u64 << (12 + 9 * (3 - level));
+            return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+        }
+        table_address = entry.addr();
+    }
+
+    Some(table_address + u64::from(addr.page_offset()))
+}
+
+/// Unmaps `page` and flushes it from the TLB of every CPU. Returns the frame it mapped.
+///
+/// Use this instead of `mapper.unmap`, which only flushes the TLB of the current CPU.
+pub fn unmap_page(mapper: &mut OffsetPageTable, page: Page) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.ignore();
+    tlb::shootdown(Page::range(page, page + 1));
+    Ok(frame)
+}
+
+/// Changes the flags of `page` and flushes it from the TLB of every CPU.
+///
+/// # Safety
+///
+/// Same as `Mapper::update_flags`: the new flags must not break memory safety.
+pub unsafe fn update_flags(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    mapper.update_flags(page, flags)?.ignore();
+    tlb::shootdown(Page::range(page, page + 1));
+    Ok(())
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -42,14 +136,20 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 }
 
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
+    memory_regions: &'static [MemoryRegion],
     next: usize,
 }
 
 impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    /// Creates a frame allocator handing out the usable regions of the bootloader's memory map.
+    ///
+    /// # Safety
+    ///
+    /// Every region marked usable in `memory_regions` must really be unused, and only one
+    /// allocator may be created from them.
+    pub unsafe fn init(memory_regions: &'static [MemoryRegion]) -> Self {
         BootInfoFrameAllocator {
-            memory_map,
+            memory_regions,
             next: 0,
         }
     }
@@ -57,11 +157,23 @@ impl BootInfoFrameAllocator {
 
 impl BootInfoFrameAllocator {
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        let regions = self.memory_regions.iter();
+        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
+        let addr_ranges = usable_regions.map(|r| r.start..r.end);
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        let high_addresses = frame_addresses.filter(|&addr| addr >= LOW_MEMORY_END);
+        high_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// A usable frame below 1 MiB. Always the same one, `allocate_frame` never returns it.
+    pub fn low_memory_frame(&self) -> Option<PhysFrame> {
+        // Frame 0 holds the real mode IVT and the BIOS data area.
+        self.memory_regions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .flat_map(|r| (r.start..r.end).step_by(4096))
+            .find(|addr| (4096..LOW_MEMORY_END).contains(addr))
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
 
@@ -0,0 +1,133 @@
+// Mod for keeping the TLBs of all CPUs in sync with the page tables.
+// invlpg only flushes the TLB of the CPU running it. When a mapping is removed or loses
+// permissions, every other CPU could still have the old entry cached, so they get a
+// TlbShootdown IPI telling them which pages to flush, and the CPU that changed the
+// page tables waits until all of them have.
+// One shootdown runs at a time. The request sits in the statics below, and every CPU
+// that has to take part gets its pending flag set in its PerCpu.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::apic::{self, IpiTarget};
+use crate::interrupts::ApicInterruptIndex;
+use crate::smp::{self, PerCpu};
+
+// Held by the CPU whose shootdown is running.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+
+// The running shootdown: the first page, how many pages, and how many CPUs still have to flush.
+static REQUEST_START: AtomicU64 = AtomicU64::new(0);
+static REQUEST_PAGES: AtomicU64 = AtomicU64::new(0);
+static REQUEST_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+static SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+static REMOTE_SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+static INTERRUPTED_CPUS: AtomicU64 = AtomicU64::new(0);
+static PAGES_FLUSHED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters for the shootdowns this kernel started.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ShootdownStats {
+    /// Every shootdown, including ones where no other CPU was running.
+    pub shootdowns: u64,
+    /// The ones that had to interrupt other CPUs.
+    pub remote_shootdowns: u64,
+    /// IPIs sent, summed over all remote shootdowns.
+    pub interrupted_cpus: u64,
+    /// Pages flushed by the CPUs starting shootdowns.
+    pub pages: u64,
+}
+
+pub fn stats() -> ShootdownStats {
+    ShootdownStats {
+        shootdowns: SHOOTDOWNS.load(Ordering::Relaxed),
+        remote_shootdowns: REMOTE_SHOOTDOWNS.load(Ordering::Relaxed),
+        interrupted_cpus: INTERRUPTED_CPUS.load(Ordering::Relaxed),
+        pages: PAGES_FLUSHED.load(Ordering::Relaxed),
+    }
+}
+
+fn flush_local(start: Page, pages: u64) {
+    for page in Page::range(start, start + pages) {
+        x86_64::instructions::tlb::flush(page.start_address());
+    }
+}
+
+// Does the flush this CPU was asked for, if any.
+fn handle_pending(cpu: &PerCpu) {
+    if !cpu.tlb_shootdown_pending.swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    let start = Page::containing_address(VirtAddr::new(REQUEST_START.load(Ordering::Acquire)));
+    flush_local(start, REQUEST_PAGES.load(Ordering::Acquire));
+    cpu.tlb_shootdowns.fetch_add(1, Ordering::Relaxed);
+    REQUEST_PENDING.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Called from the TlbShootdown IPI handler.
+pub fn handle_interrupt() {
+    if let Some(cpu) = smp::current() {
+        handle_pending(cpu);
+    }
+}
+
+/// Flushes `pages` from the TLB of every CPU, and returns once all of them have.
+///
+/// Call this after unmapping pages or taking permissions away. Works with interrupts
+/// off too, waiting CPUs keep handling shootdowns from others so two can't deadlock.
+pub fn shootdown(pages: PageRange<Size4KiB>) {
+    let count = pages.end - pages.start;
+    SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+    PAGES_FLUSHED.fetch_add(count, Ordering::Relaxed);
+    flush_local(pages.start, count);
+
+    let current = match smp::current() {
+        Some(current) if smp::cpu_count() > 1 => current,
+        _ => return,
+    };
+
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        handle_pending(current);
+        core::hint::spin_loop();
+    };
+
+    REQUEST_START.store(pages.start.start_address().as_u64(), Ordering::Release);
+    REQUEST_PAGES.store(count, Ordering::Release);
+
+    // Counted before the flag is set, a CPU waiting for the lock may flush right away.
+    let mut targets = 0;
+    for index in 0..smp::cpu_count() {
+        match smp::cpu(index) {
+            Some(cpu) if cpu.index() != current.index() && cpu.is_online() => {
+                targets += 1;
+                REQUEST_PENDING.fetch_add(1, Ordering::AcqRel);
+                cpu.tlb_shootdown_pending.store(true, Ordering::Release);
+            }
+            _ => {}
+        }
+    }
+    if targets == 0 {
+        return;
+    }
+
+    REMOTE_SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+    INTERRUPTED_CPUS.fetch_add(targets as u64, Ordering::Relaxed);
+    // Can't fail, other CPUs only run after apic::init.
+    let _ = apic::send_ipi(
+        IpiTarget::AllButSelf,
+        ApicInterruptIndex::TlbShootdown.as_u8(),
+    );
+
+    while REQUEST_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,255 @@
+// Mod for turning the machine off and rebooting it.
+// Shutdown enters ACPI sleep state S5 by writing SLP_TYP and SLP_EN to the PM1 control
+// registers from the FADT. The SLP_TYP values aren't in any table, they sit in the \_S5
+// package in the DSDT's AML, so init digs them out ahead of time.
+// Reboot tries the FADT reset register, then the 8042 reset line, then a triple fault.
+// After init neither takes a lock or allocates, so both are safe to call from a panic.
+
+use core::mem::size_of;
+use core::ptr;
+
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::acpi::fadt::Fadt;
+use crate::acpi::{self, AcpiError, AddressSpace, GenericAddress, SdtHeader};
+use crate::{hlt_loop, memory, ps2};
+
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_TYPE_MASK: u16 = 0b111 << PM1_SLEEP_TYPE_SHIFT;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+// AML opcodes needed to pick the \_S5 package apart.
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_NAME: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+const AML_DWORD_PREFIX: u8 = 0x0C;
+const AML_PACKAGE: u8 = 0x12;
+const AML_ROOT_PREFIX: u8 = b'\\';
+
+// Status polls the firmware gets to switch into ACPI mode. There might not be a timer.
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+
+// PCI config mechanism 1, for a reset register in PCI config space.
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PowerError {
+    /// The platform description couldn't be read.
+    Acpi(AcpiError),
+    /// There is no FADT, or it has no PM1 control register.
+    NoFadt,
+    /// The DSDT doesn't define \_S5, so the machine can't be turned off through ACPI.
+    NoS5,
+}
+
+impl From<AcpiError> for PowerError {
+    fn from(err: AcpiError) -> Self {
+        PowerError::Acpi(err)
+    }
+}
+
+/// The SLP_TYP values to write to the PM1a and PM1b control registers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SleepType {
+    pub a: u16,
+    pub b: u16,
+}
+
+static S5: Once<SleepType> = Once::new();
+
+//  ---AML---
+
+// Reads an integer constant at `offset` and moves past it.
+fn aml_integer(aml: &[u8], offset: &mut usize) -> Option<u32> {
+    let op = *aml.get(*offset)?;
+    *offset += 1;
+
+    let (value, size) = match op {
+        AML_ZERO => (0, 0),
+        AML_ONE => (1, 0),
+        AML_BYTE_PREFIX => (u32::from(acpi::field::<u8>(aml, *offset)?), 1),
+        AML_WORD_PREFIX => (u32::from(acpi::field::<u16>(aml, *offset)?), 2),
+        AML_DWORD_PREFIX => (acpi::field::<u32>(aml, *offset)?, 4),
+        _ => return None,
+    };
+    *offset += size;
+    Some(value)
+}
+
+// Parses `Name(\_S5, Package() { a, b, ... })` where the name starts at `at`.
+fn parse_s5(aml: &[u8], at: usize) -> Option<SleepType> {
+    // Has to be where the name is defined, not some method using it.
+    let defined = match at.checked_sub(2).map(|before| &aml[before..at]) {
+        Some([AML_NAME, AML_ROOT_PREFIX]) => true,
+        _ => at >= 1 && aml[at - 1] == AML_NAME,
+    };
+    if !defined {
+        return None;
+    }
+
+    let mut offset = at + 4;
+    if *aml.get(offset)? != AML_PACKAGE {
+        return None;
+    }
+    offset += 1;
+    // PkgLength, the top two bits of its first byte count the bytes that follow.
+    offset += 1 + (*aml.get(offset)? >> 6) as usize;
+    // NumElements.
+    offset += 1;
+
+    let a = aml_integer(aml, &mut offset)?;
+    let b = aml_integer(aml, &mut offset)?;
+    Some(SleepType {
+        a: (a & 0b111) as u16,
+        b: (b & 0b111) as u16,
+    })
+}
+
+/// Finds the \_S5 package in a block of AML and returns its SLP_TYP values.
+///
+/// This isn't an AML interpreter, it only understands the plain constant package
+/// firmware uses for \_S5 in practice.
+fn find_s5(aml: &[u8]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| *name == b"_S5_")
+        .find_map(|(at, _)| parse_s5(aml, at))
+}
+
+//  ---Init---
+
+/// Finds the \_S5 sleep type in the DSDT so `shutdown` can work later.
+///
+/// Needs memory::init and the heap, for the ACPI tables.
+pub fn init() -> Result<(), PowerError> {
+    let fadt = acpi::platform()?.fadt.ok_or(PowerError::NoFadt)?;
+    if fadt.pm1a_control_block == 0 {
+        return Err(PowerError::NoFadt);
+    }
+
+    // The AML starts right after the table header.
+    let dsdt = acpi::table_at(fadt.dsdt_address)?;
+    let s5 = find_s5(&dsdt.bytes()[size_of::<SdtHeader>()..]).ok_or(PowerError::NoS5)?;
+    S5.call_once(|| s5);
+    Ok(())
+}
+
+/// The \_S5 sleep type found by `init`.
+pub fn s5_sleep_type() -> Option<SleepType> {
+    S5.r#try().copied()
+}
+
+//  ---Shutdown---
+
+// Machines that boot in legacy mode ignore the PM1 registers until asked to switch.
+fn enable_acpi(fadt: &Fadt) {
+    let mut control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { control.read() } & PM1_SCI_ENABLE != 0
+        || fadt.smi_command_port == 0
+        || fadt.acpi_enable == 0
+    {
+        return;
+    }
+
+    unsafe { Port::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_POLLS {
+        if unsafe { control.read() } & PM1_SCI_ENABLE != 0 {
+            return;
+        }
+    }
+}
+
+fn enter_sleep_state(control_block: u32, sleep_type: u16) {
+    let mut control: Port<u16> = Port::new(control_block as u16);
+    unsafe {
+        let value = control.read() & !PM1_SLEEP_TYPE_MASK;
+        control.write(value | sleep_type << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+    }
+}
+
+/// Turns the machine off through ACPI.
+///
+/// If `init` hasn't found \_S5, or the firmware ignores the request, this halts forever instead.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let fadt = acpi::cached_platform().and_then(|platform| platform.fadt);
+    if let (Some(fadt), Some(s5)) = (fadt, S5.r#try()) {
+        enable_acpi(&fadt);
+        enter_sleep_state(fadt.pm1a_control_block, s5.a);
+        if fadt.pm1b_control_block != 0 {
+            enter_sleep_state(fadt.pm1b_control_block, s5.b);
+        }
+    }
+
+    hlt_loop()
+}
+
+//  ---Reboot---
+
+fn write_reset_register(register: GenericAddress, value: u8) {
+    match register.space {
+        AddressSpace::SystemIo => unsafe { Port::new(register.address as u16).write(value) },
+        AddressSpace::SystemMemory => {
+            // Not phys_to_virt, it panics and this may already be running in a panic.
+            if let Some(offset) = memory::physical_memory_offset() {
+                let address = offset + register.address;
+                unsafe { ptr::write_volatile(address.as_mut_ptr::<u8>(), value) };
+            }
+        }
+        AddressSpace::PciConfig => {
+            // A register on bus 0: device in bits 32-47, function in 16-31, offset in 0-15.
+            let device = ((register.address >> 32) & 0x1F) as u32;
+            let function = ((register.address >> 16) & 0x7) as u32;
+            let offset = (register.address & 0xFF) as u32;
+            let address = PCI_CONFIG_ENABLE | device << 11 | function << 8 | (offset & 0xFC);
+            unsafe {
+                Port::new(PCI_CONFIG_ADDRESS).write(address);
+                Port::new(PCI_CONFIG_DATA + (offset & 0b11) as u16).write(value);
+            }
+        }
+        AddressSpace::Other(_) => {}
+    }
+}
+
+/// Resets the machine. Works without `init`, the ACPI reset register is just skipped then.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let fadt = acpi::cached_platform().and_then(|platform| platform.fadt);
+    if let Some(fadt) = fadt {
+        if let Some(register) = fadt.reset_register {
+            write_reset_register(register, fadt.reset_value);
+        }
+    }
+
+    let _ = ps2::pulse_reset_line();
+
+    // Last resort: with an empty IDT the breakpoint can't be delivered, neither can
+    // the double fault that follows, and the triple fault resets the CPU.
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe { x86_64::instructions::tables::lidt(&empty) };
+    x86_64::instructions::interrupts::int3();
+
+    hlt_loop()
+}
+
+//  ---Tests---
+#[test_case]
+fn test_find_s5() {
+    // Name(\_S5, Package(0x04) { 0x05, Zero, One, Zero }), after a method that uses \_S5.
+    let aml: &[u8] = b"\x14\x07MTHD\x00_S5_\x08\\_S5_\x12\x07\x04\x0A\x05\x00\x01\x00";
+    assert_eq!(find_s5(aml), Some(SleepType { a: 5, b: 0 }));
+    assert_eq!(find_s5(&aml[..aml.len() - 4]), None);
+}
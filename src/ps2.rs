@@ -0,0 +1,284 @@
+// Mod for the 8042 PS/2 controller.
+// The BIOS usually leaves the controller set up for the keyboard, but nothing
+// guarantees it, and the second (mouse) port is normally left off.
+// init resets the controller, runs its self tests and brings up both ports.
+// Port 1 keeps scancode translation on so the keyboard still sends set 1.
+
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use crate::interrupts::PICS;
+
+pub mod mouse;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT_2: u8 = 0xA7;
+const CMD_ENABLE_PORT_2: u8 = 0xA8;
+const CMD_TEST_PORT_2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT_1: u8 = 0xAB;
+const CMD_DISABLE_PORT_1: u8 = 0xAD;
+const CMD_ENABLE_PORT_1: u8 = 0xAE;
+const CMD_WRITE_PORT_2: u8 = 0xD4;
+// Pulses output line 0, which is wired to the CPU reset on PCs.
+const CMD_PULSE_RESET: u8 = 0xFE;
+
+const CONFIG_PORT_1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT_2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT_1_CLOCK_OFF: u8 = 1 << 4;
+const CONFIG_PORT_2_CLOCK_OFF: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEVICE_RESET: u8 = 0xFF;
+const DEVICE_ACK: u8 = 0xFA;
+const DEVICE_RESEND: u8 = 0xFE;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xAA;
+
+// There is no timer to wait on yet, so waiting is a bounded number of status polls.
+const POLL_LIMIT: usize = 100_000;
+
+// PIC lines that have to be unmasked for the ports. IRQ 2 is the cascade to the second PIC.
+const KEYBOARD_IRQ: u8 = 1;
+const CASCADE_IRQ: u8 = 2;
+const MOUSE_IRQ: u8 = 12;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Ps2Error {
+    /// The controller didn't answer in time.
+    Timeout,
+    /// The controller self test returned this instead of 0x55.
+    SelfTestFailed(u8),
+    /// A device answered a command with this instead of an ACK.
+    UnexpectedResponse(u8),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+/// Which ports came up during init.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Ps2Ports {
+    pub keyboard: bool,
+    pub mouse: bool,
+}
+
+static PORTS: Mutex<Ps2Ports> = Mutex::new(Ps2Ports {
+    keyboard: false,
+    mouse: false,
+});
+
+/// Returns which ports were brought up by `init`.
+pub fn ports() -> Ps2Ports {
+    *PORTS.lock()
+}
+
+//  ---Controller IO---
+
+fn status() -> u8 {
+    let mut port = PortReadOnly::new(STATUS_PORT);
+    unsafe { port.read() }
+}
+
+fn wait_for_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+
+    Err(Ps2Error::Timeout)
+}
+
+fn write_command(command: u8) -> Result<(), Ps2Error> {
+    wait_for_input_empty()?;
+    let mut port = PortWriteOnly::new(COMMAND_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Ps2Error> {
+    wait_for_input_empty()?;
+    let mut port = Port::new(DATA_PORT);
+    unsafe { port.write(data) };
+    Ok(())
+}
+
+/// Waits for a byte from the controller or a device.
+pub(crate) fn read_data() -> Result<u8, Ps2Error> {
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            let mut port = Port::new(DATA_PORT);
+            return Ok(unsafe { port.read() });
+        }
+    }
+
+    Err(Ps2Error::Timeout)
+}
+
+fn flush_output() {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        unsafe { port.read() };
+    }
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    write_command(CMD_READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Sends a byte to the device on `port` and waits for its ACK, resending if asked to.
+pub(crate) fn send_to_device(port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..3 {
+        if port == Ps2Port::Second {
+            write_command(CMD_WRITE_PORT_2)?;
+        }
+        write_data(byte)?;
+
+        match read_data()? {
+            DEVICE_ACK => return Ok(()),
+            DEVICE_RESEND => continue,
+            other => return Err(Ps2Error::UnexpectedResponse(other)),
+        }
+    }
+
+    Err(Ps2Error::UnexpectedResponse(DEVICE_RESEND))
+}
+
+/// Resets the device on `port` and waits for it to pass its own self test.
+fn reset_device(port: Ps2Port) -> Result<(), Ps2Error> {
+    send_to_device(port, DEVICE_RESET)?;
+
+    match read_data()? {
+        DEVICE_SELF_TEST_PASSED => {}
+        other => return Err(Ps2Error::UnexpectedResponse(other)),
+    }
+
+    // Mice follow up with their ID byte, keyboards send nothing more.
+    if port == Ps2Port::Second {
+        read_data()?;
+    }
+    Ok(())
+}
+
+/// Asks the controller to reset the CPU. Returns only if nothing happened.
+///
+/// Touches nothing but the controller ports, so it is fine to call from a panic.
+pub fn pulse_reset_line() -> Result<(), Ps2Error> {
+    write_command(CMD_PULSE_RESET)
+}
+
+//  ---Init---
+
+/// Resets and tests the controller, then enables every working port.
+///
+/// Must run with interrupts disabled, before anything else touches port 0x60.
+pub fn init() -> Result<Ps2Ports, Ps2Error> {
+    write_command(CMD_DISABLE_PORT_1)?;
+    write_command(CMD_DISABLE_PORT_2)?;
+    flush_output();
+
+    // Turn off IRQs while we poll, keep translation for the keyboard.
+    let mut config = read_config()?;
+    config &= !(CONFIG_PORT_1_IRQ | CONFIG_PORT_2_IRQ);
+    config |= CONFIG_TRANSLATION;
+    write_config(config)?;
+
+    write_command(CMD_SELF_TEST)?;
+    match read_data()? {
+        SELF_TEST_PASSED => {}
+        other => return Err(Ps2Error::SelfTestFailed(other)),
+    }
+    // Some controllers reset themselves during the self test.
+    write_config(config)?;
+
+    // If enabling port 2 turns its clock on, the controller has two ports.
+    write_command(CMD_ENABLE_PORT_2)?;
+    let dual_port = read_config()? & CONFIG_PORT_2_CLOCK_OFF == 0;
+    write_command(CMD_DISABLE_PORT_2)?;
+
+    write_command(CMD_TEST_PORT_1)?;
+    let mut ports = Ps2Ports {
+        keyboard: read_data()? == PORT_TEST_PASSED,
+        mouse: false,
+    };
+    if dual_port {
+        write_command(CMD_TEST_PORT_2)?;
+        ports.mouse = read_data()? == PORT_TEST_PASSED;
+    }
+
+    if ports.keyboard {
+        write_command(CMD_ENABLE_PORT_1)?;
+        // A keyboard that won't reset is still worth keeping around.
+        let _ = reset_device(Ps2Port::First);
+        config |= CONFIG_PORT_1_IRQ;
+    }
+    if ports.mouse {
+        write_command(CMD_ENABLE_PORT_2)?;
+        ports.mouse = reset_device(Ps2Port::Second).is_ok() && mouse::init().is_ok();
+        if ports.mouse {
+            config |= CONFIG_PORT_2_IRQ;
+        }
+    }
+
+    // The saved config still has both clocks off from the start of init.
+    flush_output();
+    config &= !(CONFIG_PORT_1_CLOCK_OFF | CONFIG_PORT_2_CLOCK_OFF);
+    if !ports.keyboard {
+        config |= CONFIG_PORT_1_CLOCK_OFF;
+    }
+    if !ports.mouse {
+        config |= CONFIG_PORT_2_CLOCK_OFF;
+    }
+    write_config(config)?;
+
+    unmask_irqs(ports);
+    *PORTS.lock() = ports;
+    Ok(ports)
+}
+
+fn unmask_irqs(ports: Ps2Ports) {
+    let mut pics = PICS.lock();
+    let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+
+    if ports.keyboard {
+        primary &= !(1 << KEYBOARD_IRQ);
+    }
+    if ports.mouse {
+        primary &= !(1 << CASCADE_IRQ);
+        secondary &= !(1 << (MOUSE_IRQ - 8));
+    }
+
+    unsafe { pics.write_masks(primary, secondary) };
+}
+
+//  ---Tests---
+#[test_case]
+fn test_init_finds_both_ports() {
+    // QEMU always emulates a keyboard and a mouse.
+    let ports = ports();
+    assert!(ports.keyboard);
+    assert!(ports.mouse);
+}
@@ -0,0 +1,279 @@
+// Mod for the PS/2 mouse on the second controller port.
+// A plain mouse sends 3 byte packets. After the IntelliMouse "knock" (magic
+// sample rate sequences) it may switch to 4 byte packets that add a scroll
+// wheel and buttons 4 and 5.
+// Decoded packets go into a fixed size queue that is safe to fill from IRQ 12.
+
+use spin::Mutex;
+
+use crate::ps2::{self, Ps2Error, Ps2Port};
+
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const CMD_GET_ID: u8 = 0xF2;
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+const CMD_SET_DEFAULTS: u8 = 0xF6;
+
+const ID_STANDARD: u8 = 0x00;
+const ID_SCROLL_WHEEL: u8 = 0x03;
+const ID_FIVE_BUTTONS: u8 = 0x04;
+
+// Bit 3 of the first packet byte is always set, we use it to get back in sync.
+const FLAG_ALWAYS_ONE: u8 = 1 << 3;
+const FLAG_X_SIGN: u8 = 1 << 4;
+const FLAG_Y_SIGN: u8 = 1 << 5;
+const FLAG_X_OVERFLOW: u8 = 1 << 6;
+const FLAG_Y_OVERFLOW: u8 = 1 << 7;
+
+const QUEUE_SIZE: usize = 64;
+
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_RIGHT: u8 = 1 << 1;
+pub const BUTTON_MIDDLE: u8 = 1 << 2;
+pub const BUTTON_4: u8 = 1 << 3;
+pub const BUTTON_5: u8 = 1 << 4;
+
+/// One mouse packet. `dy` is positive when the mouse moves up, `dz` when
+/// the wheel scrolls down.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub dz: i8,
+    /// The BUTTON_* bits of every button held down.
+    pub buttons: u8,
+}
+
+impl MouseEvent {
+    pub fn is_pressed(&self, button: u8) -> bool {
+        self.buttons & button != 0
+    }
+}
+
+// Ring buffer so the interrupt handler never has to allocate.
+struct EventQueue {
+    events: [MouseEvent; QUEUE_SIZE],
+    start: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl EventQueue {
+    const fn new() -> Self {
+        const EMPTY: MouseEvent = MouseEvent {
+            dx: 0,
+            dy: 0,
+            dz: 0,
+            buttons: 0,
+        };
+
+        EventQueue {
+            events: [EMPTY; QUEUE_SIZE],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, event: MouseEvent) {
+        // Keep the oldest events when nobody is reading, the subscriber catches up from there.
+        if self.len == QUEUE_SIZE {
+            self.dropped += 1;
+            return;
+        }
+
+        self.events[(self.start + self.len) % QUEUE_SIZE] = event;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<MouseEvent> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let event = self.events[self.start];
+        self.start = (self.start + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+// Bytes of the packet being received.
+struct PacketState {
+    packet: [u8; 4],
+    received: usize,
+    mouse_id: u8,
+}
+
+impl PacketState {
+    fn packet_size(&self) -> usize {
+        match self.mouse_id {
+            ID_SCROLL_WHEEL | ID_FIVE_BUTTONS => 4,
+            _ => 3,
+        }
+    }
+}
+
+static STATE: Mutex<PacketState> = Mutex::new(PacketState {
+    packet: [0; 4],
+    received: 0,
+    mouse_id: ID_STANDARD,
+});
+
+static EVENTS: Mutex<EventQueue> = Mutex::new(EventQueue::new());
+
+//  ---Init---
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    ps2::send_to_device(Ps2Port::Second, CMD_SET_SAMPLE_RATE)?;
+    ps2::send_to_device(Ps2Port::Second, rate)
+}
+
+fn read_id() -> Result<u8, Ps2Error> {
+    ps2::send_to_device(Ps2Port::Second, CMD_GET_ID)?;
+    ps2::read_data()
+}
+
+/// Sets up a freshly reset mouse, turning on the wheel and extra buttons if
+/// it has them, and enables packet streaming.
+pub(crate) fn init() -> Result<(), Ps2Error> {
+    ps2::send_to_device(Ps2Port::Second, CMD_SET_DEFAULTS)?;
+
+    // Sample rates 200, 100, 80 unlock the wheel. 200, 200, 80 then unlock buttons 4 and 5.
+    for &rate in &[200, 100, 80] {
+        set_sample_rate(rate)?;
+    }
+    let mut id = read_id()?;
+    if id == ID_SCROLL_WHEEL {
+        for &rate in &[200, 200, 80] {
+            set_sample_rate(rate)?;
+        }
+        id = read_id()?;
+    }
+
+    // Go back to a normal rate, the knock leaves it at 80.
+    set_sample_rate(100)?;
+
+    // IRQ 12 locks STATE too, and packets can arrive as soon as reporting is on.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut state = STATE.lock();
+        state.mouse_id = id;
+        state.received = 0;
+    });
+
+    ps2::send_to_device(Ps2Port::Second, CMD_ENABLE_REPORTING)
+}
+
+/// Returns the ID the mouse reported: 0 for 3 buttons, 3 with a wheel, 4 with 5 buttons.
+pub fn mouse_id() -> u8 {
+    x86_64::instructions::interrupts::without_interrupts(|| STATE.lock().mouse_id)
+}
+
+//  ---Packets---
+
+/// Feeds a byte from the IRQ 12 handler into the packet decoder.
+pub fn handle_byte(byte: u8) {
+    let mut state = STATE.lock();
+
+    // Drop bytes until something that looks like the first byte of a packet shows up.
+    if state.received == 0 && byte & FLAG_ALWAYS_ONE == 0 {
+        return;
+    }
+
+    let index = state.received;
+    state.packet[index] = byte;
+    state.received += 1;
+
+    if state.received == state.packet_size() {
+        state.received = 0;
+        if let Some(event) = decode(&state.packet[..state.packet_size()], state.mouse_id) {
+            EVENTS.lock().push(event);
+        }
+    }
+}
+
+/// Decodes a 3 or 4 byte packet.
+///
+/// Returns `None` for packets with the overflow bits set, their movement is junk.
+pub fn decode(packet: &[u8], mouse_id: u8) -> Option<MouseEvent> {
+    let flags = packet[0];
+    if flags & (FLAG_X_OVERFLOW | FLAG_Y_OVERFLOW) != 0 {
+        return None;
+    }
+
+    // The movement is a 9 bit two's complement number with the sign bit in the flags.
+    let mut dx = i16::from(packet[1]);
+    if flags & FLAG_X_SIGN != 0 {
+        dx -= 0x100;
+    }
+    let mut dy = i16::from(packet[2]);
+    if flags & FLAG_Y_SIGN != 0 {
+        dy -= 0x100;
+    }
+
+    let mut buttons = flags & (BUTTON_LEFT | BUTTON_RIGHT | BUTTON_MIDDLE);
+    let dz = match (mouse_id, packet.get(3)) {
+        (ID_SCROLL_WHEEL, Some(&extra)) => extra as i8,
+        (ID_FIVE_BUTTONS, Some(&extra)) => {
+            if extra & (1 << 4) != 0 {
+                buttons |= BUTTON_4;
+            }
+            if extra & (1 << 5) != 0 {
+                buttons |= BUTTON_5;
+            }
+            // Sign extend the low 4 bits.
+            ((extra << 4) as i8) >> 4
+        }
+        _ => 0,
+    };
+
+    Some(MouseEvent {
+        dx,
+        dy,
+        dz,
+        buttons,
+    })
+}
+
+/// Takes the oldest mouse event off the queue.
+pub fn next_event() -> Option<MouseEvent> {
+    x86_64::instructions::interrupts::without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// Number of events thrown away because the queue was full.
+pub fn dropped_events() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| EVENTS.lock().dropped)
+}
+
+//  ---Tests---
+#[test_case]
+fn test_decode_standard_packet() {
+    let event = decode(&[0b0000_1001, 5, 3], ID_STANDARD).unwrap();
+    assert_eq!(event.dx, 5);
+    assert_eq!(event.dy, 3);
+    assert_eq!(event.dz, 0);
+    assert!(event.is_pressed(BUTTON_LEFT));
+    assert!(!event.is_pressed(BUTTON_RIGHT));
+}
+
+#[test_case]
+fn test_decode_negative_movement() {
+    let event = decode(&[0b0011_1010, 0xFB, 0xFE], ID_STANDARD).unwrap();
+    assert_eq!(event.dx, -5);
+    assert_eq!(event.dy, -2);
+    assert_eq!(event.buttons, BUTTON_RIGHT);
+}
+
+#[test_case]
+fn test_decode_overflow_is_dropped() {
+    assert_eq!(decode(&[0b0100_1000, 0, 0], ID_STANDARD), None);
+}
+
+#[test_case]
+fn test_decode_wheel_and_extra_buttons() {
+    let wheel = decode(&[0b0000_1000, 0, 0, 0xFF], ID_SCROLL_WHEEL).unwrap();
+    assert_eq!(wheel.dz, -1);
+
+    let five = decode(&[0b0000_1100, 0, 0, 0b0010_0001], ID_FIVE_BUTTONS).unwrap();
+    assert_eq!(five.dz, 1);
+    assert_eq!(five.buttons, BUTTON_MIDDLE | BUTTON_5);
+}
@@ -1,20 +1,40 @@
 // Mod for writing to QEMU serial ports
 // QEMU is set up to write port data to stdio.
+// Reading works too. Received bytes raise IRQ 4 (COM1 and COM3) or IRQ 3 (COM2 and
+// COM4), the handler moves them into a buffer per port for read_byte.
 
 use core::fmt::Write;
 
 use lazy_static::lazy_static;
-use spin::Mutex;
-use uart_16550::SerialPort;
+
+use crate::interrupts::PICS;
+use crate::sync::IrqSafeSpinlock;
+
+pub mod uart;
+
+use self::uart::Uart;
+
+// PIC lines, IRQ 2 is the cascade to the second PIC which we don't need here.
+const COM1_IRQ: u8 = 4;
+const COM2_IRQ: u8 = 3;
+
+// The baud rate divisor divides this.
+const UART_CLOCK: u32 = 115_200;
+
+const RECEIVE_BUFFER_SIZE: usize = 256;
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed")
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+#[doc(hidden)]
+pub fn _emergency_print(args: core::fmt::Arguments) {
+    let mut serial = unsafe { SERIAL1.force_lock(crate::emergency::LOCK_SPINS) };
+    let _ = serial.write_fmt(args);
 }
 
 /// Prints to the host through the serial interface.
@@ -35,10 +55,229 @@ macro_rules! serial_println {
 }
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
+    // COM1, set up with the default config the first time something is printed.
+    pub static ref SERIAL1: &'static IrqSafeSpinlock<Uart> = {
+        let port = self::port(ComPort::Com1);
+        // Nothing to report to if COM1 is missing, writes just go nowhere then.
+        let _ = port.lock().init(SerialConfig::default());
 
-        Mutex::new(serial_port)
+        port
     };
 }
+
+//  ---Config---
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SerialError {
+    /// The divisor for this baud rate isn't a whole number between 1 and 65535.
+    InvalidBaudRate(u32),
+    /// Nothing answered at the port's address.
+    NotPresent,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum DataBits {
+    Five = 0b00,
+    Six = 0b01,
+    Seven = 0b10,
+    Eight = 0b11,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Parity {
+    None = 0b000 << 3,
+    Odd = 0b001 << 3,
+    Even = 0b011 << 3,
+    Mark = 0b101 << 3,
+    Space = 0b111 << 3,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum StopBits {
+    One = 0,
+    /// One and a half with 5 data bits.
+    Two = 1 << 2,
+}
+
+/// Baud rate and line settings, the default is 115200 8N1.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl SerialConfig {
+    fn divisor(&self) -> Result<u16, SerialError> {
+        let error = SerialError::InvalidBaudRate(self.baud_rate);
+        if self.baud_rate == 0 || !UART_CLOCK.is_multiple_of(self.baud_rate) {
+            return Err(error);
+        }
+        let divisor = UART_CLOCK / self.baud_rate;
+        if divisor > u32::from(u16::MAX) {
+            return Err(error);
+        }
+        Ok(divisor as u16)
+    }
+
+    // The line control register bits for this config.
+    fn line_control(&self) -> u8 {
+        self.data_bits as u8 | self.stop_bits as u8 | self.parity as u8
+    }
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            baud_rate: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+//  ---Ports---
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The usual I/O port base, the BIOS data area could say otherwise but never does in QEMU.
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => COM1_IRQ,
+            ComPort::Com2 | ComPort::Com4 => COM2_IRQ,
+        }
+    }
+}
+
+static PORTS: [IrqSafeSpinlock<Uart>; 4] = [
+    IrqSafeSpinlock::new(Uart::new(0x3F8)),
+    IrqSafeSpinlock::new(Uart::new(0x2F8)),
+    IrqSafeSpinlock::new(Uart::new(0x3E8)),
+    IrqSafeSpinlock::new(Uart::new(0x2E8)),
+];
+
+/// The UART of `com`. Printing to COM1 should go through SERIAL1, which sets it up first.
+pub fn port(com: ComPort) -> &'static IrqSafeSpinlock<Uart> {
+    &PORTS[com.index()]
+}
+
+/// Sets up `com` with `config` and turns on receiving through its IRQ.
+pub fn init(com: ComPort, config: SerialConfig) -> Result<(), SerialError> {
+    if com == ComPort::Com1 {
+        lazy_static::initialize(&SERIAL1);
+    }
+
+    let mut uart = port(com).lock();
+    uart.init(config)?;
+    uart.set_receive_interrupt(true);
+    unmask_irq(com.irq());
+    Ok(())
+}
+
+fn unmask_irq(irq: u8) {
+    let mut pics = PICS.lock();
+    let [primary, secondary] = unsafe { pics.read_masks() };
+    unsafe { pics.write_masks(primary & !(1 << irq), secondary) };
+}
+
+//  ---Receiving---
+
+// Bytes received but not read yet, per port.
+struct ReceiveBuffer {
+    bytes: [u8; RECEIVE_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl ReceiveBuffer {
+    const fn new() -> Self {
+        ReceiveBuffer {
+            bytes: [0; RECEIVE_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        // Like the mouse events, keep the oldest bytes and count what didn't fit.
+        if self.len == RECEIVE_BUFFER_SIZE {
+            self.dropped += 1;
+            return;
+        }
+
+        self.bytes[(self.start + self.len) % RECEIVE_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % RECEIVE_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static RECEIVED: [IrqSafeSpinlock<ReceiveBuffer>; 4] = [
+    IrqSafeSpinlock::new(ReceiveBuffer::new()),
+    IrqSafeSpinlock::new(ReceiveBuffer::new()),
+    IrqSafeSpinlock::new(ReceiveBuffer::new()),
+    IrqSafeSpinlock::new(ReceiveBuffer::new()),
+];
+
+/// Called from the IRQ 3 and IRQ 4 handlers, drains every port on `irq`.
+pub fn handle_interrupt(irq: u8) {
+    for com in ComPort::ALL.iter().filter(|com| com.irq() == irq) {
+        let mut uart = port(*com).lock();
+        // Ports that were never set up have nothing for us, or aren't there at all.
+        if uart.config().is_none() {
+            continue;
+        }
+        let mut received = RECEIVED[com.index()].lock();
+        while let Some(byte) = uart.try_receive() {
+            received.push(byte);
+        }
+    }
+}
+
+/// The next byte received on `com`, `None` if there is none.
+pub fn read_byte(com: ComPort) -> Option<u8> {
+    RECEIVED[com.index()].lock().pop()
+}
+
+/// Bytes lost on `com` because nobody read them fast enough.
+pub fn dropped_bytes(com: ComPort) -> usize {
+    RECEIVED[com.index()].lock().dropped
+}
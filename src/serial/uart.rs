@@ -0,0 +1,138 @@
+// Mod for the 16550 UART behind each COM port.
+// Eight I/O ports starting at the base. Register 0 and 1 hold the baud rate divisor
+// instead while the DLAB bit in the line control register is set.
+
+use core::fmt;
+
+use x86_64::instructions::port::Port;
+
+use super::{SerialConfig, SerialError};
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+// With DLAB set.
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+// Enable and clear both FIFOs, interrupt for every byte. Input is mostly typed by hand.
+const FCR_ENABLE_AND_CLEAR: u8 = 0x07;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+// OUT2 connects the UART's interrupt line to the PIC.
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// Sent to itself in loopback mode to see if the UART is there.
+const TEST_BYTE: u8 = 0xAE;
+
+pub struct Uart {
+    base: u16,
+    config: Option<SerialConfig>,
+    loopback: bool,
+}
+
+impl Uart {
+    pub const fn new(base: u16) -> Self {
+        Uart {
+            base,
+            config: None,
+            loopback: false,
+        }
+    }
+
+    unsafe fn read(&self, register: u16) -> u8 {
+        Port::new(self.base + register).read()
+    }
+
+    unsafe fn write(&mut self, register: u16, value: u8) {
+        Port::new(self.base + register).write(value)
+    }
+
+    fn modem_control(&self) -> u8 {
+        let loopback = if self.loopback { MCR_LOOPBACK } else { 0 };
+        MCR_DTR | MCR_RTS | MCR_OUT2 | loopback
+    }
+
+    /// Sets up the UART with `config`, receive interrupts off.
+    pub fn init(&mut self, config: SerialConfig) -> Result<(), SerialError> {
+        let divisor = config.divisor()?;
+
+        unsafe {
+            self.write(INTERRUPT_ENABLE, 0);
+            self.write(LINE_CONTROL, LCR_DLAB);
+            self.write(DIVISOR_LOW, divisor as u8);
+            self.write(DIVISOR_HIGH, (divisor >> 8) as u8);
+            self.write(LINE_CONTROL, config.line_control());
+            self.write(FIFO_CONTROL, FCR_ENABLE_AND_CLEAR);
+
+            // Anything that reads back what it was sent in loopback mode is a UART.
+            self.write(MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_LOOPBACK);
+            self.write(DATA, TEST_BYTE);
+            if self.read(DATA) != TEST_BYTE {
+                return Err(SerialError::NotPresent);
+            }
+            self.loopback = false;
+            self.write(MODEM_CONTROL, self.modem_control());
+        }
+
+        self.config = Some(config);
+        Ok(())
+    }
+
+    /// The config from the last successful `init`.
+    pub fn config(&self) -> Option<SerialConfig> {
+        self.config
+    }
+
+    pub fn set_receive_interrupt(&mut self, enabled: bool) {
+        let value = if enabled { IER_RECEIVED_DATA } else { 0 };
+        unsafe { self.write(INTERRUPT_ENABLE, value) };
+    }
+
+    /// In loopback mode everything sent is received again instead of leaving the UART.
+    pub fn set_loopback(&mut self, enabled: bool) {
+        self.loopback = enabled;
+        let value = self.modem_control();
+        unsafe { self.write(MODEM_CONTROL, value) };
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.read(LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.write(DATA, byte);
+        }
+    }
+
+    /// A received byte, `None` if nothing is waiting.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe {
+            if self.read(LINE_STATUS) & LSR_DATA_READY == 0 {
+                return None;
+            }
+            Some(self.read(DATA))
+        }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
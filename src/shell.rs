@@ -0,0 +1,206 @@
+// Mod for a small command shell on a serial port.
+// Lets the kernel be driven without a screen or keyboard, from QEMU's -serial stdio or
+// a test feeding it lines. Bytes go through a LineDiscipline first, which does the
+// echoing and editing a terminal expects, and hands over whole lines.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use crate::serial::{self, ComPort};
+use crate::{boot_params, power, smp, time};
+
+// Longer lines are cut off, nothing typed by hand gets near this.
+const MAX_LINE: usize = 256;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+
+const PROMPT: &str = "> ";
+
+//  ---Line Discipline---
+
+/// Collects received bytes into lines.
+///
+/// Enter ends a line (CR, LF or CR LF), backspace and delete remove the last character,
+/// Ctrl-U clears the line and Ctrl-C throws it away. Other control characters are ignored.
+pub struct LineDiscipline {
+    line: String,
+    echo: bool,
+    // A LF right after a CR belongs to the same Enter.
+    after_cr: bool,
+}
+
+impl LineDiscipline {
+    /// With `echo` the characters typed are written back so the terminal shows them.
+    pub fn new(echo: bool) -> Self {
+        LineDiscipline {
+            line: String::new(),
+            echo,
+            after_cr: false,
+        }
+    }
+
+    /// Feeds one received byte, and returns the line if it finished one.
+    ///
+    /// The echo, if on, goes to `output`.
+    pub fn feed<W: Write>(&mut self, byte: u8, output: &mut W) -> Option<String> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                self.echo(output, "\r\n");
+                Some(core::mem::take(&mut self.line))
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    self.echo(output, "\x08 \x08");
+                }
+                None
+            }
+            CTRL_U => {
+                for _ in 0..self.line.len() {
+                    self.echo(output, "\x08 \x08");
+                }
+                self.line.clear();
+                None
+            }
+            CTRL_C => {
+                self.echo(output, "^C\r\n");
+                self.line.clear();
+                None
+            }
+            b' '..=b'~' if self.line.len() < MAX_LINE => {
+                self.line.push(byte as char);
+                if self.echo {
+                    let _ = output.write_char(byte as char);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// What has been typed of the current line so far.
+    pub fn pending(&self) -> &str {
+        &self.line
+    }
+
+    fn echo<W: Write>(&self, output: &mut W, text: &str) {
+        if self.echo {
+            let _ = output.write_str(text);
+        }
+    }
+}
+
+//  ---Commands---
+
+/// Runs one command line, writing what it prints to `output`.
+pub fn execute<W: Write>(line: &str, output: &mut W) -> fmt::Result {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    match command {
+        "help" => {
+            writeln!(output, "help      list the commands")?;
+            writeln!(output, "echo ...  print the arguments")?;
+            writeln!(output, "uptime    time since boot")?;
+            writeln!(output, "cpus      number of CPUs online")?;
+            writeln!(output, "params    the boot parameters")?;
+            writeln!(output, "shutdown  power off")?;
+            writeln!(output, "reboot    restart the machine")
+        }
+        "echo" => {
+            let mut first = true;
+            for word in words {
+                if !first {
+                    output.write_char(' ')?;
+                }
+                output.write_str(word)?;
+                first = false;
+            }
+            writeln!(output)
+        }
+        "uptime" => {
+            let millis = time::monotonic_nanos() / 1_000_000;
+            writeln!(output, "{}.{:03}s", millis / 1000, millis % 1000)
+        }
+        "cpus" => writeln!(output, "{}", smp::cpu_count()),
+        "params" => write!(output, "{}", boot_params::get()),
+        "shutdown" => {
+            writeln!(output, "Shutting down")?;
+            power::shutdown()
+        }
+        "reboot" => {
+            writeln!(output, "Rebooting")?;
+            power::reboot()
+        }
+        _ => writeln!(output, "unknown command: {}", command),
+    }
+}
+
+//  ---Serial Console---
+
+// Writes straight to a port, locking it for every write.
+struct PortWriter(ComPort);
+
+impl Write for PortWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::port(self.0).lock().write_str(s)
+    }
+}
+
+/// The shell on one port: reads what the port received, writes the echo and the output
+/// of the commands to `output`.
+pub struct Shell<W: Write> {
+    com: ComPort,
+    discipline: LineDiscipline,
+    output: W,
+}
+
+impl<W: Write> Shell<W> {
+    /// Starts a shell on `com` and writes the first prompt.
+    pub fn new(com: ComPort, mut output: W) -> Self {
+        let _ = output.write_str(PROMPT);
+        Shell {
+            com,
+            discipline: LineDiscipline::new(true),
+            output,
+        }
+    }
+
+    /// Handles every byte received so far, running the lines it finishes. Returns false
+    /// if there was nothing to read.
+    pub fn process_received(&mut self) -> bool {
+        let mut received = false;
+        while let Some(byte) = serial::read_byte(self.com) {
+            received = true;
+            if let Some(line) = self.discipline.feed(byte, &mut self.output) {
+                let _ = execute(&line, &mut self.output);
+                let _ = self.output.write_str(PROMPT);
+            }
+        }
+        received
+    }
+
+    /// Where the shell writes to, for tests that give it a String.
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+}
+
+/// Runs the shell on `com` forever. The port has to be set up with `serial::init`.
+pub fn run(com: ComPort) -> ! {
+    let mut shell = Shell::new(com, PortWriter(com));
+    loop {
+        if !shell.process_received() {
+            // The receive interrupt wakes us.
+            x86_64::instructions::hlt();
+        }
+    }
+}
@@ -0,0 +1,327 @@
+// Mod for bringing up the other CPUs.
+// The firmware only starts the bootstrap processor (BSP). The rest, the application
+// processors (APs), wait until they get an INIT IPI followed by startup IPIs. A startup
+// IPI points them at the real mode trampoline, which gets them into long mode and calls
+// ap_entry on a stack of their own.
+// Every CPU has a PerCpu, the GS base points at it. The BSP's is set up in
+// jonathan_os::init, the APs get theirs before they start.
+// APs are started one at a time since they all share the one trampoline.
+// AP stacks aren't on the heap, each one gets its own pages at AP_STACKS_START with an
+// unmapped guard page below, so an overflow faults instead of corrupting whatever is there.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::{Mutex, Once};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::acpi::{self, AcpiError};
+use crate::apic::{self, ApicError};
+use crate::memory::BootInfoFrameAllocator;
+use crate::{gdt, hlt_loop, interrupts, time};
+
+mod trampoline;
+
+use self::trampoline::Trampoline;
+
+/// Where `init` maps the AP stacks, see `PerCpu::stack`.
+pub const AP_STACKS_START: u64 = 0x_2222_2222_0000;
+
+const AP_STACK_SIZE: usize = 4096 * 16;
+// Per AP: a guard page, the stack, another guard page, the double fault stack.
+const AP_STACKS_SIZE: u64 = (4096 + AP_STACK_SIZE + 4096 + gdt::STACK_SIZE) as u64;
+
+// How long a CPU gets to check in after its startup IPI before we give up on it.
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SmpError {
+    Acpi(AcpiError),
+    Apic(ApicError),
+    /// `init_bsp` hasn't run, so the BSP has no per-CPU data.
+    NotInitialized,
+    /// There is no free page below 1 MiB for the trampoline.
+    NoLowMemory,
+    /// The trampoline loads CR3 in 32 bit code.
+    PageTableAbove4GiB,
+    /// The trampoline page couldn't be identity mapped.
+    TrampolineMapping,
+    /// There was no memory left for an AP's stacks.
+    StackMapping,
+    /// The CPU with this APIC ID never checked in.
+    StartupTimeout(u8),
+}
+
+impl From<AcpiError> for SmpError {
+    fn from(err: AcpiError) -> Self {
+        SmpError::Acpi(err)
+    }
+}
+
+impl From<ApicError> for SmpError {
+    fn from(err: ApicError) -> Self {
+        SmpError::Apic(err)
+    }
+}
+
+//  ---Per-CPU Data---
+
+/// Data belonging to one CPU, found through its GS base.
+#[derive(Debug)]
+pub struct PerCpu {
+    index: usize,
+    apic_id: u8,
+    // Empty for the BSP, it runs on the stacks the bootloader and gdt::init set up.
+    stack: Range<VirtAddr>,
+    double_fault_stack: Range<VirtAddr>,
+    online: AtomicBool,
+    // Set by the CPU asking this one to flush its TLB, see memory::tlb.
+    pub(crate) tlb_shootdown_pending: AtomicBool,
+    pub(crate) tlb_shootdowns: AtomicU64,
+}
+
+impl PerCpu {
+    fn new(index: usize, apic_id: u8) -> Self {
+        let empty = VirtAddr::zero()..VirtAddr::zero();
+        PerCpu {
+            index,
+            apic_id,
+            stack: empty.clone(),
+            double_fault_stack: empty,
+            online: AtomicBool::new(false),
+            tlb_shootdown_pending: AtomicBool::new(false),
+            tlb_shootdowns: AtomicU64::new(0),
+        }
+    }
+
+    /// 0 for the BSP, then counting up in the order the CPUs were started.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    /// The CPU's stack, empty for the BSP. The page below it is never mapped.
+    pub fn stack(&self) -> Range<VirtAddr> {
+        self.stack.clone()
+    }
+
+    /// The stack double faults are handled on, empty for the BSP. The page below it is
+    /// never mapped either.
+    pub fn double_fault_stack(&self) -> Range<VirtAddr> {
+        self.double_fault_stack.clone()
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// How many TLB shootdowns from other CPUs this one has handled.
+    pub fn tlb_shootdowns(&self) -> u64 {
+        self.tlb_shootdowns.load(Ordering::Relaxed)
+    }
+}
+
+static BSP: Once<PerCpu> = Once::new();
+
+// Every CPU that checked in, by index.
+static CPUS: Mutex<Vec<&'static PerCpu>> = Mutex::new(Vec::new());
+
+// Works without the local APIC being mapped.
+fn cpuid_apic_id() -> u8 {
+    // Newer compilers consider __cpuid safe.
+    #[allow(unused_unsafe)]
+    let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+    (leaf.ebx >> 24) as u8
+}
+
+fn set_current(cpu: &'static PerCpu) {
+    GsBase::write(VirtAddr::from_ptr(cpu));
+}
+
+/// Points the BSP's GS base at its per-CPU data. Called from jonathan_os::init.
+pub fn init_bsp() {
+    let cpu = BSP.call_once(|| PerCpu::new(0, cpuid_apic_id()));
+    cpu.online.store(true, Ordering::Release);
+    set_current(cpu);
+}
+
+/// The per-CPU data of the CPU we're running on, `None` before `init_bsp`.
+pub fn current() -> Option<&'static PerCpu> {
+    let base = GsBase::read();
+    if base.is_null() {
+        return None;
+    }
+    Some(unsafe { &*base.as_ptr::<PerCpu>() })
+}
+
+/// Index of the CPU we're running on, see `PerCpu::index`.
+pub fn cpu_index() -> usize {
+    current().map_or(0, PerCpu::index)
+}
+
+/// Number of CPUs running the kernel, the BSP included.
+pub fn cpu_count() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| CPUS.lock().len()).max(1)
+}
+
+/// The per-CPU data of the CPU with `index`.
+pub fn cpu(index: usize) -> Option<&'static PerCpu> {
+    x86_64::instructions::interrupts::without_interrupts(|| CPUS.lock().get(index).copied())
+}
+
+//  ---AP Startup---
+
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let cpu = unsafe { &*(cpu as *const PerCpu) };
+
+    // First, the lock recursion checks in crate::sync tell CPUs apart by their GS base.
+    set_current(cpu);
+    gdt::init_ap(cpu.double_fault_stack.end);
+    interrupts::init_idt();
+    // Can't fail, the BSP set up the local APIC before starting us.
+    let _ = apic::init_ap();
+
+    cpu.online.store(true, Ordering::Release);
+    x86_64::instructions::interrupts::enable();
+    hlt_loop()
+}
+
+// Busy waits, the delays are shorter than a timer tick.
+fn spin_wait(duration: Duration) {
+    let end = time::monotonic_nanos() + duration.as_nanos() as u64;
+    while time::monotonic_nanos() < end {
+        core::hint::spin_loop();
+    }
+}
+
+fn wait_online(cpu: &PerCpu, timeout: Duration) -> bool {
+    let end = time::monotonic_nanos() + timeout.as_nanos() as u64;
+    while !cpu.is_online() {
+        if time::monotonic_nanos() >= end {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// Maps `size` bytes of stack at `start`. The page below is left alone, `start` has to be
+// a page above the last stack.
+fn map_stack(
+    start: VirtAddr,
+    size: usize,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<Range<VirtAddr>, SmpError> {
+    let end = start + size;
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(SmpError::StackMapping)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+            .map_err(|_| SmpError::StackMapping)?
+            .flush();
+    }
+    Ok(start..end)
+}
+
+fn start_ap(
+    trampoline: &Trampoline,
+    index: usize,
+    apic_id: u8,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<&'static PerCpu, SmpError> {
+    let base = VirtAddr::new(AP_STACKS_START + index as u64 * AP_STACKS_SIZE);
+    let stack = map_stack(base + 4096u64, AP_STACK_SIZE, mapper, frame_allocator)?;
+    let double_fault_stack = map_stack(
+        stack.end + 4096u64,
+        gdt::STACK_SIZE,
+        mapper,
+        frame_allocator,
+    )?;
+
+    let mut cpu = PerCpu::new(index, apic_id);
+    cpu.stack = stack;
+    cpu.double_fault_stack = double_fault_stack;
+    let cpu: &'static PerCpu = Box::leak(Box::new(cpu));
+    trampoline.prepare(cpu.stack.end, ap_entry, cpu as *const PerCpu as u64);
+
+    // The INIT-SIPI-SIPI sequence from the MP spec. The second SIPI is only for CPUs
+    // that missed the first.
+    apic::send_init(apic_id)?;
+    spin_wait(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic::send_startup(apic_id, trampoline.vector())?;
+        if wait_online(cpu, Duration::from_micros(200)) {
+            return Ok(cpu);
+        }
+    }
+
+    if wait_online(cpu, STARTUP_TIMEOUT) {
+        Ok(cpu)
+    } else {
+        Err(SmpError::StartupTimeout(apic_id))
+    }
+}
+
+/// Starts every usable CPU in the MADT and returns how many CPUs are running.
+///
+/// Needs apic::init, the heap, and interrupts on for the timeouts. A CPU that doesn't
+/// check in stops the bring up, the ones started before it keep running. The trampoline
+/// is unmapped again once every CPU checked in, it stays if one didn't since that one may
+/// still turn up.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<usize, SmpError> {
+    let bsp = current().ok_or(SmpError::NotInitialized)?;
+    let platform = acpi::platform()?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut cpus = CPUS.lock();
+        if cpus.is_empty() {
+            cpus.push(bsp);
+        }
+    });
+
+    let frame = frame_allocator
+        .low_memory_frame()
+        .ok_or(SmpError::NoLowMemory)?;
+    let trampoline = Trampoline::install(frame, mapper, frame_allocator)?;
+
+    for processor in platform.usable_processors() {
+        // Only the xAPIC is supported, it can't address IDs above 255.
+        let apic_id = match processor.apic_id {
+            id if id > u32::from(u8::MAX) => continue,
+            id => id as u8,
+        };
+        let started = x86_64::instructions::interrupts::without_interrupts(|| {
+            CPUS.lock().iter().any(|cpu| cpu.apic_id == apic_id)
+        });
+        if started {
+            continue;
+        }
+
+        let cpu = start_ap(&trampoline, cpu_count(), apic_id, mapper, frame_allocator)?;
+        x86_64::instructions::interrupts::without_interrupts(|| CPUS.lock().push(cpu));
+    }
+
+    trampoline.uninstall(mapper);
+    Ok(cpu_count())
+}
@@ -0,0 +1,197 @@
+// Mod for the real mode trampoline application processors start in.
+// A startup IPI starts a CPU in real mode at the start of a page below 1 MiB, so the
+// code below gets copied to such a page. From there it goes straight to long mode:
+// it loads a small GDT, turns on PAE, long mode and NX, reuses the BSP's page tables
+// and turns protection and paging on together. The BSP fills in the data block at the
+// end (stack, entry point, argument) before every startup IPI.
+// The page has to be identity mapped, the CPU is still running from it when paging turns on.
+// The mapping goes away again in `uninstall` once all CPUs are up.
+
+use core::arch::global_asm;
+use core::mem::size_of;
+use core::ptr;
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::Translate;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::memory;
+use crate::smp::SmpError;
+
+// Flat 64 bit code and data descriptors.
+const CODE_SEGMENT: u64 = 0x00AF_9A00_0000_FFFF;
+const DATA_SEGMENT: u64 = 0x00CF_9200_0000_FFFF;
+const CODE_SELECTOR: u16 = 8;
+
+// The offsets used below have to match TrampolineData.
+global_asm!(
+    r#"
+.pushsection .text.ap_trampoline, "ax"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    lgdtl (ap_trampoline_data - ap_trampoline_start + 24)
+
+    // PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov (ap_trampoline_data - ap_trampoline_start + 40), %eax
+    mov %eax, %cr3
+
+    // Long mode and NX in EFER. The kernel's page tables use the NX bit.
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    // Paging, write protect and protection at once, then a far jump into 64 bit code.
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16) | 1), %eax
+    mov %eax, %cr0
+    ljmpl *(ap_trampoline_data - ap_trampoline_start + 32)
+
+.code64
+.global ap_trampoline_long_mode
+ap_trampoline_long_mode:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+    mov (ap_trampoline_data + 48)(%rip), %rsp
+    mov (ap_trampoline_data + 64)(%rip), %rdi
+    call *(ap_trampoline_data + 56)(%rip)
+    ud2
+
+.align 16
+.global ap_trampoline_data
+ap_trampoline_data:
+    .space 72
+.global ap_trampoline_end
+ap_trampoline_end:
+.popsection
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+// The data block at the end of the trampoline.
+#[repr(C, packed)]
+struct TrampolineData {
+    gdt: [u64; 3],
+    gdt_limit: u16,
+    // Physical, the trampoline runs without paging.
+    gdt_base: u32,
+    _padding: u16,
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    _padding_2: u16,
+    // Only 32 bits get loaded, the page tables have to be below 4 GiB.
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+}
+
+// Offset of a trampoline symbol from the start of the trampoline.
+fn offset_of(symbol: &u8) -> usize {
+    symbol as *const u8 as usize - unsafe { &ap_trampoline_start } as *const u8 as usize
+}
+
+pub(super) struct Trampoline {
+    frame: PhysFrame,
+    // Whether `install` added the identity mapping, it's only removed then.
+    mapped: bool,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to `frame`, which has to be below 1 MiB, and identity maps it.
+    pub(super) fn install(
+        frame: PhysFrame,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Trampoline, SmpError> {
+        let physical = frame.start_address();
+        if physical.as_u64() >= 0x10_0000 {
+            return Err(SmpError::NoLowMemory);
+        }
+        if Cr3::read().0.start_address().as_u64() > u64::from(u32::MAX) {
+            return Err(SmpError::PageTableAbove4GiB);
+        }
+
+        let identity = VirtAddr::new(physical.as_u64());
+        let mapped = mapper.translate_addr(identity) != Some(physical);
+        if mapped {
+            let page: Page<Size4KiB> = Page::containing_address(identity);
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+                .map_err(|_| SmpError::TrampolineMapping)?
+                .flush();
+        }
+
+        let length = offset_of(unsafe { &ap_trampoline_end });
+        let source = unsafe { &ap_trampoline_start } as *const u8;
+        let destination = memory::phys_to_virt(physical).as_mut_ptr::<u8>();
+        unsafe { ptr::copy_nonoverlapping(source, destination, length) };
+
+        Ok(Trampoline { frame, mapped })
+    }
+
+    /// Removes the identity mapping `install` added. No CPU may still be starting.
+    pub(super) fn uninstall(self, mapper: &mut OffsetPageTable) {
+        if self.mapped {
+            let page = Page::containing_address(VirtAddr::new(self.frame.start_address().as_u64()));
+            // Can't fail, the page was mapped in `install`.
+            let _ = memory::unmap_page(mapper, page);
+        }
+    }
+
+    /// The startup IPI vector, which is the page number.
+    pub(super) fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Sets up the trampoline for the next CPU. It will call `entry(argument)` on `stack_top`.
+    pub(super) fn prepare(
+        &self,
+        stack_top: VirtAddr,
+        entry: extern "C" fn(u64) -> !,
+        argument: u64,
+    ) {
+        let base = self.frame.start_address().as_u64() as u32;
+        let data_offset = offset_of(unsafe { &ap_trampoline_data });
+
+        let data = TrampolineData {
+            gdt: [0, CODE_SEGMENT, DATA_SEGMENT],
+            gdt_limit: (size_of::<[u64; 3]>() - 1) as u16,
+            gdt_base: base + data_offset as u32,
+            _padding: 0,
+            long_mode_offset: base + offset_of(unsafe { &ap_trampoline_long_mode }) as u32,
+            long_mode_selector: CODE_SELECTOR,
+            _padding_2: 0,
+            cr3: Cr3::read().0.start_address().as_u64(),
+            stack_top: stack_top.as_u64(),
+            entry: entry as usize as u64,
+            argument,
+        };
+
+        // Volatile so it can't be moved after the startup IPI.
+        let address = memory::phys_to_virt(self.frame.start_address()) + data_offset;
+        unsafe { ptr::write_volatile(address.as_mut_ptr::<TrampolineData>(), data) };
+    }
+}
@@ -0,0 +1,139 @@
+// Mod for the kernel's locks and other synchronisation primitives.
+// Spinning locks (IrqSafeSpinlock, TicketLock, RwLock) work anywhere, interrupt
+// handlers included. The sleeping ones (Mutex, Semaphore, Condvar) don't spin, they
+// return futures that stay pending until the lock is free, so an async task waiting
+// on them gives the CPU back to its executor.
+// There is no executor in the kernel yet, so for now nothing polls those futures but
+// tests/sync.rs, which does it by hand.
+// In debug builds the spinning locks remember which CPU holds them, and panic when
+// that CPU tries to take them again instead of spinning forever.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Waker;
+
+use crate::smp;
+
+pub mod condvar;
+pub mod mutex;
+pub mod once;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+pub mod ticket;
+
+pub use self::condvar::Condvar;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::once::{Lazy, Once};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::Semaphore;
+pub use self::spinlock::{IrqSafeSpinlock, IrqSafeSpinlockGuard};
+pub use self::ticket::{TicketLock, TicketLockGuard};
+
+//  ---Recursion Detection---
+
+const NO_OWNER: usize = usize::MAX;
+
+// The CPU holding a lock. Only tracked in debug builds, finding the CPU reads an MSR.
+struct Owner {
+    cpu: AtomicUsize,
+}
+
+impl Owner {
+    const fn new() -> Self {
+        Owner {
+            cpu: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    // Call before spinning. A CPU waiting on a lock it holds itself would spin forever.
+    fn check_recursion(&self) {
+        if cfg!(debug_assertions) && self.cpu.load(Ordering::Relaxed) == smp::cpu_index() {
+            panic!("lock recursion on CPU {}", smp::cpu_index());
+        }
+    }
+
+    fn acquired(&self) {
+        if cfg!(debug_assertions) {
+            self.cpu.store(smp::cpu_index(), Ordering::Relaxed);
+        }
+    }
+
+    fn released(&self) {
+        if cfg!(debug_assertions) {
+            self.cpu.store(NO_OWNER, Ordering::Relaxed);
+        }
+    }
+}
+
+//  ---Wait Queue---
+
+// Wakers of the tasks waiting on a sleeping primitive. Every waiting future has one
+// entry, found by a key the future keeps, so it can take itself out again once it's done
+// waiting. Otherwise a waker left behind would get the next wakeup instead of a waiter.
+struct WaitQueue {
+    wakers: IrqSafeSpinlock<VecDeque<(usize, Waker)>>,
+    next_key: AtomicUsize,
+}
+
+impl WaitQueue {
+    const fn new() -> Self {
+        WaitQueue {
+            wakers: IrqSafeSpinlock::new(VecDeque::new()),
+            next_key: AtomicUsize::new(0),
+        }
+    }
+
+    // Futures get polled more than once. If `key`'s entry is still queued only its waker
+    // is updated, otherwise the future gets a new entry at the back.
+    fn register(&self, key: &mut Option<usize>, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if let Some(key) = *key {
+            if let Some((_, queued)) = wakers.iter_mut().find(|(queued, _)| *queued == key) {
+                if !queued.will_wake(waker) {
+                    *queued = waker.clone();
+                }
+                return;
+            }
+        }
+
+        let new_key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        wakers.push_back((new_key, waker.clone()));
+        *key = Some(new_key);
+    }
+
+    // Takes `key`'s entry out, for a future that got what it was waiting for. Returns
+    // false if it was already woken.
+    fn remove(&self, key: usize) -> bool {
+        let mut wakers = self.wakers.lock();
+        match wakers.iter().position(|(queued, _)| *queued == key) {
+            Some(index) => {
+                wakers.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // For a future dropped while waiting. If it was woken it never used the wakeup, so
+    // that goes to the next waiter.
+    fn cancel(&self, key: usize) {
+        if !self.remove(key) {
+            self.wake_one();
+        }
+    }
+
+    fn wake_one(&self) {
+        let waker = self.wakers.lock().pop_front();
+        if let Some((_, waker)) = waker {
+            waker.wake();
+        }
+    }
+
+    fn wake_all(&self) {
+        let wakers = core::mem::take(&mut *self.wakers.lock());
+        for (_, waker) in wakers {
+            waker.wake();
+        }
+    }
+}
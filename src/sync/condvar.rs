@@ -0,0 +1,110 @@
+// Mod for condition variables to go with the sleeping Mutex.
+// wait() unlocks the mutex and sleeps until notified, then locks the mutex again.
+// Notifying bumps a generation counter, a waiter that sees it changed stops waiting.
+// A waiter that gets polled for another reason after a notify_one meant for someone
+// else also sees the change, so wakeups can be spurious. Always wait in a loop that
+// checks the condition, like with any condition variable.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use super::mutex::{Mutex, MutexGuard, MutexLock};
+use super::WaitQueue;
+
+pub struct Condvar {
+    generation: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+/// Future returned by `Condvar::wait`.
+pub struct Wait<'a, 'b, T: ?Sized> {
+    condvar: &'a Condvar,
+    mutex: &'b Mutex<T>,
+    generation: usize,
+    // Our entry in the wait queue, once we were queued.
+    key: Option<usize>,
+    relock: Option<MutexLock<'b, T>>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks `guard`'s mutex, waits for a notification and locks it again.
+    pub fn wait<'a, 'b, T: ?Sized>(&'a self, guard: MutexGuard<'b, T>) -> Wait<'a, 'b, T> {
+        // Read before unlocking, a notify right after the unlock must not be missed.
+        let generation = self.generation.load(Ordering::Acquire);
+        let mutex = guard.mutex();
+        drop(guard);
+
+        Wait {
+            condvar: self,
+            mutex,
+            generation,
+            key: None,
+            relock: None,
+        }
+    }
+
+    /// Wakes one waiting task.
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wakes every waiting task.
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+impl<'a, 'b, T: ?Sized> Wait<'a, 'b, T> {
+    fn notified(&self) -> bool {
+        self.condvar.generation.load(Ordering::Acquire) != self.generation
+    }
+}
+
+impl<'a, 'b, T: ?Sized> Future for Wait<'a, 'b, T> {
+    type Output = MutexGuard<'b, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.relock.is_none() {
+            let this = &mut *self;
+            if !this.notified() {
+                this.condvar.waiters.register(&mut this.key, cx.waker());
+                if !this.notified() {
+                    return Poll::Pending;
+                }
+            }
+            // A notify_one meant for someone else may have left us queued.
+            if let Some(key) = this.key.take() {
+                this.condvar.waiters.remove(key);
+            }
+            this.relock = Some(this.mutex.lock());
+        }
+
+        // MutexLock only holds a reference, it doesn't care about being moved.
+        Pin::new(self.relock.as_mut().unwrap()).poll(cx)
+    }
+}
+
+impl<T: ?Sized> Drop for Wait<'_, '_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.condvar.waiters.cancel(key);
+        }
+    }
+}
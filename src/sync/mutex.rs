@@ -0,0 +1,151 @@
+// Mod for a mutex that puts waiting tasks to sleep.
+// lock() returns a future. While the mutex is held it stays pending and the task's
+// waker is queued, unlocking wakes the first task in the queue. The woken task still
+// has to win the lock, another one may have taken it in the meantime, in which case
+// it queues up again. A task that stops waiting, because it got the mutex or the future
+// was dropped, takes its waker out of the queue so the next unlock wakes someone waiting.
+// Can't be used from interrupt handlers, they can't wait. Use try_lock there.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use super::WaitQueue;
+
+/// A mutex for async tasks, waiting for it doesn't spin.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// Unlocks the mutex and wakes the next waiting task when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct MutexLock<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    // Our entry in the wait queue, once we were queued.
+    key: Option<usize>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Returns a future that completes with the guard once the mutex is free.
+    pub fn lock(&self) -> MutexLock<'_, T> {
+        MutexLock {
+            mutex: self,
+            key: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    // For Condvar, which unlocks the mutex itself while waiting.
+    pub(super) fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<'a, T: ?Sized> Future for MutexLock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(guard) = self.mutex.try_lock() {
+            self.dequeue();
+            return Poll::Ready(guard);
+        }
+
+        let this = &mut *self;
+        this.mutex.waiters.register(&mut this.key, cx.waker());
+        // The mutex may have been unlocked before we were queued, and nobody would wake us.
+        match this.mutex.try_lock() {
+            Some(guard) => {
+                this.dequeue();
+                Poll::Ready(guard)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T: ?Sized> MutexLock<'_, T> {
+    // Done waiting, the next unlock is for someone else.
+    fn dequeue(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.mutex.waiters.remove(key);
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexLock<'_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.mutex.waiters.cancel(key);
+        }
+    }
+}
+
+// Sharing the guard only shares `&T`.
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,128 @@
+// Mod for one-time initialisation.
+// Once runs its closure the first time call_once is called, every other caller spins
+// until that is done and gets the same value. Lazy wraps that up for statics, it's
+// what lazy_static! does without the macro.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is set once and then only read.
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Runs `init` if nobody has yet, and returns the value.
+    ///
+    /// Calling it again from inside `init` spins forever.
+    pub fn call_once<F: FnOnce() -> T>(&self, init: F) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.data.get()).as_mut_ptr().write(init()) };
+                self.state.store(COMPLETE, Ordering::Release);
+            }
+            Err(RUNNING) => {
+                // Another CPU may be initialising it. On a single CPU it can only be us.
+                debug_assert!(crate::smp::cpu_count() > 1, "Once initialised recursively");
+                while self.state.load(Ordering::Acquire) == RUNNING {
+                    core::hint::spin_loop();
+                }
+            }
+            Err(_) => {}
+        }
+
+        unsafe { &*(*self.data.get()).as_ptr() }
+    }
+
+    /// The value, `None` if `call_once` hasn't finished yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { &*(*self.data.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Once::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { core::ptr::drop_in_place((*self.data.get()).as_mut_ptr()) };
+        }
+    }
+}
+
+/// A value created by `init` the first time it is used.
+pub struct Lazy<T> {
+    once: Once<T>,
+    init: fn() -> T,
+}
+
+impl<T> Lazy<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        Lazy {
+            once: Once::new(),
+            init,
+        }
+    }
+
+    /// Creates the value now, if it doesn't exist yet.
+    pub fn force(this: &Lazy<T>) -> &T {
+        this.once.call_once(this.init)
+    }
+}
+
+impl<T> Deref for Lazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+//  ---Tests---
+
+#[test_case]
+fn test_call_once_runs_once() {
+    let once = Once::new();
+    assert!(once.get().is_none());
+    assert_eq!(*once.call_once(|| 1), 1);
+    assert_eq!(*once.call_once(|| 2), 1);
+    assert_eq!(once.get(), Some(&1));
+}
+
+#[test_case]
+fn test_lazy() {
+    static VALUE: Lazy<u64> = Lazy::new(|| 6 * 7);
+    assert_eq!(*VALUE, 42);
+}
@@ -0,0 +1,149 @@
+// Mod for a spinning reader-writer lock.
+// Any number of readers or one writer. A waiting writer stops new readers from coming
+// in, otherwise a steady stream of readers could keep it out forever.
+// The state is one word: bit 0 is set while a writer holds the lock, bit 1 while one
+// is waiting, and the rest counts readers.
+// Only the writer is tracked for recursion detection, readers can't be told apart.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::Owner;
+
+const WRITER: usize = 1;
+const WRITER_WAITING: usize = 1 << 1;
+const READER: usize = 1 << 2;
+
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    owner: Owner,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            owner: Owner::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Spins while a writer holds or waits for the lock.
+    ///
+    /// In debug builds, panics if this CPU holds the write lock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.owner.check_recursion();
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// Spins until all readers and any other writer are gone.
+    ///
+    /// In debug builds, panics if this CPU holds the write lock.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.owner.check_recursion();
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        // Only the waiting bit may be set, whichever writer gets in clears it. Other
+        // waiting writers set it again on their next try.
+        if state & !WRITER_WAITING != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.owner.acquired();
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// How many readers hold the lock right now.
+    pub fn reader_count(&self) -> usize {
+        self.state.load(Ordering::Relaxed) / READER
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.released();
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
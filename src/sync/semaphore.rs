@@ -0,0 +1,108 @@
+// Mod for a counting semaphore that puts waiting tasks to sleep.
+// Works like the Mutex, but hands out up to `permits` at a time and the permits
+// aren't tied to a guard: acquire takes one, release gives one back. That way a
+// permit can be released by another task or an interrupt handler, which makes it
+// usable for signalling too.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use super::WaitQueue;
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+/// Future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    // Our entry in the wait queue, once we were queued.
+    key: Option<usize>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Returns a future that completes once a permit was taken.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            key: None,
+        }
+    }
+
+    /// Takes a permit if one is left.
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        loop {
+            if permits == 0 {
+                return false;
+            }
+            match self.permits.compare_exchange_weak(
+                permits,
+                permits - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => permits = current,
+            }
+        }
+    }
+
+    /// Gives a permit back and wakes one waiting task. Safe to call from interrupt handlers.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+impl Future for Acquire<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.semaphore.try_acquire() {
+            self.dequeue();
+            return Poll::Ready(());
+        }
+
+        let this = &mut *self;
+        this.semaphore.waiters.register(&mut this.key, cx.waker());
+        // Same as Mutex, a release before we were queued wouldn't have woken us.
+        if this.semaphore.try_acquire() {
+            this.dequeue();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Acquire<'_> {
+    // Same as Mutex, the next release is for someone else.
+    fn dequeue(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.semaphore.waiters.remove(key);
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.semaphore.waiters.cancel(key);
+        }
+    }
+}
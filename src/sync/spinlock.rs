@@ -0,0 +1,219 @@
+// Mod for a spinlock that keeps interrupts off while it is held.
+// With a plain spin::Mutex an interrupt handler taking a lock the interrupted code
+// already holds spins forever, which is why every WRITER.lock() used to be wrapped
+// in without_interrupts. This lock does that itself.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::Owner;
+
+/// A spinlock that disables interrupts until its guard is dropped.
+pub struct IrqSafeSpinlock<T: ?Sized> {
+    locked: AtomicBool,
+    owner: Owner,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for IrqSafeSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSafeSpinlock<T> {}
+
+/// Unlocks the lock and restores interrupts when dropped.
+pub struct IrqSafeSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSafeSpinlock<T>,
+    // Whether interrupts were on before locking.
+    interrupts_enabled: bool,
+    // Interrupts are restored on the CPU dropping the guard, so it has to stay on the
+    // CPU that locked.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> IrqSafeSpinlock<T> {
+    pub const fn new(data: T) -> Self {
+        IrqSafeSpinlock {
+            locked: AtomicBool::new(false),
+            owner: Owner::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSafeSpinlock<T> {
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Disables interrupts and spins until the lock is free.
+    ///
+    /// In debug builds, panics if this CPU already holds the lock.
+    pub fn lock(&self) -> IrqSafeSpinlockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        self.owner.check_recursion();
+
+        while !self.try_acquire() {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        self.owner.acquired();
+
+        IrqSafeSpinlockGuard {
+            lock: self,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Locks without spinning, `None` if the lock is held.
+    pub fn try_lock(&self) -> Option<IrqSafeSpinlockGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        if self.try_acquire() {
+            self.owner.acquired();
+            Some(IrqSafeSpinlockGuard {
+                lock: self,
+                interrupts_enabled,
+                _not_send: PhantomData,
+            })
+        } else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    /// Spins `spins` times at most, then takes the lock even if it is still held.
+    ///
+    /// For printing a panic when the holder may never unlock, like the code that
+    /// panicked or a CPU that hung.
+    ///
+    /// # Safety
+    ///
+    /// The holder still thinks it owns the data, and may be using it at the same time.
+    /// The caller must make sure that can't happen, or that a torn value is harmless.
+    pub unsafe fn force_lock(&self, spins: usize) -> IrqSafeSpinlockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        for _ in 0..spins {
+            if self.try_acquire() {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.locked.swap(true, Ordering::Acquire);
+        self.owner.acquired();
+
+        IrqSafeSpinlockGuard {
+            lock: self,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// No locking needed, `&mut self` means nobody else has the lock.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for IrqSafeSpinlock<T> {
+    fn default() -> Self {
+        IrqSafeSpinlock::new(T::default())
+    }
+}
+
+// Sharing the guard only shares `&T`.
+unsafe impl<T: ?Sized + Sync> Sync for IrqSafeSpinlockGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for IrqSafeSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.released();
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+//  ---Tests---
+
+#[test_case]
+fn test_lock_disables_interrupts() {
+    let lock = IrqSafeSpinlock::new(0);
+    interrupts::enable();
+    {
+        let mut guard = lock.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn test_nested_locks_restore_interrupts() {
+    let outer = IrqSafeSpinlock::new(());
+    let inner = IrqSafeSpinlock::new(());
+    interrupts::enable();
+    {
+        let _outer = outer.lock();
+        {
+            let _inner = inner.lock();
+        }
+        // The inner guard found interrupts off, so it leaves them off.
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_force_lock_takes_held_lock() {
+    let lock = IrqSafeSpinlock::new(0);
+    let _held = lock.lock();
+    *unsafe { lock.force_lock(100) } += 1;
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn test_try_lock() {
+    let lock = IrqSafeSpinlock::new(());
+    let guard = lock.lock();
+    assert!(lock.is_locked());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(lock.try_lock().is_some());
+    assert!(!lock.is_locked());
+}
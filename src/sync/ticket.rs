@@ -0,0 +1,105 @@
+// Mod for a fair spinlock.
+// Every CPU wanting the lock draws a ticket and waits until its number is served, so
+// the lock is handed out in the order it was asked for. A plain spinlock goes to
+// whichever CPU wins the race, and under contention one CPU can lose every time.
+// Leaves interrupts alone, use IrqSafeSpinlock for data interrupt handlers touch.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::Owner;
+
+/// A spinlock handed out first come, first served.
+pub struct TicketLock<T: ?Sized> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    owner: Owner,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(data: T) -> Self {
+        TicketLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            owner: Owner::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    /// Spins until every CPU that asked earlier had its turn.
+    ///
+    /// In debug builds, panics if this CPU already holds the lock.
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        self.owner.check_recursion();
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        self.owner.acquired();
+
+        TicketLockGuard { lock: self }
+    }
+
+    /// Locks only if nobody holds or waits for the lock.
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket
+            .compare_exchange(ticket, ticket + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.owner.acquired();
+
+        Some(TicketLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// CPUs waiting for the lock, not counting the one holding it.
+    pub fn waiting(&self) -> usize {
+        let next = self.next_ticket.load(Ordering::Relaxed);
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        next.wrapping_sub(serving).saturating_sub(1)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.released();
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
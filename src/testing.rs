@@ -0,0 +1,276 @@
+// Mod for the test framework.
+// Every test binary is a kernel that QEMU boots. The harness collects the #[test_case]
+// items into a list and passes it to test_runner, which runs them one after the other
+// and exits QEMU through the isa-debug-exit device, see boot/src/main.rs.
+// Plain #[test_case] functions only have a name. #[kernel_test] functions also carry
+// the should_panic and ignore attributes.
+// A panicking test doesn't take the run down, catch.rs gets us back to the runner,
+// which goes on with the next test and prints a summary at the end. A failed test can
+// leave a lock held though, and the tests after it hang on it.
+// A test that hangs ends the run, see watchdog.rs.
+// Which tests run, for how long and how the results are written is set in options.rs.
+
+use core::fmt;
+use core::panic::PanicInfo;
+use core::time::Duration;
+
+use crate::time::Instant;
+use crate::{boot_params, emergency, exit_qemu, hlt_loop, serial, serial_println, QemuExitCode};
+
+pub mod catch;
+pub mod options;
+mod report;
+pub mod watchdog;
+
+use self::catch::Panic;
+use self::options::Options;
+
+/// Whether a test has to panic to pass, like the `should_panic` attribute of std tests.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShouldPanic {
+    No,
+    Yes,
+    /// The panic message has to contain the string.
+    WithMessage(&'static str),
+}
+
+pub trait Testable {
+    fn run(&self);
+
+    fn name(&self) -> &'static str;
+
+    fn should_panic(&self) -> ShouldPanic {
+        ShouldPanic::No
+    }
+
+    fn ignore(&self) -> bool {
+        false
+    }
+}
+
+// #[test_case] functions
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        self();
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// A test declared with `#[kernel_test]`. The macro fills this in.
+pub struct Test {
+    pub name: &'static str,
+    pub function: fn(),
+    pub should_panic: ShouldPanic,
+    pub ignore: bool,
+}
+
+impl Testable for Test {
+    fn run(&self) {
+        (self.function)();
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn should_panic(&self) -> ShouldPanic {
+        self.should_panic
+    }
+
+    fn ignore(&self) -> bool {
+        self.ignore
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+// Tests past this still run, they are only left out of the summary table.
+const MAX_RESULTS: usize = 256;
+
+/// What a run of tests came to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    /// Tests the filters left out.
+    pub filtered_out: usize,
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    // The test boot parameter goes first.
+    let options = match boot_params::get().test_args() {
+        Some(args) => Options::parse(args),
+        None => Options::from_fw_cfg(),
+    };
+    let options = match options {
+        Ok(options) => options,
+        Err(err) => {
+            serial_println!("Bad test options: {:?}", err);
+            exit_qemu(QemuExitCode::Failed);
+            hlt_loop();
+        }
+    };
+
+    if run_tests(tests, &options).failed == 0 {
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+/// Runs the `tests` that match the filters in `options`, reporting how each went and
+/// a summary at the end.
+pub fn run_tests(tests: &[&dyn Testable], options: &Options) -> Summary {
+    run_tests_with_output(tests, options, &mut report::Serial)
+}
+
+/// Like `run_tests`, but writes the report to `output` instead of the serial port. For
+/// running tests from a test without their results ending up in its own.
+pub fn run_tests_with_output(
+    tests: &[&dyn Testable],
+    options: &Options,
+    output: &mut dyn fmt::Write,
+) -> Summary {
+    let format = options.format();
+    let selected = || {
+        tests
+            .iter()
+            .enumerate()
+            .filter(|(_, test)| options.matches(test.name()))
+    };
+    let mut summary = Summary {
+        passed: 0,
+        failed: 0,
+        ignored: 0,
+        filtered_out: tests.len() - selected().count(),
+    };
+    let mut results = [(0, Outcome::Ignored, Duration::ZERO); MAX_RESULTS];
+
+    let _ = report::suite_started(output, format, tests.len() - summary.filtered_out);
+    let mut panic = Panic::default();
+    let started = Instant::now();
+    for (number, (index, &test)) in selected().enumerate() {
+        let _ = report::test_started(output, format, test.name());
+
+        let test_started = Instant::now();
+        let (outcome, failure) = if test.ignore() {
+            (Outcome::Ignored, None)
+        } else {
+            match run_test(test, number + 1, options, &mut panic) {
+                Ok(()) => (Outcome::Passed, None),
+                Err(failure) => (Outcome::Failed, Some(failure)),
+            }
+        };
+        let duration = test_started.elapsed();
+        let _ = report::test_finished(
+            output,
+            format,
+            number + 1,
+            test.name(),
+            outcome,
+            duration,
+            failure.as_ref().map(|failure| failure as &dyn fmt::Display),
+        );
+
+        match outcome {
+            Outcome::Passed => summary.passed += 1,
+            Outcome::Failed => summary.failed += 1,
+            Outcome::Ignored => summary.ignored += 1,
+        }
+        if let Some(result) = results.get_mut(number) {
+            *result = (index, outcome, duration);
+        }
+    }
+
+    let ran = tests.len() - summary.filtered_out;
+    let _ = report::suite_finished(
+        output,
+        format,
+        tests,
+        &results[..ran.min(MAX_RESULTS)],
+        &summary,
+        started.elapsed(),
+    );
+    summary
+}
+
+// Borrows the panic from the runner's slot, a Panic is too big to pass around.
+enum Failure<'a> {
+    Panicked(&'a Panic),
+    DidNotPanic,
+    WrongMessage(&'a Panic, &'static str),
+}
+
+impl fmt::Display for Failure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Panicked(panic) => panic.fmt(f),
+            Failure::DidNotPanic => f.write_str("test did not panic as expected"),
+            Failure::WrongMessage(panic, expected) => write!(
+                f,
+                "panic did not contain expected string\n      panic message: {:?}\n expected substring: {:?}",
+                panic.message.as_str(),
+                expected
+            ),
+        }
+    }
+}
+
+// `number` counts from 1. `panic` is where a panic is kept for the Failure.
+fn run_test<'a>(
+    test: &dyn Testable,
+    number: usize,
+    options: &Options,
+    panic: &'a mut Panic,
+) -> Result<(), Failure<'a>> {
+    let watchdog = options.timeout().map(|timeout| {
+        let timed = watchdog::Test {
+            number,
+            name: test.name(),
+            format: options.format(),
+        };
+        watchdog::arm(timeout, timed)
+    });
+    let result = catch::catch_panic(|| test.run(), panic);
+    if let Some(previous) = watchdog {
+        watchdog::disarm(previous);
+    }
+
+    match (test.should_panic(), result) {
+        (ShouldPanic::No, Ok(())) => Ok(()),
+        (ShouldPanic::No, Err(panic)) => Err(Failure::Panicked(panic)),
+        (_, Ok(())) => Err(Failure::DidNotPanic),
+        (ShouldPanic::WithMessage(expected), Err(panic))
+            if !panic.message.as_str().contains(expected) =>
+        {
+            Err(Failure::WrongMessage(panic, expected))
+        }
+        (_, Err(_)) => Ok(()),
+    }
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    // Doesn't return if the panic is from a test, only if it's from the runner itself or
+    // another CPU.
+    catch::resume(info);
+
+    if !emergency::begin_panic() {
+        hlt_loop();
+    }
+    serial::_emergency_print(format_args!("[failed]\n\nError: {}\n\n", info));
+    exit_qemu(QemuExitCode::Failed);
+
+    hlt_loop()
+}
@@ -0,0 +1,236 @@
+// Mod for getting back to the test runner after a panic.
+// There is no unwinding, the kernel is built with panic=abort. So every test is
+// called through catch_call, which saves the callee saved registers, the stack
+// pointer and where to return to, like setjmp. If the test panics, the panic handler
+// calls catch_resume, which loads them back, like longjmp, and catch_call returns a
+// second time with 1. Everything the test had on the stack is dropped on the floor,
+// destructors don't run: memory it allocated leaks and locks it held stay locked.
+
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::backtrace::Backtrace;
+use crate::smp;
+
+// Saved as rbx, rbp, r12, r13, r14, r15, rsp, rip.
+global_asm!(
+    r#"
+.global testing_catch_call
+testing_catch_call:
+    mov %rbx, 0(%rdx)
+    mov %rbp, 8(%rdx)
+    mov %r12, 16(%rdx)
+    mov %r13, 24(%rdx)
+    mov %r14, 32(%rdx)
+    mov %r15, 40(%rdx)
+    lea 8(%rsp), %rax
+    mov %rax, 48(%rdx)
+    mov (%rsp), %rax
+    mov %rax, 56(%rdx)
+
+    // The return address made the stack 8 bytes off, undo that for the call.
+    mov %rdi, %rax
+    mov %rsi, %rdi
+    sub $8, %rsp
+    call *%rax
+    add $8, %rsp
+    xor %eax, %eax
+    ret
+
+.global testing_catch_resume
+testing_catch_resume:
+    mov 0(%rdi), %rbx
+    mov 8(%rdi), %rbp
+    mov 16(%rdi), %r12
+    mov 24(%rdi), %r13
+    mov 32(%rdi), %r14
+    mov 40(%rdi), %r15
+    mov 48(%rdi), %rsp
+    mov $1, %eax
+    jmp *56(%rdi)
+"#,
+    options(att_syntax)
+);
+
+type Registers = [u64; 8];
+
+extern "C" {
+    fn testing_catch_call(
+        function: extern "C" fn(*const u8),
+        data: *const u8,
+        registers: *mut Registers,
+    ) -> u64;
+    fn testing_catch_resume(registers: *const Registers) -> !;
+}
+
+pub(super) const TEXT_SIZE: usize = 256;
+
+/// Text formatted without a heap, `N` bytes at most. Longer text is cut off.
+pub struct Text<const N: usize = TEXT_SIZE> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Text<N> {
+    pub const fn new() -> Self {
+        Text {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // write_str only cuts at char boundaries.
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Default for Text<N> {
+    fn default() -> Self {
+        Text::new()
+    }
+}
+
+impl<const N: usize> Write for Text<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(N - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Display for Text<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A caught panic.
+pub struct Panic {
+    pub message: Text,
+    /// file:line:column
+    pub location: Text,
+    pub backtrace: Backtrace,
+}
+
+impl Default for Panic {
+    fn default() -> Self {
+        Panic {
+            message: Text::new(),
+            location: Text::new(),
+            backtrace: Backtrace::empty(),
+        }
+    }
+}
+
+impl fmt::Display for Panic {
+    // The way PanicInfo shows itself, and the backtrace.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "panicked at {}:\n{}\n\nbacktrace:\n{}",
+            self.location, self.message, self.backtrace
+        )
+    }
+}
+
+struct Catch {
+    registers: Registers,
+    // A panic on another CPU can't jump onto our stack.
+    cpu: usize,
+    panic: Panic,
+}
+
+static CATCHING: AtomicPtr<Catch> = AtomicPtr::new(ptr::null_mut());
+
+extern "C" fn call<F: Fn()>(data: *const u8) {
+    let function = unsafe { &*(data as *const F) };
+    function();
+}
+
+/// Runs `function`. If it panics instead of going down, the panic is stored in `panic`
+/// and returned as the error.
+///
+/// The caller owns the space so the error stays a pointer, a `Panic` is some 800 bytes.
+/// Only for tests, see the top of the file for what a panic leaves behind.
+pub fn catch_panic<F: Fn()>(function: F, panic: &mut Panic) -> Result<(), &mut Panic> {
+    let mut catch = Catch {
+        registers: [0; 8],
+        cpu: smp::cpu_index(),
+        panic: Panic::default(),
+    };
+    let interrupts = x86_64::instructions::interrupts::are_enabled();
+
+    // Catches nest, the outer one is back in charge when this returns.
+    let outer = CATCHING.swap(&mut catch, Ordering::SeqCst);
+    let panicked = unsafe {
+        testing_catch_call(
+            call::<F>,
+            &function as *const F as *const u8,
+            &mut catch.registers,
+        )
+    };
+    CATCHING.store(outer, Ordering::SeqCst);
+
+    // The panic may have happened with interrupts off.
+    if interrupts {
+        x86_64::instructions::interrupts::enable();
+    }
+    if panicked != 0 {
+        *panic = catch.panic;
+        Err(panic)
+    } else {
+        Ok(())
+    }
+}
+
+/// Called first thing in the test panic handler. Jumps back into `catch_panic` if this
+/// CPU is in one, returns otherwise.
+pub fn resume(info: &PanicInfo) {
+    let backtrace = Backtrace::capture();
+    let catch = CATCHING.load(Ordering::SeqCst);
+    if catch.is_null() || unsafe { (*catch).cpu } != smp::cpu_index() {
+        return;
+    }
+    // A panic while formatting the message goes down the normal way.
+    CATCHING.store(ptr::null_mut(), Ordering::SeqCst);
+
+    let catch = unsafe { &mut *catch };
+    let _ = write!(catch.panic.message, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(catch.panic.location, "{}", location);
+    }
+    catch.panic.backtrace = backtrace;
+    unsafe { testing_catch_resume(&catch.registers) }
+}
+
+//  ---Tests---
+
+use crate::kernel_test;
+
+#[kernel_test]
+fn test_catch_panic() {
+    let mut slot = Panic::default();
+    let panic = catch_panic(|| panic!("caught {}", 42), &mut slot).unwrap_err();
+    assert_eq!(panic.message.as_str(), "caught 42");
+    assert!(panic.location.as_str().starts_with("src/testing/catch.rs:"));
+    // The panic handler doesn't get to disable them for good.
+    assert!(x86_64::instructions::interrupts::are_enabled());
+
+    assert!(catch_panic(|| {}, &mut slot).is_ok());
+}
+
+#[kernel_test]
+fn test_long_message_is_cut() {
+    let mut slot = Panic::default();
+    let panic = catch_panic(|| panic!("{:300}", "é"), &mut slot).unwrap_err();
+    assert!(panic.message.as_str().len() <= TEXT_SIZE);
+    assert!(panic.message.as_str().starts_with('é'));
+}
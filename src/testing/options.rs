@@ -0,0 +1,171 @@
+// Mod for the options of a test run.
+// They are written like libtest's: test name filters and flags, separated by spaces,
+//     --exact --format=json --timeout=10 sync::mutex
+// and come from the test boot parameter, or else the opt/jonathan_os/test-args fw_cfg
+// file, which QEMU creates from
+//     -fw_cfg name=opt/jonathan_os/test-args,string=...
+// on its command line. Without either every test runs with the normal output.
+
+use core::fmt::Write;
+use core::time::Duration;
+
+use super::catch::{Text, TEXT_SIZE};
+use super::watchdog::DEFAULT_TIMEOUT;
+use crate::fw_cfg;
+
+/// The fw_cfg file the options are read from.
+pub const ARGS_FILE: &str = "opt/jonathan_os/test-args";
+
+/// How results are written to the serial port.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    /// For people: `name... [ok]` lines and a summary.
+    Pretty,
+    /// A JSON object per line, like libtest's `--format=json`.
+    Json,
+    /// Test Anything Protocol version 13.
+    Tap,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OptionsError {
+    UnknownOption,
+    UnknownFormat,
+    InvalidTimeout,
+    TooLong,
+    NotUtf8,
+}
+
+pub struct Options {
+    // Kept for the filters.
+    args: Text,
+    format: Format,
+    exact: bool,
+    timeout: Option<Duration>,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Options {
+            args: Text::new(),
+            format: Format::Pretty,
+            exact: false,
+            timeout: Some(DEFAULT_TIMEOUT),
+        }
+    }
+
+    pub fn parse(args: &str) -> Result<Self, OptionsError> {
+        if args.len() > TEXT_SIZE {
+            return Err(OptionsError::TooLong);
+        }
+        let mut options = Options::new();
+        let _ = options.args.write_str(args);
+
+        for word in args.split_whitespace() {
+            match word {
+                "--exact" => options.exact = true,
+                "--format=pretty" => options.format = Format::Pretty,
+                "--format=json" => options.format = Format::Json,
+                "--format=tap" => options.format = Format::Tap,
+                _ if word.starts_with("--format=") => return Err(OptionsError::UnknownFormat),
+                _ if word.starts_with("--timeout=") => {
+                    // In seconds, 0 for none.
+                    options.timeout = match word["--timeout=".len()..].parse() {
+                        Ok(0) => None,
+                        Ok(secs) => Some(Duration::from_secs(secs)),
+                        Err(_) => return Err(OptionsError::InvalidTimeout),
+                    };
+                }
+                _ if word.starts_with("--") => return Err(OptionsError::UnknownOption),
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+
+    /// Reads the options from `ARGS_FILE`, the defaults if there is none.
+    pub fn from_fw_cfg() -> Result<Self, OptionsError> {
+        let file = match fw_cfg::find(ARGS_FILE) {
+            Some(file) => file,
+            None => return Ok(Options::new()),
+        };
+        if file.size() > TEXT_SIZE {
+            return Err(OptionsError::TooLong);
+        }
+
+        let mut buffer = [0; TEXT_SIZE];
+        let len = file.read(&mut buffer);
+        let args = core::str::from_utf8(&buffer[..len]).map_err(|_| OptionsError::NotUtf8)?;
+        Options::parse(args)
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// How long a test may run, `None` for as long as it wants.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Whether the test called `name` runs: no filters, or one of them matches the name,
+    /// the whole of it with `--exact`.
+    pub fn matches(&self, name: &str) -> bool {
+        let mut filters = self
+            .args
+            .as_str()
+            .split_whitespace()
+            .filter(|word| !word.starts_with("--"))
+            .peekable();
+
+        filters.peek().is_none()
+            || filters.any(|filter| {
+                if self.exact {
+                    name == filter
+                } else {
+                    name.contains(filter)
+                }
+            })
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options::new()
+    }
+}
+
+//  ---Tests---
+
+#[test_case]
+fn test_filters() {
+    let options = Options::parse("mutex  --format=tap once").unwrap();
+    assert_eq!(options.format(), Format::Tap);
+    assert_eq!(options.timeout(), Some(DEFAULT_TIMEOUT));
+    assert!(options.matches("sync::mutex::test_lock"));
+    assert!(options.matches("sync::once::test_lazy"));
+    assert!(!options.matches("sync::rwlock::test_read"));
+
+    let options = Options::parse("--exact --timeout=0 sync::mutex").unwrap();
+    assert_eq!(options.timeout(), None);
+    assert!(options.matches("sync::mutex"));
+    assert!(!options.matches("sync::mutex::test_lock"));
+
+    assert!(Options::parse("").unwrap().matches("anything"));
+}
+
+#[test_case]
+fn test_bad_options() {
+    assert_eq!(
+        Options::parse("--format=xml").err(),
+        Some(OptionsError::UnknownFormat)
+    );
+    assert_eq!(
+        Options::parse("--nocapture").err(),
+        Some(OptionsError::UnknownOption)
+    );
+    assert_eq!(
+        Options::parse("--timeout=1s").err(),
+        Some(OptionsError::InvalidTimeout)
+    );
+}
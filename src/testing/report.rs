@@ -0,0 +1,317 @@
+// Mod for writing test results in the chosen Format, to the serial port unless the
+// runner was given another output.
+// Anything a test prints itself ends up between the lines, parsers for the JSON and
+// TAP output have to skip lines that aren't theirs.
+
+use core::fmt;
+use core::time::Duration;
+
+use super::catch::Text;
+use super::options::Format;
+use super::{Outcome, Summary, Testable};
+use crate::serial;
+
+/// Writes to the serial port, where the results go by default.
+pub(super) struct Serial;
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::_print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+/// Writes to the serial port even if the test holds it, for the watchdog.
+pub(super) struct EmergencySerial;
+
+impl fmt::Write for EmergencySerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::_emergency_print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+// A string as a JSON string literal.
+struct Json<'a>(&'a str);
+
+impl fmt::Display for Json<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\t' => f.write_str("\\t")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+// Enough for a panic message and a full backtrace.
+const MESSAGE_SIZE: usize = 4096;
+
+// Formats into a Text so the message can be escaped or indented as a whole.
+fn to_text(message: &dyn fmt::Display) -> Text<MESSAGE_SIZE> {
+    let mut text = Text::new();
+    let _ = fmt::write(&mut text, format_args!("{}", message));
+    text
+}
+
+fn status(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Passed => "[ok]",
+        Outcome::Failed => "[failed]",
+        Outcome::Ignored => "[ignored]",
+    }
+}
+
+pub(super) fn suite_started(out: &mut dyn fmt::Write, format: Format, count: usize) -> fmt::Result {
+    match format {
+        Format::Pretty => {
+            writeln!(out, "Running {} tests", count)?;
+        }
+        Format::Json => {
+            writeln!(
+                out,
+                r#"{{ "type": "suite", "event": "started", "test_count": {} }}"#,
+                count
+            )?;
+        }
+        Format::Tap => {
+            writeln!(out, "TAP version 13\n1..{}", count)?;
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn test_started(out: &mut dyn fmt::Write, format: Format, name: &str) -> fmt::Result {
+    match format {
+        Format::Pretty => {
+            write!(out, "{}...\t", name)?;
+        }
+        Format::Json => {
+            writeln!(
+                out,
+                r#"{{ "type": "test", "event": "started", "name": {} }}"#,
+                Json(name)
+            )?;
+        }
+        Format::Tap => {}
+    }
+    Ok(())
+}
+
+/// `number` counts from 1, `failure` is there for failed tests.
+pub(super) fn test_finished(
+    out: &mut dyn fmt::Write,
+    format: Format,
+    number: usize,
+    name: &str,
+    outcome: Outcome,
+    duration: Duration,
+    failure: Option<&dyn fmt::Display>,
+) -> fmt::Result {
+    match (format, outcome) {
+        (Format::Pretty, Outcome::Passed) => {
+            writeln!(out, "{} {:?}", status(outcome), duration)?;
+        }
+        (Format::Pretty, Outcome::Ignored) => {
+            writeln!(out, "{}", status(outcome))?;
+        }
+        (Format::Pretty, Outcome::Failed) => {
+            write!(out, "{} {:?}\n\n", status(outcome), duration)?;
+            if let Some(failure) = failure {
+                writeln!(out, "Error: {}\n", failure)?;
+            }
+        }
+
+        (Format::Json, Outcome::Passed) => {
+            writeln!(
+                out,
+                r#"{{ "type": "test", "name": {}, "event": "ok", "exec_time": {} }}"#,
+                Json(name),
+                duration.as_secs_f64()
+            )?;
+        }
+        (Format::Json, Outcome::Ignored) => {
+            writeln!(
+                out,
+                r#"{{ "type": "test", "name": {}, "event": "ignored" }}"#,
+                Json(name)
+            )?;
+        }
+        (Format::Json, Outcome::Failed) => {
+            let message = failure.map(to_text);
+            writeln!(
+                out,
+                r#"{{ "type": "test", "name": {}, "event": "failed", "exec_time": {}, "message": {} }}"#,
+                Json(name),
+                duration.as_secs_f64(),
+                Json(message.as_ref().map_or("", |message| message.as_str()))
+            )?;
+        }
+
+        (Format::Tap, Outcome::Passed) => {
+            writeln!(out, "ok {} - {}", number, name)?;
+        }
+        (Format::Tap, Outcome::Ignored) => {
+            writeln!(out, "ok {} - {} # SKIP", number, name)?;
+        }
+        (Format::Tap, Outcome::Failed) => {
+            writeln!(out, "not ok {} - {}", number, name)?;
+            // A YAML block for the details.
+            writeln!(
+                out,
+                "  ---\n  duration_ms: {}",
+                duration.as_secs_f64() * 1000.0
+            )?;
+            if let Some(failure) = failure {
+                writeln!(out, "  message: |")?;
+                for line in to_text(failure).as_str().lines() {
+                    writeln!(out, "    {}", line)?;
+                }
+            }
+            writeln!(out, "  ...")?;
+        }
+    }
+    Ok(())
+}
+
+/// For the watchdog, when test `number` is still running after `timeout`. Nothing comes
+/// after this, the run ends with the test.
+pub(super) fn test_timed_out(
+    out: &mut dyn fmt::Write,
+    format: Format,
+    number: usize,
+    name: &str,
+    timeout: Duration,
+    backtrace: &dyn fmt::Display,
+) -> fmt::Result {
+    match format {
+        Format::Pretty => {
+            write!(
+                out,
+                "[timed out]\n\nError: test timed out after {:?}\n\nbacktrace:\n{}\n",
+                timeout, backtrace
+            )?;
+        }
+        Format::Json => {
+            let message = to_text(&format_args!(
+                "test timed out after {:?}\n\nbacktrace:\n{}",
+                timeout, backtrace
+            ));
+            writeln!(
+                out,
+                r#"{{ "type": "test", "name": {}, "event": "timeout", "exec_time": {}, "message": {} }}"#,
+                Json(name),
+                timeout.as_secs_f64(),
+                Json(message.as_str())
+            )?;
+        }
+        Format::Tap => {
+            writeln!(out, "not ok {} - {} # timeout", number, name)?;
+            writeln!(
+                out,
+                "  ---\n  duration_ms: {}\n  backtrace: |",
+                timeout.as_secs_f64() * 1000.0
+            )?;
+            for line in to_text(backtrace).as_str().lines() {
+                writeln!(out, "    {}", line)?;
+            }
+            // The plan promised more tests than will come.
+            writeln!(out, "  ...\nBail out! test timed out")?;
+        }
+    }
+    Ok(())
+}
+
+/// `results` has the outcomes of the tests that ran, by their index in `tests`.
+pub(super) fn suite_finished(
+    out: &mut dyn fmt::Write,
+    format: Format,
+    tests: &[&dyn Testable],
+    results: &[(usize, Outcome, Duration)],
+    summary: &Summary,
+    duration: Duration,
+) -> fmt::Result {
+    let ok = summary.failed == 0;
+    match format {
+        Format::Pretty => {
+            writeln!(out, "\nSummary:")?;
+            for &(index, outcome, test_duration) in results {
+                if outcome == Outcome::Ignored {
+                    writeln!(out, "  {:9} {}", status(outcome), tests[index].name())?;
+                } else {
+                    writeln!(
+                        out,
+                        "  {:9} {} {:?}",
+                        status(outcome),
+                        tests[index].name(),
+                        test_duration
+                    )?;
+                }
+            }
+            let ran = summary.passed + summary.failed + summary.ignored;
+            if ran > results.len() {
+                writeln!(out, "  ... {} more", ran - results.len())?;
+            }
+            writeln!(
+                out,
+                "\ntest result: {}. {} passed; {} failed; {} ignored; {} filtered out; finished in {:?}\n",
+                if ok { "ok" } else { "FAILED" },
+                summary.passed,
+                summary.failed,
+                summary.ignored,
+                summary.filtered_out,
+                duration
+            )?;
+        }
+        Format::Json => {
+            writeln!(
+                out,
+                r#"{{ "type": "suite", "event": "{}", "passed": {}, "failed": {}, "ignored": {}, "filtered_out": {}, "exec_time": {} }}"#,
+                if ok { "ok" } else { "failed" },
+                summary.passed,
+                summary.failed,
+                summary.ignored,
+                summary.filtered_out,
+                duration.as_secs_f64()
+            )?;
+        }
+        Format::Tap => {
+            writeln!(
+                out,
+                "# passed {}, failed {}, ignored {}, filtered out {}",
+                summary.passed, summary.failed, summary.ignored, summary.filtered_out
+            )?;
+        }
+    }
+    Ok(())
+}
+
+//  ---Tests---
+
+#[test_case]
+fn test_json_escaping() {
+    let text = to_text(&Json("a \"b\"\\\n\u{1}"));
+    assert_eq!(text.as_str(), r#""a \"b\"\\\n\u0001""#);
+}
+
+#[test_case]
+fn test_timeout_lines() {
+    let timeout = Duration::from_secs(2);
+    let mut json: Text<1024> = Text::new();
+    test_timed_out(&mut json, Format::Json, 3, "a::b", timeout, &"at 0x1").unwrap();
+    let line = json.as_str();
+    assert!(line.starts_with(r#"{ "type": "test", "name": "a::b", "event": "timeout", "#));
+    assert!(line.contains(r#""message": "test timed out after 2s\n\nbacktrace:\nat 0x1" }"#));
+
+    let mut tap: Text<1024> = Text::new();
+    test_timed_out(&mut tap, Format::Tap, 3, "a::b", timeout, &"at 0x1").unwrap();
+    assert!(tap.as_str().starts_with("not ok 3 - a::b # timeout\n"));
+    assert!(tap.as_str().contains("  backtrace: |\n    at 0x1\n  ...\n"));
+}
@@ -0,0 +1,96 @@
+// Mod for per-test timeouts.
+// The runner arms the watchdog with a deadline before each test, and the timer
+// interrupt checks it on every tick. When a test runs past it, the check reports the
+// test as timed out in the run's format, with a backtrace of where it was stuck, and
+// exits QEMU from the interrupt handler. The run ends there: getting back to the runner would mean jumping
+// out of the interrupt handler, past whatever the test was in the middle of, locks it
+// holds with interrupts off included.
+// The timer has to be running, jonathan_os::init starts it, and interrupts have to be
+// on. A test that hangs with them off, in `cli; hlt` or spinning on a lock that keeps
+// them off while waiting, still hangs the run.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::options::Format;
+use super::report;
+use crate::backtrace::Backtrace;
+use crate::sync::IrqSafeSpinlock;
+use crate::{exit_qemu, hlt_loop, time, QemuExitCode};
+
+/// How long a test may run unless the options say otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// In monotonic nanoseconds, 0 when disarmed.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+// The timeout the deadline was armed with, for the message.
+static TIMEOUT_NANOS: AtomicU64 = AtomicU64::new(0);
+// The test the deadline is for.
+static TEST: IrqSafeSpinlock<Option<Test>> = IrqSafeSpinlock::new(None);
+
+/// The test being timed, so a timeout is reported like the runner reports results.
+#[derive(Debug, Copy, Clone)]
+pub struct Test {
+    /// Counts from 1, like TAP's test numbers.
+    pub number: usize,
+    pub name: &'static str,
+    pub format: Format,
+}
+
+/// The deadline before `arm`, to be given back to `disarm`.
+#[derive(Debug, Copy, Clone)]
+pub struct Armed {
+    deadline: u64,
+    timeout_nanos: u64,
+    test: Option<Test>,
+}
+
+/// Starts the clock on a test. Returns what was armed before, runs nest.
+pub fn arm(timeout: Duration, test: Test) -> Armed {
+    let timeout_nanos = timeout.as_nanos() as u64;
+    let previous = Armed {
+        deadline: DEADLINE.load(Ordering::SeqCst),
+        timeout_nanos: TIMEOUT_NANOS.swap(timeout_nanos, Ordering::SeqCst),
+        test: TEST.lock().replace(test),
+    };
+    DEADLINE.store(time::monotonic_nanos() + timeout_nanos, Ordering::SeqCst);
+    previous
+}
+
+/// Stops the clock and puts back the deadline `arm` returned.
+pub fn disarm(previous: Armed) {
+    DEADLINE.store(previous.deadline, Ordering::SeqCst);
+    TIMEOUT_NANOS.store(previous.timeout_nanos, Ordering::SeqCst);
+    *TEST.lock() = previous.test;
+}
+
+/// Called from the timer interrupt, after the end of interrupt was sent. Doesn't
+/// return if the test ran out of time.
+pub fn check() {
+    let deadline = DEADLINE.load(Ordering::Relaxed);
+    if deadline == 0 || time::monotonic_nanos() < deadline {
+        return;
+    }
+    DEADLINE.store(0, Ordering::SeqCst);
+
+    let timeout = Duration::from_nanos(TIMEOUT_NANOS.load(Ordering::SeqCst));
+    // Only held with interrupts off, so never by this CPU. Set whenever the deadline is.
+    let test = TEST.lock().unwrap_or(Test {
+        number: 0,
+        name: "",
+        format: Format::Pretty,
+    });
+    // Starts in here and goes through the interrupt handler into the test.
+    let backtrace = Backtrace::capture();
+    // The test may be holding the serial port.
+    let _ = report::test_timed_out(
+        &mut report::EmergencySerial,
+        test.format,
+        test.number,
+        test.name,
+        timeout,
+        &backtrace,
+    );
+    exit_qemu(QemuExitCode::TimedOut);
+    hlt_loop();
+}
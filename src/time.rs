@@ -0,0 +1,213 @@
+// Mod for keeping time.
+// Two clocks live here:
+// Instant counts nanoseconds since boot and only ever goes forward. It is driven by
+// whichever interrupt is picked as the tick source, or by the TSC once that is
+// calibrated and known to run at a constant rate.
+// SystemTime is the wall clock: the RTC date read at boot plus the time since then.
+
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+
+use crate::boot_params;
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+pub mod tsc;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// What the BIOS leaves the PIT at, dividing by 65536, until init reprograms it.
+const PIT_DEFAULT_PERIOD_NANOS: u64 = 65536 * NANOS_PER_SEC / pit::BASE_FREQUENCY as u64;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum TickSource {
+    /// IRQ 0, the programmable interval timer.
+    Pit,
+    /// IRQ 8, the RTC periodic interrupt. See `rtc::enable_periodic_interrupt`.
+    Rtc,
+    /// HPET timer 0 through the I/O APIC. See `hpet::start_periodic`.
+    Hpet,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+static PIT_PERIOD: AtomicU64 = AtomicU64::new(PIT_DEFAULT_PERIOD_NANOS);
+static RTC_PERIOD: AtomicU64 = AtomicU64::new(0);
+static HPET_PERIOD: AtomicU64 = AtomicU64::new(0);
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static MONOTONIC_NANOS: AtomicU64 = AtomicU64::new(0);
+// Unix time in nanoseconds when MONOTONIC_NANOS was 0.
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+//  ---Ticks---
+
+fn period(source: TickSource) -> &'static AtomicU64 {
+    match source {
+        TickSource::Pit => &PIT_PERIOD,
+        TickSource::Rtc => &RTC_PERIOD,
+        TickSource::Hpet => &HPET_PERIOD,
+    }
+}
+
+/// Tells the clock how far one interrupt from `source` moves time forward.
+pub fn set_tick_period(source: TickSource, nanos: u64) {
+    period(source).store(nanos, Ordering::Relaxed);
+}
+
+/// Picks the interrupt that drives the monotonic clock, ticks from the other one are ignored.
+pub fn set_tick_source(source: TickSource) {
+    SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+pub fn tick_source() -> TickSource {
+    match SOURCE.load(Ordering::Relaxed) {
+        source if source == TickSource::Rtc as u8 => TickSource::Rtc,
+        source if source == TickSource::Hpet as u8 => TickSource::Hpet,
+        _ => TickSource::Pit,
+    }
+}
+
+/// Called from the timer interrupt handlers.
+pub fn tick(source: TickSource) {
+    if source != tick_source() {
+        return;
+    }
+
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    MONOTONIC_NANOS.fetch_add(period(source).load(Ordering::Relaxed), Ordering::Relaxed);
+    timer::run_expired();
+}
+
+/// Number of ticks counted since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since boot as counted by the tick interrupts alone.
+pub fn tick_nanos() -> u64 {
+    MONOTONIC_NANOS.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since boot from the best clock available. Never goes backwards.
+pub fn monotonic_nanos() -> u64 {
+    if tsc::is_enabled() {
+        tsc::nanos()
+    } else {
+        tick_nanos()
+    }
+}
+
+//  ---Init---
+
+/// Speeds the PIT up to the timer_hz boot parameter, 1000 Hz by default, calibrates the
+/// TSC against it and reads the RTC so the wall clock has a starting point.
+pub fn init() {
+    pit::set_frequency(boot_params::get().timer_hz());
+    tsc::calibrate(tick_nanos());
+
+    // A date the clock can't hold leaves it at the epoch, better than panicking.
+    let date = rtc::read();
+    let boot = date
+        .to_unix_timestamp()
+        .and_then(|seconds| seconds.checked_mul(NANOS_PER_SEC))
+        .unwrap_or_else(|| {
+            crate::log!(Warn, "RTC date {:?} is out of range, using 1970", date);
+            0
+        });
+    let since_boot = monotonic_nanos();
+    BOOT_UNIX_NANOS.store(boot - since_boot.min(boot), Ordering::Relaxed);
+}
+
+//  ---Instant---
+
+/// A point on the monotonic clock. Only good for measuring time between two of them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant {
+            nanos: monotonic_nanos(),
+        }
+    }
+
+    /// Time since boot.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// Returns zero if `earlier` is actually later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant {
+            nanos: self.nanos + duration.as_nanos() as u64,
+        }
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+//  ---SystemTime---
+
+/// A wall clock time in UTC.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct SystemTime {
+    unix_nanos: u64,
+}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime { unix_nanos: 0 };
+
+    pub fn now() -> Self {
+        SystemTime {
+            unix_nanos: BOOT_UNIX_NANOS.load(Ordering::Relaxed) + monotonic_nanos(),
+        }
+    }
+
+    pub fn since_unix_epoch(&self) -> Duration {
+        Duration::from_nanos(self.unix_nanos)
+    }
+
+    /// Whole seconds since 1970-01-01 00:00:00 UTC.
+    pub fn unix_timestamp(&self) -> u64 {
+        self.unix_nanos / NANOS_PER_SEC
+    }
+
+    pub fn date_time(&self) -> rtc::DateTime {
+        rtc::DateTime::from_unix_timestamp(self.unix_timestamp())
+    }
+}
+
+/// The current wall clock time.
+pub fn now() -> SystemTime {
+    SystemTime::now()
+}
@@ -0,0 +1,268 @@
+// Mod for the High Precision Event Timer.
+// The HPET is a free running counter of at least 10 MHz plus a few comparators that
+// fire an interrupt when the counter reaches them. Its address comes from the ACPI
+// HPET table. We leave legacy replacement off and route timer 0 through the I/O APIC,
+// so the PIT keeps working until the HPET is picked as the tick source.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+use crate::acpi::{self, AcpiError};
+use crate::apic::{self, ApicError, Polarity, Trigger};
+use crate::interrupts::ApicInterruptIndex;
+use crate::memory;
+use crate::time::{self, TickSource};
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+const REG_TIMER_BASE: usize = 0x100;
+const TIMER_STRIDE: usize = 0x20;
+const TIMER_CONFIG: usize = 0x00;
+const TIMER_COMPARATOR: usize = 0x08;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32_BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+// The comparator we use. Every HPET has at least 3 and only timers 0 and 1 can go periodic.
+const TIMER: usize = 0;
+// I/O APIC inputs below 16 belong to ISA devices, keep off them if we can.
+const FIRST_FREE_GSI: u32 = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HpetError {
+    /// There is no ACPI HPET table.
+    NotPresent,
+    /// The platform description couldn't be read.
+    Acpi(AcpiError),
+    NotInitialized,
+    /// Timer 0 can't fire periodically.
+    NoPeriodicSupport,
+    /// The timer can't be routed to any I/O APIC input.
+    NoRoute,
+    Apic(ApicError),
+}
+
+impl From<AcpiError> for HpetError {
+    fn from(err: AcpiError) -> Self {
+        HpetError::Acpi(err)
+    }
+}
+
+impl From<ApicError> for HpetError {
+    fn from(err: ApicError) -> Self {
+        HpetError::Apic(err)
+    }
+}
+
+struct Hpet {
+    base: VirtAddr,
+    // Length of one counter tick in femtoseconds.
+    period_femtos: u64,
+    timer_count: usize,
+}
+
+impl Hpet {
+    unsafe fn read(&self, register: usize) -> u64 {
+        ptr::read_volatile((self.base + register).as_ptr::<u64>())
+    }
+
+    unsafe fn write(&mut self, register: usize, value: u64) {
+        ptr::write_volatile((self.base + register).as_mut_ptr::<u64>(), value);
+    }
+
+    fn timer_register(timer: usize, register: usize) -> usize {
+        REG_TIMER_BASE + timer * TIMER_STRIDE + register
+    }
+
+    fn counter(&self) -> u64 {
+        unsafe { self.read(REG_MAIN_COUNTER) }
+    }
+
+    fn ticks_for(&self, duration: Duration) -> u64 {
+        let ticks = duration.as_nanos() * FEMTOS_PER_NANO as u128 / self.period_femtos as u128;
+        (ticks as u64).max(1)
+    }
+
+    // Routes our timer to the lowest I/O APIC input it supports, preferring ones past the ISA range.
+    fn route(&mut self) -> Result<(), HpetError> {
+        let config_register = Hpet::timer_register(TIMER, TIMER_CONFIG);
+        let config = unsafe { self.read(config_register) };
+
+        let allowed = (config >> 32) as u32;
+        let free = allowed & !((1 << FIRST_FREE_GSI) - 1);
+        let choices = if free != 0 { free } else { allowed };
+        if choices == 0 {
+            return Err(HpetError::NoRoute);
+        }
+        let gsi = choices.trailing_zeros();
+
+        let vector = ApicInterruptIndex::Hpet.as_u8();
+        apic::route_gsi(gsi, vector, Trigger::Edge, Polarity::ActiveHigh)?;
+
+        let mut config = config & !(TIMER_ROUTE_MASK | TIMER_FSB_ENABLE | TIMER_LEVEL_TRIGGERED);
+        config |= (gsi as u64) << TIMER_ROUTE_SHIFT;
+        unsafe { self.write(config_register, config) };
+        Ok(())
+    }
+
+    fn stop_timer(&mut self) {
+        let register = Hpet::timer_register(TIMER, TIMER_CONFIG);
+        unsafe {
+            let config = self.read(register);
+            self.write(
+                register,
+                config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+            );
+        }
+    }
+}
+
+static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+fn with_hpet<T>(f: impl FnOnce(&mut Hpet) -> Result<T, HpetError>) -> Result<T, HpetError> {
+    without_interrupts(|| match HPET.lock().as_mut() {
+        Some(hpet) => f(hpet),
+        None => Err(HpetError::NotInitialized),
+    })
+}
+
+//  ---Init---
+
+/// Finds the HPET through ACPI and starts its main counter. Needs apic::init to have run.
+pub fn init() -> Result<(), HpetError> {
+    let info = acpi::platform()?.hpet.ok_or(HpetError::NotPresent)?;
+
+    let mut hpet = Hpet {
+        base: memory::phys_to_virt(info.address),
+        period_femtos: 0,
+        timer_count: 0,
+    };
+    let capabilities = unsafe { hpet.read(REG_CAPABILITIES) };
+    hpet.period_femtos = capabilities >> 32;
+    hpet.timer_count = ((capabilities >> 8) & 0x1F) as usize + 1;
+    if hpet.period_femtos == 0 {
+        return Err(HpetError::NotPresent);
+    }
+
+    hpet.stop_timer();
+    hpet.route()?;
+
+    unsafe {
+        let config = hpet.read(REG_CONFIG);
+        hpet.write(REG_CONFIG, (config & !CONFIG_LEGACY_ROUTE) | CONFIG_ENABLE);
+    }
+
+    without_interrupts(|| *HPET.lock() = Some(hpet));
+    Ok(())
+}
+
+//  ---Clock Source---
+
+/// The raw main counter.
+pub fn read_counter() -> Result<u64, HpetError> {
+    with_hpet(|hpet| Ok(hpet.counter()))
+}
+
+/// Counter ticks per second.
+pub fn frequency() -> Result<u64, HpetError> {
+    with_hpet(|hpet| Ok(FEMTOS_PER_SEC / hpet.period_femtos))
+}
+
+/// Number of comparators the HPET has.
+pub fn timer_count() -> Result<usize, HpetError> {
+    with_hpet(|hpet| Ok(hpet.timer_count))
+}
+
+/// Time since the main counter was started, in nanoseconds.
+pub fn nanos() -> Result<u64, HpetError> {
+    with_hpet(|hpet| {
+        let femtos = hpet.counter() as u128 * hpet.period_femtos as u128;
+        Ok((femtos / FEMTOS_PER_NANO as u128) as u64)
+    })
+}
+
+//  ---Interrupts---
+
+/// Fires the HPET interrupt every `period` until stopped. Returns the period actually used.
+pub fn start_periodic(period: Duration) -> Result<Duration, HpetError> {
+    with_hpet(|hpet| {
+        let register = Hpet::timer_register(TIMER, TIMER_CONFIG);
+        let config = unsafe { hpet.read(register) };
+        if config & TIMER_PERIODIC_CAPABLE == 0 {
+            return Err(HpetError::NoPeriodicSupport);
+        }
+
+        let ticks = hpet.ticks_for(period);
+        let actual_nanos = ticks * hpet.period_femtos / FEMTOS_PER_NANO;
+        time::set_tick_period(TickSource::Hpet, actual_nanos);
+
+        // With VALUE_SET, the first comparator write sets when the timer first fires and
+        // the second one sets the period.
+        let comparator = Hpet::timer_register(TIMER, TIMER_COMPARATOR);
+        let config = (config & !TIMER_32_BIT_MODE)
+            | TIMER_INTERRUPT_ENABLE
+            | TIMER_PERIODIC
+            | TIMER_VALUE_SET;
+        unsafe {
+            hpet.write(register, config);
+            hpet.write(comparator, hpet.counter() + ticks);
+            hpet.write(comparator, ticks);
+        }
+
+        Ok(Duration::from_nanos(actual_nanos))
+    })
+}
+
+/// Fires the HPET interrupt once, `delay` from now.
+pub fn start_one_shot(delay: Duration) -> Result<(), HpetError> {
+    with_hpet(|hpet| {
+        let register = Hpet::timer_register(TIMER, TIMER_CONFIG);
+        let ticks = hpet.ticks_for(delay);
+        unsafe {
+            let config = hpet.read(register) & !(TIMER_PERIODIC | TIMER_32_BIT_MODE);
+            hpet.write(register, config | TIMER_INTERRUPT_ENABLE);
+            let comparator = Hpet::timer_register(TIMER, TIMER_COMPARATOR);
+            hpet.write(comparator, hpet.counter() + ticks);
+        }
+        Ok(())
+    })
+}
+
+/// Stops comparator interrupts, the main counter keeps running.
+pub fn stop() -> Result<(), HpetError> {
+    with_hpet(|hpet| {
+        hpet.stop_timer();
+        Ok(())
+    })
+}
+
+/// Number of HPET interrupts handled so far.
+pub fn interrupt_count() -> u64 {
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Called from the HPET interrupt handler.
+pub fn handle_interrupt() {
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    time::tick(TickSource::Hpet);
+}
@@ -0,0 +1,120 @@
+// Mod for the 8253/8254 programmable interval timer.
+// Channel 0 is wired to IRQ 0. The BIOS leaves it at its slowest rate, about 18.2 Hz,
+// which is far too coarse for timeouts, so init speeds it up.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::time::{self, TickSource};
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+// Port B of the old keyboard controller holds the channel 2 gate and output bits.
+const CONTROL_PORT: u16 = 0x61;
+
+// Channel 0, low byte then high byte, mode 2 (rate generator), binary counting.
+const COMMAND_CHANNEL_0_RATE: u8 = 0b0011_0100;
+// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count).
+const COMMAND_CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+const CONTROL_GATE_2: u8 = 1 << 0;
+const CONTROL_SPEAKER: u8 = 1 << 1;
+const CONTROL_OUTPUT_2: u8 = 1 << 5;
+
+/// The PIT input clock in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The rate init sets, one tick per millisecond.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+struct Pit {
+    channel_0: Port<u8>,
+    channel_2: Port<u8>,
+    command: Port<u8>,
+    control: Port<u8>,
+}
+
+static PIT: Mutex<Pit> = Mutex::new(Pit {
+    channel_0: Port::new(CHANNEL_0_PORT),
+    channel_2: Port::new(CHANNEL_2_PORT),
+    command: Port::new(COMMAND_PORT),
+    control: Port::new(CONTROL_PORT),
+});
+
+static FREQUENCY: Mutex<u32> = Mutex::new(0);
+
+// The reload value is 16 bits, with 0 meaning 65536.
+fn divisor_for(frequency: u32) -> u32 {
+    let frequency = frequency.max(1);
+    ((BASE_FREQUENCY + frequency / 2) / frequency).clamp(1, 65536)
+}
+
+/// Reprograms channel 0 to fire as close to `frequency` Hz as the divisor allows.
+///
+/// Returns the frequency actually used. The tick period of the clock is updated to match.
+pub fn set_frequency(frequency: u32) -> u32 {
+    let divisor = divisor_for(frequency);
+    let actual = BASE_FREQUENCY / divisor;
+
+    without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe {
+            pit.command.write(COMMAND_CHANNEL_0_RATE);
+            pit.channel_0.write(divisor as u8);
+            pit.channel_0.write((divisor >> 8) as u8);
+        }
+
+        *FREQUENCY.lock() = actual;
+        time::set_tick_period(
+            TickSource::Pit,
+            divisor as u64 * 1_000_000_000 / BASE_FREQUENCY as u64,
+        );
+    });
+
+    actual
+}
+
+/// The frequency channel 0 was last set to, or 0 if it still runs at the BIOS rate.
+pub fn frequency() -> u32 {
+    without_interrupts(|| *FREQUENCY.lock())
+}
+
+//  ---Channel 2---
+// Channel 2 normally drives the speaker, but its gate and output can be read back,
+// which makes it a known-length stopwatch for calibrating other clocks.
+
+/// Loads channel 2 with `count` without starting it, and keeps the speaker quiet.
+pub(crate) fn arm_channel_2(count: u16) {
+    without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe {
+            let control = pit.control.read();
+            pit.control
+                .write(control & !(CONTROL_GATE_2 | CONTROL_SPEAKER));
+            pit.command.write(COMMAND_CHANNEL_2_ONE_SHOT);
+            pit.channel_2.write(count as u8);
+            pit.channel_2.write((count >> 8) as u8);
+        }
+    });
+}
+
+/// Opens the gate, channel 2 counts down from here.
+pub(crate) fn start_channel_2() {
+    without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe {
+            let control = pit.control.read();
+            pit.control.write(control | CONTROL_GATE_2);
+        }
+    });
+}
+
+/// Whether channel 2 has counted down to zero since it was started.
+pub(crate) fn channel_2_done() -> bool {
+    without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe { pit.control.read() & CONTROL_OUTPUT_2 != 0 }
+    })
+}
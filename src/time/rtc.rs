@@ -0,0 +1,342 @@
+// Mod for the CMOS real-time clock.
+// The RTC keeps the date while the machine is off. Depending on status register B
+// it stores values as BCD or binary and hours as 12 or 24 hour, so every read
+// gets normalised here.
+// It can also fire IRQ 8 at a fixed rate, which the time mod can use as its tick.
+
+use core::convert::TryFrom;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::PICS;
+use crate::{acpi, time};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+// Not standard, but QEMU and most PCs keep the century here. Used until ACPI tells us otherwise.
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+// The RTC crystal, the periodic rate divides this.
+const BASE_FREQUENCY: u32 = 32768;
+
+// IRQ 8 is the first line on the second PIC, IRQ 2 is the cascade.
+const RTC_IRQ: u8 = 8;
+const CASCADE_IRQ: u8 = 2;
+
+/// A calendar date and time in UTC, the way the RTC stores it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC, `None` for dates before that.
+    pub fn to_unix_timestamp(&self) -> Option<u64> {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        u64::try_from(days * 86400 + seconds).ok()
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+// Howard Hinnant's days_from_civil, counts days from 1970-01-01 in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of days_from_civil.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+//  ---CMOS Access---
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+}
+
+// Selecting a register and using it are two port accesses, so this has to be locked.
+// Always taken with interrupts off, the IRQ 8 handler needs it too.
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    index: Port::new(INDEX_PORT),
+    data: Port::new(DATA_PORT),
+});
+
+// The raw register values, before BCD and 12 hour decoding.
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(cmos: &mut Cmos) -> RawTime {
+    // The RTC updates its registers once a second. Reading during the update gives junk.
+    while cmos.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+
+    RawTime {
+        second: cmos.read(REG_SECONDS),
+        minute: cmos.read(REG_MINUTES),
+        hour: cmos.read(REG_HOURS),
+        day: cmos.read(REG_DAY),
+        month: cmos.read(REG_MONTH),
+        year: cmos.read(REG_YEAR),
+        century: cmos.read(century_register()),
+    }
+}
+
+fn century_register() -> u8 {
+    acpi::cached_platform()
+        .and_then(|platform| platform.fadt)
+        .and_then(|fadt| fadt.century_register)
+        .unwrap_or(REG_CENTURY)
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+// Turns the raw registers into a DateTime using the format bits from status register B.
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| {
+        if binary {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    // In 12 hour mode the PM flag sits in the top bit of the hour, outside the BCD digits.
+    let pm = status_b & STATUS_B_24_HOUR == 0 && raw.hour & HOUR_PM != 0;
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // A missing century register reads as 0 or 0xFF, assume the 2000s then.
+    let century = match convert(raw.century) {
+        century @ 19..=21 => century as u16,
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+/// Reads the current date and time from the RTC.
+pub fn read() -> DateTime {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+
+        // An update can still start between the check and the reads, so read until two reads agree.
+        let mut raw = read_raw(&mut cmos);
+        loop {
+            let again = read_raw(&mut cmos);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status_b = cmos.read(REG_STATUS_B);
+        decode(raw, status_b)
+    })
+}
+
+//  ---Periodic Interrupt---
+
+/// Makes the RTC fire IRQ 8 at `32768 >> (rate - 1)` Hz and returns that frequency.
+///
+/// `rate` is clamped to 3..=15, 8192 Hz down to 2 Hz. Rates 1 and 2 are unreliable.
+pub fn enable_periodic_interrupt(rate: u8) -> u32 {
+    let rate = rate.clamp(3, 15);
+    let frequency = BASE_FREQUENCY >> (rate - 1);
+    time::set_tick_period(time::TickSource::Rtc, 1_000_000_000 / frequency as u64);
+
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(REG_STATUS_A);
+        cmos.write(REG_STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+        // Nothing more arrives until C has been read.
+        cmos.read(REG_STATUS_C);
+
+        let mut pics = PICS.lock();
+        let [primary, secondary] = unsafe { pics.read_masks() };
+        unsafe {
+            pics.write_masks(
+                primary & !(1 << CASCADE_IRQ),
+                secondary & !(1 << (RTC_IRQ - 8)),
+            )
+        };
+    });
+
+    frequency
+}
+
+pub fn disable_periodic_interrupt() {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+        cmos.read(REG_STATUS_C);
+
+        let mut pics = PICS.lock();
+        let [primary, secondary] = unsafe { pics.read_masks() };
+        unsafe { pics.write_masks(primary, secondary | 1 << (RTC_IRQ - 8)) };
+    });
+}
+
+/// Called from the IRQ 8 handler.
+pub fn handle_interrupt() {
+    // Status register C says why the interrupt fired, the RTC stays quiet until it is read.
+    CMOS.lock().read(REG_STATUS_C);
+    time::tick(time::TickSource::Rtc);
+}
+
+//  ---Tests---
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let epoch = DateTime::from_unix_timestamp(0);
+    assert_eq!((epoch.year, epoch.month, epoch.day), (1970, 1, 1));
+
+    // 2024 is a leap year, this is the day after February 29th.
+    let date = DateTime {
+        year: 2024,
+        month: 3,
+        day: 1,
+        hour: 13,
+        minute: 37,
+        second: 42,
+    };
+    assert_eq!(date.to_unix_timestamp(), Some(1_709_300_262));
+    assert_eq!(DateTime::from_unix_timestamp(1_709_300_262), date);
+
+    // Century 19 and year 69, or garbage in the CMOS.
+    let before_epoch = DateTime { year: 1969, ..date };
+    assert_eq!(before_epoch.to_unix_timestamp(), None);
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() {
+    let raw = RawTime {
+        second: 0x59,
+        minute: 0x30,
+        hour: HOUR_PM | 0x12,
+        day: 0x31,
+        month: 0x12,
+        year: 0x99,
+        century: 0x19,
+    };
+    let date = decode(raw, 0);
+    assert_eq!((date.year, date.month, date.day), (1999, 12, 31));
+    assert_eq!((date.hour, date.minute, date.second), (12, 30, 59));
+
+    let midnight = RawTime { hour: 0x12, ..raw };
+    assert_eq!(decode(midnight, 0).hour, 0);
+}
+
+#[test_case]
+fn test_decode_binary_24_hour() {
+    let raw = RawTime {
+        second: 5,
+        minute: 4,
+        hour: 23,
+        day: 2,
+        month: 1,
+        year: 26,
+        century: 0,
+    };
+    let date = decode(raw, STATUS_B_BINARY | STATUS_B_24_HOUR);
+    assert_eq!((date.year, date.month, date.day), (2026, 1, 2));
+    assert_eq!((date.hour, date.minute, date.second), (23, 4, 5));
+}
@@ -0,0 +1,521 @@
+// Mod for timers and sleeping.
+// Timers live in a hierarchical timer wheel with millisecond resolution. Level 0 has a
+// slot per millisecond for the next 64 ms, every level above covers 64 times more
+// time with 64 times coarser slots. When the lower level wraps around, the matching
+// slot of the level above gets "cascaded" down, so adding and expiring stay O(1).
+//
+// The wheel is advanced from the timer interrupt, so callbacks run in interrupt context.
+// They must be short and must not wait on anything the interrupted code could hold,
+// which includes the heap, so a callback can cancel any timer, its own included, but
+// can't add new ones. TimerWheel::add checks for that. Other CPUs carry on as usual.
+// The wheel never allocates or frees from the interrupt: finished one-shot timers are
+// parked on a list and their callbacks are dropped the next time a timer is added or
+// cancelled outside a callback. Sleep wakes its waker by reference for the same reason,
+// the task drops it.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::smp;
+use crate::time::Instant;
+
+const LEVELS: usize = 6;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+// Timers further out than this, a bit over two years, get clamped.
+const MAX_DELAY_MS: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+const NANOS_PER_MILLI: u64 = 1_000_000;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+/// Handle for cancelling a timer. Stays safe to use after the timer is gone.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TimerId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Free,
+    Pending,
+    // Taken off the wheel and queued to run.
+    Expired,
+    Running,
+    // Cancelled from inside its own callback.
+    Cancelled,
+    // Done, waiting for its callback to be dropped.
+    Finished,
+}
+
+struct Entry {
+    state: State,
+    generation: u32,
+    expires: u64,
+    period: Option<u64>,
+    callback: Option<Callback>,
+    slot: (usize, usize),
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+struct TimerWheel {
+    // The next millisecond to be processed.
+    now: u64,
+    slots: [[Option<usize>; SLOTS]; LEVELS],
+    entries: Vec<Entry>,
+    // These three lists are linked through Entry::next.
+    free: Option<usize>,
+    finished: Option<usize>,
+    expired: Option<usize>,
+    expired_tail: Option<usize>,
+    // The CPU running callbacks, by smp::PerCpu::index. Nothing may be allocated or
+    // freed on it meanwhile.
+    callback_cpu: Option<usize>,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        TimerWheel {
+            now: 0,
+            slots: [[None; SLOTS]; LEVELS],
+            entries: Vec::new(),
+            free: None,
+            finished: None,
+            expired: None,
+            expired_tail: None,
+            callback_cpu: None,
+        }
+    }
+
+    fn in_callback(&self) -> bool {
+        self.callback_cpu.is_some() && self.callback_cpu == Some(smp::cpu_index())
+    }
+
+    fn is_current(&self, id: TimerId) -> bool {
+        self.entries
+            .get(id.index)
+            .is_some_and(|entry| entry.generation == id.generation)
+    }
+
+    // Puts a pending entry into the slot matching how far away it expires.
+    fn link(&mut self, index: usize) {
+        let expires = self.entries[index].expires.max(self.now);
+        let expires = expires.min(self.now + MAX_DELAY_MS);
+        let delta = expires - self.now;
+
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >> (SLOT_BITS * (level as u32 + 1)) != 0 {
+            level += 1;
+        }
+        let slot = ((expires >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+
+        let head = self.slots[level][slot];
+        if let Some(head) = head {
+            self.entries[head].prev = Some(index);
+        }
+        let entry = &mut self.entries[index];
+        entry.slot = (level, slot);
+        entry.prev = None;
+        entry.next = head;
+        self.slots[level][slot] = Some(index);
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (level, slot) = self.entries[index].slot;
+        let prev = self.entries[index].prev.take();
+        let next = self.entries[index].next.take();
+
+        match prev {
+            Some(prev) => self.entries[prev].next = next,
+            None => self.slots[level][slot] = next,
+        }
+        if let Some(next) = next {
+            self.entries[next].prev = prev;
+        }
+    }
+
+    fn push_expired(&mut self, index: usize) {
+        self.entries[index].state = State::Expired;
+        self.entries[index].next = None;
+        match self.expired_tail {
+            Some(tail) => self.entries[tail].next = Some(index),
+            None => self.expired = Some(index),
+        }
+        self.expired_tail = Some(index);
+    }
+
+    fn push_finished(&mut self, index: usize) {
+        self.entries[index].state = State::Finished;
+        self.entries[index].next = self.finished;
+        self.finished = Some(index);
+    }
+
+    // Moves the whole slot into a local list and links every entry again relative to now.
+    fn cascade(&mut self, level: usize, slot: usize) {
+        let mut next = self.slots[level][slot].take();
+        while let Some(index) = next {
+            next = self.entries[index].next;
+            self.link(index);
+        }
+    }
+
+    /// Processes every millisecond up to and including `target`, queueing what expires.
+    fn advance(&mut self, target: u64) {
+        while self.now <= target {
+            if self.now & SLOT_MASK == 0 {
+                for level in 1..LEVELS {
+                    let slot = ((self.now >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+                    self.cascade(level, slot);
+                    if slot != 0 {
+                        break;
+                    }
+                }
+            }
+
+            let slot = (self.now & SLOT_MASK) as usize;
+            let mut next = self.slots[0][slot].take();
+            while let Some(index) = next {
+                next = self.entries[index].next;
+                self.entries[index].prev = None;
+                self.push_expired(index);
+            }
+
+            self.now += 1;
+        }
+    }
+
+    fn pop_expired(&mut self) -> Option<(usize, Callback)> {
+        let index = self.expired?;
+        self.expired = self.entries[index].next.take();
+        if self.expired.is_none() {
+            self.expired_tail = None;
+        }
+
+        let entry = &mut self.entries[index];
+        entry.state = State::Running;
+        let callback = entry.callback.take()?;
+        Some((index, callback))
+    }
+
+    // Called after a callback ran. Periodic timers go back on the wheel.
+    fn finish(&mut self, index: usize, callback: Callback) {
+        let now = self.now;
+        let entry = &mut self.entries[index];
+        entry.callback = Some(callback);
+
+        match (entry.state, entry.period) {
+            (State::Running, Some(period)) => {
+                // Keep the original schedule, unless we fell so far behind that it would burst.
+                entry.expires = (entry.expires + period).max(now);
+                entry.state = State::Pending;
+                self.link(index);
+            }
+            _ => self.push_finished(index),
+        }
+    }
+
+    // Drops the callbacks of finished timers and frees their entries.
+    fn reap(&mut self) {
+        if self.in_callback() {
+            return;
+        }
+
+        while let Some(index) = self.finished {
+            let entry = &mut self.entries[index];
+            self.finished = entry.next;
+            entry.callback = None;
+            entry.state = State::Free;
+            entry.generation = entry.generation.wrapping_add(1);
+            entry.next = self.free;
+            self.free = Some(index);
+        }
+    }
+
+    fn add(&mut self, expires: u64, period: Option<u64>, callback: Callback) -> TimerId {
+        assert!(
+            !self.in_callback(),
+            "timers can't be added from a timer callback"
+        );
+        self.reap();
+
+        let entry = Entry {
+            state: State::Pending,
+            generation: 0,
+            expires,
+            period,
+            callback: Some(callback),
+            slot: (0, 0),
+            prev: None,
+            next: None,
+        };
+        let index = match self.free {
+            Some(index) => {
+                self.free = self.entries[index].next;
+                let generation = self.entries[index].generation;
+                self.entries[index] = Entry {
+                    generation,
+                    ..entry
+                };
+                index
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+
+        self.link(index);
+        TimerId {
+            index,
+            generation: self.entries[index].generation,
+        }
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        if !self.is_current(id) {
+            return false;
+        }
+
+        let cancelled = match self.entries[id.index].state {
+            State::Pending => {
+                self.unlink(id.index);
+                self.push_finished(id.index);
+                true
+            }
+            State::Expired => {
+                // Already queued to run, pull it out of the expired list.
+                let mut prev = None;
+                let mut next = self.expired;
+                while let Some(index) = next {
+                    if index == id.index {
+                        break;
+                    }
+                    prev = Some(index);
+                    next = self.entries[index].next;
+                }
+                let after = self.entries[id.index].next;
+                match prev {
+                    Some(prev) => self.entries[prev].next = after,
+                    None => self.expired = after,
+                }
+                if self.expired_tail == Some(id.index) {
+                    self.expired_tail = prev;
+                }
+                self.push_finished(id.index);
+                true
+            }
+            State::Running => {
+                self.entries[id.index].state = State::Cancelled;
+                true
+            }
+            State::Free | State::Cancelled | State::Finished => false,
+        };
+
+        self.reap();
+        cancelled
+    }
+
+    fn is_pending(&self, id: TimerId) -> bool {
+        self.is_current(id)
+            && match self.entries[id.index].state {
+                State::Pending | State::Expired | State::Running => true,
+                State::Free | State::Cancelled | State::Finished => false,
+            }
+    }
+}
+
+// Only locked with interrupts off outside the interrupt handler, see run_expired.
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+fn now_nanos() -> u64 {
+    Instant::now().since_boot().as_nanos() as u64
+}
+
+// The millisecond at which `delay` from now has fully passed.
+fn deadline_ms(delay: Duration) -> u64 {
+    let deadline = now_nanos() + delay.as_nanos() as u64;
+    deadline.div_ceil(NANOS_PER_MILLI)
+}
+
+//  ---Timers---
+
+/// Runs `callback` once, from the timer interrupt, after at least `delay`.
+pub fn add_one_shot<F>(delay: Duration, callback: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    let expires = deadline_ms(delay);
+    without_interrupts(|| WHEEL.lock().add(expires, None, Box::new(callback)))
+}
+
+/// Runs `callback` every `period`, from the timer interrupt, until cancelled.
+///
+/// Periods under a millisecond are rounded up to one.
+pub fn add_periodic<F>(period: Duration, callback: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    let period_ms = (period.as_millis() as u64).max(1);
+    let expires = deadline_ms(period);
+    without_interrupts(|| {
+        WHEEL
+            .lock()
+            .add(expires, Some(period_ms), Box::new(callback))
+    })
+}
+
+/// Stops a timer. Returns false if it already fired (one-shot) or was cancelled before.
+///
+/// Can be called from inside the timer's own callback.
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| WHEEL.lock().cancel(id))
+}
+
+/// Whether the timer is still going to fire.
+pub fn is_pending(id: TimerId) -> bool {
+    without_interrupts(|| WHEEL.lock().is_pending(id))
+}
+
+/// Called by the time mod on every tick.
+pub(crate) fn run_expired() {
+    let target = now_nanos() / NANOS_PER_MILLI;
+
+    // Interrupt handlers run with interrupts off, and everyone else takes the lock with
+    // interrupts off, so this can't deadlock against the code we interrupted.
+    let mut wheel = WHEEL.lock();
+    wheel.advance(target);
+    wheel.callback_cpu = Some(smp::cpu_index());
+
+    // The lock is dropped while a callback runs so it can cancel timers, adding them
+    // would allocate and is refused.
+    while let Some((index, mut callback)) = wheel.pop_expired() {
+        drop(wheel);
+        callback();
+        wheel = WHEEL.lock();
+        wheel.finish(index, callback);
+    }
+    wheel.callback_cpu = None;
+}
+
+/// Waits for `duration` by halting between ticks. Interrupts have to be enabled.
+pub fn delay(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+//  ---Futures---
+
+/// Future returned by `sleep`.
+pub struct Sleep {
+    deadline: Instant,
+    waker: Arc<Mutex<Option<Waker>>>,
+    timer: Option<TimerId>,
+}
+
+/// Returns a future that completes after `duration`.
+///
+/// The waker gets woken from the timer interrupt.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        waker: Arc::new(Mutex::new(None)),
+        timer: None,
+    }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            if let Some(timer) = self.timer.take() {
+                cancel(timer);
+            }
+            return Poll::Ready(());
+        }
+
+        let waker = cx.waker().clone();
+        without_interrupts(|| *self.waker.lock() = Some(waker));
+
+        // Arm a timer the first time, or again if the last one fired a little early.
+        if !self.timer.is_some_and(is_pending) {
+            let shared = self.waker.clone();
+            let timer = add_one_shot(self.deadline - now, move || {
+                if let Some(waker) = &*shared.lock() {
+                    waker.wake_by_ref();
+                }
+            });
+            self.timer = Some(timer);
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            cancel(timer);
+        }
+    }
+}
+
+/// Error from `timeout` when the time ran out first.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Elapsed;
+
+/// Future returned by `timeout`.
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Runs `future` but gives up with `Elapsed` once `duration` has passed.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The inner future is never moved out of self, so pinning it in place is fine.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
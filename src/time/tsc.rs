@@ -0,0 +1,108 @@
+// Mod for the time stamp counter.
+// The TSC counts CPU cycles (or a fixed reference clock on newer CPUs) and is read in
+// a single instruction, which makes it the cheapest high resolution clock we have.
+// Its frequency isn't reported anywhere reliable, so init times it against PIT channel 2.
+// Only an invariant TSC (constant rate through P-states and C-states) is used to drive
+// Instant, on older CPUs it is still readable but the tick clock stays in charge.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::time::pit;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// 10 ms of PIT cycles. Long enough that reading the ports adds little error.
+const CALIBRATION_COUNT: u16 = (pit::BASE_FREQUENCY / 100) as u16;
+const CALIBRATION_RUNS: usize = 5;
+// Reading port 0x61 takes about a microsecond, so this is around a second, a hundred
+// times a run. Where channel 2 never finishes the TSC is left off.
+const MAX_POLLS: u32 = 1_000_000;
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+// Cycles per second.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// Nanoseconds per cycle as a 32.32 fixed point number, so converting is one multiply.
+static NANOS_PER_CYCLE: AtomicU64 = AtomicU64::new(0);
+// The TSC value and the monotonic time at calibration, nanos() counts on from there.
+static BASE_CYCLES: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Reads the raw counter.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether CPUID says the TSC runs at a constant rate no matter the power state.
+// Newer compilers made __cpuid safe to call, older ones still need the unsafe block.
+#[allow(unused_unsafe)]
+pub fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(CPUID_EXTENDED_MAX) }.eax;
+    if max_extended < CPUID_POWER_MANAGEMENT {
+        return false;
+    }
+
+    unsafe { __cpuid(CPUID_POWER_MANAGEMENT) }.edx & CPUID_INVARIANT_TSC != 0
+}
+
+/// Cycles per second, or 0 before calibration.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Whether Instant and SystemTime are driven by the TSC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// One run of the stopwatch, returns the cycles it took. None if it never stopped.
+fn measure() -> Option<u64> {
+    pit::arm_channel_2(CALIBRATION_COUNT);
+    let start = read();
+    pit::start_channel_2();
+    if !(0..MAX_POLLS).any(|_| pit::channel_2_done()) {
+        return None;
+    }
+    Some(read() - start)
+}
+
+/// Works out the TSC frequency against the PIT.
+///
+/// `now_nanos` is the monotonic time right now, nanos() continues from it so the
+/// clock doesn't jump when it switches over. Returns the frequency in Hz, 0 if channel 2
+/// doesn't work and the TSC stays off.
+pub(crate) fn calibrate(now_nanos: u64) -> u64 {
+    // An SMI or a hiccup on the host can only make a run longer, so the shortest one wins.
+    // If one run never finishes the rest won't either, so it gives up right away.
+    let cycles = (0..CALIBRATION_RUNS)
+        .map(|_| measure())
+        .try_fold(u64::MAX, |shortest, run| Some(shortest.min(run?)))
+        .unwrap_or(0);
+    let frequency = cycles * pit::BASE_FREQUENCY as u64 / CALIBRATION_COUNT as u64;
+    if frequency == 0 {
+        return 0;
+    }
+
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    NANOS_PER_CYCLE.store((NANOS_PER_SEC << 32) / frequency, Ordering::Relaxed);
+    BASE_NANOS.store(now_nanos, Ordering::Relaxed);
+    BASE_CYCLES.store(read(), Ordering::Relaxed);
+    ENABLED.store(is_invariant(), Ordering::Relaxed);
+    frequency
+}
+
+/// Converts a number of cycles to nanoseconds. Returns 0 before calibration.
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    let scale = NANOS_PER_CYCLE.load(Ordering::Relaxed);
+    ((cycles as u128 * scale as u128) >> 32) as u64
+}
+
+/// Nanoseconds since boot, measured with the TSC.
+pub fn nanos() -> u64 {
+    let cycles = read().saturating_sub(BASE_CYCLES.load(Ordering::Relaxed));
+    BASE_NANOS.load(Ordering::Relaxed) + cycles_to_nanos(cycles)
+}
@@ -1,10 +1,43 @@
 // Mod for writing to the screen using the VGA port 0xb8000
+// With a framebuffer there is no text mode, print! goes to framebuffer::CONSOLE then.
+// ANSI escape sequences set the colors and move the cursor, see ansi.rs.
+// The blinking hardware cursor follows the writer, it's set through the CRT controller.
+// Lines scrolled off the top are kept, Shift+PageUp/PageDown on the keyboard pages
+// through them. Any output jumps back down. The framebuffer console does the same.
 
 use core::fmt;
+use core::ops::Range;
+use core::ptr;
 
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::ansi::{Action, Erase, Parser, Style, BACKSPACE, BELL, TAB_WIDTH};
+use crate::framebuffer::CONSOLE;
+use crate::memory;
+use crate::sync::IrqSafeSpinlock;
+
+// Physical, the bootloader doesn't identity map it.
+const BUFFER_ADDRESS: u64 = 0xb8000;
+
+// What ESC [ 0 m goes back to.
+const DEFAULT_FOREGROUND: Color = Color::Green;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+
+// CRT controller registers, reached by writing the index and then the data port
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_END: u8 = 0x0b;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+// In CURSOR_START, hides the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+// Lines kept after they scroll off the top, four screens' worth
+const SCROLLBACK_LINES: usize = 100;
 
 // Create print macro by using built-in code but changing it to call our print function
 #[macro_export]
@@ -22,9 +55,45 @@ macro_rules! println {
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    match CONSOLE.get() {
+        Some(console) => console.lock().write_fmt(args).unwrap(),
+        None => WRITER.lock().write_fmt(args).unwrap(),
+    }
+}
+
+/// Shows the previous screen of scrollback, on whichever console print! goes to.
+pub fn page_up() {
+    match CONSOLE.get() {
+        Some(console) => {
+            let mut console = console.lock();
+            let page = console.rows() - 1;
+            console.scroll_back(page);
+        }
+        None => WRITER.lock().scroll_back(BUFFER_HEIGHT - 1),
+    }
+}
+
+/// Shows the next screen of scrollback, back down to the live screen.
+pub fn page_down() {
+    match CONSOLE.get() {
+        Some(console) => {
+            let mut console = console.lock();
+            let page = console.rows() - 1;
+            console.scroll_forward(page);
+        }
+        None => WRITER.lock().scroll_forward(BUFFER_HEIGHT - 1),
+    }
+}
+
+#[doc(hidden)]
+pub fn _emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let spins = crate::emergency::LOCK_SPINS;
+    let _ = match CONSOLE.get() {
+        Some(console) => unsafe { console.force_lock(spins) }.write_fmt(args),
+        None => unsafe { WRITER.force_lock(spins) }.write_fmt(args),
+    };
 }
 
 // VGA Colors
@@ -74,6 +143,11 @@ struct ScreenChar {
     color: ColorCode,
 }
 
+const BLANK: ScreenChar = ScreenChar {
+    ascii_char: b' ',
+    color: ColorCode(0),
+};
+
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
@@ -83,16 +157,178 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+// Ring of the lines that scrolled off, oldest first
+// The live screen is kept in live_lines while the scrollback is showing
+struct Scrollback {
+    lines: [Line; SCROLLBACK_LINES],
+    start: usize,
+    len: usize,
+    live_lines: [Line; BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            lines: [[BLANK; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            start: 0,
+            len: 0,
+            live_lines: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        }
+    }
+
+    // Once it's full the oldest line makes room
+    fn push(&mut self, line: Line) {
+        let end = (self.start + self.len) % SCROLLBACK_LINES;
+        self.lines[end] = line;
+        if self.len < SCROLLBACK_LINES {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    fn get(&self, index: usize) -> &Line {
+        &self.lines[(self.start + index) % SCROLLBACK_LINES]
+    }
+}
+
+/// What the hardware cursor looks like, in a 16 scan line character.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CursorShape {
+    Underline,
+    HalfBlock,
+    Block,
+}
+
+impl CursorShape {
+    // First and last scan line
+    fn scan_lines(self) -> (u8, u8) {
+        match self {
+            CursorShape::Underline => (14, 15),
+            CursorShape::HalfBlock => (8, 15),
+            CursorShape::Block => (0, 15),
+        }
+    }
+}
+
+// The CRT controller ports, for the hardware cursor
+struct Crtc {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Crtc {
+    const fn new() -> Self {
+        Crtc {
+            index: Port::new(CRTC_INDEX),
+            data: Port::new(CRTC_DATA),
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+}
+
 // Writer struct which has a buffer and items needed for that buffer
-pub struct Writer {
+pub struct Writer<'a> {
     column_pos: usize,
+    row_pos: usize,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    buffer: &'a mut Buffer,
+    // Escape sequences are put together here, the colors they pick are kept in style
+    parser: Parser,
+    style: Style,
+    saved_pos: (usize, usize),
+    // None when the buffer isn't the one on screen
+    crtc: Option<Crtc>,
+    cursor_shape: CursorShape,
+    cursor_visible: bool,
+    // Kept apart, it's too big to move around with the writer
+    scrollback: &'a mut Scrollback,
+    // How many lines back the screen shows
+    view_offset: usize,
 }
 
-impl Writer {
+impl<'a> Writer<'a> {
+    // Start on the bottom line, text scrolls up from there, with an empty scrollback
+    fn new(buffer: &'a mut Buffer, scrollback: &'a mut Scrollback, crtc: Option<Crtc>) -> Self {
+        scrollback.start = 0;
+        scrollback.len = 0;
+        let style = Style::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+        Writer {
+            column_pos: 0,
+            row_pos: BUFFER_HEIGHT - 1,
+            color_code: ColorCode::new(style.background(), style.foreground()),
+            buffer,
+            parser: Parser::new(),
+            style,
+            saved_pos: (BUFFER_HEIGHT - 1, 0),
+            crtc,
+            cursor_shape: CursorShape::Underline,
+            cursor_visible: true,
+            scrollback,
+            view_offset: 0,
+        }
+    }
+}
+
+impl Writer<'_> {
+    /// The row and column the next character goes to.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row_pos, self.column_pos)
+    }
+
+    /// Moves to `row`, `col`, counting from the top left. Both are clamped to the screen.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.scroll_to_live();
+        self.row_pos = row.min(BUFFER_HEIGHT - 1);
+        self.column_pos = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.cursor_shape = shape;
+        self.update_cursor();
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+        self.update_cursor();
+    }
+
+    // Move the hardware cursor to where the next character goes
+    // It's hidden while the scrollback is showing
+    fn update_cursor(&mut self) {
+        let (start, end) = self.cursor_shape.scan_lines();
+        let hidden = !self.cursor_visible || self.view_offset > 0;
+        // Right after the last column it stays on the last column until the next char
+        let location = self.row_pos * BUFFER_WIDTH + self.column_pos.min(BUFFER_WIDTH - 1);
+
+        let start = if hidden {
+            start | CURSOR_DISABLE
+        } else {
+            start
+        };
+
+        if let Some(crtc) = &mut self.crtc {
+            crtc.write(CURSOR_START, start);
+            crtc.write(CURSOR_END, end);
+            crtc.write(CURSOR_LOCATION_HIGH, (location >> 8) as u8);
+            crtc.write(CURSOR_LOCATION_LOW, location as u8);
+        }
+    }
+}
+
+impl Writer<'_> {
     // Check the char or byte and then write to buffer
     pub fn write_byte(&mut self, char: u8) {
+        self.scroll_to_live();
         match char {
             b'\n' => self.new_line(),
             char => {
@@ -102,7 +338,7 @@ impl Writer {
                 }
 
                 let col = self.column_pos;
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_pos;
 
                 self.buffer.chars[row][col].write(ScreenChar {
                     ascii_char: char,
@@ -115,10 +351,18 @@ impl Writer {
     }
 }
 
-impl Writer {
-    // Move everything up one line deleting the top line
+impl Writer<'_> {
+    // Go down a line, on the bottom line move everything up one line deleting the top line
     // Delete the bottom line when done
     fn new_line(&mut self) {
+        self.column_pos = 0;
+        if self.row_pos < BUFFER_HEIGHT - 1 {
+            self.row_pos += 1;
+            return;
+        }
+
+        let top_line = self.read_line(0);
+        self.scrollback.push(top_line);
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -127,37 +371,168 @@ impl Writer {
         }
 
         self.clear_line(BUFFER_HEIGHT - 1);
-        self.column_pos = 0;
     }
 }
 
-impl Writer {
+impl Writer<'_> {
     // Move across a line and replace each char with a blank or "null" char.
     fn clear_line(&mut self, row: usize) {
+        self.clear_cols(row, 0..BUFFER_WIDTH);
+    }
+
+    // Blanks keep the background color, like a terminal's
+    fn clear_cols(&mut self, row: usize, cols: Range<usize>) {
         let null_char = ScreenChar {
             ascii_char: b' ',
-            color: ColorCode::new(Color::Black, Color::Black),
+            color: self.color_code,
         };
 
-        for col in 0..BUFFER_WIDTH {
+        for col in cols {
             self.buffer.chars[row][col].write(null_char);
         }
     }
 }
 
-impl Writer {
-    // Turn the string into bytes and call write_byte foreach
+impl Writer<'_> {
+    // Turn the string into bytes and run them through the ANSI parser
     pub fn write_string(&mut self, str: &str) {
+        self.scroll_to_live();
         for char in str.bytes() {
-            match char {
-                0x20..=0x7e | b'\n' => self.write_byte(char),
-                _ => self.write_byte(0xfe),
+            if let Some(action) = self.parser.advance(char) {
+                self.perform(action);
+            }
+        }
+        self.update_cursor();
+    }
+
+    // Do what the parser says, the cursor never leaves the screen
+    fn perform(&mut self, action: Action) {
+        let last_row = BUFFER_HEIGHT - 1;
+        let last_col = BUFFER_WIDTH - 1;
+
+        match action {
+            Action::Print(char @ 0x20..=0x7e) => self.write_byte(char),
+            Action::Control(b'\n') => self.new_line(),
+            Action::Control(b'\r') => self.column_pos = 0,
+            Action::Control(b'\t') => {
+                self.column_pos = ((self.column_pos / TAB_WIDTH + 1) * TAB_WIDTH).min(last_col)
+            }
+            Action::Control(BACKSPACE) => {
+                self.column_pos = self.column_pos.min(last_col).saturating_sub(1)
+            }
+            Action::Control(BELL) => {}
+            Action::Print(_) | Action::Control(_) => self.write_byte(0xfe),
+            Action::CursorUp(count) => self.row_pos = self.row_pos.saturating_sub(count),
+            Action::CursorDown(count) => self.row_pos = (self.row_pos + count).min(last_row),
+            Action::CursorForward(count) => {
+                self.column_pos = (self.column_pos + count).min(last_col)
+            }
+            Action::CursorBack(count) => self.column_pos = self.column_pos.saturating_sub(count),
+            Action::CursorPosition { row, column } => {
+                self.row_pos = row.min(last_row);
+                self.column_pos = column.min(last_col);
+            }
+            Action::EraseDisplay(erase) => {
+                let rows = match erase {
+                    Erase::ToEnd => self.row_pos + 1..BUFFER_HEIGHT,
+                    Erase::ToStart => 0..self.row_pos,
+                    Erase::All => 0..BUFFER_HEIGHT,
+                };
+                for row in rows {
+                    self.clear_line(row);
+                }
+                if erase != Erase::All {
+                    self.perform(Action::EraseLine(erase));
+                }
+            }
+            Action::EraseLine(erase) => {
+                // Right after the last column the cursor is past the end of the line
+                let col = self.column_pos.min(last_col);
+                let cols = match erase {
+                    Erase::ToEnd => col..BUFFER_WIDTH,
+                    Erase::ToStart => 0..col + 1,
+                    Erase::All => 0..BUFFER_WIDTH,
+                };
+                self.clear_cols(self.row_pos, cols);
+            }
+            Action::ShowCursor(visible) => self.cursor_visible = visible,
+            Action::SaveCursor => self.saved_pos = (self.row_pos, self.column_pos),
+            Action::RestoreCursor => (self.row_pos, self.column_pos) = self.saved_pos,
+            Action::SelectGraphics(params) => {
+                self.style.apply(&params);
+                self.set_style();
+            }
+            Action::Reset => {
+                self.style.reset();
+                self.set_style();
+                self.perform(Action::EraseDisplay(Erase::All));
+                self.row_pos = 0;
+                self.column_pos = 0;
+            }
+        }
+    }
+
+    fn set_style(&mut self) {
+        self.color_code = ColorCode::new(self.style.background(), self.style.foreground());
+    }
+}
+
+impl Writer<'_> {
+    /// Shows `lines` older lines, as far back as the scrollback goes.
+    pub fn scroll_back(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset + lines);
+    }
+
+    /// Shows `lines` newer lines, as far as the live screen.
+    pub fn scroll_forward(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_sub(lines));
+    }
+
+    fn scroll_to_live(&mut self) {
+        if self.view_offset > 0 {
+            self.set_view_offset(0);
+        }
+    }
+
+    // The scrollback and the live screen are one list of lines, the screen shows
+    // BUFFER_HEIGHT of them ending view_offset lines before the end
+    fn set_view_offset(&mut self, offset: usize) {
+        let offset = offset.min(self.scrollback.len);
+        if offset == self.view_offset {
+            return;
+        }
+        if self.view_offset == 0 {
+            for row in 0..BUFFER_HEIGHT {
+                self.scrollback.live_lines[row] = self.read_line(row);
+            }
+        }
+        self.view_offset = offset;
+
+        let top = self.scrollback.len - offset;
+        for row in 0..BUFFER_HEIGHT {
+            let index = top + row;
+            let line = if index < self.scrollback.len {
+                *self.scrollback.get(index)
+            } else {
+                self.scrollback.live_lines[index - self.scrollback.len]
+            };
+            for (char, &value) in self.buffer.chars[row].iter_mut().zip(line.iter()) {
+                char.write(value);
             }
         }
+        self.update_cursor();
+    }
+
+    fn read_line(&self, row: usize) -> Line {
+        let mut line = [BLANK; BUFFER_WIDTH];
+        for (col, char) in line.iter_mut().enumerate() {
+            *char = self.buffer.chars[row][col].read();
+        }
+        line
     }
 }
 
-impl fmt::Write for Writer {
+impl fmt::Write for Writer<'_> {
     // fmt's write_str function
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
@@ -168,13 +543,19 @@ impl fmt::Write for Writer {
 // Raw pointers cannot be determined at compile time.
 // Lazy statics don't initialize until the first use which is at runtime.
 // Our OS doesn't have threads, but we need "thread safety".
-// We are using a spin mutex which means a thread spins or loops and keeps asking to lock until it can lock.
+// We are using a spinlock which means a thread spins or loops and keeps asking to lock until it can lock.
+// It keeps interrupts off while locked, so an interrupt handler printing can't deadlock with us.
+// The buffer is reached through the physical memory mapping, boot::take sets that up.
+// The scrollback is too big to build on the stack, so it's a static of its own. This
+// only runs once, so it's the only reference to it.
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_pos: 0,
-        color_code: ColorCode::new(Color::Black, Color::Green),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: IrqSafeSpinlock<Writer<'static>> = {
+        static mut SCROLLBACK: Scrollback = Scrollback::new();
+
+        let buffer = memory::phys_to_virt(PhysAddr::new(BUFFER_ADDRESS)).as_mut_ptr::<Buffer>();
+        let (buffer, scrollback) = unsafe { (&mut *buffer, &mut *ptr::addr_of_mut!(SCROLLBACK)) };
+        IrqSafeSpinlock::new(Writer::new(buffer, scrollback, Some(Crtc::new())))
+    };
 }
 
 //  ---Tests---
@@ -189,3 +570,104 @@ fn test_println_many() {
         println!("test_println_many output")
     }
 }
+
+// The scrollback is too big for the test stack, the tests take turns with this one.
+#[cfg(test)]
+static TEST_SCROLLBACK: IrqSafeSpinlock<Scrollback> = IrqSafeSpinlock::new(Scrollback::new());
+
+// Like WRITER, on a buffer of its own.
+#[cfg(test)]
+fn test_writer(test: impl FnOnce(&mut Writer)) {
+    // All zeros is a valid buffer, black NULs.
+    let mut buffer: Buffer = unsafe { core::mem::zeroed() };
+    let mut scrollback = TEST_SCROLLBACK.lock();
+    test(&mut Writer::new(&mut buffer, &mut scrollback, None));
+}
+
+#[cfg(test)]
+fn char_at(writer: &Writer, row: usize, col: usize) -> ScreenChar {
+    writer.buffer.chars[row][col].read()
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    test_writer(|writer| {
+        writer.write_string("\x1b[31;47ma\x1b[1mb\x1b[0mc");
+        let row = BUFFER_HEIGHT - 1;
+        assert_eq!(
+            char_at(writer, row, 0).color,
+            ColorCode::new(Color::LightGray, Color::Red)
+        );
+        assert_eq!(
+            char_at(writer, row, 1).color,
+            ColorCode::new(Color::LightGray, Color::LightRed)
+        );
+        assert_eq!(
+            char_at(writer, row, 2).color,
+            ColorCode::new(Color::Black, Color::Green)
+        );
+        assert_eq!(char_at(writer, row, 2).ascii_char, b'c');
+    });
+}
+
+#[test_case]
+fn test_ansi_cursor() {
+    test_writer(|writer| {
+        writer.write_string("\x1b[2J\x1b[3;5Hx\x1b[1;1Hyz\x1b7\x1b[1;2H\x1b[K\x1b8w");
+        assert_eq!(char_at(writer, 2, 4).ascii_char, b'x');
+        assert_eq!(char_at(writer, 0, 0).ascii_char, b'y');
+        assert_eq!(char_at(writer, 0, 1).ascii_char, b' ');
+        assert_eq!(char_at(writer, 0, 2).ascii_char, b'w');
+    });
+}
+
+#[test_case]
+fn test_control_chars() {
+    test_writer(|writer| {
+        writer.write_string("ab\rc\td\x08e");
+        let row = BUFFER_HEIGHT - 1;
+        assert_eq!(char_at(writer, row, 0).ascii_char, b'c');
+        assert_eq!(char_at(writer, row, 1).ascii_char, b'b');
+        assert_eq!(char_at(writer, row, TAB_WIDTH).ascii_char, b'e');
+        assert_eq!(writer.position(), (row, TAB_WIDTH + 1));
+    });
+}
+
+#[test_case]
+fn test_set_position() {
+    test_writer(|writer| {
+        writer.set_position(3, 70);
+        writer.write_string("x");
+        assert_eq!(char_at(writer, 3, 70).ascii_char, b'x');
+        writer.set_position(100, 100);
+        assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1));
+    });
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+
+    test_writer(|writer| {
+        for line in 0..30 {
+            writeln!(writer, "{}", line).unwrap();
+        }
+        // The bottom line is empty, the cursor is on it
+        let row = BUFFER_HEIGHT - 2;
+        assert_eq!(char_at(writer, row, 0).ascii_char, b'2');
+        assert_eq!(char_at(writer, row, 1).ascii_char, b'9');
+
+        writer.scroll_back(5);
+        assert_eq!(char_at(writer, row, 1).ascii_char, b'4');
+        writer.scroll_back(SCROLLBACK_LINES * 2);
+        assert_eq!(writer.view_offset, writer.scrollback.len);
+        writer.scroll_forward(writer.scrollback.len - 5);
+        assert_eq!(char_at(writer, row, 1).ascii_char, b'4');
+
+        // Output goes back to the live screen first
+        writer.write_string("x");
+        assert_eq!(writer.view_offset, 0);
+        assert_eq!(char_at(writer, row, 1).ascii_char, b'9');
+        assert_eq!(char_at(writer, row + 1, 0).ascii_char, b'x');
+    });
+}
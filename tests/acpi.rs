@@ -0,0 +1,175 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use x86_64::PhysAddr;
+
+use jonathan_os::acpi::madt::{InterruptOverride, Madt};
+use jonathan_os::acpi::{self, mcfg};
+use jonathan_os::apic::{self, Polarity, Trigger};
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::memory::BootInfoFrameAllocator;
+use jonathan_os::{allocator, entry_point, memory, power};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+    apic::init().expect("APIC init failed");
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+// A table header with the given signature and length, checksum left at 0.
+fn header(signature: &[u8; 4], length: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(signature);
+    bytes.extend_from_slice(&(length as u32).to_le_bytes());
+    bytes.resize(36, 0);
+    bytes
+}
+
+fn fix_checksum(bytes: &mut [u8]) {
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes[9] = bytes[9].wrapping_sub(sum);
+}
+
+//  ---Tests---
+
+#[test_case]
+fn checksum() {
+    let mut bytes = header(b"TEST", 40);
+    bytes.extend_from_slice(&[1, 2, 3, 4]);
+    fix_checksum(&mut bytes);
+    assert!(acpi::checksum_ok(&bytes));
+
+    bytes[37] ^= 0xFF;
+    assert!(!acpi::checksum_ok(&bytes));
+}
+
+#[test_case]
+fn parse_synthetic_madt() {
+    let mut bytes = header(b"APIC", 0);
+    bytes.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    // Local APIC: processor 0, APIC ID 0, enabled.
+    bytes.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    // Local APIC: processor 1, APIC ID 2, online capable.
+    bytes.extend_from_slice(&[0, 8, 1, 2, 2, 0, 0, 0]);
+    // I/O APIC 1 at 0xFEC00000, GSI base 0.
+    bytes.extend_from_slice(&[1, 12, 1, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+    // ISA IRQ 9 on GSI 9, level triggered and active high.
+    bytes.extend_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0b1101, 0]);
+    let length = bytes.len() as u32;
+    bytes[4..8].copy_from_slice(&length.to_le_bytes());
+
+    let madt = Madt::parse(&bytes).unwrap();
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xFEE0_0000));
+    assert!(madt.has_legacy_pics);
+    assert_eq!(madt.processors.len(), 2);
+    assert!(madt.processors[0].enabled);
+    assert!(!madt.processors[1].enabled && madt.processors[1].usable());
+    assert_eq!(madt.processors[1].apic_id, 2);
+    assert_eq!(madt.io_apics[0].address, PhysAddr::new(0xFEC0_0000));
+    assert_eq!(
+        madt.interrupt_overrides[0],
+        InterruptOverride {
+            bus: 0,
+            source: 9,
+            gsi: 9,
+            trigger: Some(Trigger::Level),
+            polarity: Some(Polarity::ActiveHigh),
+        }
+    );
+
+    // An entry running past the end of the table.
+    bytes.extend_from_slice(&[0, 8, 2]);
+    assert!(Madt::parse(&bytes).is_err());
+}
+
+#[test_case]
+fn parse_synthetic_mcfg() {
+    let mut bytes = header(b"MCFG", 60);
+    bytes.resize(44, 0);
+    bytes.extend_from_slice(&0xB000_0000u64.to_le_bytes());
+    bytes.extend_from_slice(&[0, 0, 0, 0xFF, 0, 0, 0, 0]);
+
+    let segments = mcfg::parse(&bytes).unwrap();
+    assert_eq!(segments.len(), 1);
+    let segment = segments[0];
+    assert_eq!(
+        segment.config_address(1, 2, 3),
+        Some(PhysAddr::new(
+            0xB000_0000 + (1 << 20) + (2 << 15) + (3 << 12)
+        ))
+    );
+    assert_eq!(segment.config_address(0, 32, 0), None);
+
+    // A base address above 52 bits can't be a physical address.
+    bytes[44..52].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert_eq!(
+        mcfg::parse(&bytes),
+        Err(acpi::AcpiError::Unsupported(*b"MCFG"))
+    );
+}
+
+#[test_case]
+fn platform_has_boot_processor() {
+    let platform = acpi::platform().unwrap();
+    let boot_id = u32::from(apic::local_apic_id().unwrap());
+    assert!(platform
+        .processors
+        .iter()
+        .any(|processor| processor.enabled && processor.apic_id == boot_id));
+}
+
+#[test_case]
+fn platform_interrupt_routing() {
+    let platform = acpi::platform().unwrap();
+    assert!(platform.has_legacy_pics);
+    assert_eq!(platform.io_apic_for_gsi(0).unwrap().gsi_base, 0);
+
+    // QEMU wires the PIT to GSI 2, the keyboard has no override.
+    assert_eq!(platform.isa_interrupt(0).gsi, 2);
+    assert_eq!(platform.isa_interrupt(1).gsi, 1);
+}
+
+#[test_case]
+fn platform_fadt_and_dsdt() {
+    let fadt = acpi::platform().unwrap().fadt.expect("no FADT");
+    assert_ne!(fadt.sci_interrupt, 0);
+    assert_ne!(fadt.pm1a_control_block, 0);
+
+    let dsdt = acpi::table_at(fadt.dsdt_address).unwrap();
+    assert_eq!(&dsdt.header.signature, b"DSDT");
+}
+
+#[test_case]
+fn platform_hpet() {
+    let hpet = acpi::platform().unwrap().hpet.expect("no HPET");
+    assert_eq!(hpet.address, PhysAddr::new(0xFED0_0000));
+    assert!(hpet.comparators >= 3);
+}
+
+#[test_case]
+fn power_finds_s5() {
+    power::init().unwrap();
+    assert!(power::s5_sleep_type().is_some());
+}
@@ -6,10 +6,14 @@
 
 use core::panic::PanicInfo;
 
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::entry_point;
 use jonathan_os::println;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
     test_main();
     loop {}
 }
@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::panic::PanicInfo;
+
+use jonathan_os::block::RamDisk;
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::fs::ext2::Ext2FileSystem;
+use jonathan_os::fs::{self, FileSystem, FileType, FsError};
+use jonathan_os::memory::BootInfoFrameAllocator;
+use jonathan_os::{allocator, entry_point, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Helpers---
+
+// Made on the host with:
+// mke2fs -t ext2 -b 1024 -g 256 -N 64 -L jonathan_os -d <dir> ext2.img 768
+// <dir> holds hello.txt, big.bin (byte i is (i * 7 + i / 1024) % 251), a few
+// symlinks and dir/ with nested.txt, an empty directory and more symlinks.
+static IMAGE: &[u8] = include_bytes!("fixtures/ext2.img");
+
+const BIG_SIZE: usize = 300 * 1024 + 123;
+
+fn big_byte(index: usize) -> u8 {
+    ((index * 7 + index / 1024) % 251) as u8
+}
+
+fn mount() -> Ext2FileSystem {
+    Ext2FileSystem::mount(Box::new(RamDisk::from_image(IMAGE))).expect("mount failed")
+}
+
+/// Mounts a copy of the image with the superblock field at `offset` set to `value`.
+fn mount_patched(offset: usize, value: u32) -> Result<Ext2FileSystem, FsError> {
+    const SUPERBLOCK_OFFSET: usize = 1024;
+    let mut disk = RamDisk::from_image(IMAGE);
+    let field = SUPERBLOCK_OFFSET + offset;
+    disk.as_bytes_mut()[field..field + 4].copy_from_slice(&value.to_le_bytes());
+    Ext2FileSystem::mount(Box::new(disk))
+}
+
+//  ---Tests---
+
+#[test_case]
+fn reads_superblock() {
+    let fs = mount();
+    assert_eq!(fs.volume_name(), "jonathan_os");
+    assert_eq!(fs.block_size(), 1024);
+}
+
+#[test_case]
+fn reads_small_file() {
+    let mut fs = mount();
+    let mut buf = [0; 64];
+    assert_eq!(fs.read("/hello.txt", 0, &mut buf), Ok(17));
+    assert_eq!(&buf[..17], b"Hello from ext2!\n");
+    assert_eq!(fs.read("/dir/nested.txt", 7, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"file\n");
+}
+
+#[test_case]
+fn lists_directories() {
+    let mut fs = mount();
+    let entries = fs.read_dir("/dir").unwrap();
+    assert_eq!(entries.len(), 4);
+
+    let empty = entries.iter().find(|entry| entry.name == "empty").unwrap();
+    assert_eq!(empty.file_type, FileType::Directory);
+    let link = entries
+        .iter()
+        .find(|entry| entry.name == "up-link")
+        .unwrap();
+    assert_eq!(link.file_type, FileType::Symlink);
+
+    assert!(fs.read_dir("/dir/empty").unwrap().is_empty());
+    assert_eq!(fs.read_dir("/hello.txt"), Err(FsError::NotADirectory));
+    assert_eq!(fs.metadata("/missing"), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn reads_through_indirect_blocks() {
+    let mut fs = mount();
+    assert_eq!(fs.metadata("/big.bin").unwrap().size, BIG_SIZE as u64);
+
+    // 1 KiB blocks: 12 direct, 256 single indirect, then double indirect.
+    let mut buf = vec![0; 4096];
+    for &offset in &[0, 11 * 1024 + 1000, 267 * 1024 + 500, BIG_SIZE - 100] {
+        let read = fs.read("/big.bin", offset as u64, &mut buf).unwrap();
+        assert_eq!(read, (BIG_SIZE - offset).min(buf.len()));
+        for (index, &byte) in buf[..read].iter().enumerate() {
+            assert_eq!(byte, big_byte(offset + index));
+        }
+    }
+}
+
+#[test_case]
+fn rejects_corrupt_superblock() {
+    // first_data_block past blocks_count
+    assert_eq!(mount_patched(20, 1000).err(), Some(FsError::Corrupt));
+    // blocks_per_group bigger than one bitmap block can describe
+    assert_eq!(mount_patched(32, u32::MAX).err(), Some(FsError::Corrupt));
+    // blocks_count so big that rounding up to whole groups overflows
+    assert_eq!(mount_patched(4, u32::MAX).err(), Some(FsError::Corrupt));
+    // blocks_count past the end of the disk
+    assert_eq!(mount_patched(4, 100_000).err(), Some(FsError::Corrupt));
+    // more inodes than the groups hold
+    assert_eq!(mount_patched(0, u32::MAX).err(), Some(FsError::Corrupt));
+}
+
+#[test_case]
+fn refuses_writes() {
+    let mut fs = mount();
+    assert_eq!(fs.write("/hello.txt", 0, b"nope"), Err(FsError::ReadOnly));
+    assert_eq!(fs.create_dir("/new"), Err(FsError::ReadOnly));
+}
+
+#[test_case]
+fn follows_symlinks_in_vfs() {
+    fs::mount("/", Box::new(mount())).unwrap();
+
+    assert_eq!(fs::read_link("/link").unwrap(), "hello.txt");
+    assert_eq!(
+        fs::symlink_metadata("/link").unwrap().file_type,
+        FileType::Symlink
+    );
+    assert_eq!(fs::metadata("/link").unwrap().file_type, FileType::File);
+
+    assert_eq!(fs::read_to_vec("/link").unwrap(), b"Hello from ext2!\n");
+    assert_eq!(
+        fs::read_to_vec("/dir/up-link").unwrap(),
+        b"Hello from ext2!\n"
+    );
+    assert_eq!(fs::read_to_vec("/dir/slow-link").unwrap(), b"nested file\n");
+    assert_eq!(fs::metadata("/loop-a"), Err(FsError::TooManyLinks));
+
+    fs::unmount("/").unwrap();
+}
@@ -0,0 +1,273 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use jonathan_os::block::ata::{AtaDisk, Bus, Drive};
+use jonathan_os::block::{RamDisk, SECTOR_SIZE};
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::fs::fat::{FatFileSystem, FatType};
+use jonathan_os::fs::{self, FileSystem, FileType, FsError};
+use jonathan_os::memory::BootInfoFrameAllocator;
+use jonathan_os::{allocator, entry_point, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Helpers---
+
+const TOTAL_SECTORS: u16 = 6144;
+const FAT_SECTORS: u16 = 24;
+const ROOT_ENTRIES: u16 = 512;
+
+/// Builds an empty 3 MiB FAT16 volume, the same layout mkfs.fat would make.
+fn fat16_disk() -> RamDisk {
+    let mut disk = RamDisk::new(TOTAL_SECTORS as usize);
+    let image = disk.as_bytes_mut();
+
+    image[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    image[3..11].copy_from_slice(b"JONOS   ");
+    image[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    image[13] = 1; // sectors per cluster
+    image[14..16].copy_from_slice(&1u16.to_le_bytes()); // reserved sectors
+    image[16] = 2; // number of FATs
+    image[17..19].copy_from_slice(&ROOT_ENTRIES.to_le_bytes());
+    image[19..21].copy_from_slice(&TOTAL_SECTORS.to_le_bytes());
+    image[21] = 0xF8; // fixed disk
+    image[22..24].copy_from_slice(&FAT_SECTORS.to_le_bytes());
+    image[38] = 0x29;
+    image[43..54].copy_from_slice(b"NO NAME    ");
+    image[54..62].copy_from_slice(b"FAT16   ");
+    image[510] = 0x55;
+    image[511] = 0xAA;
+
+    // The first two FAT entries hold the media byte and the end of chain marker.
+    for fat in 0..2 {
+        let start = (1 + fat * FAT_SECTORS as usize) * SECTOR_SIZE;
+        image[start..start + 4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+    }
+
+    disk
+}
+
+fn mount() -> FatFileSystem {
+    FatFileSystem::mount(Box::new(fat16_disk())).expect("mount failed")
+}
+
+// The runner attaches a FAT32 image made by the fatfs crate on the host as the primary
+// slave, see boot/src/main.rs. It holds hello.txt and docs/A file with a long name.bin,
+// HOST_BIG_SIZE bytes where byte i is i % 251. It's made fresh on every run, and the
+// free cluster count in its FSINFO sector is filled in.
+const HOST_BIG_SIZE: usize = 20 * 1024 + 7;
+const HOST_BIG_PATH: &str = "/docs/A file with a long name.bin";
+
+fn mount_host_image() -> FatFileSystem {
+    let disk = AtaDisk::probe(Bus::Primary, Drive::Slave).expect("no disk on the primary slave");
+    FatFileSystem::mount(Box::new(disk)).expect("mount failed")
+}
+
+fn read_all(fat: &mut FatFileSystem, path: &str) -> Vec<u8> {
+    let size = fat.metadata(path).unwrap().size as usize;
+    let mut data = vec![0; size];
+    assert_eq!(fat.read(path, 0, &mut data), Ok(size));
+    data
+}
+
+//  ---Tests---
+
+#[test_case]
+fn detects_fat16() {
+    let mut fat = mount();
+    assert_eq!(fat.fat_type(), FatType::Fat16);
+    assert!(fat.read_dir("/").unwrap().is_empty());
+}
+
+#[test_case]
+fn rejects_unformatted_disk() {
+    let result = FatFileSystem::mount(Box::new(RamDisk::new(TOTAL_SECTORS as usize)));
+    assert_eq!(result.err(), Some(FsError::Corrupt));
+}
+
+#[test_case]
+fn rejects_bogus_sizes() {
+    // A FAT too small for the clusters.
+    let mut disk = fat16_disk();
+    disk.as_bytes_mut()[22..24].copy_from_slice(&1u16.to_le_bytes());
+    let result = FatFileSystem::mount(Box::new(disk));
+    assert_eq!(result.err(), Some(FsError::Corrupt));
+
+    // More clusters than FAT32 can number.
+    let mut disk = fat16_disk();
+    let image = disk.as_bytes_mut();
+    image[19..21].copy_from_slice(&0u16.to_le_bytes());
+    image[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+    let result = FatFileSystem::mount(Box::new(disk));
+    assert_eq!(result.err(), Some(FsError::Corrupt));
+}
+
+#[test_case]
+fn write_offset_overflow() {
+    let mut fat = mount();
+    fat.create_file("/BIG.TXT").unwrap();
+    assert_eq!(fat.write("/BIG.TXT", u64::MAX, b"x"), Err(FsError::NoSpace));
+}
+
+#[test_case]
+fn write_and_read_back() {
+    let mut fat = mount();
+    fat.create_file("/HELLO.TXT").unwrap();
+    assert_eq!(fat.write("/HELLO.TXT", 0, b"Hello FAT"), Ok(9));
+
+    assert_eq!(read_all(&mut fat, "/hello.txt"), b"Hello FAT");
+    assert_eq!(fat.create_file("/hello.TXT"), Err(FsError::AlreadyExists));
+}
+
+#[test_case]
+fn long_file_names() {
+    let mut fat = mount();
+    let name = "/A rather long file name with spaces.text";
+    fat.create_file(name).unwrap();
+    fat.create_file("/another rather long file name.text").unwrap();
+    fat.write(name, 0, b"long").unwrap();
+
+    let entries = fat.read_dir("/").unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, "A rather long file name with spaces.text");
+    assert_eq!(entries[1].name, "another rather long file name.text");
+    assert_eq!(read_all(&mut fat, name), b"long");
+}
+
+#[test_case]
+fn nested_directories() {
+    let mut fat = mount();
+    fat.create_dir("/dir").unwrap();
+    fat.create_dir("/dir/sub").unwrap();
+    fat.create_file("/dir/sub/file").unwrap();
+    fat.write("/dir/sub/file", 0, b"nested").unwrap();
+
+    assert_eq!(fat.metadata("/dir/sub").unwrap().file_type, FileType::Directory);
+    assert_eq!(fat.read_dir("/dir").unwrap()[0].name, "sub");
+    assert_eq!(read_all(&mut fat, "/dir/sub/../sub/file"), b"nested");
+    assert_eq!(fat.create_file("/dir/sub/file/x"), Err(FsError::NotADirectory));
+}
+
+#[test_case]
+fn directory_grows_past_one_cluster() {
+    let mut fat = mount();
+    fat.create_dir("/many").unwrap();
+
+    // Each name needs a long name entry, so 40 files take 80 slots over several clusters.
+    for index in 0..40u8 {
+        let name = [b'/', b'm', b'a', b'n', b'y', b'/', b'f', b'-', b'a' + index % 26, b'0' + index / 26];
+        fat.create_file(core::str::from_utf8(&name).unwrap()).unwrap();
+    }
+
+    assert_eq!(fat.read_dir("/many").unwrap().len(), 40);
+}
+
+#[test_case]
+fn write_past_end_fills_with_zeros() {
+    let mut fat = mount();
+    fat.create_file("/SPARSE").unwrap();
+    fat.write("/SPARSE", 0, &[0xAA; 100]).unwrap();
+    fat.write("/SPARSE", 1500, &[0xBB; 1000]).unwrap();
+
+    let data = read_all(&mut fat, "/SPARSE");
+    assert_eq!(data.len(), 2500);
+    assert!(data[..100].iter().all(|&byte| byte == 0xAA));
+    assert!(data[100..1500].iter().all(|&byte| byte == 0));
+    assert!(data[1500..].iter().all(|&byte| byte == 0xBB));
+}
+
+#[test_case]
+fn truncate_and_remove() {
+    let mut fat = mount();
+    fat.create_dir("/DIR").unwrap();
+    fat.create_file("/DIR/DATA").unwrap();
+    fat.write("/DIR/DATA", 0, &[1; 4000]).unwrap();
+
+    fat.truncate("/DIR/DATA", 10).unwrap();
+    assert_eq!(read_all(&mut fat, "/DIR/DATA"), [1; 10]);
+    fat.truncate("/DIR/DATA", 20).unwrap();
+    assert_eq!(&read_all(&mut fat, "/DIR/DATA")[10..], [0; 10]);
+
+    assert_eq!(fat.remove("/DIR"), Err(FsError::DirectoryNotEmpty));
+    fat.remove("/DIR/DATA").unwrap();
+    fat.remove("/DIR").unwrap();
+    assert_eq!(fat.metadata("/DIR"), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn mounted_in_vfs() {
+    fs::mount("/", Box::new(mount())).unwrap();
+    fs::create_dir("/host").unwrap();
+    fs::create_file("/host/notes.txt").unwrap();
+    fs::write("/host/notes.txt", 0, b"via the vfs").unwrap();
+
+    assert_eq!(fs::read_to_vec("/host/notes.txt").unwrap(), b"via the vfs");
+    fs::unmount("/").unwrap();
+    assert_eq!(fs::metadata("/host"), Err(FsError::NotMounted));
+}
+
+#[test_case]
+fn reads_host_image_over_ata() {
+    let mut fat = mount_host_image();
+    assert_eq!(fat.fat_type(), FatType::Fat32);
+    assert_eq!(read_all(&mut fat, "/hello.txt"), b"Hello from the host!\n");
+
+    let entries = fat.read_dir("/docs").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "A file with a long name.bin");
+
+    // 512 byte clusters, so this follows a chain of 41 of them.
+    let data = read_all(&mut fat, HOST_BIG_PATH);
+    assert_eq!(data.len(), HOST_BIG_SIZE);
+    assert!(data
+        .iter()
+        .enumerate()
+        .all(|(index, &byte)| byte == (index % 251) as u8));
+}
+
+#[test_case]
+fn fsinfo_tracks_free_clusters() {
+    let mut fat = mount_host_image();
+    let free = fat.free_clusters().expect("no free count in FSINFO");
+
+    fat.create_file("/FSINFO.BIN").unwrap();
+    fat.write("/FSINFO.BIN", 0, &[0x5A; 1500]).unwrap();
+    assert_eq!(fat.free_clusters(), Some(free - 3));
+    fat.sync().unwrap();
+
+    // A fresh mount only knows the count from the FSINFO sector.
+    let mut fat = mount_host_image();
+    assert_eq!(fat.free_clusters(), Some(free - 3));
+    assert_eq!(read_all(&mut fat, "/FSINFO.BIN"), [0x5A; 1500]);
+
+    fat.remove("/FSINFO.BIN").unwrap();
+    fat.sync().unwrap();
+    assert_eq!(mount_host_image().free_clusters(), Some(free));
+}
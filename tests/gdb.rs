@@ -0,0 +1,216 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::entry_point;
+use jonathan_os::gdb::packet::Response;
+use jonathan_os::gdb::{Resume, Stub};
+use jonathan_os::interrupts::trap::{TrapFrame, TRAP_FLAG};
+use jonathan_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    // Memory commands go through the physical memory mapping.
+    unsafe { memory::init(boot.physical_memory_offset) };
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Helpers---
+
+fn run(stub: &mut Stub, packet: &[u8], frame: &mut TrapFrame, response: &mut Response) -> Resume {
+    response.clear();
+    stub.handle_packet(packet, frame, response)
+}
+
+// A command followed by a hex address, then `rest`.
+fn with_address(command: &[u8], address: *const u8, rest: &[u8]) -> Response {
+    let mut packet = Response::new();
+    packet.push(command);
+    for &byte in &(address as u64).to_be_bytes() {
+        packet.push_hex_byte(byte);
+    }
+    packet.push(rest);
+    packet
+}
+
+fn read(bytes: &[u8; 4]) -> [u8; 4] {
+    unsafe { core::ptr::read_volatile(bytes) }
+}
+
+//  ---Tests---
+
+#[test_case]
+fn read_registers() {
+    let mut stub = Stub::new();
+    let mut response = Response::new();
+    let mut frame = TrapFrame {
+        rax: 0x1122_3344_5566_7788,
+        rip: 0xFFFF_8000_0000_1000,
+        ..TrapFrame::default()
+    };
+
+    run(&mut stub, b"g", &mut frame, &mut response);
+    // 17 registers of 8 bytes and 7 of 4, two hex digits a byte.
+    assert_eq!(response.as_bytes().len(), (17 * 8 + 7 * 4) * 2);
+    assert!(response.as_bytes().starts_with(b"8877665544332211"));
+
+    run(&mut stub, b"p10", &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"001000000080ffff");
+}
+
+#[test_case]
+fn write_registers() {
+    let mut stub = Stub::new();
+    let mut response = Response::new();
+    let mut frame = TrapFrame::default();
+
+    run(&mut stub, b"P3=efbeadde00000000", &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"OK");
+    assert_eq!(frame.rdx, 0xDEAD_BEEF);
+
+    // Too short for an 8 byte register, and past the last one.
+    run(&mut stub, b"P3=efbe", &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"E16");
+    run(&mut stub, b"p40", &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"E16");
+    assert_eq!(frame.rdx, 0xDEAD_BEEF);
+}
+
+#[test_case]
+fn read_and_write_memory() {
+    let mut stub = Stub::new();
+    let mut response = Response::new();
+    let mut frame = TrapFrame::default();
+    let bytes = [0x12u8, 0x34, 0x56, 0x78];
+
+    let packet = with_address(b"m", bytes.as_ptr(), b",4");
+    run(&mut stub, packet.as_bytes(), &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"12345678");
+
+    let packet = with_address(b"M", bytes.as_ptr(), b",2:abcd");
+    run(&mut stub, packet.as_bytes(), &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"OK");
+    assert_eq!(read(&bytes), [0xAB, 0xCD, 0x56, 0x78]);
+
+    // Nothing is mapped at the bottom of the address space.
+    run(&mut stub, b"m0,4", &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"E0e");
+}
+
+#[test_case]
+fn software_breakpoints() {
+    let mut stub = Stub::new();
+    let mut response = Response::new();
+    let mut frame = TrapFrame::default();
+    let bytes = [0x90u8; 4];
+
+    let insert = with_address(b"Z0,", bytes[1..].as_ptr(), b",1");
+    run(&mut stub, insert.as_bytes(), &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"OK");
+    assert_eq!(read(&bytes), [0x90, 0xCC, 0x90, 0x90]);
+    assert_eq!(stub.breakpoint_count(), 1);
+
+    let remove = with_address(b"z0,", bytes[1..].as_ptr(), b",1");
+    run(&mut stub, remove.as_bytes(), &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"OK");
+    assert_eq!(read(&bytes), [0x90; 4]);
+    assert_eq!(stub.breakpoint_count(), 0);
+
+    // Hardware breakpoints and watchpoints aren't supported.
+    let watch = with_address(b"Z2,", bytes.as_ptr(), b",4");
+    run(&mut stub, watch.as_bytes(), &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"");
+}
+
+#[test_case]
+fn detach_removes_breakpoints() {
+    let mut stub = Stub::new();
+    let mut response = Response::new();
+    let mut frame = TrapFrame::default();
+    let bytes = [0x90u8; 4];
+
+    let insert = with_address(b"Z0,", bytes.as_ptr(), b",1");
+    run(&mut stub, insert.as_bytes(), &mut frame, &mut response);
+    assert_eq!(read(&bytes)[0], 0xCC);
+
+    assert_eq!(
+        run(&mut stub, b"D", &mut frame, &mut response),
+        Resume::Detach
+    );
+    assert_eq!(response.as_bytes(), b"OK");
+    assert_eq!(read(&bytes), [0x90; 4]);
+    assert_eq!(stub.breakpoint_count(), 0);
+}
+
+#[test_case]
+fn continue_and_step() {
+    let mut stub = Stub::new();
+    let mut response = Response::new();
+    let mut frame = TrapFrame::default();
+
+    assert_eq!(
+        run(&mut stub, b"s", &mut frame, &mut response),
+        Resume::Step
+    );
+    assert_eq!(frame.rflags & TRAP_FLAG, TRAP_FLAG);
+
+    assert_eq!(
+        run(&mut stub, b"c1000", &mut frame, &mut response),
+        Resume::Continue
+    );
+    assert_eq!(frame.rflags & TRAP_FLAG, 0);
+    assert_eq!(frame.rip, 0x1000);
+}
+
+#[test_case]
+fn queries() {
+    let mut stub = Stub::new();
+    let mut response = Response::new();
+    let mut frame = TrapFrame::default();
+
+    run(
+        &mut stub,
+        b"qSupported:multiprocess+;swbreak+",
+        &mut frame,
+        &mut response,
+    );
+    assert_eq!(response.as_bytes(), b"PacketSize=1000");
+    run(&mut stub, b"?", &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"S05");
+    run(&mut stub, b"vMustReplyEmpty", &mut frame, &mut response);
+    assert_eq!(response.as_bytes(), b"");
+}
+
+#[test_case]
+fn traps_without_the_stub() {
+    // Nothing set the stub up, so both exceptions return to where they came from.
+    x86_64::instructions::interrupts::int3();
+
+    // The debug exception after popfq clears the trap flag again.
+    unsafe {
+        core::arch::asm!(
+            "pushfq",
+            "orq $0x100, (%rsp)",
+            "popfq",
+            "nop",
+            options(att_syntax)
+        );
+    }
+    let rflags = x86_64::registers::rflags::read_raw();
+    assert_eq!(rflags & TRAP_FLAG, 0);
+}
@@ -10,20 +10,18 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::panic::PanicInfo;
 
-use bootloader::{BootInfo, entry_point};
-use x86_64::VirtAddr;
-
-use jonathan_os::{allocator, memory};
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::{allocator, entry_point, memory};
 use jonathan_os::allocator::HEAP_SIZE;
 use jonathan_os::memory::BootInfoFrameAllocator;
 
 entry_point!(main);
 
-fn main(boot_info: &'static BootInfo) -> ! {
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
     jonathan_os::init();
-    let phys_memory_offset = boot_info.physical_memory_offset;
-    let mut mapper = unsafe { memory::init(VirtAddr::new(phys_memory_offset)) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
 
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::time::Duration;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::memory::BootInfoFrameAllocator;
+use jonathan_os::time::{self, hpet, timer, Instant, TickSource};
+use jonathan_os::{allocator, apic, entry_point, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+    // The ACPI tables get parsed into heap allocated lists.
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+    apic::init().expect("APIC init failed");
+    hpet::init().expect("HPET init failed");
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Tests---
+
+#[test_case]
+fn counter_runs() {
+    // The spec asks for at least 10 MHz and 3 comparators.
+    assert!(hpet::frequency().unwrap() >= 10_000_000);
+    assert!(hpet::timer_count().unwrap() >= 3);
+
+    let start = hpet::read_counter().unwrap();
+    timer::delay(Duration::from_millis(2));
+    assert!(hpet::read_counter().unwrap() > start);
+}
+
+#[test_case]
+fn counter_agrees_with_tick_clock() {
+    let start_ticks = time::tick_nanos();
+    let start = hpet::nanos().unwrap();
+    timer::delay(Duration::from_millis(100));
+    let hpet_elapsed = hpet::nanos().unwrap() - start;
+    let tick_elapsed = time::tick_nanos() - start_ticks;
+
+    // Within 2%, plus one PIT tick for where in a tick we started.
+    let difference = hpet_elapsed.max(tick_elapsed) - hpet_elapsed.min(tick_elapsed);
+    assert!(difference < tick_elapsed / 50 + 1_000_000);
+}
+
+#[test_case]
+fn periodic_interrupts() {
+    let start = hpet::interrupt_count();
+    let period = hpet::start_periodic(Duration::from_millis(1)).unwrap();
+    assert!(period <= Duration::from_millis(1));
+
+    timer::delay(Duration::from_millis(50));
+    hpet::stop().unwrap();
+    let fired = hpet::interrupt_count() - start;
+    assert!((45..=55).contains(&fired));
+
+    // Nothing more after stopping.
+    timer::delay(Duration::from_millis(5));
+    assert_eq!(hpet::interrupt_count() - start, fired);
+}
+
+#[test_case]
+fn one_shot_interrupt() {
+    let start = hpet::interrupt_count();
+    hpet::start_one_shot(Duration::from_millis(5)).unwrap();
+
+    timer::delay(Duration::from_millis(20));
+    assert_eq!(hpet::interrupt_count() - start, 1);
+    hpet::stop().unwrap();
+}
+
+#[test_case]
+fn drives_clock_instead_of_pit() {
+    hpet::start_periodic(Duration::from_millis(1)).unwrap();
+    time::set_tick_source(TickSource::Hpet);
+
+    let start_ticks = time::ticks();
+    let start = Instant::now();
+    let start_counter = hpet::interrupt_count();
+    while time::ticks() < start_ticks + 20 {
+        x86_64::instructions::hlt();
+    }
+
+    assert!(hpet::interrupt_count() - start_counter >= 20);
+    assert!(start.elapsed() >= Duration::from_millis(19));
+
+    time::set_tick_source(TickSource::Pit);
+    hpet::stop().unwrap();
+}
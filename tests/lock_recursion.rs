@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::sync::TicketLock;
+use jonathan_os::{entry_point, exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static LOCK: TicketLock<()> = TicketLock::new(());
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
+    jonathan_os::init();
+    lock_twice();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// Without recursion detection this spins forever.
+fn lock_twice() {
+    serial_print!("lock_recursion::lock_twice...\t");
+    let _first = LOCK.lock();
+    let _second = LOCK.lock();
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::framebuffer::CONSOLE;
+use jonathan_os::serial::SERIAL1;
+use jonathan_os::vga_buffer::WRITER;
+use jonathan_os::{
+    emergency, emergency_println, entry_point, exit_qemu, serial_print, QemuExitCode,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
+    jonathan_os::init();
+    serial_print!("panic_while_printing::panic_with_print_locks_held...\t");
+    panic_with_print_locks_held();
+    loop {}
+}
+
+// Like a panic in the middle of a println!, the locks are never unlocked.
+fn panic_with_print_locks_held() {
+    let _writer = WRITER.lock();
+    let _console = CONSOLE.get().map(|console| console.lock());
+    let _serial = SERIAL1.lock();
+    panic!("with the print locks held");
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    // With println! this would spin forever and the test would time out.
+    if emergency::begin_panic() {
+        emergency_println!("[ok]");
+    }
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,187 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::panic::PanicInfo;
+use core::time::Duration;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::memory::BootInfoFrameAllocator;
+use jonathan_os::serial::{self, ComPort, DataBits, Parity, SerialConfig, SerialError, StopBits};
+use jonathan_os::shell::{self, LineDiscipline, Shell};
+use jonathan_os::time::Instant;
+use jonathan_os::{allocator, entry_point, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Helpers---
+
+// Waits for the receive interrupt to deliver a byte.
+fn read_byte_within(com: ComPort, timeout: Duration) -> Option<u8> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(byte) = serial::read_byte(com) {
+            return Some(byte);
+        }
+        x86_64::instructions::hlt();
+    }
+    None
+}
+
+fn feed_all(discipline: &mut LineDiscipline, bytes: &[u8], echo: &mut String) -> Option<String> {
+    let mut line = None;
+    for &byte in bytes {
+        if let Some(finished) = discipline.feed(byte, echo) {
+            line = Some(finished);
+        }
+    }
+    line
+}
+
+//  ---Tests---
+
+#[test_case]
+fn com1_receives_through_irq4() {
+    // Loopback keeps the bytes off the test output.
+    serial::SERIAL1.lock().set_loopback(true);
+    for &byte in b"hi" {
+        serial::SERIAL1.lock().send(byte);
+    }
+    let first = read_byte_within(ComPort::Com1, Duration::from_millis(100));
+    let second = read_byte_within(ComPort::Com1, Duration::from_millis(100));
+    serial::SERIAL1.lock().set_loopback(false);
+
+    assert_eq!(first, Some(b'h'));
+    assert_eq!(second, Some(b'i'));
+    assert_eq!(serial::dropped_bytes(ComPort::Com1), 0);
+}
+
+#[test_case]
+fn line_settings() {
+    let config = SerialConfig {
+        baud_rate: 9600,
+        data_bits: DataBits::Seven,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+    };
+    // QEMU doesn't care about the line settings, the output still comes through.
+    serial::init(ComPort::Com1, config).unwrap();
+    assert_eq!(serial::SERIAL1.lock().config(), Some(config));
+
+    serial::init(ComPort::Com1, SerialConfig::default()).unwrap();
+    assert_eq!(
+        serial::SERIAL1.lock().config(),
+        Some(SerialConfig::default())
+    );
+}
+
+#[test_case]
+fn invalid_baud_rate() {
+    for &baud_rate in &[0, 1, 7000, 200_000] {
+        let config = SerialConfig {
+            baud_rate,
+            ..SerialConfig::default()
+        };
+        assert_eq!(
+            serial::init(ComPort::Com2, config),
+            Err(SerialError::InvalidBaudRate(baud_rate))
+        );
+    }
+}
+
+#[test_case]
+fn missing_port() {
+    // QEMU only creates a UART for every -serial option, see boot/src/main.rs.
+    assert_eq!(
+        serial::init(ComPort::Com4, SerialConfig::default()),
+        Err(SerialError::NotPresent)
+    );
+}
+
+#[test_case]
+fn line_discipline_editing() {
+    let mut discipline = LineDiscipline::new(true);
+    let mut echo = String::new();
+
+    let line = feed_all(&mut discipline, b"ecgo\x08\x08ho hi\r\n", &mut echo);
+    assert_eq!(line.as_deref(), Some("echo hi"));
+    assert_eq!(echo, "ecgo\x08 \x08\x08 \x08ho hi\r\n");
+
+    // The LF of the CR LF didn't end a second, empty line.
+    let line = feed_all(&mut discipline, b"a\n", &mut echo);
+    assert_eq!(line.as_deref(), Some("a"));
+}
+
+#[test_case]
+fn line_discipline_discards() {
+    let mut discipline = LineDiscipline::new(false);
+    let mut echo = String::new();
+
+    assert_eq!(feed_all(&mut discipline, b"junk\x03", &mut echo), None);
+    assert_eq!(discipline.pending(), "");
+    assert_eq!(feed_all(&mut discipline, b"more\x15\x1b", &mut echo), None);
+    assert_eq!(discipline.pending(), "");
+    assert_eq!(echo, "");
+}
+
+#[test_case]
+fn shell_commands() {
+    let mut output = String::new();
+    shell::execute("  echo  one two ", &mut output).unwrap();
+    shell::execute("", &mut output).unwrap();
+    shell::execute("frobnicate", &mut output).unwrap();
+    assert_eq!(output, "one two\nunknown command: frobnicate\n");
+}
+
+#[test_case]
+fn shell_params() {
+    let mut output = String::new();
+    shell::execute("params", &mut output).unwrap();
+    assert!(output.contains("log_level=info\n"));
+    assert!(output.contains("on_panic=halt\n"));
+    assert!(output.contains("timer_hz=1000\n"));
+}
+
+#[test_case]
+fn shell_runs_received_line() {
+    let mut shell = Shell::new(ComPort::Com1, String::new());
+
+    // Like com1_receives_through_irq4, the line comes back in through the receive
+    // interrupt and the RX buffer.
+    serial::SERIAL1.lock().set_loopback(true);
+    for &byte in b"echo hj\x08i\r" {
+        serial::SERIAL1.lock().send(byte);
+    }
+    let deadline = Instant::now() + Duration::from_millis(100);
+    while !shell.output().ends_with("hi\n> ") && Instant::now() < deadline {
+        if !shell.process_received() {
+            x86_64::instructions::hlt();
+        }
+    }
+    serial::SERIAL1.lock().set_loopback(false);
+
+    assert_eq!(shell.output(), "> echo hj\x08 \x08i\r\nhi\n> ");
+    assert_eq!(serial::dropped_bytes(ComPort::Com1), 0);
+}
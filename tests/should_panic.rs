@@ -1,26 +1,52 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
 
-use jonathan_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::entry_point;
+use jonathan_os::kernel_test;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    should_fail();
-    serial_println!("[test did not panic]");
-    exit_qemu(QemuExitCode::Failed);
-    loop {}
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
+    jonathan_os::init();
+    test_main();
+    jonathan_os::hlt_loop();
 }
 
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+#[kernel_test]
+#[should_panic]
 fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
     assert_eq!(0, 1);
 }
 
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+#[kernel_test]
+#[should_panic(expected = "index out of bounds")]
+fn out_of_bounds() {
+    let numbers = [1, 2, 3];
+    let index = numbers.len();
+    let _ = numbers[core::hint::black_box(index)];
+}
+
+// Runs between two panicking tests, the runner got back on its feet.
+#[test_case]
+fn plain_test_case() {
+    let numbers = [1, 2, 3];
+    assert_eq!(numbers.iter().sum::<i32>(), 6);
+}
+
+#[kernel_test]
+#[ignore]
+fn ignored() {
+    panic!("ignored tests don't run");
 }
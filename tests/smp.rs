@@ -0,0 +1,173 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use core::ptr;
+
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::memory::{tlb, BootInfoFrameAllocator};
+use jonathan_os::{acpi, allocator, apic, entry_point, memory, smp};
+
+// QEMU runs the tests with -smp 4, see boot/src/main.rs.
+const EXPECTED_CPUS: usize = 4;
+
+// Somewhere nothing else is mapped.
+const TEST_PAGE: u64 = 0x5555_0000_0000;
+
+// The tests need to change mappings too.
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+    apic::init().expect("APIC init failed");
+    smp::init(&mut mapper, &mut frame_allocator).expect("SMP init failed");
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Tests---
+
+#[test_case]
+fn all_cpus_checked_in() {
+    let usable = acpi::platform().unwrap().usable_processors().count();
+    assert_eq!(usable, EXPECTED_CPUS);
+    assert_eq!(smp::cpu_count(), EXPECTED_CPUS);
+
+    for index in 0..smp::cpu_count() {
+        let cpu = smp::cpu(index).unwrap();
+        assert_eq!(cpu.index(), index);
+        assert!(cpu.is_online());
+    }
+}
+
+#[test_case]
+fn apic_ids_are_unique() {
+    for a in 0..smp::cpu_count() {
+        for b in a + 1..smp::cpu_count() {
+            assert_ne!(
+                smp::cpu(a).unwrap().apic_id(),
+                smp::cpu(b).unwrap().apic_id()
+            );
+        }
+    }
+}
+
+#[test_case]
+fn bsp_per_cpu_data() {
+    let current = smp::current().unwrap();
+    assert_eq!(current.index(), 0);
+    assert_eq!(smp::cpu_index(), 0);
+    assert_eq!(current.apic_id(), apic::local_apic_id().unwrap());
+}
+
+#[test_case]
+fn ap_stacks_have_guard_pages() {
+    for index in 1..smp::cpu_count() {
+        let cpu = smp::cpu(index).unwrap();
+        for stack in [cpu.stack(), cpu.double_fault_stack()].iter() {
+            assert!(stack.start.as_u64() >= smp::AP_STACKS_START);
+            assert!(memory::translate(stack.start).is_some());
+            assert!(memory::translate(stack.end - 1u64).is_some());
+            assert_eq!(memory::translate(stack.start - 1u64), None);
+        }
+    }
+}
+
+#[test_case]
+fn trampoline_is_unmapped() {
+    let memory = MEMORY.lock();
+    let (_, frames) = memory.as_ref().unwrap();
+    let frame = frames.low_memory_frame().unwrap();
+    let identity = VirtAddr::new(frame.start_address().as_u64());
+    assert_eq!(memory::translate(identity), None);
+}
+
+// Maps the test page, writes to it so every CPU could cache it, and returns it.
+fn map_test_page(mapper: &mut OffsetPageTable, frames: &mut BootInfoFrameAllocator) -> Page {
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE));
+    let frame = FrameAllocator::<Size4KiB>::allocate_frame(frames).unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.map_to(page, frame, flags, frames).unwrap().flush() };
+    unsafe { ptr::write_volatile(page.start_address().as_mut_ptr::<u64>(), 42) };
+    page
+}
+
+fn shootdowns_per_cpu() -> [u64; EXPECTED_CPUS] {
+    let mut counts = [0; EXPECTED_CPUS];
+    for (index, count) in counts.iter_mut().enumerate() {
+        *count = smp::cpu(index).unwrap().tlb_shootdowns();
+    }
+    counts
+}
+
+#[test_case]
+fn unmap_shoots_down_every_other_cpu() {
+    let mut memory = MEMORY.lock();
+    let (mapper, frames) = memory.as_mut().unwrap();
+    let page = map_test_page(mapper, frames);
+
+    let before = tlb::stats();
+    let handled_before = shootdowns_per_cpu();
+    memory::unmap_page(mapper, page).unwrap();
+    let after = tlb::stats();
+    let handled_after = shootdowns_per_cpu();
+
+    assert_eq!(after.shootdowns, before.shootdowns + 1);
+    assert_eq!(after.remote_shootdowns, before.remote_shootdowns + 1);
+    assert_eq!(
+        after.interrupted_cpus,
+        before.interrupted_cpus + EXPECTED_CPUS as u64 - 1
+    );
+    assert_eq!(after.pages, before.pages + 1);
+
+    // The BSP flushed its own TLB, every AP got an IPI and acknowledged it.
+    assert_eq!(handled_after[0], handled_before[0]);
+    for (after, before) in handled_after.iter().zip(&handled_before).skip(1) {
+        assert_eq!(*after, before + 1);
+    }
+}
+
+#[test_case]
+fn flag_changes_shoot_down() {
+    let mut memory = MEMORY.lock();
+    let (mapper, frames) = memory.as_mut().unwrap();
+    let page = map_test_page(mapper, frames);
+
+    let before = tlb::stats();
+    unsafe { memory::update_flags(mapper, page, PageTableFlags::PRESENT).unwrap() };
+    assert_eq!(tlb::stats().remote_shootdowns, before.remote_shootdowns + 1);
+
+    memory::unmap_page(mapper, page).unwrap();
+}
+
+#[test_case]
+fn shootdown_of_a_range() {
+    let start = Page::containing_address(VirtAddr::new(TEST_PAGE));
+    let before = tlb::stats();
+    tlb::shootdown(Page::range(start, start + 16));
+    assert_eq!(tlb::stats().pages, before.pages + 16);
+}
@@ -10,11 +10,14 @@ use core::panic::PanicInfo;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+use jonathan_os::boot::{self, BootInfo};
 use jonathan_os::gdt::DOUBLE_FAULT_IST_INDEX;
-use jonathan_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use jonathan_os::{entry_point, exit_qemu, serial_print, serial_println, QemuExitCode};
 
-#[no_mangle]
-extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
     serial_print!("stack_overflow::stack_overflow...\t");
 
     jonathan_os::gdt::init();
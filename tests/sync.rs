@@ -0,0 +1,239 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::memory::BootInfoFrameAllocator;
+use jonathan_os::sync::{Condvar, Mutex, RwLock, Semaphore, TicketLock};
+use jonathan_os::{allocator, entry_point, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Helpers---
+
+// How often each waiter was woken. A waker's data pointer is the index of its waiter.
+static WAKES: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+fn waker(waiter: usize) -> Waker {
+    fn clone(waiter: *const ()) -> RawWaker {
+        RawWaker::new(waiter, &VTABLE)
+    }
+    fn wake(waiter: *const ()) {
+        WAKES[waiter as usize].fetch_add(1, Ordering::SeqCst);
+    }
+    fn drop(_: *const ()) {}
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+    unsafe { Waker::from_raw(RawWaker::new(waiter as *const (), &VTABLE)) }
+}
+
+fn wakes() -> usize {
+    wakes_of(0)
+}
+
+fn wakes_of(waiter: usize) -> usize {
+    WAKES[waiter].load(Ordering::SeqCst)
+}
+
+fn poll<F: Future>(future: &mut Pin<Box<F>>) -> Poll<F::Output> {
+    poll_as(0, future)
+}
+
+// Polls `future` as if it belonged to another task.
+fn poll_as<F: Future>(waiter: usize, future: &mut Pin<Box<F>>) -> Poll<F::Output> {
+    let waker = waker(waiter);
+    let mut context = Context::from_waker(&waker);
+    future.as_mut().poll(&mut context)
+}
+
+//  ---Tests---
+
+#[test_case]
+fn ticket_lock() {
+    let lock = TicketLock::new(0);
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(lock.is_locked());
+        assert_eq!(lock.waiting(), 0);
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.try_lock().unwrap(), 1);
+}
+
+#[test_case]
+fn rwlock_readers_share() {
+    let lock = RwLock::new(5);
+    let first = lock.read();
+    let second = lock.read();
+    assert_eq!(*first + *second, 10);
+    assert_eq!(lock.reader_count(), 2);
+    assert!(lock.try_write().is_none());
+
+    drop(first);
+    drop(second);
+    *lock.write() += 1;
+    assert_eq!(*lock.read(), 6);
+}
+
+#[test_case]
+fn rwlock_writer_excludes_readers() {
+    let lock = RwLock::new(());
+    let writer = lock.write();
+    assert!(lock.is_write_locked());
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+    drop(writer);
+    assert!(lock.try_read().is_some());
+}
+
+#[test_case]
+fn mutex_wakes_waiter_on_unlock() {
+    let mutex = Mutex::new(0);
+    let guard = mutex.try_lock().unwrap();
+
+    let mut waiting = Box::pin(mutex.lock());
+    assert!(poll(&mut waiting).is_pending());
+    // Polled again, but only queued once.
+    assert!(poll(&mut waiting).is_pending());
+
+    let before = wakes();
+    drop(guard);
+    assert_eq!(wakes(), before + 1);
+
+    match poll(&mut waiting) {
+        Poll::Ready(mut guard) => *guard += 1,
+        Poll::Pending => panic!("mutex still locked after unlock"),
+    }
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+}
+
+#[test_case]
+fn mutex_wakes_second_waiter() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+
+    let mut first = Box::pin(mutex.lock());
+    let mut second = Box::pin(mutex.lock());
+    assert!(poll_as(0, &mut first).is_pending());
+    assert!(poll_as(1, &mut second).is_pending());
+
+    let (first_wakes, second_wakes) = (wakes_of(0), wakes_of(1));
+    drop(guard);
+    assert_eq!(wakes_of(0), first_wakes + 1);
+
+    // The second waiter gets polled before the first and takes the mutex. It must not
+    // stay queued, or the next unlock would go to it instead of the first.
+    let guard = match poll_as(1, &mut second) {
+        Poll::Ready(guard) => guard,
+        Poll::Pending => panic!("mutex still locked after unlock"),
+    };
+    assert!(poll_as(0, &mut first).is_pending());
+    drop(guard);
+    assert_eq!(wakes_of(0), first_wakes + 2);
+    assert_eq!(wakes_of(1), second_wakes);
+    assert!(poll_as(0, &mut first).is_ready());
+}
+
+#[test_case]
+fn dropped_waiter_passes_wakeup_on() {
+    let semaphore = Semaphore::new(0);
+    let mut first = Box::pin(semaphore.acquire());
+    let mut second = Box::pin(semaphore.acquire());
+    assert!(poll_as(0, &mut first).is_pending());
+    assert!(poll_as(1, &mut second).is_pending());
+
+    let second_wakes = wakes_of(1);
+    semaphore.release();
+    // Woken, but gone before it took the permit.
+    drop(first);
+    assert_eq!(wakes_of(1), second_wakes + 1);
+    assert!(poll_as(1, &mut second).is_ready());
+    assert_eq!(semaphore.available_permits(), 0);
+}
+
+#[test_case]
+fn semaphore_limits_permits() {
+    let semaphore = Semaphore::new(2);
+    assert!(semaphore.try_acquire());
+    assert!(semaphore.try_acquire());
+    assert!(!semaphore.try_acquire());
+
+    let mut waiting = Box::pin(semaphore.acquire());
+    assert!(poll(&mut waiting).is_pending());
+
+    let before = wakes();
+    semaphore.release();
+    assert_eq!(wakes(), before + 1);
+    assert!(poll(&mut waiting).is_ready());
+    assert_eq!(semaphore.available_permits(), 0);
+}
+
+#[test_case]
+fn condvar_waits_for_notify() {
+    let mutex = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    let guard = mutex.try_lock().unwrap();
+    let mut waiting = Box::pin(condvar.wait(guard));
+    assert!(poll(&mut waiting).is_pending());
+
+    // wait() gave the mutex up.
+    *mutex.try_lock().unwrap() = true;
+
+    let before = wakes();
+    condvar.notify_one();
+    assert_eq!(wakes(), before + 1);
+
+    match poll(&mut waiting) {
+        Poll::Ready(guard) => assert!(*guard),
+        Poll::Pending => panic!("condvar not notified"),
+    }
+    assert!(!mutex.is_locked());
+}
+
+#[test_case]
+fn condvar_relocks_after_notify() {
+    let mutex = Mutex::new(());
+    let condvar = Condvar::new();
+
+    let mut waiting = Box::pin(condvar.wait(mutex.try_lock().unwrap()));
+    assert!(poll(&mut waiting).is_pending());
+
+    // Notified, but the mutex is taken, so it waits for that instead.
+    let other = mutex.try_lock().unwrap();
+    condvar.notify_all();
+    assert!(poll(&mut waiting).is_pending());
+
+    drop(other);
+    assert!(poll(&mut waiting).is_ready());
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::entry_point;
+use jonathan_os::testing::catch::Text;
+use jonathan_os::testing::options::Options;
+use jonathan_os::testing::{self, ShouldPanic, Summary, Test, Testable};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
+    jonathan_os::init();
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+fn passes() {}
+
+fn fails() {
+    assert_eq!(1, 2);
+}
+
+// The inner runs report into this instead of the serial port, where their "[failed]"
+// lines and summaries would read like this binary's own.
+type Report = Text<8192>;
+
+fn run(tests: &[&dyn Testable], options: &Options) -> (Summary, Report) {
+    let mut report = Report::new();
+    let summary = testing::run_tests_with_output(tests, options, &mut report);
+    (summary, report)
+}
+
+const fn test(name: &'static str, function: fn(), should_panic: ShouldPanic) -> Test {
+    Test {
+        name,
+        function,
+        should_panic,
+        ignore: false,
+    }
+}
+
+static FAILS: Test = test("fails", fails, ShouldPanic::No);
+static PASSES: Test = test("passes", passes, ShouldPanic::No);
+static DOES_NOT_PANIC: Test = test("does_not_panic", passes, ShouldPanic::Yes);
+static WRONG_MESSAGE: Test = test(
+    "wrong_message",
+    fails,
+    ShouldPanic::WithMessage("out of memory"),
+);
+static IGNORED: Test = Test {
+    ignore: true,
+    ..test("ignored", fails, ShouldPanic::No)
+};
+
+#[test_case]
+fn keeps_going_after_failures() {
+    let tests: [&dyn Testable; 5] = [&FAILS, &PASSES, &DOES_NOT_PANIC, &WRONG_MESSAGE, &IGNORED];
+    let (summary, report) = run(&tests, &Options::new());
+    let report = report.as_str();
+    assert!(report.starts_with("Running 5 tests\nfails...\t[failed]"));
+    assert!(report.contains("passes...\t[ok]"));
+    assert_eq!(
+        summary,
+        Summary {
+            passed: 1,
+            failed: 3,
+            ignored: 1,
+            filtered_out: 0,
+        }
+    );
+}
+
+#[test_case]
+fn should_panic_passes() {
+    static PANICS: Test = test("panics", fails, ShouldPanic::WithMessage("assertion"));
+    let tests: [&dyn Testable; 1] = [&PANICS];
+    assert_eq!(run(&tests, &Options::new()).0.passed, 1);
+}
+
+#[test_case]
+fn filters() {
+    let tests: [&dyn Testable; 4] = [&FAILS, &PASSES, &DOES_NOT_PANIC, &IGNORED];
+
+    let options = Options::parse("--exact passes").unwrap();
+    let (summary, _) = run(&tests, &options);
+    assert_eq!((summary.passed, summary.filtered_out), (1, 3));
+
+    // Substrings, and the output format doesn't change what runs.
+    let options = Options::parse("--format=json pass panic ignored").unwrap();
+    let (summary, _) = run(&tests, &options);
+    assert_eq!((summary.passed, summary.failed, summary.ignored), (1, 1, 1));
+    assert_eq!(summary.filtered_out, 1);
+}
+
@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::time::Duration;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::entry_point;
+use jonathan_os::time::{self, pit, rtc, tsc, Instant, SystemTime, TickSource};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    boot::take(boot_info);
+    jonathan_os::init();
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Helpers---
+
+// 2020-01-01, anything earlier means the RTC was read wrong.
+const SANE_TIMESTAMP: u64 = 1_577_836_800;
+
+fn wait_for_ticks(count: u64) {
+    let target = time::ticks() + count;
+    while time::ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+//  ---Tests---
+
+#[test_case]
+fn rtc_date_is_sane() {
+    let date = rtc::read();
+    assert!(date.year >= 2020);
+    assert!((1..=12).contains(&date.month));
+    assert!((1..=31).contains(&date.day));
+    assert!(date.hour < 24 && date.minute < 60 && date.second < 60);
+}
+
+#[test_case]
+fn wall_clock_matches_rtc() {
+    let now = time::now().unix_timestamp();
+    let rtc = rtc::read().to_unix_timestamp().unwrap();
+    assert!(now >= SANE_TIMESTAMP);
+    // The tick based clock drifts a little from the RTC, but not by much this early.
+    assert!(now.max(rtc) - now.min(rtc) <= 2);
+    assert!(time::now() >= SystemTime::UNIX_EPOCH);
+}
+
+#[test_case]
+fn monotonic_clock_advances_with_pit() {
+    // 100 ms worth of ticks at whatever rate time::init set. Two extra make up for
+    // starting partway through a tick and for the rounded frequency.
+    let ticks = u64::from(pit::frequency()) / 10 + 2;
+    let start = Instant::now();
+    wait_for_ticks(ticks);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert!(Instant::now() > start);
+}
+
+#[test_case]
+fn rtc_periodic_interrupt_drives_clock() {
+    assert_eq!(rtc::enable_periodic_interrupt(6), 1024);
+    time::set_tick_source(TickSource::Rtc);
+
+    let start = Instant::now();
+    wait_for_ticks(64);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(60) && elapsed <= Duration::from_millis(70));
+
+    time::set_tick_source(TickSource::Pit);
+    rtc::disable_periodic_interrupt();
+}
+
+#[test_case]
+fn tsc_is_calibrated() {
+    let frequency = tsc::frequency();
+    assert!(frequency > 0);
+    let second = tsc::cycles_to_nanos(frequency);
+    assert!((999_999_999..=1_000_000_000).contains(&second));
+
+    // The host picks the TSC rate, so there is no range worth checking. Count cycles over
+    // PIT ticks instead. A loaded host or TCG can delay ticks, hence the 10%.
+    wait_for_ticks(1);
+    let start_ticks = time::ticks();
+    let start_cycles = tsc::read();
+    wait_for_ticks(100);
+    let cycles = tsc::read() - start_cycles;
+    let ticks = time::ticks() - start_ticks;
+
+    let expected = frequency * ticks / pit::frequency() as u64;
+    let difference = cycles.max(expected) - cycles.min(expected);
+    assert!(difference < expected / 10);
+}
+
+#[test_case]
+fn tsc_advances_with_ticks() {
+    wait_for_ticks(1);
+    let start_ticks = time::ticks();
+    let start_nanos = tsc::nanos();
+    wait_for_ticks(200);
+    let tsc_elapsed = tsc::nanos() - start_nanos;
+    let ticks = time::ticks() - start_ticks;
+
+    // Both clocks should agree to within 2%.
+    let tick_elapsed = ticks * 1_000_000_000 / pit::frequency() as u64;
+    let difference = tsc_elapsed.max(tick_elapsed) - tsc_elapsed.min(tick_elapsed);
+    assert!(difference < tick_elapsed / 50);
+}
+
+#[test_case]
+fn monotonic_clock_never_goes_back() {
+    let mut last = time::monotonic_nanos();
+    for _ in 0..10_000 {
+        let now = time::monotonic_nanos();
+        assert!(now >= last);
+        last = now;
+    }
+}
@@ -0,0 +1,229 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jonathan_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+
+use jonathan_os::boot::{self, BootInfo};
+use jonathan_os::memory::BootInfoFrameAllocator;
+use jonathan_os::time::timer::{self, Elapsed, TimerId};
+use jonathan_os::time::{self, pit, Instant};
+use jonathan_os::{allocator, entry_point, memory};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    let boot = boot::take(boot_info);
+    jonathan_os::init();
+    let mut mapper = unsafe { memory::init(boot.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot.memory_regions) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+
+    test_main();
+    jonathan_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    jonathan_os::test_panic_handler(info)
+}
+
+//  ---Helpers---
+
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+fn waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WOKEN.store(true, Ordering::SeqCst);
+    }
+    fn drop(_: *const ()) {}
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+    unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+}
+
+/// Polls `future` to completion, halting until woken. Returns the output and the number of polls.
+fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+    let mut future = Box::pin(future);
+    let waker = waker();
+    let mut context = Context::from_waker(&waker);
+    let mut polls = 0;
+
+    loop {
+        WOKEN.store(false, Ordering::SeqCst);
+        polls += 1;
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return (output, polls);
+        }
+        while !WOKEN.load(Ordering::SeqCst) {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+// Never completes.
+struct Forever;
+
+impl Future for Forever {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        Poll::Pending
+    }
+}
+
+//  ---Tests---
+
+#[test_case]
+fn pit_runs_at_default_frequency() {
+    assert_eq!(pit::frequency(), pit::DEFAULT_FREQUENCY);
+
+    let start = time::ticks();
+    timer::delay(Duration::from_millis(50));
+    let ticks = time::ticks() - start;
+    assert!((50..=52).contains(&ticks));
+}
+
+#[test_case]
+fn one_shot_fires_once() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let id = timer::add_one_shot(Duration::from_millis(20), move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    assert!(timer::is_pending(id));
+    timer::delay(Duration::from_millis(10));
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+    timer::delay(Duration::from_millis(30));
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    assert!(!timer::is_pending(id));
+    assert!(!timer::cancel(id));
+}
+
+#[test_case]
+fn periodic_fires_until_cancelled() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let id = timer::add_periodic(Duration::from_millis(5), move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    timer::delay(Duration::from_millis(52));
+    assert!(timer::cancel(id));
+    let fired = count.load(Ordering::SeqCst);
+    assert!((9..=11).contains(&fired));
+
+    timer::delay(Duration::from_millis(20));
+    assert_eq!(count.load(Ordering::SeqCst), fired);
+}
+
+#[test_case]
+fn cancelled_timer_never_fires() {
+    let fired = Arc::new(AtomicBool::new(false));
+    let flag = fired.clone();
+    let id = timer::add_one_shot(Duration::from_millis(10), move || {
+        flag.store(true, Ordering::SeqCst);
+    });
+
+    assert!(timer::cancel(id));
+    assert!(!timer::cancel(id));
+    timer::delay(Duration::from_millis(20));
+    assert!(!fired.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn callbacks_cancel_timers() {
+    // Cancels itself on the third run.
+    let runs = Arc::new(AtomicUsize::new(0));
+    let own_id = Arc::new(Mutex::new(None::<TimerId>));
+    let (counter, id) = (runs.clone(), own_id.clone());
+    let periodic = timer::add_periodic(Duration::from_millis(2), move || {
+        if counter.fetch_add(1, Ordering::SeqCst) == 2 {
+            assert!(timer::cancel(id.lock().unwrap()));
+        }
+    });
+    without_interrupts(|| *own_id.lock() = Some(periodic));
+
+    // Due together, whichever runs first cancels the other. That one may already be
+    // queued to run in the same tick.
+    let fired = Arc::new(AtomicUsize::new(0));
+    let ids = Arc::new(Mutex::new([None::<TimerId>; 2]));
+    let mut timers = [None; 2];
+    for (index, timer) in timers.iter_mut().enumerate() {
+        let (fired, ids) = (fired.clone(), ids.clone());
+        *timer = Some(timer::add_one_shot(Duration::from_millis(10), move || {
+            fired.fetch_add(1, Ordering::SeqCst);
+            assert!(timer::cancel(ids.lock()[1 - index].unwrap()));
+        }));
+    }
+    without_interrupts(|| *ids.lock() = timers);
+
+    timer::delay(Duration::from_millis(30));
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert!(!timer::is_pending(periodic));
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+    assert!(!timers.iter().any(|timer| timer::is_pending(timer.unwrap())));
+}
+
+#[test_case]
+fn timers_fire_in_order_across_levels() {
+    // 3 ms stays on the first level, 200 ms has to be cascaded down from the second.
+    let order = Arc::new(AtomicUsize::new(0));
+    let first = order.clone();
+    let second = order.clone();
+    timer::add_one_shot(Duration::from_millis(200), move || {
+        second
+            .compare_exchange(1, 2, Ordering::SeqCst, Ordering::SeqCst)
+            .unwrap();
+    });
+    timer::add_one_shot(Duration::from_millis(3), move || {
+        first
+            .compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+            .unwrap();
+    });
+
+    let start = Instant::now();
+    while order.load(Ordering::SeqCst) != 2 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200) && elapsed <= Duration::from_millis(202));
+}
+
+#[test_case]
+fn sleep_future_wakes_after_duration() {
+    let start = Instant::now();
+    let ((), polls) = block_on(timer::sleep(Duration::from_millis(30)));
+    assert!(start.elapsed() >= Duration::from_millis(30));
+    assert_eq!(polls, 2);
+}
+
+#[test_case]
+fn timeout_gives_up() {
+    let (result, _) = block_on(timer::timeout(Duration::from_millis(10), Forever));
+    assert_eq!(result, Err(Elapsed));
+
+    let (result, _) = block_on(timer::timeout(
+        Duration::from_millis(100),
+        timer::sleep(Duration::from_millis(5)),
+    ));
+    assert_eq!(result, Ok(()));
+}